@@ -9,6 +9,7 @@ print("<html><body>")
 print("<h1>CGI Test - Python</h1>")
 print(f"<p>Method: {os.environ.get('REQUEST_METHOD', 'N/A')}</p>")
 print(f"<p>Query: {os.environ.get('QUERY_STRING', 'N/A')}</p>")
+print(f"<p>Script: {os.environ.get('SCRIPT_NAME', 'N/A')}</p>")
 print(f"<p>Path: {os.environ.get('PATH_INFO', 'N/A')}</p>")
 
 # Lire POST data si présent
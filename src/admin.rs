@@ -0,0 +1,488 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use crate::check::check_route;
+use crate::config::{Config, Route, parse_route_entry};
+use crate::metrics;
+use crate::models::{HttpResponseCommon, SimpleResponse, SseEvent, SseResponse};
+use crate::request::HttpRequest;
+use crate::response::HttpResponseBuilder;
+use crate::utils::{HttpMethod, json};
+
+/// Methods refused while read-only mode is on
+const MUTATING_METHODS: [&str; 7] = ["POST", "PUT", "DELETE", "PATCH", "MKCOL", "MOVE", "COPY"];
+
+/// Comment sent on an idle event stream so proxies keep it open
+const EVENTS_HEARTBEAT: Duration = Duration::from_secs(15);
+
+/// Runtime switches, shared by every worker
+static READ_ONLY: AtomicBool = AtomicBool::new(false);
+static SETTINGS: OnceLock<AdminSettings> = OnceLock::new();
+static LISTENERS: Mutex<BTreeMap<String, ListenerState>> = Mutex::new(BTreeMap::new());
+
+/// The loaded config with its routes as changed through the admin API.
+/// Workers copy the routes whenever the generation moves.
+static ROUTE_TABLE: Mutex<Option<Config>> = Mutex::new(None);
+static ROUTES_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Live connections of each worker, as of its last snapshot
+static CONNECTIONS: Mutex<BTreeMap<usize, (Instant, Vec<ConnectionInfo>)>> = Mutex::new(BTreeMap::new());
+
+/// One live connection, as published by its worker for `/connections`
+pub struct ConnectionInfo {
+    pub token: usize,
+    pub peer: Option<SocketAddr>,
+    pub state: &'static str,
+    pub tls: bool,
+    pub keep_alive: bool,       // The connection stays open after the current response
+    pub upgrade: Option<String>, // Protocol the client asked to switch to, never granted
+    pub request_bytes: usize,   // Held in the request parser
+    pub read_buffer_bytes: usize,
+    pub response_bytes: usize,  // Response data not written yet
+    pub route: Option<String>,  // Server name and route path of the request in flight
+    pub requests: usize,
+    pub age: Duration,
+}
+
+/// Health of a host:port listener, as reported on the status endpoint
+#[derive(Debug, Clone)]
+pub enum ListenerState {
+    Listening,
+    Retrying { error: String, failures: u32 },
+    Failed { error: String },
+}
+
+struct AdminSettings {
+    path: Option<String>,
+    allow: Vec<IpAddr>,
+}
+
+/// Seed the runtime switches from the loaded config, once per process
+pub fn init(config: &Config) {
+    READ_ONLY.store(config.read_only, Ordering::Relaxed);
+    let _ = SETTINGS.set(AdminSettings {
+        path: config.admin_path.clone(),
+        allow: config.admin_allow.clone(),
+    });
+    let mut table = ROUTE_TABLE.lock().unwrap_or_else(|e| e.into_inner());
+    table.get_or_insert_with(|| config.clone());
+}
+
+pub fn read_only() -> bool {
+    READ_ONLY.load(Ordering::Relaxed)
+}
+
+pub fn set_read_only(on: bool) {
+    READ_ONLY.store(on, Ordering::Relaxed);
+}
+
+/// Whether the admin API is served, so workers know to publish for it
+pub fn enabled() -> bool {
+    SETTINGS.get().is_some_and(|settings| settings.path.is_some())
+}
+
+/// Replace the connection snapshot of `worker`
+pub fn publish_connections(worker: usize, connections: Vec<ConnectionInfo>) {
+    let mut published = CONNECTIONS.lock().unwrap_or_else(|e| e.into_inner());
+    published.insert(worker, (Instant::now(), connections));
+}
+
+pub fn record_listener(address: &str, state: ListenerState) {
+    let mut listeners = LISTENERS.lock().unwrap_or_else(|e| e.into_inner());
+    listeners.insert(address.to_string(), state);
+}
+
+/// The routes of every server, in config order, if they changed since
+/// generation `seen`, with the generation they are at
+pub fn route_changes(seen: u64) -> Option<(u64, Vec<Vec<Route>>)> {
+    if ROUTES_GENERATION.load(Ordering::Acquire) == seen {
+        return None;
+    }
+    let table = ROUTE_TABLE.lock().unwrap_or_else(|e| e.into_inner());
+    let config = table.as_ref()?;
+    let routes = config.servers.iter().map(|server| server.routes.clone()).collect();
+    Some((ROUTES_GENERATION.load(Ordering::Acquire), routes))
+}
+
+pub fn is_mutating(method: &HttpMethod) -> bool {
+    MUTATING_METHODS.contains(&method.to_str())
+}
+
+/// Whether the request must be refused because the site or route is frozen
+pub fn rejects(route: &Route, method: &HttpMethod) -> bool {
+    is_mutating(method) && (read_only() || route.read_only == Some(true))
+}
+
+/// Methods still allowed on a frozen route, for the 405 Allow header
+pub fn read_only_methods(route: &Route) -> Vec<String> {
+    route
+        .methods
+        .iter()
+        .filter(|m| !MUTATING_METHODS.contains(&m.as_str()))
+        .cloned()
+        .collect()
+}
+
+/// Whether `peer` is one of the `admin_allow` addresses, trusted with the
+/// admin API and the cache controls
+pub fn privileged(peer: Option<IpAddr>) -> bool {
+    let Some(settings) = SETTINGS.get() else {
+        return false;
+    };
+    peer.is_some_and(|ip| settings.allow.contains(&ip.to_canonical()))
+}
+
+/// Whether `path` is under the admin path, so `handle` will answer it
+pub fn is_admin_path(path: &str) -> bool {
+    admin_endpoint(path).is_some()
+}
+
+/// The part of `path` after the admin path
+fn admin_endpoint(path: &str) -> Option<&str> {
+    let base = SETTINGS.get()?.path.as_deref()?;
+    let endpoint = path.strip_prefix(base)?;
+    if !endpoint.is_empty() && !endpoint.starts_with('/') {
+        return None;
+    }
+    Some(endpoint)
+}
+
+/// Answer requests under the admin path, or None for regular traffic.
+///
+/// - `GET {admin_path}/read_only` reports `on` or `off`
+/// - `PUT|POST {admin_path}/read_only` with body `on`/`off` flips the switch
+/// - `GET {admin_path}/status` reports the switches and listener health as JSON
+/// - `GET {admin_path}/metrics` exports event loop latency for Prometheus
+/// - `GET {admin_path}/events` streams status changes as Server-Sent Events
+/// - `GET {admin_path}/connections` lists live connections by token with
+///   their peer, state, keep-alive/TLS/upgrade, buffered bytes and route
+/// - `GET {admin_path}/routes[/{server_name}]` lists the routes as config YAML
+/// - `PUT|POST {admin_path}/routes/{server_name}` with one route entry as
+///   body adds it, or replaces the route with the same path
+/// - `GET|DELETE {admin_path}/routes/{server_name}{route_path}` shows or
+///   removes one route
+pub fn handle(request: &HttpRequest, peer: Option<IpAddr>) -> Option<Box<dyn HttpResponseCommon>> {
+    let endpoint = admin_endpoint(&request.path)?;
+
+    if !privileged(peer) {
+        return Some(Box::new(SimpleResponse::new(
+            HttpResponseBuilder::new(403, "Forbidden")
+                .body(b"Admin API not allowed from this address\n".to_vec())
+                .build(),
+        )));
+    }
+
+    if endpoint == "/events" && request.method == HttpMethod::GET {
+        return Some(Box::new(status_events()));
+    }
+    Some(Box::new(SimpleResponse::new(respond(endpoint, request))))
+}
+
+fn respond(endpoint: &str, request: &HttpRequest) -> Vec<u8> {
+    if let Some(target) = endpoint.strip_prefix("/routes")
+        && (target.is_empty() || target.starts_with('/'))
+    {
+        return respond_routes(target, request);
+    }
+    match (endpoint, request.method.to_str()) {
+        ("/status", "GET") => HttpResponseBuilder::ok()
+            .header("Content-Type", "application/json")
+            .body(status_json().into_bytes())
+            .build(),
+        ("/connections", "GET") => HttpResponseBuilder::ok()
+            .header("Content-Type", "application/json")
+            .body(connections_json().into_bytes())
+            .build(),
+        ("/metrics", "GET") => HttpResponseBuilder::ok()
+            .header("Content-Type", "text/plain; version=0.0.4")
+            .body(metrics::render().into_bytes())
+            .build(),
+        ("/status" | "/metrics" | "/events" | "/connections", _) => HttpResponseBuilder::method_not_allowed()
+            .header("Allow", "GET")
+            .build(),
+        ("/read_only", "GET") => text_response(on_off(read_only())),
+        ("/read_only", "PUT" | "POST") => {
+            let body = match request.read_body() {
+                Ok(body) => body,
+                Err(e) => return unreadable_body(e),
+            };
+            match String::from_utf8_lossy(&body).trim() {
+                "on" | "true" | "1" => set_read_only(true),
+                "off" | "false" | "0" => set_read_only(false),
+                _ => {
+                    return HttpResponseBuilder::bad_request()
+                        .body(b"Expected 'on' or 'off'\n".to_vec())
+                        .build();
+                }
+            }
+            info!("Admin: read-only mode {}", on_off(read_only()));
+            text_response(on_off(read_only()))
+        }
+        ("/read_only", _) => HttpResponseBuilder::method_not_allowed()
+            .header("Allow", "GET, PUT, POST")
+            .build(),
+        _ => not_found("Unknown admin endpoint"),
+    }
+}
+
+/// The `/routes` endpoints; `target` is what follows, `/{server_name}`
+/// then the route path. Changes apply to every server with that name.
+fn respond_routes(target: &str, request: &HttpRequest) -> Vec<u8> {
+    let (server_name, route_path) = match target.strip_prefix('/') {
+        Some(rest) => match rest.find('/') {
+            Some(slash) => (Some(&rest[..slash]), Some(&rest[slash..])),
+            None => (Some(rest), None),
+        },
+        None => (None, None),
+    };
+
+    let mut table = ROUTE_TABLE.lock().unwrap_or_else(|e| e.into_inner());
+    let Some(config) = table.as_mut() else {
+        return not_found("Unknown admin endpoint");
+    };
+    let servers: Vec<usize> = config
+        .servers
+        .iter()
+        .enumerate()
+        .filter(|(_, server)| server_name.is_none_or(|name| server.server_name == name))
+        .map(|(idx, _)| idx)
+        .collect();
+    if servers.is_empty() {
+        return not_found("Unknown server");
+    }
+
+    match (request.method.to_str(), server_name, route_path) {
+        ("GET", _, None) => {
+            let mut out = String::from("servers:\n");
+            for &idx in &servers {
+                let server = &config.servers[idx];
+                out.push_str(&format!("  - server_name: \"{}\"\n", server.server_name));
+                out.push_str("    routes:\n");
+                for route in &server.routes {
+                    out.push_str(&route.dump());
+                }
+            }
+            yaml_response(HttpResponseBuilder::ok(), out)
+        }
+        ("GET", Some(_), Some(path)) => {
+            let route = servers
+                .iter()
+                .find_map(|&idx| config.servers[idx].routes.iter().find(|r| r.path == path));
+            match route {
+                Some(route) => yaml_response(HttpResponseBuilder::ok(), route.dump()),
+                None => not_found("Unknown route"),
+            }
+        }
+        ("PUT" | "POST", Some(name), None) => {
+            let body = match request.read_body() {
+                Ok(body) => body,
+                Err(e) => return unreadable_body(e),
+            };
+            put_route(config, &servers, name, &String::from_utf8_lossy(&body))
+        }
+        ("DELETE", Some(name), Some(path)) => {
+            let mut removed = false;
+            for &idx in &servers {
+                let routes = &mut config.servers[idx].routes;
+                let before = routes.len();
+                routes.retain(|r| r.path != path);
+                removed |= routes.len() != before;
+            }
+            if !removed {
+                return not_found("Unknown route");
+            }
+            ROUTES_GENERATION.fetch_add(1, Ordering::Release);
+            info!("Admin: route {} removed from {}", path, name);
+            HttpResponseBuilder::no_content().build()
+        }
+        (_, None, _) => HttpResponseBuilder::method_not_allowed()
+            .header("Allow", "GET")
+            .build(),
+        (_, Some(_), None) => HttpResponseBuilder::method_not_allowed()
+            .header("Allow", "GET, PUT, POST")
+            .build(),
+        (_, Some(_), Some(_)) => HttpResponseBuilder::method_not_allowed()
+            .header("Allow", "GET, DELETE")
+            .build(),
+    }
+}
+
+/// Add `text`, one route entry, to the `servers` of the table or replace the
+/// route with its path, once it passes the same checks as the startup config
+fn put_route(config: &mut Config, servers: &[usize], name: &str, text: &str) -> Vec<u8> {
+    let route = match parse_route_entry(text) {
+        Ok(route) => route,
+        Err(e) => {
+            return HttpResponseBuilder::bad_request()
+                .body(format!("{}\n", e).into_bytes())
+                .build();
+        }
+    };
+    if !route.path.starts_with('/') {
+        return HttpResponseBuilder::bad_request()
+            .body(b"Route path must start with '/'\n".to_vec())
+            .build();
+    }
+
+    let mut problems = Vec::new();
+    for &idx in servers {
+        check_route(config, &config.servers[idx], &route, &mut problems);
+    }
+    if !problems.is_empty() {
+        let mut body = problems.join("\n");
+        body.push('\n');
+        return HttpResponseBuilder::bad_request().body(body.into_bytes()).build();
+    }
+
+    let mut added = false;
+    for &idx in servers {
+        let routes = &mut config.servers[idx].routes;
+        match routes.iter_mut().find(|r| r.path == route.path) {
+            Some(existing) => *existing = route.clone(),
+            None => {
+                routes.push(route.clone());
+                added = true;
+            }
+        }
+    }
+    ROUTES_GENERATION.fetch_add(1, Ordering::Release);
+    info!(
+        "Admin: route {} {} on {}",
+        route.path,
+        if added { "added" } else { "replaced" },
+        name
+    );
+    let builder = if added { HttpResponseBuilder::created() } else { HttpResponseBuilder::ok() };
+    yaml_response(builder, route.dump())
+}
+
+/// `text/event-stream` of the status document, sent again whenever it
+/// changes (read-only flips, listeners going down or back up)
+fn status_events() -> SseResponse {
+    let head = HttpResponseBuilder::ok()
+        .header("Content-Type", "text/event-stream")
+        .header("Cache-Control", "no-cache")
+        .build_chunked_head();
+
+    let mut last_sent: Option<String> = None;
+    let mut last_write = Instant::now();
+    SseResponse::new(
+        head,
+        Box::new(move || {
+            let status = status_json();
+            if last_sent.as_deref() != Some(status.as_str()) {
+                last_write = Instant::now();
+                let event = SseEvent::new(status.trim_end()).event("status");
+                last_sent = Some(status);
+                return Some(vec![event]);
+            }
+            if last_write.elapsed() >= EVENTS_HEARTBEAT {
+                last_write = Instant::now();
+                return Some(vec![SseEvent::comment("keepalive")]);
+            }
+            Some(Vec::new())
+        }),
+    )
+}
+
+fn status_json() -> String {
+    let listeners = LISTENERS.lock().unwrap_or_else(|e| e.into_inner());
+    let entries: Vec<String> = listeners
+        .iter()
+        .map(|(address, state)| {
+            let details = match state {
+                ListenerState::Listening => "\"state\": \"listening\"".to_string(),
+                ListenerState::Retrying { error, failures } => format!(
+                    "\"state\": \"retrying\", \"error\": \"{}\", \"failures\": {}",
+                    json::escape(error),
+                    failures
+                ),
+                ListenerState::Failed { error } => format!(
+                    "\"state\": \"failed\", \"error\": \"{}\"",
+                    json::escape(error)
+                ),
+            };
+            format!("{{\"address\": \"{}\", {}}}", json::escape(address), details)
+        })
+        .collect();
+
+    format!(
+        "{{\"read_only\": {}, \"listeners\": [{}]}}\n",
+        read_only(),
+        entries.join(", ")
+    )
+}
+
+/// Every worker's last snapshot, connections by worker then token. The
+/// snapshots are taken a few times a second, `snapshot_age_ms` is the oldest.
+fn connections_json() -> String {
+    let published = CONNECTIONS.lock().unwrap_or_else(|e| e.into_inner());
+    let oldest = published.values().map(|(taken, _)| taken.elapsed()).max().unwrap_or_default();
+    let optional = |value: &Option<String>| match value {
+        Some(value) => format!("\"{}\"", json::escape(value)),
+        None => "null".to_string(),
+    };
+    let entries: Vec<String> = published
+        .iter()
+        .flat_map(|(worker, (_, connections))| connections.iter().map(move |conn| (worker, conn)))
+        .map(|(worker, conn)| {
+            format!(
+                "{{\"worker\": {}, \"token\": {}, \"peer\": {}, \"state\": \"{}\", \"tls\": {}, \"keep_alive\": {}, \"upgrade\": {}, \"buffered\": {{\"request\": {}, \"read_buffer\": {}, \"response\": {}}}, \"route\": {}, \"requests\": {}, \"age_ms\": {}}}",
+                worker,
+                conn.token,
+                optional(&conn.peer.map(|addr| addr.to_string())),
+                conn.state,
+                conn.tls,
+                conn.keep_alive,
+                optional(&conn.upgrade),
+                conn.request_bytes,
+                conn.read_buffer_bytes,
+                conn.response_bytes,
+                optional(&conn.route),
+                conn.requests,
+                conn.age.as_millis()
+            )
+        })
+        .collect();
+    format!(
+        "{{\"snapshot_age_ms\": {}, \"connections\": [{}]}}\n",
+        oldest.as_millis(),
+        entries.join(", ")
+    )
+}
+
+fn on_off(on: bool) -> &'static str {
+    if on { "on" } else { "off" }
+}
+
+fn yaml_response(builder: HttpResponseBuilder, yaml: String) -> Vec<u8> {
+    builder
+        .header("Content-Type", "application/yaml")
+        .body(yaml.into_bytes())
+        .build()
+}
+
+fn not_found(message: &str) -> Vec<u8> {
+    HttpResponseBuilder::new(404, "Not Found")
+        .body(format!("{}\n", message).into_bytes())
+        .build()
+}
+
+/// A body spilled to disk that can no longer be read back
+fn unreadable_body(e: io::Error) -> Vec<u8> {
+    error!("Admin: cannot read spooled request body: {}", e);
+    HttpResponseBuilder::internal_error()
+        .body(b"Cannot read request body\n".to_vec())
+        .build()
+}
+
+fn text_response(text: &str) -> Vec<u8> {
+    HttpResponseBuilder::ok()
+        .header("Content-Type", "text/plain")
+        .body(format!("{}\n", text).into_bytes())
+        .build()
+}
@@ -0,0 +1,256 @@
+use std::fs;
+use std::sync::{Mutex, OnceLock};
+use std::time::SystemTime;
+
+use ring::digest;
+
+use crate::config::{Config, Route};
+use crate::request::HttpRequest;
+use crate::response::HttpResponseBuilder;
+use crate::utils::cookie::Cookie;
+
+/// Schemes a route can accept in its `auth:` list
+pub const AUTH_SCHEMES: &[&str] = &["basic", "bearer", "session"];
+
+/// Where routes with `auth` check who is asking. The built-in providers read
+/// users and tokens from the config or a file; embedders can register their
+/// own with `set_provider` before the server starts.
+pub trait AuthProvider: Send + Sync {
+    /// Whether `password` is right for `user`
+    fn check_basic(&self, user: &str, password: &str) -> bool;
+    /// The user a bearer token was issued to
+    fn check_bearer(&self, token: &str) -> Option<String>;
+    /// The user logged in on a session. The built-in providers know of no
+    /// logins, so sessions only authenticate with a custom provider.
+    fn check_session(&self, _session_id: &str) -> Option<String> {
+        None
+    }
+}
+
+static PROVIDER: OnceLock<Box<dyn AuthProvider>> = OnceLock::new();
+
+/// Use `provider` for every route with `auth`. Only the first call counts,
+/// so a provider set before the server starts replaces the built-in ones.
+pub fn set_provider(provider: Box<dyn AuthProvider>) -> bool {
+    PROVIDER.set(provider).is_ok()
+}
+
+/// Register the built-in provider the config asks for, unless one is set
+/// already: `auth_file` when given, else `auth_users` and `auth_tokens`
+pub fn init(config: &Config) {
+    let provider: Box<dyn AuthProvider> = match &config.auth_file {
+        Some(path) => Box::new(FileProvider::new(path)),
+        None => Box::new(StaticProvider {
+            users: config.auth_users.clone(),
+            tokens: config.auth_tokens.clone(),
+        }),
+    };
+    set_provider(provider);
+}
+
+/// Users and tokens fixed in the config
+pub struct StaticProvider {
+    pub users: Vec<(String, String)>,  // Name and stored password (`sha256:<hex>` or `plain:<text>`)
+    pub tokens: Vec<(String, String)>, // Token and the user it belongs to
+}
+
+impl AuthProvider for StaticProvider {
+    fn check_basic(&self, user: &str, password: &str) -> bool {
+        self.users
+            .iter()
+            .any(|(name, stored)| name == user && password_matches(stored, password))
+    }
+
+    fn check_bearer(&self, token: &str) -> Option<String> {
+        self.tokens
+            .iter()
+            .find(|(known, _)| constant_time_eq(known.as_bytes(), token.as_bytes()))
+            .map(|(_, user)| user.clone())
+    }
+}
+
+/// Users and tokens read from a file, one per line:
+///
+/// ```text
+/// user alice sha256:2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b
+/// token 6f1c0a2e alice
+/// ```
+///
+/// The file is read again whenever its modification time changes, so
+/// accounts can be edited without a restart.
+pub struct FileProvider {
+    path: String,
+    loaded: Mutex<(Option<SystemTime>, StaticProvider)>,
+}
+
+impl FileProvider {
+    pub fn new(path: &str) -> Self {
+        Self {
+            path: path.to_string(),
+            loaded: Mutex::new((None, StaticProvider { users: Vec::new(), tokens: Vec::new() })),
+        }
+    }
+
+    /// Run `check` against the current entries, reloading them first if the
+    /// file changed. A file that can't be read keeps the last entries.
+    fn with_entries<T>(&self, check: impl FnOnce(&StaticProvider) -> T) -> T {
+        let mut loaded = self.loaded.lock().unwrap_or_else(|e| e.into_inner());
+        let modified = fs::metadata(&self.path).and_then(|m| m.modified()).ok();
+        if modified.is_some() && modified != loaded.0 {
+            match fs::read_to_string(&self.path) {
+                Ok(text) => {
+                    *loaded = (modified, parse_auth_file(&self.path, &text));
+                    info!("Auth: loaded {}", self.path);
+                }
+                Err(e) => error!("Auth: cannot read {}: {}", self.path, e),
+            }
+        }
+        check(&loaded.1)
+    }
+}
+
+impl AuthProvider for FileProvider {
+    fn check_basic(&self, user: &str, password: &str) -> bool {
+        self.with_entries(|entries| entries.check_basic(user, password))
+    }
+
+    fn check_bearer(&self, token: &str) -> Option<String> {
+        self.with_entries(|entries| entries.check_bearer(token))
+    }
+}
+
+fn parse_auth_file(path: &str, text: &str) -> StaticProvider {
+    let mut entries = StaticProvider { users: Vec::new(), tokens: Vec::new() };
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields.as_slice() {
+            ["user", name, stored] if valid_password_format(stored) => {
+                entries.users.push((name.to_string(), stored.to_string()));
+            }
+            ["token", token, user] => entries.tokens.push((token.to_string(), user.to_string())),
+            _ => warn!("Auth: {} line {} ignored, expected 'user NAME HASH' or 'token TOKEN USER'", path, number + 1),
+        }
+    }
+    entries
+}
+
+/// Whether a stored password is `sha256:` and 64 hex digits, or `plain:` and anything
+pub fn valid_password_format(stored: &str) -> bool {
+    match stored.split_once(':') {
+        Some(("sha256", hex)) => hex.len() == 64 && hex.chars().all(|c| c.is_ascii_hexdigit()),
+        Some(("plain", _)) => true,
+        _ => false,
+    }
+}
+
+fn password_matches(stored: &str, password: &str) -> bool {
+    match stored.split_once(':') {
+        Some(("sha256", hex)) => {
+            let hashed: String = digest::digest(&digest::SHA256, password.as_bytes())
+                .as_ref()
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect();
+            constant_time_eq(hashed.as_bytes(), hex.to_ascii_lowercase().as_bytes())
+        }
+        Some(("plain", plain)) => constant_time_eq(plain.as_bytes(), password.as_bytes()),
+        _ => false,
+    }
+}
+
+/// Compare without stopping at the first difference, so timing tells
+/// nothing about how much of a secret was guessed
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// The 401 for a request that doesn't authenticate by one of the route's
+/// `auth` schemes; None when it does, or when the route is open. OPTIONS
+/// goes through so browsers can run CORS preflights.
+pub fn challenge(route: &Route, request: &HttpRequest, cookie: &Cookie) -> Option<Vec<u8>> {
+    let schemes = route.auth.as_ref()?;
+    if request.method.to_str() == "OPTIONS" {
+        return None;
+    }
+    if let Some(user) = PROVIDER.get().and_then(|provider| authenticate(provider.as_ref(), schemes, request)) {
+        debug!("Auth: {} {} as {}", request.method.to_str(), request.path, user);
+        return None;
+    }
+
+    info!("Auth: refusing {} {}", request.method.to_str(), request.path);
+    let realm = route.auth_realm.as_deref().unwrap_or("localserver").replace('"', "'");
+    let challenges: Vec<String> = schemes
+        .iter()
+        .filter_map(|scheme| match scheme.as_str() {
+            "basic" => Some(format!("Basic realm=\"{}\", charset=\"UTF-8\"", realm)),
+            "bearer" => Some(format!("Bearer realm=\"{}\"", realm)),
+            _ => None,
+        })
+        .collect();
+    let mut builder = HttpResponseBuilder::new(401, "Unauthorized")
+        .header("Content-Type", "text/plain")
+        .body(b"Authentication required".to_vec())
+        .cookie(cookie);
+    if !challenges.is_empty() {
+        builder = builder.header("WWW-Authenticate", &challenges.join(", "));
+    }
+    Some(builder.build())
+}
+
+/// Whether the request gets through the route's `auth`, with nothing
+/// logged or answered; for work that must not start before `challenge`
+pub fn allows(route: &Route, request: &HttpRequest) -> bool {
+    let Some(schemes) = &route.auth else {
+        return true;
+    };
+    request.method.to_str() == "OPTIONS"
+        || PROVIDER.get().is_some_and(|provider| authenticate(provider.as_ref(), schemes, request).is_some())
+}
+
+/// The user the request authenticates as, trying the route's schemes in order
+fn authenticate(provider: &dyn AuthProvider, schemes: &[String], request: &HttpRequest) -> Option<String> {
+    let authorization = request.headers.get("authorization").map(|v| v.trim());
+    let credentials = |name: &str| {
+        let (scheme, value) = authorization?.split_once(' ')?;
+        scheme.eq_ignore_ascii_case(name).then(|| value.trim())
+    };
+
+    schemes.iter().find_map(|scheme| match scheme.as_str() {
+        "basic" => {
+            let decoded = decode_base64(credentials("basic")?)?;
+            let (user, password) = std::str::from_utf8(&decoded).ok()?.split_once(':')?;
+            provider.check_basic(user, password).then(|| user.to_string())
+        }
+        "bearer" => provider.check_bearer(credentials("bearer")?),
+        "session" => provider.check_session(request.session_id.as_deref()?),
+        _ => None,
+    })
+}
+
+/// Standard base64 with optional padding, as Basic credentials are sent
+fn decode_base64(text: &str) -> Option<Vec<u8>> {
+    let value = |c: u8| match c {
+        b'A'..=b'Z' => Some(c - b'A'),
+        b'a'..=b'z' => Some(c - b'a' + 26),
+        b'0'..=b'9' => Some(c - b'0' + 52),
+        b'+' => Some(62),
+        b'/' => Some(63),
+        _ => None,
+    };
+    let mut out = Vec::with_capacity(text.len() * 3 / 4);
+    let mut bits = 0u32;
+    let mut count = 0;
+    for c in text.trim_end_matches('=').bytes() {
+        bits = ((bits << 6) | value(c)? as u32) & 0x3fff;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            out.push((bits >> count) as u8);
+        }
+    }
+    Some(out)
+}
@@ -0,0 +1,409 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use uuid::Uuid;
+
+use crate::admin;
+use crate::auth;
+use crate::config::ServerConfig;
+use crate::read::{find_matching_route, resolve_file_path};
+use crate::request::HttpRequest;
+use crate::response::{HttpResponseBuilder, reason_phrase};
+use crate::utils::cookie::Cookie;
+use crate::utils::json::{self, JsonValue};
+use crate::utils::HttpMethod;
+
+/// Operations accepted in one request. They run on the event loop, so the
+/// cap bounds how long a batch holds it.
+const MAX_OPERATIONS: usize = 100;
+
+/// A file operation from the batch document
+enum Operation {
+    Delete { path: String },
+    Move { from: String, to: String },
+    Copy { from: String, to: String },
+}
+
+impl Operation {
+    fn parse(item: &JsonValue) -> Result<Self, String> {
+        let field = |name: &str| {
+            item.get(name)
+                .and_then(JsonValue::as_str)
+                .map(str::to_string)
+                .ok_or_else(|| format!("missing '{}'", name))
+        };
+        match item.get("op").and_then(JsonValue::as_str) {
+            Some("delete") => Ok(Operation::Delete { path: field("path")? }),
+            Some("move") => Ok(Operation::Move { from: field("from")?, to: field("to")? }),
+            Some("copy") => Ok(Operation::Copy { from: field("from")?, to: field("to")? }),
+            Some(other) => Err(format!("unknown op '{}'", other)),
+            None => Err("missing 'op'".to_string()),
+        }
+    }
+}
+
+/// How to take back an operation that already ran
+enum Undo {
+    Restore { staged: PathBuf, original: PathBuf }, // Deleted files wait here until commit
+    Rename { from: PathBuf, to: PathBuf },
+    Remove(PathBuf),
+}
+
+struct ItemResult {
+    summary: String, // JSON members describing the operation
+    status: u16,
+    error: Option<String>,
+}
+
+/// Run a JSON batch of file operations for a file-manager route:
+///
+/// `{"atomic": true, "operations": [{"op": "delete", "path": "/files/a.txt"},
+///  {"op": "move", "from": "/files/b.txt", "to": "/files/old/b.txt"},
+///  {"op": "copy", "from": "/files/c.txt", "to": "/files/c-copy.txt"}]}`
+///
+/// Paths are request paths checked like single-file requests: the route
+/// they fall in must allow the method (DELETE to remove, POST to create,
+/// GET to copy from) and they must resolve inside its root. Atomic batches
+/// (the default) stop at the first failure and undo what already ran;
+/// otherwise every operation is attempted. Each one gets its own status.
+pub fn handle_batch(server: &ServerConfig, request: &HttpRequest, cookie: &Cookie) -> Vec<u8> {
+    let is_json = request
+        .headers
+        .get("content-type")
+        .is_some_and(|v| v.starts_with("application/json"));
+    if !is_json {
+        return HttpResponseBuilder::unsupported_media_type()
+            .body(b"Batch requests must be application/json\n".to_vec())
+            .cookie(cookie)
+            .build();
+    }
+
+    let body = match request.read_body() {
+        Ok(body) => body,
+        Err(e) => {
+            error!("BATCH: cannot read spooled request body: {}", e);
+            return HttpResponseBuilder::internal_error()
+                .body(b"Cannot read request body\n".to_vec())
+                .cookie(cookie)
+                .build();
+        }
+    };
+    let document = match json::parse(&String::from_utf8_lossy(&body)) {
+        Ok(document) => document,
+        Err(e) => return bad_batch(&format!("Invalid JSON: {}", e), cookie),
+    };
+    let Some(items) = document.get("operations").and_then(JsonValue::as_array) else {
+        return bad_batch("Expected an 'operations' array", cookie);
+    };
+    if items.len() > MAX_OPERATIONS {
+        return bad_batch(&format!("At most {} operations per batch", MAX_OPERATIONS), cookie);
+    }
+    let atomic = document.get("atomic").and_then(JsonValue::as_bool).unwrap_or(true);
+
+    let mut results = Vec::with_capacity(items.len());
+    let mut undo_log: Vec<Undo> = Vec::new();
+    let mut failed = false;
+
+    for item in items {
+        let operation = Operation::parse(item);
+        let summary = match &operation {
+            Ok(Operation::Delete { path }) => {
+                format!("\"op\": \"delete\", \"path\": \"{}\"", json::escape(path))
+            }
+            Ok(Operation::Move { from, to }) | Ok(Operation::Copy { from, to }) => format!(
+                "\"op\": \"{}\", \"from\": \"{}\", \"to\": \"{}\"",
+                if matches!(operation, Ok(Operation::Move { .. })) { "move" } else { "copy" },
+                json::escape(from),
+                json::escape(to)
+            ),
+            Err(_) => "\"op\": null".to_string(),
+        };
+
+        if failed && atomic {
+            results.push(ItemResult {
+                summary,
+                status: 424,
+                error: Some("not attempted".to_string()),
+            });
+            continue;
+        }
+
+        let outcome = operation
+            .map_err(|e| (400, e))
+            .and_then(|op| execute(server, request, &op, &mut undo_log));
+        match outcome {
+            Ok(status) => results.push(ItemResult {
+                summary,
+                status,
+                error: None,
+            }),
+            Err((status, error)) => {
+                failed = true;
+                results.push(ItemResult {
+                    summary,
+                    status,
+                    error: Some(error),
+                });
+            }
+        }
+    }
+
+    let rolled_back = failed && atomic;
+    if rolled_back {
+        roll_back(undo_log);
+        for result in results.iter_mut().filter(|r| r.error.is_none()) {
+            result.status = 424;
+            result.error = Some("rolled back".to_string());
+        }
+    } else {
+        commit(undo_log);
+    }
+
+    let entries: Vec<String> = results
+        .iter()
+        .map(|r| {
+            let error = match &r.error {
+                Some(e) => format!("\"{}\"", json::escape(e)),
+                None => "null".to_string(),
+            };
+            format!("{{{}, \"status\": {}, \"error\": {}}}", r.summary, r.status, error)
+        })
+        .collect();
+    let body = format!(
+        "{{\"atomic\": {}, \"committed\": {}, \"results\": [{}]}}\n",
+        atomic,
+        !rolled_back,
+        entries.join(", ")
+    );
+
+    let status = match (failed, rolled_back) {
+        (false, _) => 200,
+        (true, true) => 409,
+        (true, false) => 207,
+    };
+    HttpResponseBuilder::new(status, reason_phrase(status))
+        .header("Content-Type", "application/json")
+        .body(body.into_bytes())
+        .cookie(cookie)
+        .build()
+}
+
+fn bad_batch(message: &str, cookie: &Cookie) -> Vec<u8> {
+    HttpResponseBuilder::bad_request()
+        .body(format!("{}\n", message).into_bytes())
+        .cookie(cookie)
+        .build()
+}
+
+/// Run one operation, recording how to undo it
+fn execute(
+    server: &ServerConfig,
+    request: &HttpRequest,
+    op: &Operation,
+    undo_log: &mut Vec<Undo>,
+) -> Result<u16, (u16, String)> {
+    match op {
+        Operation::Delete { path } => {
+            let target = resolve(server, request, path, &HttpMethod::DELETE)?;
+            require_file(&target)?;
+            let staged = stage(&target).map_err(io_failure)?;
+            info!("BATCH: deleted {}", target.display());
+            undo_log.push(Undo::Restore { staged, original: target });
+            Ok(204)
+        }
+        Operation::Move { from, to } => {
+            let source = resolve(server, request, from, &HttpMethod::DELETE)?;
+            let destination = resolve(server, request, to, &HttpMethod::POST)?;
+            require_file(&source)?;
+            require_free(&destination)?;
+            if fs::rename(&source, &destination).is_ok() {
+                undo_log.push(Undo::Rename { from: destination, to: source });
+            } else {
+                // Roots on different filesystems: copy, then delete the source
+                fs::copy(&source, &destination).map_err(io_failure)?;
+                undo_log.push(Undo::Remove(destination));
+                let staged = stage(&source).map_err(io_failure)?;
+                undo_log.push(Undo::Restore { staged, original: source.clone() });
+            }
+            info!("BATCH: moved {} to {}", from, to);
+            Ok(201)
+        }
+        Operation::Copy { from, to } => {
+            let source = resolve(server, request, from, &HttpMethod::GET)?;
+            let destination = resolve(server, request, to, &HttpMethod::POST)?;
+            require_file(&source)?;
+            require_free(&destination)?;
+            fs::copy(&source, &destination).map_err(io_failure)?;
+            info!("BATCH: copied {} to {}", from, to);
+            undo_log.push(Undo::Remove(destination));
+            Ok(201)
+        }
+    }
+}
+
+/// Map a request path to a file with the checks a single request would get,
+/// the credentials of the batch request standing in for its own
+fn resolve(
+    server: &ServerConfig,
+    request: &HttpRequest,
+    path: &str,
+    method: &HttpMethod,
+) -> Result<PathBuf, (u16, String)> {
+    let route = find_matching_route(server, path).ok_or((404, "no route for path".to_string()))?;
+    if route.bundle.is_some() || route.redirect.is_some() || route.proxy_pass.is_some() {
+        return Err((405, "route does not serve files from disk".to_string()));
+    }
+    if !route.methods.iter().any(|m| HttpMethod::from_str(m) == *method) {
+        return Err((405, format!("{} not allowed on this route", method.to_str())));
+    }
+    if admin::rejects(route, method) {
+        return Err((405, "route is read-only".to_string()));
+    }
+    if !auth::allows(route, request) {
+        return Err((401, "route requires authentication".to_string()));
+    }
+    resolve_file_path(server, route, path)
+        .map(PathBuf::from)
+        .ok_or((403, "path escapes the route root".to_string()))
+}
+
+fn require_file(path: &Path) -> Result<(), (u16, String)> {
+    if path.is_file() {
+        Ok(())
+    } else {
+        Err((404, "file not found".to_string()))
+    }
+}
+
+fn require_free(path: &Path) -> Result<(), (u16, String)> {
+    if path.exists() {
+        return Err((409, "destination already exists".to_string()));
+    }
+    if !path.parent().is_some_and(Path::is_dir) {
+        return Err((409, "destination directory does not exist".to_string()));
+    }
+    Ok(())
+}
+
+fn io_failure(e: std::io::Error) -> (u16, String) {
+    (500, e.to_string())
+}
+
+/// Set a file aside next to itself so the deletion can still be undone
+fn stage(path: &Path) -> std::io::Result<PathBuf> {
+    let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    let staged = path.with_file_name(format!(".batch-{}-{}", Uuid::new_v4(), name));
+    fs::rename(path, &staged)?;
+    Ok(staged)
+}
+
+fn roll_back(undo_log: Vec<Undo>) {
+    for undo in undo_log.into_iter().rev() {
+        let result = match &undo {
+            Undo::Restore { staged, original } => fs::rename(staged, original),
+            Undo::Rename { from, to } => fs::rename(from, to),
+            Undo::Remove(path) => fs::remove_file(path),
+        };
+        if let Err(e) = result {
+            error!("BATCH: rollback step failed: {}", e);
+        }
+    }
+}
+
+fn commit(undo_log: Vec<Undo>) {
+    for undo in undo_log {
+        if let Undo::Restore { staged, .. } = undo
+            && let Err(e) = fs::remove_file(&staged)
+        {
+            error!("BATCH: cannot remove staged file {}: {}", staged.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::parse_config;
+    use crate::request::{HeadLimits, HttpRequestBuilder};
+
+    const LIMITS: HeadLimits = HeadLimits {
+        max_request_line: 8 * 1024,
+        max_header_size: 32 * 1024,
+        max_header_count: 100,
+        strict: false,
+    };
+
+    /// A server whose only route manages files in a fresh directory
+    fn file_manager() -> (ServerConfig, PathBuf) {
+        let root = std::env::temp_dir().join(format!("batch-{}", Uuid::new_v4()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("a.txt"), "a").unwrap();
+        fs::write(root.join("b.txt"), "b").unwrap();
+        let config = parse_config(&format!(
+            "servers:
+  - server_name: \"test\"
+    host: 127.0.0.1
+    ports:
+      - 8080
+    root: \"{}\"
+    routes:
+      - path: \"/\"
+        methods: [\"GET\", \"POST\", \"DELETE\"]
+        root: \".\"
+",
+            root.display()
+        ))
+        .expect("test config");
+        (config.servers.into_iter().next().unwrap(), root)
+    }
+
+    fn run(server: &ServerConfig, atomic: bool) -> String {
+        let body = format!(
+            "{{\"atomic\": {}, \"operations\": [{{\"op\": \"delete\", \"path\": \"/a.txt\"}}, \
+             {{\"op\": \"move\", \"from\": \"/b.txt\", \"to\": \"/moved.txt\"}}, \
+             {{\"op\": \"copy\", \"from\": \"/missing.txt\", \"to\": \"/c.txt\"}}]}}",
+            atomic
+        );
+        let raw = format!(
+            "POST /batch HTTP/1.1\r\nHost: test\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        let mut builder = HttpRequestBuilder::new();
+        builder.append(raw.into_bytes(), &LIMITS).unwrap();
+        let response = handle_batch(server, builder.get().unwrap(), &Cookie::new("session", "test"));
+        String::from_utf8(response).unwrap()
+    }
+
+    fn names(root: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(root)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn atomic_batch_is_rolled_back_on_failure() {
+        let (server, root) = file_manager();
+        let response = run(&server, true);
+        assert!(response.starts_with("HTTP/1.1 409"), "{}", response);
+        assert!(response.contains("\"committed\": false"));
+        assert!(response.contains("\"error\": \"rolled back\""));
+        assert_eq!(names(&root), ["a.txt", "b.txt"]);
+        assert_eq!(fs::read_to_string(root.join("b.txt")).unwrap(), "b");
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn non_atomic_batch_keeps_what_succeeded() {
+        let (server, root) = file_manager();
+        let response = run(&server, false);
+        assert!(response.starts_with("HTTP/1.1 207"), "{}", response);
+        assert!(response.contains("\"status\": 404"));
+        // No staged copy of the deleted file is left behind
+        assert_eq!(names(&root), ["moved.txt"]);
+        let _ = fs::remove_dir_all(&root);
+    }
+}
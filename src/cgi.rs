@@ -1,19 +1,134 @@
 use crate::{
-    config::Route, models::SimpleResponse, request::HttpRequest, response::HttpResponseBuilder, server::{SocketData, Status}, utils::HttpHeaders
+    config::{CgiHandler, Route}, metrics, models::{HttpResponseCommon, SimpleResponse}, read::strip_route_prefix, request::{BodyStream, BodyStreamReader, HttpRequest, SpooledBody}, response::{HttpResponseBuilder, reason_phrase, status_code}, server::{IDLE_TIMEOUT, SocketData, Status}, utils::{HttpHeaders, cookie::Cookie, session::SessionStore}
 };
-use std::io::Write;
-use std::process::{Command, Stdio};
+#[cfg(unix)]
+use mio::unix::pipe::{Receiver, Sender};
+#[cfg(not(unix))]
+use crate::utils::pipe::{Receiver, Sender};
+use mio::{Interest, Registry, Token};
+use std::cell::RefCell;
+use std::io::{self, Read, Write};
+use std::process::{Child, Command, Stdio};
+use std::rc::Rc;
+use std::sync::atomic::Ordering;
+use std::time::Instant;
 
 /// Structure pour les données CGI (sans référence à socket_data)
 pub struct CgiContext {
     pub method: String,
     pub path: String,
+    pub script_name: String,            // Partie du chemin qui désigne le script
+    pub path_info: String,              // Reste du chemin après le script
+    pub path_translated: Option<String>, // PATH_INFO résolu sur le disque
+    pub server_name: String,
+    pub server_port: u16,
+    pub protocol: String,               // Version de la requête, pour SERVER_PROTOCOL
     pub query_string: String,
     pub headers: Vec<(String, String)>,
     pub body: Vec<u8>,
+    pub body_file: Option<Rc<SpooledBody>>,
+    pub body_stream: Option<Rc<RefCell<BodyStream>>>, // Body encore en cours de réception
+    pub env: Vec<(String, String)>, // Variables en plus des variables CGI standard
+    pub headers_allow: Option<Vec<String>>, // Seuls ces headers deviennent des HTTP_* (noms normalisés)
+    pub headers_deny: Vec<String>,          // Headers jamais transmis au script
+    pub status: u16,                // Statut de la réponse si le script n'envoie pas de Status:
+    pub deadline: Option<Instant>,  // Échéance de la requête ; le script est tué au-delà
+    pub timeout_page: Option<String>, // Page d'erreur 504 configurée pour le serveur
+    pub buffer_size: usize,         // Sortie bufferisée au-delà de laquelle on passe en chunked
+    pub max_output: Option<usize>,  // Sortie au-delà de laquelle le script est tué
+    pub session_id: Option<String>, // Session du client, nouvelle ou non
+    pub session_cookie: Option<Cookie>, // Cookie de cette session, renvoyé avec la réponse
+    pub session: Option<CgiSession>, // Sur les routes `cgi_session` seulement
+}
+
+/// Session partagée avec le script d'une route `cgi_session` : ses valeurs
+/// deviennent des variables SESSION_*, et les headers `X-Session-Set: clé=valeur`
+/// et `X-Session-Unset: clé` de la réponse la modifient
+#[derive(Clone)]
+pub struct CgiSession {
+    pub store: SessionStore,
+    pub id: String,
+    pub cookie: Option<Cookie>,
+}
+
+impl CgiSession {
+    /// Variables SESSION_* des valeurs de la session, triées ; les clés qu'on
+    /// ne pourrait pas relire telles quelles sont ignorées
+    pub fn variables(&self) -> Vec<(String, String)> {
+        let Some(session) = self.store.get(&self.id) else {
+            return Vec::new();
+        };
+        let mut vars: Vec<(String, String)> = session
+            .data
+            .iter()
+            .filter(|(key, _)| valid_session_key(key))
+            .map(|(key, value)| (format!("SESSION_{}", key.to_ascii_uppercase()), value.clone()))
+            .collect();
+        vars.sort();
+        vars
+    }
+}
+
+/// Clé que X-Session-Set peut écrire : minuscules, chiffres et `_`, pour
+/// que SESSION_<CLÉ> désigne une seule clé
+fn valid_session_key(key: &str) -> bool {
+    !key.is_empty() && key.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'_')
+}
+
+/// Ajoute le cookie de la session à la réponse, pour qu'une session créée
+/// par cette requête garde ce que le script y a écrit
+pub(crate) fn with_session_cookie(builder: HttpResponseBuilder, session: Option<&CgiSession>) -> HttpResponseBuilder {
+    match session.and_then(|session| session.cookie.as_ref()) {
+        Some(cookie) => builder.cookie(cookie),
+        None => builder,
+    }
+}
+
+/// Retire les headers X-Session-* de la réponse et applique leurs changements
+/// à la session ; sans session (route sans `cgi_session`), ils sont ignorés
+pub(crate) fn apply_session_headers(headers: &mut HttpHeaders, session: Option<&CgiSession>) {
+    let sets: Vec<String> = headers.get_all("x-session-set").cloned().collect();
+    let unsets: Vec<String> = headers.get_all("x-session-unset").cloned().collect();
+    headers.remove("x-session-set");
+    headers.remove("x-session-unset");
+    if sets.is_empty() && unsets.is_empty() {
+        return;
+    }
+    let Some(session) = session else {
+        warn!("CGI session headers ignored, the route has no cgi_session");
+        return;
+    };
+
+    let mut applied = 0;
+    let found = session.store.with_session(&session.id, |stored| {
+        for set in &sets {
+            match set.split_once('=') {
+                Some((key, value)) if valid_session_key(&key.trim().to_ascii_lowercase()) => {
+                    stored.set_data(&key.trim().to_ascii_lowercase(), value.trim());
+                    applied += 1;
+                }
+                _ => warn!("CGI session: invalid X-Session-Set '{}'", set),
+            }
+        }
+        for key in &unsets {
+            stored.remove_data(&key.trim().to_ascii_lowercase());
+        }
+    });
+    if found {
+        info!("CGI session {}: {} set, {} unset", session.id, applied, unsets.len());
+    } else {
+        warn!("CGI session {} expired, changes dropped", session.id);
+    }
 }
 
 impl CgiContext {
+    /// Le header `key` peut-il devenir une variable HTTP_* d'après les listes de la route
+    fn passes_header(&self, key: &str) -> bool {
+        let name = key.to_ascii_lowercase().replace('_', "-");
+        self.headers_allow.as_ref().is_none_or(|allow| allow.contains(&name))
+            && !self.headers_deny.contains(&name)
+    }
+
     /// Extrait les données nécessaires de la request
     pub fn from_request(request: &HttpRequest) -> Self {
         // Use the parsed path and query_string directly from the request
@@ -26,33 +141,217 @@ impl CgiContext {
         Self {
             method: request.method.to_str().to_string(),
             path: request.path.clone(),
+            script_name: request.path.clone(),
+            path_info: String::new(),
+            path_translated: None,
+            server_name: String::new(),
+            server_port: 0,
+            protocol: request.version.clone(),
             query_string: request.query_string.clone(),
             headers,
-            body: request.body.clone().unwrap_or(Vec::new()),
+            body: request.body.clone().unwrap_or_default(),
+            body_file: request.body_file.clone(),
+            body_stream: request.body_stream.clone(),
+            env: Vec::new(),
+            headers_allow: None,
+            headers_deny: Vec::new(),
+            status: 200,
+            deadline: None,
+            timeout_page: None,
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            max_output: None,
+            session_id: request.session_id.clone(),
+            session_cookie: None,
+            session: None,
+        }
+    }
+}
+
+/// Sortie bufferisée (Content-Length ou Range) au-delà de laquelle on passe
+/// en chunked, sans `cgi_buffer_size` sur la route
+pub const DEFAULT_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Sépare `/cgi-bin/script.py/extra` en nom du script et PATH_INFO : le
+/// script est le premier segment qui se termine par une des extensions CGI
+/// de la route, renvoyée avec
+pub fn split_script_path<'a, 'h>(
+    path: &'a str,
+    handlers: &'h [CgiHandler],
+) -> Option<(&'h CgiHandler, &'a str, &'a str)> {
+    let mut end = 0;
+    for segment in path.split_inclusive('/') {
+        end += segment.len();
+        let name = segment.trim_end_matches('/');
+        let handler = handlers
+            .iter()
+            .find(|h| name.ends_with(h.extension.as_str()) && name.len() > h.extension.len());
+        if let Some(handler) = handler {
+            let script_end = end - (segment.len() - name.len());
+            let (script, path_info) = path.split_at(script_end);
+            return Some((handler, script, path_info));
+        }
+    }
+    None
+}
+
+/// Comme `split_script_path`, mais seulement pour les scripts que la route
+/// autorise : avec `cgi_scripts`, les autres fichiers à extension CGI ne
+/// s'exécutent jamais (un .py déposé par un upload reste inerte)
+pub fn route_script<'a, 'r>(route: &'r Route, path: &'a str) -> Option<(&'r CgiHandler, &'a str, &'a str)> {
+    split_script_path(path, &route.cgi).filter(|(_, script, _)| script_allowed(route, script))
+}
+
+/// Le script `script_name` (chemin de l'URL) figure dans `cgi_scripts`, ou la
+/// route n'en a pas. Les entrées sont relatives à la route.
+pub fn script_allowed(route: &Route, script_name: &str) -> bool {
+    let Some(allowed) = &route.cgi_scripts else {
+        return true;
+    };
+    let Some(relative) = strip_route_prefix(route, script_name) else {
+        return false;
+    };
+    let relative = relative.trim_start_matches('/');
+    allowed.iter().any(|script| script == relative)
+}
+
+/// Variables de la RFC 3875 (plus quelques usages courants : REQUEST_URI,
+/// REMOTE_PORT, REDIRECT_STATUS pour php-cgi), communes au CGI et au FastCGI.
+/// Les variables de `context.env` remplacent celles du même nom.
+pub(crate) fn cgi_variables(context: &CgiContext, script_path: &str, socket_data: &SocketData) -> Vec<(String, String)> {
+    let request_uri = if context.query_string.is_empty() {
+        context.path.clone()
+    } else {
+        format!("{}?{}", context.path, context.query_string)
+    };
+
+    let mut vars = vec![
+        ("GATEWAY_INTERFACE".to_string(), "CGI/1.1".to_string()),
+        ("SERVER_PROTOCOL".to_string(), context.protocol.clone()),
+        ("SERVER_SOFTWARE".to_string(), "localserver".to_string()),
+        ("SERVER_NAME".to_string(), context.server_name.clone()),
+        ("SERVER_PORT".to_string(), context.server_port.to_string()),
+        ("REQUEST_METHOD".to_string(), context.method.clone()),
+        ("QUERY_STRING".to_string(), context.query_string.clone()),
+        ("SCRIPT_NAME".to_string(), context.script_name.clone()),
+        ("SCRIPT_FILENAME".to_string(), script_path.to_string()),
+        ("PATH_INFO".to_string(), context.path_info.clone()),
+        ("REQUEST_URI".to_string(), request_uri),
+        // php-cgi refuse de s'exécuter sans, quand force-cgi-redirect est actif
+        ("REDIRECT_STATUS".to_string(), "200".to_string()),
+        // Limites effectives, pour que le script valide avec les mêmes valeurs
+        ("SERVER_IDLE_TIMEOUT".to_string(), IDLE_TIMEOUT.as_secs().to_string()),
+    ];
+
+    if let Some(translated) = &context.path_translated {
+        vars.push(("PATH_TRANSLATED".to_string(), translated.clone()));
+    }
+    if let Ok(peer) = socket_data.stream.peer_addr() {
+        vars.push(("REMOTE_ADDR".to_string(), peer.ip().to_canonical().to_string()));
+        vars.push(("REMOTE_PORT".to_string(), peer.port().to_string()));
+    }
+    if socket_data.stream.is_tls() {
+        vars.push(("HTTPS".to_string(), "on".to_string()));
+    }
+    if let Some(max) = socket_data.status.max_body_size {
+        vars.push(("SERVER_MAX_BODY_SIZE".to_string(), max.to_string()));
+    }
+
+    let body_len = match (&context.body_file, &context.body_stream) {
+        (Some(spooled), _) => spooled.len(),
+        (None, Some(stream)) => stream.borrow().len(),
+        (None, None) => context.body.len(),
+    };
+    if body_len > 0 {
+        vars.push(("CONTENT_LENGTH".to_string(), body_len.to_string()));
+        if let Some((_, content_type)) = context.headers.iter().find(|(k, _)| k == "content-type") {
+            vars.push(("CONTENT_TYPE".to_string(), content_type.clone()));
         }
     }
+
+    // Les headers HTTP deviennent des variables HTTP_* ; Proxy est ignoré pour
+    // qu'un client ne puisse pas imposer HTTP_PROXY au script (httpoxy)
+    let mut header_vars: Vec<(String, String)> = Vec::new();
+    for (key, value) in &context.headers {
+        if key.eq_ignore_ascii_case("proxy") || !context.passes_header(key) {
+            continue;
+        }
+        let Some(name) = header_variable(key) else {
+            warn!("CGI: dropping header '{}', its name can't become a variable safely", key);
+            continue;
+        };
+        match header_vars.iter_mut().find(|(existing, _)| *existing == name) {
+            // Un header répété devient une seule variable, comme s'il avait été
+            // envoyé en liste ; Cookie se joint avec `; `, les autres avec `, `
+            Some((_, joined)) => {
+                joined.push_str(if name == "HTTP_COOKIE" { "; " } else { ", " });
+                joined.push_str(value);
+            }
+            None => header_vars.push((name, value.clone())),
+        }
+    }
+    header_vars.sort();
+    vars.extend(header_vars);
+
+    for (key, value) in &context.env {
+        set_variable(&mut vars, key, value);
+    }
+    vars
+}
+
+/// Variable CGI d'un header : `X-Forwarded-For` devient `HTTP_X_FORWARDED_FOR`.
+/// Un `_` dans le nom ferait passer `X_Foo` pour `X-Foo` une fois converti, et
+/// un client pourrait remplacer un header posé par un proxy de confiance : ces
+/// noms sont refusés, comme tout caractère hors lettres, chiffres et `-`.
+pub(crate) fn header_variable(key: &str) -> Option<String> {
+    if key.is_empty() || !key.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-') {
+        return None;
+    }
+    Some(format!("HTTP_{}", key.to_ascii_uppercase().replace('-', "_")))
+}
+
+/// Remplace la variable si elle existe déjà, sinon l'ajoute
+pub(crate) fn set_variable(vars: &mut Vec<(String, String)>, key: &str, value: &str) {
+    match vars.iter_mut().find(|(k, _)| k == key) {
+        Some(existing) => existing.1 = value.to_string(),
+        None => vars.push((key.to_string(), value.to_string())),
+    }
+}
+
+/// Interpréteur configuré pour l'extension, sinon celui d'usage
+pub fn handler_interpreter(handler: &CgiHandler) -> Option<&str> {
+    handler
+        .interpreter
+        .as_deref()
+        .or_else(|| interpreter_for(&handler.extension))
+}
+
+/// Interpréteur utilisé par défaut pour une extension CGI
+pub fn interpreter_for(extension: &str) -> Option<&'static str> {
+    match extension {
+        // Les installations Windows n'ont en général que `python`
+        ".py" => Some(if cfg!(windows) { "python" } else { "python3" }),
+        ".php" => Some("php"),
+        ".sh" => Some("bash"),
+        ".pl" => Some("perl"),
+        _ => None,
+    }
 }
 
 pub fn run_cgi(
     route: &Route,
-    context: CgiContext,
+    mut context: CgiContext,
     script_path: &str,
     socket_data: &mut SocketData,
 ) -> bool {
-    // Déterminer l'interpréteur basé sur l'extension
-    let interpreter = match route.cgi.as_deref() {
-        Some(".py") => "python3",
-        Some(".php") => "php",
-        Some(".sh") => "bash",
-        Some(".pl") => "perl",
-        _ => {
-            eprintln!("Unsupported CGI extension: {:?}", route.cgi);
-            send_error_response(socket_data, 500, "Unsupported CGI extension");
-            return false;
-        }
+    // Déterminer l'interpréteur d'après l'extension du script
+    let handler = route.cgi.iter().find(|h| script_path.ends_with(h.extension.as_str()));
+    let Some(interpreter) = handler.and_then(handler_interpreter) else {
+        warn!("Unsupported CGI extension for {}", script_path);
+        send_error_response(socket_data, 500, "Unsupported CGI extension");
+        return false;
     };
 
-    println!(
+    debug!(
         "Executing CGI: {} {} with query: {}",
         interpreter, script_path, context.query_string
     );
@@ -60,127 +359,609 @@ pub fn run_cgi(
     // Construire la commande
     let mut cmd = Command::new(interpreter);
     cmd.arg(script_path)
-        .env("REQUEST_METHOD", &context.method)
-        .env("QUERY_STRING", &context.query_string)
-        .env("SCRIPT_FILENAME", script_path)
-        .env("PATH_INFO", &context.path)
-        .env("SERVER_PROTOCOL", "HTTP/1.1")
-        .env("GATEWAY_INTERFACE", "CGI/1.1")
+        .envs(cgi_variables(&context, script_path, socket_data))
+        .stdin(Stdio::null())
         .stdout(Stdio::piped())
-        .stderr(Stdio::piped());
+        .stderr(Stdio::inherit());
 
-    // Ajouter les headers HTTP comme variables d'environnement CGI
-    for (key, value) in &context.headers {
-        let env_key = format!("HTTP_{}", key.to_uppercase().replace("-", "_"));
-        cmd.env(env_key, value);
-    }
-
-    // Si c'est un POST avec un body, configurer stdin
-    if context.method == "POST" && !context.body.is_empty() {
+    // Le body (en mémoire ou spillé sur disque) est écrit sur stdin au fil
+    // des événements, sans bloquer la boucle
+    let input: Option<Box<dyn Read>> = match (&context.body_file, &context.body_stream) {
+        (Some(spooled), _) => match spooled.open() {
+            Ok(file) => Some(Box::new(file)),
+            Err(e) => {
+                error!("Failed to open spooled body: {:?}", e);
+                send_error_response(socket_data, 500, "Failed to send data to CGI script");
+                return false;
+            }
+        },
+        // Reçu au fil de l'eau : le script lit pendant que le client envoie
+        (None, Some(stream)) => Some(Box::new(BodyStreamReader(Rc::clone(stream)))),
+        (None, None) if !context.body.is_empty() => {
+            Some(Box::new(io::Cursor::new(std::mem::take(&mut context.body))))
+        }
+        (None, None) => None,
+    };
+    if input.is_some() {
         cmd.stdin(Stdio::piped());
-        cmd.env("CONTENT_LENGTH", context.body.len().to_string());
+    }
 
-        // Détecter le Content-Type
-        if let Some((_, content_type)) = context
-            .headers
-            .iter()
-            .find(|(k, _)| k.to_lowercase() == "content-type")
-        {
-            cmd.env("CONTENT_TYPE", content_type);
+    // Spawner le processus
+    let mut child = match cmd.spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            error!("Failed to spawn CGI process: {:?}", e);
+            send_error_response(socket_data, 500, "Failed to start CGI script");
+            return false;
         }
+    };
+
+    let stdout = Receiver::from(child.stdout.take().expect("CGI stdout is piped"));
+    let stdin = child.stdin.take().map(Sender::from);
+    let nonblocking = stdout
+        .set_nonblocking(true)
+        .and_then(|_| stdin.as_ref().map_or(Ok(()), |s| s.set_nonblocking(true)));
+    if let Err(e) = nonblocking {
+        error!("Failed to set up CGI pipes: {:?}", e);
+        let _ = child.kill();
+        let _ = child.wait();
+        send_error_response(socket_data, 500, "CGI process error");
+        return false;
     }
 
-    // Spawner le processus
-    match cmd.spawn() {
-        Ok(mut child) => {
-            // Si POST, écrire le body dans stdin
-            if context.method == "POST" && !context.body.is_empty() {
-                if let Some(mut stdin) = child.stdin.take() {
-                    if let Err(e) = stdin.write_all(&context.body) {
-                        eprintln!("Failed to write body to CGI stdin: {:?}", e);
-                        send_error_response(socket_data, 500, "Failed to send data to CGI script");
-                        return false;
+    let range = context
+        .headers
+        .iter()
+        .find(|(k, _)| k == "range")
+        .map(|(_, v)| v.clone());
+
+    socket_data.status.response = Some(Box::new(CgiResponse {
+        child: Some(child),
+        stdout,
+        stdin: stdin.map(|pipe| CgiInput {
+            pipe,
+            source: input.unwrap_or_else(|| Box::new(io::empty())),
+            buf: Vec::new(),
+            pos: 0,
+        }),
+        phase: CgiPhase::Head,
+        output: Vec::new(),
+        pending: Vec::new(),
+        index: 0,
+        status: None,
+        default_status: context.status,
+        range,
+        deadline: context.deadline,
+        timeout_page: context.timeout_page,
+        script: script_path.to_string(),
+        buffer_size: context.buffer_size,
+        max_output: context.max_output,
+        read_total: 0,
+        session: context.session,
+        registered: false,
+    }));
+    socket_data.status.status = Status::Write;
+    true
+}
+
+/// Taille maximale des headers du script avant de tout bufferiser
+const MAX_HEAD: usize = 64 * 1024;
+
+/// Octets du script en attente d'envoi au-delà desquels on arrête de lire
+/// stdout : le script est freiné par le pipe plein, pas par notre mémoire
+const MAX_PENDING: usize = 64 * 1024;
+
+#[derive(PartialEq)]
+enum CgiPhase {
+    Head,      // Lecture des headers du script
+    Streaming, // Body envoyé en chunked au fil de l'eau
+    Buffering, // Body complet attendu (Content-Length, Range ou headers incomplets)
+    Exiting,   // stdout fermé, on attend le code de sortie pour répondre
+    Done,
+}
+
+/// Body de la requête en cours d'écriture sur le stdin du script
+struct CgiInput {
+    pipe: Sender,
+    source: Box<dyn Read>,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+/// Réponse d'un script CGI, pilotée par les événements de ses pipes.
+///
+/// Les pipes sont non bloquants et enregistrés dans le Poll : un script lent
+/// ne bloque plus la boucle, la connexion est parquée jusqu'à ce qu'il écrive.
+/// L'échéance de la requête est vérifiée à chaque réveil (au moins à chaque
+/// tour de boucle) ; au-delà, le script est tué.
+pub struct CgiResponse {
+    child: Option<Child>,
+    stdout: Receiver,
+    stdin: Option<CgiInput>,
+    phase: CgiPhase,
+    output: Vec<u8>,  // Sortie brute du script (headers, ou tout en mode bufferisé)
+    pending: Vec<u8>, // Octets prêts pour le client
+    index: usize,
+    status: Option<u16>, // Connu une fois les headers du script reçus
+    default_status: u16,
+    range: Option<String>,
+    deadline: Option<Instant>,
+    timeout_page: Option<String>,
+    script: String,
+    buffer_size: usize,
+    max_output: Option<usize>,
+    read_total: usize, // Octets lus sur stdout depuis le début
+    session: Option<CgiSession>,
+    registered: bool,
+}
+
+impl CgiResponse {
+    /// Fait avancer le script autant que possible sans bloquer
+    fn pump(&mut self) -> io::Result<()> {
+        if self.phase == CgiPhase::Done {
+            return Ok(());
+        }
+
+        if self.deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            warn!("CGI script ran past its deadline, killed");
+            self.kill();
+            if self.phase == CgiPhase::Streaming {
+                // Les headers sont partis : seule la fermeture signale l'échec
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "CGI script timed out"));
+            }
+            self.finish_with(timeout_response(self.timeout_page.as_deref()));
+            return Ok(());
+        }
+
+        self.feed_stdin();
+
+        let mut buf = [0u8; 8192];
+        while matches!(self.phase, CgiPhase::Head | CgiPhase::Buffering)
+            || (self.phase == CgiPhase::Streaming && self.pending.len() - self.index < MAX_PENDING)
+        {
+            match self.stdout.read(&mut buf) {
+                Ok(0) => {
+                    self.end_of_output();
+                    break;
+                }
+                Ok(n) => {
+                    self.read_total += n;
+                    if let Some(max) = self.max_output
+                        && self.read_total > max
+                    {
+                        return self.overflow(max);
                     }
-                    // Fermer stdin pour signaler la fin du body
-                    drop(stdin);
+                    self.handle_output(&buf[..n]);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    error!("Failed to read CGI output: {:?}", e);
+                    self.kill();
+                    if self.phase == CgiPhase::Streaming {
+                        return Err(e);
+                    }
+                    self.finish_with(error_page(500, "CGI process error"));
+                    return Ok(());
                 }
             }
+        }
+
+        if self.phase == CgiPhase::Exiting {
+            self.try_complete();
+        }
+        Ok(())
+    }
 
-            // Attendre la fin du processus
-            match child.wait_with_output() {
-                Ok(output) => {
-                    if output.status.success() {
-                        let body = output.stdout;
-                        let mut headers = HttpHeaders::new();
-                        let mut lines = body.split(|&b| b == b'\n');
-
-                        while let Some(line) = lines.next() {
-                            // Trim CR (\r) at the end
-                            let line = line.strip_suffix(&[b'\r']).unwrap_or(line);
-
-                            if line.is_empty() {
-                                break;
-                            }
-
-                            if let Some(colon_pos) = line.iter().position(|&b| b == b':') {
-                                let key = &line[..colon_pos];
-                                let value = &line[colon_pos + 1..];
-                                let key_str = String::from_utf8_lossy(key).trim().to_string();
-                                let value_str = String::from_utf8_lossy(value).trim().to_string();
-                                headers.insert(&key_str, &value_str);
-                            }
-                        }
-
-                        let body = lines
-                            .flat_map(|l| l.iter().cloned().chain(std::iter::once(b'\n')))
-                            .collect::<Vec<u8>>();
-
-                        println!("CGI execution successful");
-
-                        // Construire la réponse HTTP
-                        let response = HttpResponseBuilder::new(200, "OK")
-                            .headers(headers)
-                            .body(body)
-                            .build();
-
-                        socket_data.status.response = Some(Box::new(SimpleResponse::new(response)));
-                        socket_data.status.status = Status::Write;
-                        true
-                    } else {
-                        eprintln!("CGI script failed with status: {:?}", output.status);
-                        eprintln!("stderr: {}", String::from_utf8_lossy(&output.stderr));
-
-                        send_error_response(socket_data, 500, "CGI script execution failed");
-                        true // On retourne true car on a géré l'erreur
+    fn feed_stdin(&mut self) {
+        let Some(input) = self.stdin.as_mut() else {
+            return;
+        };
+        loop {
+            if input.pos == input.buf.len() {
+                input.buf.resize(8192, 0);
+                match input.source.read(&mut input.buf) {
+                    Ok(0) => {
+                        // Fermer stdin pour signaler la fin du body
+                        self.stdin = None;
+                        return;
+                    }
+                    Ok(n) => {
+                        input.buf.truncate(n);
+                        input.pos = 0;
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        // La suite du body n'est pas encore arrivée
+                        input.buf.clear();
+                        input.pos = 0;
+                        return;
+                    }
+                    Err(e) => {
+                        error!("Failed to read request body for CGI: {:?}", e);
+                        self.stdin = None;
+                        return;
                     }
                 }
+            }
+            match input.pipe.write(&input.buf[input.pos..]) {
+                Ok(n) => input.pos += n,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
                 Err(e) => {
-                    eprintln!("Failed to wait for CGI process: {:?}", e);
-                    send_error_response(socket_data, 500, "CGI process error");
-                    false
+                    // Le script n'a pas tout lu (BrokenPipe) : il répond quand même
+                    if e.kind() != io::ErrorKind::BrokenPipe {
+                        error!("Failed to write body to CGI stdin: {:?}", e);
+                    }
+                    self.stdin = None;
+                    return;
                 }
             }
         }
-        Err(e) => {
-            eprintln!("Failed to spawn CGI process: {:?}", e);
-            send_error_response(socket_data, 500, "Failed to start CGI script");
-            false
+    }
+
+    fn handle_output(&mut self, data: &[u8]) {
+        match self.phase {
+            CgiPhase::Head => {
+                self.output.extend_from_slice(data);
+                let head_complete = self.output.windows(2).any(|w| w == b"\n\n")
+                    || self.output.windows(3).any(|w| w == b"\n\r\n");
+                if head_complete {
+                    self.start_body();
+                } else if self.output.len() >= MAX_HEAD {
+                    self.phase = CgiPhase::Buffering;
+                }
+            }
+            CgiPhase::Streaming => self.push_chunk(data),
+            CgiPhase::Buffering => self.output.extend_from_slice(data),
+            CgiPhase::Exiting | CgiPhase::Done => {}
+        }
+        if self.phase == CgiPhase::Buffering && self.output.len() > self.buffer_size {
+            self.stream_buffered();
+        }
+    }
+
+    /// Headers reçus : choisir entre streaming et buffering
+    fn start_body(&mut self) {
+        let headers = split_cgi_output(&self.output).0;
+        // Sans Content-Length ni Range, le body part en chunked au fil de l'eau
+        if headers.get("content-length").is_some() || self.range.is_some() {
+            self.phase = CgiPhase::Buffering;
+            return;
+        }
+        debug!("Streaming CGI output with chunked encoding");
+        self.start_streaming();
+    }
+
+    /// Sortie bufferisée trop grosse : on renonce au Content-Length du script
+    /// et au Range du client, le reste part en chunked comme sans eux
+    fn stream_buffered(&mut self) {
+        let head_complete = self.output.windows(2).any(|w| w == b"\n\n")
+            || self.output.windows(3).any(|w| w == b"\n\r\n");
+        if !head_complete {
+            warn!("CGI script {} sent no end of headers in {} bytes, killed", self.script, self.output.len());
+            self.kill();
+            self.finish_with(error_page(502, "CGI headers too large"));
+            return;
+        }
+        debug!(
+            "CGI output of {} passed cgi_buffer_size ({} bytes), streaming with chunked encoding",
+            self.script, self.buffer_size
+        );
+        self.start_streaming();
+    }
+
+    fn start_streaming(&mut self) {
+        let (mut headers, already_read) = split_cgi_output(&self.output);
+        let already_read = already_read.to_vec();
+        headers.remove("content-length");
+        let (status_code, status_text) = take_cgi_status(&mut headers, self.default_status);
+        apply_session_headers(&mut headers, self.session.as_ref());
+        let builder = HttpResponseBuilder::new(status_code, &status_text).headers(headers);
+        self.pending = with_session_cookie(builder, self.session.as_ref()).build_chunked_head();
+        self.status = Some(status_code);
+        self.index = 0;
+        self.output = Vec::new();
+        self.phase = CgiPhase::Streaming;
+        self.push_chunk(&already_read);
+    }
+
+    fn push_chunk(&mut self, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        self.pending.extend_from_slice(format!("{:x}\r\n", data.len()).as_bytes());
+        self.pending.extend_from_slice(data);
+        self.pending.extend_from_slice(b"\r\n");
+    }
+
+    fn end_of_output(&mut self) {
+        if self.phase == CgiPhase::Streaming {
+            // Dernier chunk, sans trailers
+            self.pending.extend_from_slice(b"0\r\n\r\n");
+            self.phase = CgiPhase::Done;
+            // Le script a répondu : le reste du body ne l'intéresse plus
+            self.stdin = None;
+            if let Some(mut child) = self.child.take() {
+                match child.try_wait() {
+                    Ok(Some(status)) if status.success() => debug!("CGI execution successful"),
+                    Ok(Some(status)) => warn!("CGI script failed with status: {:?}", status),
+                    // Encore en vie après avoir fermé stdout : Drop s'en charge
+                    Ok(None) => self.child = Some(child),
+                    Err(e) => error!("Failed to wait for CGI process: {:?}", e),
+                }
+            }
+            return;
+        }
+        self.phase = CgiPhase::Exiting;
+    }
+
+    /// Réponse bufferisée, une fois le processus terminé
+    fn try_complete(&mut self) {
+        let Some(child) = self.child.as_mut() else {
+            self.finish_with(error_page(500, "CGI process error"));
+            return;
+        };
+        let status = match child.try_wait() {
+            Ok(Some(status)) => status,
+            Ok(None) => return,
+            Err(e) => {
+                error!("Failed to wait for CGI process: {:?}", e);
+                self.finish_with(error_page(500, "CGI process error"));
+                return;
+            }
+        };
+        self.child = None;
+
+        if !status.success() {
+            warn!("CGI script failed with status: {:?}", status);
+            self.finish_with(error_page(500, "CGI script execution failed"));
+            return;
+        }
+
+        let output = std::mem::take(&mut self.output);
+        let (mut headers, body) = split_cgi_output(&output);
+        let (status_code, status_text) = take_cgi_status(&mut headers, self.default_status);
+        apply_session_headers(&mut headers, self.session.as_ref());
+        let body = verify_content_length(&mut headers, body.to_vec());
+
+        debug!("CGI execution successful");
+
+        // Construire la réponse HTTP
+        let builder = HttpResponseBuilder::new(status_code, &status_text).headers(headers);
+        let response = with_session_cookie(builder, self.session.as_ref())
+            .body(body)
+            .range(self.range.as_ref())
+            .build();
+        self.finish_with(response);
+    }
+
+    fn finish_with(&mut self, response: Vec<u8>) {
+        self.status = status_code(&response);
+        self.pending = response;
+        self.index = 0;
+        self.output = Vec::new();
+        self.stdin = None;
+        self.phase = CgiPhase::Done;
+    }
+
+    /// Le script a dépassé `cgi_max_output` : il est tué, et le client reçoit
+    /// un 502 ou, si les headers sont partis, une connexion fermée
+    fn overflow(&mut self, max: usize) -> io::Result<()> {
+        warn!("CGI script {} wrote more than cgi_max_output ({} bytes), killed", self.script, max);
+        metrics::CGI_OUTPUT_OVERFLOWS.fetch_add(1, Ordering::Relaxed);
+        self.kill();
+        if self.phase == CgiPhase::Streaming {
+            return Err(io::Error::other("CGI output too large"));
+        }
+        self.finish_with(error_page(502, "CGI output too large"));
+        Ok(())
+    }
+
+    fn kill(&mut self) {
+        if let Some(mut child) = self.child.take() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+impl HttpResponseCommon for CgiResponse {
+    fn peek(&self) -> &[u8] {
+        &self.pending[self.index..]
+    }
+
+    fn next(&mut self, n: usize) {
+        self.index += n;
+    }
+
+    fn is_finished(&self) -> bool {
+        self.phase == CgiPhase::Done && self.index >= self.pending.len()
+    }
+
+    fn fill_if_needed(&mut self) -> io::Result<()> {
+        if self.index >= self.pending.len() && self.phase != CgiPhase::Done {
+            self.pending.clear();
+            self.index = 0;
         }
+        self.pump()
     }
+
+    fn status(&self) -> Option<u16> {
+        self.status
+    }
+
+    fn buffered_len(&self) -> usize {
+        self.pending.capacity() + self.output.capacity()
+    }
+
+    fn is_waiting(&self) -> bool {
+        self.phase != CgiPhase::Done && self.index >= self.pending.len()
+    }
+
+    fn poll_producer(&mut self) -> bool {
+        if self.pump().is_err() {
+            // Plus rien à envoyer : le write suivant fermera la connexion
+            self.phase = CgiPhase::Done;
+            self.pending.clear();
+            self.index = 0;
+            return true;
+        }
+        self.index < self.pending.len() || self.phase == CgiPhase::Done
+    }
+
+    fn register_sources(&mut self, registry: &Registry, next_token: &mut usize) -> io::Result<Vec<Token>> {
+        if self.registered || self.phase == CgiPhase::Done {
+            return Ok(Vec::new());
+        }
+        self.registered = true;
+
+        let mut tokens = Vec::new();
+        let token = Token(*next_token);
+        *next_token += 1;
+        registry.register(&mut self.stdout, token, Interest::READABLE)?;
+        tokens.push(token);
+        if let Some(input) = self.stdin.as_mut() {
+            let token = Token(*next_token);
+            *next_token += 1;
+            registry.register(&mut input.pipe, token, Interest::WRITABLE)?;
+            tokens.push(token);
+        }
+        Ok(tokens)
+    }
+}
+
+impl Drop for CgiResponse {
+    fn drop(&mut self) {
+        // Client parti avant la fin : ne pas laisser de zombie
+        self.kill();
+    }
+}
+
+/// Sépare la sortie du script en headers et body, sans toucher aux octets du body
+pub(crate) fn split_cgi_output(output: &[u8]) -> (HttpHeaders, &[u8]) {
+    let mut headers = HttpHeaders::new();
+    let mut pos = 0;
+
+    while pos < output.len() {
+        let line_end = output[pos..]
+            .iter()
+            .position(|&b| b == b'\n')
+            .map(|p| pos + p)
+            .unwrap_or(output.len());
+
+        // Trim CR (\r) at the end
+        let line = &output[pos..line_end];
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        pos = (line_end + 1).min(output.len());
+
+        if line.is_empty() {
+            break;
+        }
+
+        if let Some(colon_pos) = line.iter().position(|&b| b == b':') {
+            let key_str = String::from_utf8_lossy(&line[..colon_pos]).trim().to_string();
+            let value_str = String::from_utf8_lossy(&line[colon_pos + 1..]).trim().to_string();
+            headers.append(&key_str, &value_str);
+        }
+    }
+
+    (headers, &output[pos..])
+}
+
+/// Headers dont le serveur garde la maîtrise : le framing et la connexion
+/// dépendent de la façon dont la réponse est envoyée, pas du script
+const SERVER_CONTROLLED: &[&str] = &["connection", "keep-alive", "transfer-encoding", "trailer", "upgrade"];
+
+/// Statut de la réponse d'après les headers CGI (RFC 3875 §6.3) :
+/// `Status: 404 Not Found` est retiré et utilisé, un `Location:` sans
+/// `Status` fait un 302. Les headers propres au serveur sont retirés ;
+/// les autres passent tels quels, répétitions comprises.
+pub(crate) fn take_cgi_status(headers: &mut HttpHeaders, default: u16) -> (u16, String) {
+    for name in SERVER_CONTROLLED {
+        if headers.remove(name).is_some() {
+            warn!("CGI header '{}' ignored, the server sets it", name);
+        }
+    }
+
+    let parsed = headers.remove("status").and_then(|value| {
+        let value = value.trim();
+        let (code, text) = value.split_once(' ').unwrap_or((value, ""));
+        let code = code.parse::<u16>().ok().filter(|c| (100..600).contains(c))?;
+        Some((code, text.trim().to_string()))
+    });
+
+    let (code, text) = match parsed {
+        Some(status) => status,
+        None if headers.get("location").is_some() => (302, String::new()),
+        None => (default, String::new()),
+    };
+    if text.is_empty() {
+        (code, reason_phrase(code).to_string())
+    } else {
+        (code, text)
+    }
+}
+
+/// Vérifie le Content-Length déclaré par le script et répare le framing.
+///
+/// Un script qui annonce plus d'octets qu'il n'en envoie casserait le keep-alive
+/// du client ; on log l'écart et on renvoie un body dont la taille est exacte.
+pub(crate) fn verify_content_length(headers: &mut HttpHeaders, mut body: Vec<u8>) -> Vec<u8> {
+    let Some(declared) = headers.remove("content-length") else {
+        return body;
+    };
+
+    match declared.trim().parse::<usize>() {
+        Ok(len) if len == body.len() => {}
+        Ok(len) if len < body.len() => {
+            warn!(
+                "CGI Content-Length mismatch: declared {} but sent {} bytes, truncating",
+                len,
+                body.len()
+            );
+            body.truncate(len);
+        }
+        Ok(len) => {
+            warn!(
+                "CGI Content-Length mismatch: declared {} but sent {} bytes, using actual length",
+                len,
+                body.len()
+            );
+        }
+        Err(_) => {
+            warn!("CGI sent invalid Content-Length '{}', ignoring it", declared);
+        }
+    }
+
+    body
 }
 
 /// Helper pour envoyer une réponse d'erreur
-fn send_error_response(socket_data: &mut SocketData, status_code: u16, message: &str) {
+pub(crate) fn send_error_response(socket_data: &mut SocketData, status_code: u16, message: &str) {
+    let response = error_page(status_code, message);
+    socket_data.status.response = Some(Box::new(SimpleResponse::new(response)));
+    socket_data.status.status = Status::Write;
+}
+
+/// 504 avec la page d'erreur configurée, ou la page générique à défaut
+pub(crate) fn timeout_response(page: Option<&str>) -> Vec<u8> {
+    match page.and_then(|path| std::fs::read(path).ok()) {
+        Some(content) => HttpResponseBuilder::new(504, "Gateway Timeout")
+            .header("Content-Type", "text/html")
+            .body(content)
+            .build(),
+        None => error_page(504, "Gateway Timeout"),
+    }
+}
+
+pub(crate) fn error_page(status_code: u16, message: &str) -> Vec<u8> {
     let error_body = format!(
         "<html><body><h1>{} Error</h1><p>{}</p></body></html>",
         status_code, message
     );
 
-    let response = HttpResponseBuilder::new(status_code, message)
+    HttpResponseBuilder::new(status_code, message)
         .header("Content-Type", "text/html")
         .body(error_body.into_bytes())
-        .build();
-
-    socket_data.status.response = Some(Box::new(SimpleResponse::new(response)));
-    socket_data.status.status = Status::Write;
+        .build()
 }
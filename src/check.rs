@@ -0,0 +1,410 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::cgi::handler_interpreter;
+use crate::config::{Config, CpuAffinity, Route, ServerConfig};
+use crate::storage::TarBundle;
+use crate::tls::load_certified_key;
+use crate::utils::affinity;
+use crate::utils::net::{bind_std_listener, resolve_listen_addr};
+use crate::utils::HttpMethod;
+
+/// Verify that everything the config points at is usable before serving.
+///
+/// Returns one precise message per problem so operators can fix them all in
+/// one go instead of discovering them as 500s at runtime.
+pub fn self_check(config: &Config) -> Result<(), Vec<String>> {
+    let mut problems = Vec::new();
+
+    for server in &config.servers {
+        for page in &server.error_pages {
+            if !Path::new(&page.path).is_file() {
+                problems.push(format!(
+                    "server '{}': error page {} '{}' does not exist",
+                    server.server_name, page.code, page.path
+                ));
+            } else if fs::File::open(&page.path).is_err() {
+                problems.push(format!(
+                    "server '{}': error page {} '{}' is not readable",
+                    server.server_name, page.code, page.path
+                ));
+            }
+        }
+
+        if let Some(tls) = &server.tls
+            && let Err(e) = load_certified_key(tls)
+        {
+            problems.push(format!("server '{}': tls: {}", server.server_name, e));
+        }
+
+        for route in &server.routes {
+            check_route(config, server, route, &mut problems);
+        }
+    }
+
+    for upstream in &config.upstreams {
+        for backend in &upstream.servers {
+            let port = backend.rsplit_once(':').map(|(_, port)| port.parse::<u16>());
+            if !matches!(port, Some(Ok(_))) {
+                problems.push(format!(
+                    "upstream '{}': server '{}' is not a host:port address",
+                    upstream.name, backend
+                ));
+            }
+        }
+    }
+
+    if let CpuAffinity::Cores(cores) = &config.worker_cpu_affinity {
+        let allowed = affinity::allowed_cpus();
+        for core in cores.iter().filter(|core| !allowed.contains(core)) {
+            problems.push(format!("worker_cpu_affinity: core {} is not available to this process", core));
+        }
+    }
+
+    if let Some(path) = &config.auth_file
+        && fs::read_to_string(path).is_err()
+    {
+        problems.push(format!("auth_file: '{}' cannot be read", path));
+    }
+
+    // A port speaks either TLS or plain HTTP, with or without PROXY headers,
+    // for every server on it
+    let mut listeners: HashMap<(&str, u16), Vec<&ServerConfig>> = HashMap::new();
+    for server in &config.servers {
+        for port in &server.ports {
+            listeners.entry((&server.host, *port)).or_default().push(server);
+        }
+    }
+    for ((host, port), servers) in listeners {
+        let tls_count = servers.iter().filter(|s| s.tls.is_some()).count();
+        if tls_count != 0 && tls_count != servers.len() {
+            problems.push(format!(
+                "listener {}:{}: servers sharing a port must all use TLS or none",
+                host, port
+            ));
+        }
+        // The head is read before Host picks a server
+        let limits = |s: &ServerConfig| (s.max_request_line, s.max_header_size, s.max_header_count, s.strict);
+        if servers.iter().any(|s| limits(s) != limits(servers[0])) {
+            problems.push(format!(
+                "listener {}:{}: servers sharing a port must use the same max_request_line, max_header_size, max_header_count and strict",
+                host, port
+            ));
+        }
+        if servers.iter().any(|s| s.proxy_protocol != servers[0].proxy_protocol) {
+            problems.push(format!(
+                "listener {}:{}: servers sharing a port must all use proxy_protocol or none",
+                host, port
+            ));
+        }
+        // Host picks the first one, the others would never be reached
+        for (i, server) in servers.iter().enumerate() {
+            if servers[..i].iter().any(|s| s.server_name.eq_ignore_ascii_case(&server.server_name)) {
+                problems.push(format!(
+                    "listener {}:{}: server_name '{}' is used by more than one server",
+                    host, port, server.server_name
+                ));
+            }
+        }
+    }
+
+    // Otherwise only found out at shutdown, with the numbers of the run lost;
+    // the others once the terminal that could show the error is gone
+    let files = [
+        ("stats_file", &config.stats_file),
+        ("pidfile", &config.pidfile),
+        ("log_file", &config.log_file),
+        ("error_log", &config.error_log),
+    ];
+    for (key, file) in files {
+        let Some(file) = file else {
+            continue;
+        };
+        let dir = Path::new(file)
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        if !is_writable_dir(dir) {
+            problems.push(format!("{} '{}': directory is not writable", key, file));
+        }
+    }
+
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(problems)
+    }
+}
+
+/// `localserver -t|--test`: run every check, try binding every listen
+/// address, and report. Returns the exit status, nonzero on any problem.
+pub fn run_test(config: &Config, path: &str) -> i32 {
+    let routes: usize = config.servers.iter().map(|server| server.routes.len()).sum();
+    println!("{}: syntax ok, {} server(s), {} route(s)", path, config.servers.len(), routes);
+
+    let mut problems = self_check(config).err().unwrap_or_default();
+    let mut warnings = Vec::new();
+    check_ports(config, &mut problems, &mut warnings);
+
+    for warning in &warnings {
+        println!("  warning: {}", warning);
+    }
+    if problems.is_empty() {
+        println!("{}: test is successful", path);
+        return 0;
+    }
+    eprintln!("{}: test failed, {} problem(s):", path, problems.len());
+    for problem in &problems {
+        eprintln!("  - {}", problem);
+    }
+    1
+}
+
+/// Bind every listen address once and let go of it. A port in use may be
+/// the running server's, checked before a reload, so that is only a warning.
+fn check_ports(config: &Config, problems: &mut Vec<String>, warnings: &mut Vec<String>) {
+    let mut seen = Vec::new();
+    for server in &config.servers {
+        for &port in &server.ports {
+            if seen.contains(&(&server.host, port)) {
+                continue;
+            }
+            seen.push((&server.host, port));
+            let bound = resolve_listen_addr(&server.host, port).and_then(|addr| bind_std_listener(addr, false));
+            match bound {
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::AddrInUse => {
+                    warnings.push(format!("listener {}:{}: port already in use", server.host, port));
+                }
+                Err(e) => problems.push(format!("listener {}:{}: cannot bind: {}", server.host, port, e)),
+            }
+        }
+    }
+}
+
+/// Problems with one route of `server`, also run on routes changed at runtime
+pub fn check_route(config: &Config, server: &ServerConfig, route: &Route, problems: &mut Vec<String>) {
+    let context = format!("server '{}' route '{}'", server.server_name, route.path);
+
+    // Redirect routes never touch the filesystem
+    if route.redirect.is_some() {
+        return;
+    }
+
+    // Neither do proxied ones, but their upstream must exist
+    if let Some(target) = &route.proxy_pass {
+        if !target.starts_with("http://") && !config.upstreams.iter().any(|u| u.name == *target) {
+            problems.push(format!("{}: proxy_pass to unknown upstream '{}'", context, target));
+        }
+        return;
+    }
+
+    if let Some(bundle) = &route.bundle {
+        if let Err(e) = TarBundle::load(bundle) {
+            problems.push(format!("{}: bundle '{}' cannot be loaded: {}", context, bundle, e));
+        }
+        if route.stat == Some(true) {
+            problems.push(format!("{}: stat needs files on disk, not a bundle", context));
+        }
+        return;
+    }
+
+    // Every variant of a split must be there, whichever one a client lands on
+    for variant in &route.split {
+        let root = format!("{}/{}", server.root, variant.root);
+        if fs::read_dir(&root).is_err() {
+            problems.push(format!(
+                "{}: split root '{}' is not a readable directory",
+                context, root
+            ));
+        }
+    }
+    let mut root = format!("{}/{}", server.root, route.root);
+    if let Some(fallback_root) = &route.fallback_root {
+        let fallback = format!("{}/{}", server.root, fallback_root);
+        if fs::read_dir(&fallback).is_err() {
+            problems.push(format!(
+                "{}: fallback_root '{}' is not a readable directory",
+                context, fallback
+            ));
+            return;
+        }
+        // Starting mid-deploy is what the fallback is for
+        if fs::read_dir(&root).is_err() {
+            println!("{}: root '{}' is unavailable, starting on fallback_root", context, root);
+            root = fallback;
+        }
+    }
+    if route.split.is_empty() && fs::read_dir(&root).is_err() {
+        problems.push(format!(
+            "{}: root '{}' is not a readable directory",
+            context, root
+        ));
+        return;
+    }
+
+    if let Some(not_found) = &route.not_found
+        && route.split.is_empty()
+        && route.bundle.is_none()
+        && !Path::new(&root).join(not_found).is_file()
+    {
+        problems.push(format!("{}: not_found file '{}/{}' does not exist", context, root, not_found));
+    }
+
+    if let Some(address) = &route.fastcgi_pass {
+        // The application runs the scripts, no local interpreter needed
+        if route.cgi.is_empty() {
+            problems.push(format!(
+                "{}: fastcgi_pass '{}' needs a 'cgi' extension to select scripts",
+                context, address
+            ));
+        }
+    } else {
+        for handler in &route.cgi {
+            let ext = &handler.extension;
+            match handler_interpreter(handler) {
+                None => problems.push(format!("{}: unsupported CGI extension '{}'", context, ext)),
+                // An explicit path is used as is, a bare name is looked up in PATH
+                Some(interpreter) if interpreter.contains(std::path::is_separator) => {
+                    if !is_executable(Path::new(interpreter)) {
+                        problems.push(format!(
+                            "{}: CGI interpreter '{}' for '{}' is not an executable file",
+                            context, interpreter, ext
+                        ));
+                    }
+                }
+                Some(interpreter) => {
+                    if find_executable(interpreter).is_none() {
+                        problems.push(format!(
+                            "{}: CGI interpreter '{}' for '{}' not found in PATH",
+                            context, interpreter, ext
+                        ));
+                    }
+                }
+            }
+        }
+    }
+
+    if let Some(scripts) = &route.cgi_scripts {
+        if route.cgi.is_empty() {
+            problems.push(format!("{}: cgi_scripts has no effect without a 'cgi' extension", context));
+        }
+        for script in scripts {
+            if !route.cgi.iter().any(|h| script.ends_with(h.extension.as_str())) {
+                problems.push(format!("{}: cgi_scripts entry '{}' has none of the 'cgi' extensions", context, script));
+            } else if route.fastcgi_pass.is_none() && !Path::new(&root).join(script).is_file() {
+                problems.push(format!("{}: cgi_scripts entry '{}/{}' does not exist", context, root, script));
+            }
+        }
+    }
+    if route.cgi_timeout.is_some() && route.cgi.is_empty() {
+        problems.push(format!("{}: cgi_timeout has no effect without a 'cgi' extension", context));
+    }
+    if route.cgi_session == Some(true) && route.cgi.is_empty() {
+        problems.push(format!("{}: cgi_session has no effect without a 'cgi' extension", context));
+    }
+    if (route.cgi_buffer_size.is_some() || route.cgi_max_output.is_some()) && route.cgi.is_empty() {
+        problems.push(format!(
+            "{}: cgi_buffer_size/cgi_max_output have no effect without a 'cgi' extension",
+            context
+        ));
+    }
+
+    let edits_headers = !route.add_headers.is_empty() || !route.remove_headers.is_empty();
+    if edits_headers && route.filters.as_ref().is_some_and(|f| !f.iter().any(|n| n == "headers")) {
+        problems.push(format!(
+            "{}: add_headers/remove_headers have no effect, 'headers' is missing from filters",
+            context
+        ));
+    }
+    if route.compression.is_some() && route.filters.as_ref().is_some_and(|f| !f.iter().any(|n| n == "compress")) {
+        problems.push(format!(
+            "{}: compression has no effect, 'compress' is missing from filters",
+            context
+        ));
+    }
+
+    let configures_cgi = route.cgi_headers_allow.is_some()
+        || !route.cgi_headers_deny.is_empty()
+        || !route.cgi_env.is_empty();
+    if configures_cgi && route.cgi.is_empty() {
+        problems.push(format!(
+            "{}: cgi_headers_allow/cgi_headers_deny/cgi_env have no effect without 'cgi'",
+            context
+        ));
+    }
+
+    let accepts_uploads = route
+        .methods
+        .iter()
+        .any(|m| HttpMethod::from_str(m) == HttpMethod::POST);
+    if accepts_uploads && route.cgi.is_empty() && !is_writable_dir(Path::new(&root)) {
+        problems.push(format!(
+            "{}: upload directory '{}' is not writable",
+            context, root
+        ));
+    }
+}
+
+/// Asks the OS rather than creating a file, so checking never leaves anything
+/// behind in an upload root. Search permission is needed too, to create entries.
+#[cfg(unix)]
+fn is_writable_dir(dir: &Path) -> bool {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    if !dir.is_dir() {
+        return false;
+    }
+    let Ok(path) = CString::new(dir.as_os_str().as_bytes()) else {
+        return false;
+    };
+    unsafe { libc::access(path.as_ptr(), libc::W_OK | libc::X_OK) == 0 }
+}
+
+/// Only the read-only attribute is known here; ACLs that deny writing
+/// still surface when the first upload fails
+#[cfg(not(unix))]
+fn is_writable_dir(dir: &Path) -> bool {
+    fs::metadata(dir).is_ok_and(|meta| meta.is_dir() && !meta.permissions().readonly())
+}
+
+fn find_executable(name: &str) -> Option<PathBuf> {
+    let paths = env::var_os("PATH")?;
+    env::split_paths(&paths)
+        .flat_map(|dir| executable_names(name).map(move |n| dir.join(n)))
+        .find(|candidate| is_executable(candidate))
+}
+
+#[cfg(unix)]
+fn executable_names(name: &str) -> impl Iterator<Item = String> {
+    std::iter::once(name.to_string())
+}
+
+/// `python` is found as `python.exe`, with the extensions PATHEXT lists
+#[cfg(not(unix))]
+fn executable_names(name: &str) -> impl Iterator<Item = String> {
+    let extensions = env::var("PATHEXT").unwrap_or_else(|_| ".COM;.EXE;.BAT;.CMD".to_string());
+    let with_extensions: Vec<String> = extensions
+        .split(';')
+        .filter(|ext| !ext.is_empty())
+        .map(|ext| format!("{}{}", name, ext.to_ascii_lowercase()))
+        .collect();
+    std::iter::once(name.to_string()).chain(with_extensions)
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    fs::metadata(path)
+        .map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
@@ -0,0 +1,755 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr, ToSocketAddrs};
+use std::rc::Rc;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use mio::net::TcpStream;
+use mio::{Interest, Registry, Token};
+
+use crate::utils::HttpHeaders;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Largest response head accepted from an upstream
+const MAX_RESPONSE_HEAD: usize = 64 * 1024;
+
+/// Body bytes a streamed response holds before reading from the upstream
+/// pauses, until the consumer takes them
+pub const STREAM_HIGH_WATER: usize = 64 * 1024;
+
+/// A request body sent from a file is read this much at a time
+const BODY_CHUNK: usize = 64 * 1024;
+
+pub type ClientCallback = Box<dyn FnOnce(io::Result<ClientResponse>)>;
+
+/// An outbound request built by a handler
+pub struct ClientRequest {
+    method: String,
+    url: String,
+    headers: HttpHeaders,
+    body: Vec<u8>,
+    body_file: Option<(File, u64)>,
+    timeout: Duration,
+    max_response: Option<usize>,
+}
+
+impl ClientRequest {
+    pub fn new(method: &str, url: &str) -> Self {
+        Self {
+            method: method.to_uppercase(),
+            url: url.to_string(),
+            headers: HttpHeaders::new(),
+            body: Vec::new(),
+            body_file: None,
+            timeout: DEFAULT_TIMEOUT,
+            max_response: None,
+        }
+    }
+
+    pub fn get(url: &str) -> Self {
+        Self::new("GET", url)
+    }
+
+    pub fn post(url: &str, body: Vec<u8>) -> Self {
+        Self::new("POST", url).body(body)
+    }
+
+    pub fn header(mut self, key: &str, value: &str) -> Self {
+        self.headers.insert(key, value);
+        self
+    }
+
+    /// Add a value without replacing the ones already set for `key`
+    pub fn append_header(mut self, key: &str, value: &str) -> Self {
+        self.headers.append(key, value);
+        self
+    }
+
+    pub fn body(mut self, body: Vec<u8>) -> Self {
+        self.body = body;
+        self
+    }
+
+    /// Send `len` bytes of `file` as the body, read as the socket takes them
+    /// instead of all at once
+    pub fn body_file(mut self, file: File, len: u64) -> Self {
+        self.body_file = Some((file, len));
+        self
+    }
+
+    /// Longest wait for the upstream: to connect, or between two reads or writes
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Fail once the response body grows past `max` bytes
+    pub fn max_response_size(mut self, max: Option<usize>) -> Self {
+        self.max_response = max;
+        self
+    }
+}
+
+/// Response received from an upstream server
+#[derive(Debug, Clone)]
+pub struct ClientResponse {
+    pub status: u16,
+    pub headers: HttpHeaders,
+    pub body: Vec<u8>,
+}
+
+/// Response of a request sent with `HttpClient::stream`, filled by the
+/// event loop as it arrives. Reading from the upstream pauses while `body`
+/// holds `STREAM_HIGH_WATER` bytes, and stops once the consumer drops it.
+#[derive(Default)]
+pub struct ClientStream {
+    pub head: Option<(u16, HttpHeaders)>, // Status and headers, until the consumer takes them
+    pub body: Vec<u8>,                    // Body bytes not taken yet
+    pub done: bool,                       // Nothing more will come, see `error`
+    pub error: Option<io::Error>,
+}
+
+/// Where the response of an outbound request goes
+enum Sink {
+    Callback {
+        callback: ClientCallback,
+        head: Option<(u16, HttpHeaders)>,
+        body: Vec<u8>,
+    },
+    Stream(Rc<RefCell<ClientStream>>),
+}
+
+impl Sink {
+    fn head(&mut self, status: u16, headers: HttpHeaders) {
+        match self {
+            Sink::Callback { head, .. } => *head = Some((status, headers)),
+            Sink::Stream(stream) => stream.borrow_mut().head = Some((status, headers)),
+        }
+    }
+
+    fn body(&mut self, data: &[u8]) {
+        match self {
+            Sink::Callback { body, .. } => body.extend_from_slice(data),
+            Sink::Stream(stream) => stream.borrow_mut().body.extend_from_slice(data),
+        }
+    }
+
+    /// The consumer is behind, reading waits for it
+    fn full(&self) -> bool {
+        matches!(self, Sink::Stream(stream) if stream.borrow().body.len() >= STREAM_HIGH_WATER)
+    }
+
+    /// The consumer went away, the rest of the response is unwanted
+    fn abandoned(&self) -> bool {
+        matches!(self, Sink::Stream(stream) if Rc::strong_count(stream) == 1)
+    }
+
+    fn complete(self) {
+        match self {
+            Sink::Callback { callback, head: Some((status, headers)), body } => {
+                callback(Ok(ClientResponse { status, headers, body }))
+            }
+            Sink::Callback { callback, head: None, .. } => callback(Err(malformed())),
+            Sink::Stream(stream) => stream.borrow_mut().done = true,
+        }
+    }
+
+    fn fail(self, e: io::Error) {
+        match self {
+            Sink::Callback { callback, .. } => callback(Err(e)),
+            Sink::Stream(stream) => {
+                let mut stream = stream.borrow_mut();
+                stream.error = Some(e);
+                stream.done = true;
+            }
+        }
+    }
+}
+
+/// Handle used by handlers to queue outbound requests.
+///
+/// Requests are only queued here; the server picks them up on its next loop
+/// iteration and drives the sockets through the same Poll as client connections.
+#[derive(Clone, Default)]
+pub struct HttpClient {
+    queue: Rc<RefCell<Vec<(ClientRequest, Sink)>>>,
+}
+
+impl HttpClient {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queue `request`; `callback` gets the whole response once it is in
+    pub fn send<F>(&self, request: ClientRequest, callback: F)
+    where
+        F: FnOnce(io::Result<ClientResponse>) + 'static,
+    {
+        let sink = Sink::Callback {
+            callback: Box::new(callback),
+            head: None,
+            body: Vec::new(),
+        };
+        self.queue.borrow_mut().push((request, sink));
+    }
+
+    /// Queue `request`, its response handed over piece by piece as it arrives
+    pub fn stream(&self, request: ClientRequest) -> Rc<RefCell<ClientStream>> {
+        let stream = Rc::new(RefCell::new(ClientStream::default()));
+        self.queue.borrow_mut().push((request, Sink::Stream(Rc::clone(&stream))));
+        stream
+    }
+
+    fn take_pending(&self) -> Vec<(ClientRequest, Sink)> {
+        std::mem::take(&mut *self.queue.borrow_mut())
+    }
+}
+
+#[derive(PartialEq)]
+enum OutboundState {
+    Connecting,
+    Writing,
+    Reading,
+}
+
+/// One request and its response, from the lookup of the host to the last byte
+struct Exchange {
+    request_bytes: Vec<u8>, // Head, then each chunk of a body sent from a file
+    written: usize,
+    body_file: Option<File>,
+    body_left: u64,     // Bytes of `body_file` not read yet
+    head_request: bool, // HEAD: no body follows, whatever the headers say
+    raw_head: Vec<u8>,  // Response head, until complete
+    head_received: bool,
+    expected: Option<u64>, // Body length announced by the upstream
+    received: u64,
+    max_response: Option<usize>,
+    timeout: Duration,
+    deadline: Instant, // Pushed back on every read or write
+    sink: Sink,
+}
+
+impl Exchange {
+    /// Next piece of a body sent from a file, once the last one is written
+    fn next_body_chunk(&mut self) -> io::Result<()> {
+        let Some(file) = self.body_file.as_mut() else {
+            return Err(short_body());
+        };
+        let wanted = usize::try_from(self.body_left).map_or(BODY_CHUNK, |left| left.min(BODY_CHUNK));
+        self.request_bytes.resize(wanted, 0);
+        let n = file.read(&mut self.request_bytes)?;
+        if n == 0 {
+            return Err(short_body());
+        }
+        self.request_bytes.truncate(n);
+        self.written = 0;
+        self.body_left -= n as u64;
+        Ok(())
+    }
+
+    /// Take bytes from the upstream; true once the whole response is in
+    fn receive(&mut self, data: &[u8]) -> io::Result<bool> {
+        if self.head_received {
+            return self.receive_body(data);
+        }
+        self.raw_head.extend_from_slice(data);
+        let Some(end) = self.raw_head.windows(4).position(|w| w == b"\r\n\r\n") else {
+            if self.raw_head.len() > MAX_RESPONSE_HEAD {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "upstream response head too large"));
+            }
+            return Ok(false);
+        };
+        let (status, headers) = parse_head(&self.raw_head[..end])?;
+        self.expected = match (self.head_request, status) {
+            (true, _) | (_, 100..=199 | 204 | 304) => Some(0),
+            _ => headers.get("content-length").and_then(|v| v.trim().parse::<u64>().ok()),
+        };
+        if let (Some(max), Some(len)) = (self.max_response, self.expected)
+            && len > max as u64
+        {
+            return Err(too_large());
+        }
+        let rest = self.raw_head.split_off(end + 4);
+        self.raw_head = Vec::new();
+        self.head_received = true;
+        self.sink.head(status, headers);
+        self.receive_body(&rest)
+    }
+
+    fn receive_body(&mut self, data: &[u8]) -> io::Result<bool> {
+        // Bytes past the announced length are dropped, the exchange is over
+        let data = match self.expected {
+            Some(len) => &data[..usize::try_from(len - self.received).map_or(data.len(), |left| left.min(data.len()))],
+            None => data,
+        };
+        self.received += data.len() as u64;
+        if let Some(max) = self.max_response
+            && self.received > max as u64
+        {
+            return Err(too_large());
+        }
+        self.sink.body(data);
+        Ok(self.expected == Some(self.received))
+    }
+
+    /// The upstream closed its end: fine once the announced body is in
+    fn end_of_response(&self) -> io::Result<()> {
+        if !self.head_received {
+            return Err(malformed());
+        }
+        if self.expected.is_some_and(|len| self.received < len) {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "upstream closed before sending the full body",
+            ));
+        }
+        Ok(())
+    }
+}
+
+struct OutboundConnection {
+    stream: TcpStream,
+    state: OutboundState,
+    paused: bool, // Reading waits for the consumer of a streamed response
+    exchange: Exchange,
+}
+
+/// Request waiting for its host name to be resolved on a helper thread
+struct PendingLookup {
+    address: Receiver<io::Result<SocketAddr>>,
+    exchange: Exchange,
+}
+
+/// Outbound connections owned by the event loop
+#[derive(Default)]
+pub struct OutboundPool {
+    connections: HashMap<Token, OutboundConnection>,
+    lookups: Vec<PendingLookup>,
+}
+
+impl OutboundPool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn contains(&self, token: Token) -> bool {
+        self.connections.contains_key(&token)
+    }
+
+    /// Start every request queued on the client since the last iteration,
+    /// and connect those whose host name was resolved since
+    pub fn start_pending(
+        &mut self,
+        client: &HttpClient,
+        registry: &Registry,
+        next_token: &mut usize,
+    ) {
+        for (request, sink) in client.take_pending() {
+            let (host, port, path) = match parse_url(&request.url) {
+                Ok(parts) => parts,
+                Err(e) => {
+                    sink.fail(e);
+                    continue;
+                }
+            };
+            debug!("Outbound {} {}", request.method, request.url);
+            let request_bytes = encode_request(&request, &host, &path);
+            let (body_file, body_left) = match request.body_file {
+                Some((file, len)) => (Some(file), len),
+                None => (None, 0),
+            };
+            let exchange = Exchange {
+                request_bytes,
+                written: 0,
+                body_file,
+                body_left,
+                head_request: request.method == "HEAD",
+                raw_head: Vec::new(),
+                head_received: false,
+                expected: None,
+                received: 0,
+                max_response: request.max_response,
+                timeout: request.timeout,
+                deadline: Instant::now() + request.timeout,
+                sink,
+            };
+
+            // getaddrinfo blocks, so names are looked up off the event loop
+            let (sender, address) = mpsc::channel();
+            match host.parse::<IpAddr>() {
+                Ok(ip) => {
+                    let _ = sender.send(Ok(SocketAddr::new(ip, port)));
+                }
+                Err(_) => {
+                    if let Err(e) = spawn_lookup(host, port, sender) {
+                        exchange.sink.fail(e);
+                        continue;
+                    }
+                }
+            }
+            self.lookups.push(PendingLookup { address, exchange });
+        }
+
+        let mut waiting = Vec::new();
+        for lookup in std::mem::take(&mut self.lookups) {
+            let address = match lookup.address.try_recv() {
+                Ok(address) => address,
+                Err(TryRecvError::Empty) => {
+                    waiting.push(lookup);
+                    continue;
+                }
+                Err(TryRecvError::Disconnected) => {
+                    Err(io::Error::other("name resolution thread failed"))
+                }
+            };
+            match address.and_then(|addr| open(addr, registry, next_token)) {
+                Ok((stream, token)) => {
+                    self.connections.insert(
+                        token,
+                        OutboundConnection {
+                            stream,
+                            state: OutboundState::Connecting,
+                            paused: false,
+                            exchange: lookup.exchange,
+                        },
+                    );
+                }
+                Err(e) => lookup.exchange.sink.fail(e),
+            }
+        }
+        self.lookups = waiting;
+    }
+
+    /// Read again from the upstreams whose consumer caught up, and drop
+    /// those whose consumer went away
+    pub fn resume(&mut self, registry: &Registry) {
+        let ready: Vec<Token> = self
+            .connections
+            .iter()
+            .filter(|(_, conn)| (conn.paused && !conn.exchange.sink.full()) || conn.exchange.sink.abandoned())
+            .map(|(token, _)| *token)
+            .collect();
+        for token in ready {
+            self.handle_event(token, registry);
+        }
+    }
+
+    /// Drive the connection behind `token` after a readiness event
+    pub fn handle_event(&mut self, token: Token, registry: &Registry) {
+        let Some(conn) = self.connections.get_mut(&token) else {
+            return;
+        };
+        if conn.exchange.sink.abandoned() {
+            debug!("Outbound {:?} no longer wanted, closing", token);
+            self.finish(token, registry);
+            return;
+        }
+
+        match drive(conn) {
+            Ok(false) => {}
+            Ok(true) => self.finish(token, registry).exchange.sink.complete(),
+            Err(e) => self.finish(token, registry).exchange.sink.fail(e),
+        }
+    }
+
+    /// Fail every request whose deadline has passed
+    pub fn check_timeouts(&mut self, registry: &Registry) {
+        let now = Instant::now();
+        // A consumer that stopped taking the body holds the upstream back,
+        // it is not the upstream's silence
+        let expired: Vec<Token> = self
+            .connections
+            .iter()
+            .filter(|(_, conn)| !conn.paused && now >= conn.exchange.deadline)
+            .map(|(token, _)| *token)
+            .collect();
+
+        for token in expired {
+            self.finish(token, registry).exchange.sink.fail(timed_out());
+        }
+
+        // A stuck lookup is left to finish on its own, its answer unread
+        let (expired, waiting) = std::mem::take(&mut self.lookups)
+            .into_iter()
+            .partition(|lookup| now >= lookup.exchange.deadline);
+        self.lookups = waiting;
+        for lookup in expired {
+            lookup.exchange.sink.fail(timed_out());
+        }
+    }
+
+    fn finish(&mut self, token: Token, registry: &Registry) -> OutboundConnection {
+        let mut conn = self
+            .connections
+            .remove(&token)
+            .expect("finishing unknown outbound connection");
+        let _ = registry.deregister(&mut conn.stream);
+        let _ = conn.stream.shutdown(Shutdown::Both);
+        conn
+    }
+}
+
+fn timed_out() -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, "outbound request timed out")
+}
+
+fn malformed() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "malformed upstream response")
+}
+
+fn too_large() -> io::Error {
+    io::Error::new(io::ErrorKind::FileTooLarge, "upstream response over its size limit")
+}
+
+fn short_body() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "request body file shorter than announced")
+}
+
+/// Look `host` up on a thread of its own; the result arrives on `sender`
+pub(crate) fn spawn_lookup(host: String, port: u16, sender: mpsc::Sender<io::Result<SocketAddr>>) -> io::Result<()> {
+    thread::Builder::new()
+        .name("resolver".to_string())
+        .spawn(move || {
+            let _ = sender.send(resolve(&host, port));
+        })
+        .map(drop)
+}
+
+/// First address of `host`, looked up with the system resolver
+fn resolve(host: &str, port: u16) -> io::Result<SocketAddr> {
+    (host, port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "host not found"))
+}
+
+/// Start a non-blocking connect, registered under a fresh token
+fn open(addr: SocketAddr, registry: &Registry, next_token: &mut usize) -> io::Result<(TcpStream, Token)> {
+    let mut stream = TcpStream::connect(addr)?;
+    let token = Token(*next_token);
+    *next_token += 1;
+    registry.register(&mut stream, token, Interest::READABLE.add(Interest::WRITABLE))?;
+    debug!("Outbound connection to {} on {:?}", addr, token);
+    Ok((stream, token))
+}
+
+/// Request head, followed by the body unless it is sent from a file
+fn encode_request(request: &ClientRequest, host: &str, path: &str) -> Vec<u8> {
+    // An IPv6 literal keeps its brackets in the Host header
+    let host = match host.contains(':') {
+        true => format!("[{}]", host),
+        false => host.to_string(),
+    };
+    // HTTP/1.0 keeps the exchange simple: no chunked body, server closes when done
+    let mut head = format!("{} {} HTTP/1.0\r\nHost: {}\r\n", request.method, path, host);
+    for (key, value) in request.headers.iter() {
+        head.push_str(&format!("{}: {}\r\n", key, value));
+    }
+    let body_len = request.body_file.as_ref().map_or(request.body.len() as u64, |(_, len)| *len);
+    if body_len > 0 || request.method == "POST" {
+        head.push_str(&format!("Content-Length: {}\r\n", body_len));
+    }
+    head.push_str("Connection: close\r\n\r\n");
+
+    let mut bytes = head.into_bytes();
+    if request.body_file.is_none() {
+        bytes.extend_from_slice(&request.body);
+    }
+    bytes
+}
+
+/// Returns Ok(true) once the full response has been received
+fn drive(conn: &mut OutboundConnection) -> io::Result<bool> {
+    if conn.state == OutboundState::Connecting {
+        if let Some(e) = conn.stream.take_error()? {
+            return Err(e);
+        }
+        match conn.stream.peer_addr() {
+            Ok(_) => conn.state = OutboundState::Writing,
+            Err(ref e) if e.kind() == io::ErrorKind::NotConnected => return Ok(false),
+            Err(e) => return Err(e),
+        }
+    }
+
+    let exchange = &mut conn.exchange;
+    while conn.state == OutboundState::Writing {
+        if exchange.written >= exchange.request_bytes.len() {
+            if exchange.body_left == 0 {
+                conn.state = OutboundState::Reading;
+                break;
+            }
+            exchange.next_body_chunk()?;
+        }
+        match conn.stream.write(&exchange.request_bytes[exchange.written..]) {
+            Ok(n) => {
+                exchange.written += n;
+                exchange.deadline = Instant::now() + exchange.timeout;
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+            Err(e) => return Err(e),
+        }
+    }
+    // The request is out, its buffers are not needed any more
+    exchange.request_bytes = Vec::new();
+    exchange.body_file = None;
+
+    let mut buf = [0u8; 16 * 1024];
+    loop {
+        conn.paused = exchange.sink.full();
+        if conn.paused {
+            return Ok(false);
+        }
+        match conn.stream.read(&mut buf) {
+            Ok(0) => return exchange.end_of_response().map(|()| true),
+            Ok(n) => {
+                exchange.deadline = Instant::now() + exchange.timeout;
+                if exchange.receive(&buf[..n])? {
+                    return Ok(true);
+                }
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn parse_url(url: &str) -> io::Result<(String, u16, String)> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidInput, "only http:// URLs are supported");
+
+    let rest = url.strip_prefix("http://").ok_or_else(invalid)?;
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+
+    // An IPv6 literal is bracketed, its colons are not the port's
+    let (host, port) = match authority.strip_prefix('[') {
+        Some(bracketed) => {
+            let (h, rest) = bracketed.split_once(']').ok_or_else(invalid)?;
+            match rest.strip_prefix(':') {
+                Some(p) => (h, p.parse::<u16>().map_err(|_| invalid())?),
+                None if rest.is_empty() => (h, 80),
+                None => return Err(invalid()),
+            }
+        }
+        None => match authority.rsplit_once(':') {
+            Some((h, p)) => (h, p.parse::<u16>().map_err(|_| invalid())?),
+            None => (authority, 80),
+        },
+    };
+
+    if host.is_empty() {
+        return Err(invalid());
+    }
+
+    Ok((host.to_string(), port, path.to_string()))
+}
+
+/// Status and headers of a response head, without its blank line
+fn parse_head(head: &[u8]) -> io::Result<(u16, HttpHeaders)> {
+    let head = String::from_utf8_lossy(head);
+    let mut lines = head.lines();
+
+    let status = lines
+        .next()
+        .and_then(|l| l.split_whitespace().nth(1))
+        .and_then(|s| s.parse::<u16>().ok())
+        .ok_or_else(malformed)?;
+
+    let mut headers = HttpHeaders::new();
+    for line in lines {
+        if let Some((key, value)) = line.split_once(':') {
+            headers.append(key, value);
+        }
+    }
+    Ok((status, headers))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exchange(max_response: Option<usize>) -> (Exchange, Rc<RefCell<ClientStream>>) {
+        let stream = Rc::new(RefCell::new(ClientStream::default()));
+        let exchange = Exchange {
+            request_bytes: Vec::new(),
+            written: 0,
+            body_file: None,
+            body_left: 0,
+            head_request: false,
+            raw_head: Vec::new(),
+            head_received: false,
+            expected: None,
+            received: 0,
+            max_response,
+            timeout: DEFAULT_TIMEOUT,
+            deadline: Instant::now() + DEFAULT_TIMEOUT,
+            sink: Sink::Stream(Rc::clone(&stream)),
+        };
+        (exchange, stream)
+    }
+
+    #[test]
+    fn response_is_streamed_as_it_arrives() {
+        let (mut exchange, stream) = exchange(None);
+        assert!(!exchange.receive(b"HTTP/1.0 200 OK\r\nContent-Le").unwrap());
+        assert!(stream.borrow().head.is_none());
+        assert!(!exchange.receive(b"ngth: 10\r\n\r\nhello").unwrap());
+        let (status, headers) = stream.borrow_mut().head.take().expect("head");
+        assert_eq!(status, 200);
+        assert_eq!(headers.get("content-length").map(|v| v.trim()), Some("10"));
+        assert_eq!(std::mem::take(&mut stream.borrow_mut().body), b"hello");
+        // Bytes past Content-Length are not part of the response
+        assert!(exchange.receive(b"worldEXTRA").unwrap());
+        assert_eq!(stream.borrow().body, b"world");
+    }
+
+    #[test]
+    fn close_before_the_announced_length_fails() {
+        let (mut exchange, _stream) = exchange(None);
+        exchange.receive(b"HTTP/1.0 200 OK\r\nContent-Length: 10\r\n\r\nhel").unwrap();
+        assert_eq!(exchange.end_of_response().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn close_delimited_body_ends_with_the_connection() {
+        let (mut exchange, stream) = exchange(None);
+        assert!(!exchange.receive(b"HTTP/1.0 200 OK\r\n\r\nsome body").unwrap());
+        assert!(exchange.end_of_response().is_ok());
+        assert_eq!(stream.borrow().body, b"some body");
+    }
+
+    #[test]
+    fn response_over_the_cap_fails() {
+        let (mut exchange, stream) = exchange(Some(8));
+        let announced = exchange.receive(b"HTTP/1.0 200 OK\r\nContent-Length: 9\r\n\r\n");
+        assert_eq!(announced.unwrap_err().kind(), io::ErrorKind::FileTooLarge);
+        assert!(stream.borrow().head.is_none());
+
+        let (mut exchange, _stream) = self::exchange(Some(8));
+        assert!(!exchange.receive(b"HTTP/1.0 200 OK\r\n\r\n12345678").unwrap());
+        assert_eq!(exchange.receive(b"9").unwrap_err().kind(), io::ErrorKind::FileTooLarge);
+    }
+
+    #[test]
+    fn head_response_has_no_body() {
+        let (mut exchange, _stream) = exchange(None);
+        exchange.head_request = true;
+        assert!(exchange.receive(b"HTTP/1.0 200 OK\r\nContent-Length: 100\r\n\r\n").unwrap());
+    }
+
+    #[test]
+    fn parse_url_splits_the_port_after_an_ipv6_literal() {
+        let parts = |url| parse_url(url).ok();
+        assert_eq!(parts("http://[::1]:8080/a"), Some(("::1".to_string(), 8080, "/a".to_string())));
+        assert_eq!(parts("http://[::1]"), Some(("::1".to_string(), 80, "/".to_string())));
+        assert_eq!(parts("http://127.0.0.1:81"), Some(("127.0.0.1".to_string(), 81, "/".to_string())));
+        assert_eq!(parts("http://[::1]8080/"), None);
+        assert_eq!(parts("http://[::1/"), None);
+    }
+}
@@ -0,0 +1,164 @@
+use std::collections::hash_map::DefaultHasher;
+use std::fs::{self, File};
+use std::hash::{Hash, Hasher};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+
+use crate::filters::CODINGS;
+
+/// Tells apart the temporary files of copies written at the same time
+static NEXT_TEMP: AtomicU64 = AtomicU64::new(0);
+
+/// Where the `coding` copy of `source` is kept under `dir`. Names come from a
+/// hash of the path, so a file's new copy replaces its old one.
+fn entry_path(dir: &str, source: &Path, coding: &str) -> PathBuf {
+    let mut hasher = DefaultHasher::new();
+    source.hash(&mut hasher);
+    Path::new(dir).join(format!("{:016x}.{}", hasher.finish(), coding))
+}
+
+/// Copies of `file_path` in `dir` that still match it, as (coding, path), for
+/// the codings in `offered`. A copy carries the modification time of the file
+/// it was made from; any other time means the file changed since.
+pub fn fresh_entries(dir: &str, file_path: &str, offered: &[String]) -> Vec<(String, String)> {
+    let Some(modified) = fs::metadata(file_path).and_then(|m| m.modified()).ok() else {
+        return Vec::new();
+    };
+    offered
+        .iter()
+        .filter_map(|coding| {
+            let path = entry_path(dir, Path::new(file_path), coding);
+            let entry_modified = fs::metadata(&path).and_then(|m| m.modified()).ok()?;
+            (entry_modified == modified).then(|| (coding.clone(), path.to_string_lossy().into_owned()))
+        })
+        .collect()
+}
+
+/// Remove the copies of `file_path` in every coding. Returns how many there were.
+pub fn purge(dir: &str, file_path: &str) -> usize {
+    CODINGS
+        .iter()
+        .filter(|coding| fs::remove_file(entry_path(dir, Path::new(file_path), coding)).is_ok())
+        .count()
+}
+
+/// Temporary files untouched this long were left by a server that stopped
+/// mid-write; a copy still being written is appended to far more often
+const ABANDONED_TEMP_AGE: Duration = Duration::from_secs(3600);
+
+/// Bring `dir` back under `max_size` bytes, removing the copies read least
+/// recently first (by access time, or modification time where the filesystem
+/// keeps none), along with abandoned temporary files. Returns how many files
+/// were removed.
+pub fn prune(dir: &str, max_size: usize) -> usize {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return 0;
+    };
+
+    let now = SystemTime::now();
+    let mut removed = 0;
+    let mut copies = Vec::new();
+    let mut total = 0u64;
+
+    for entry in entries.flatten() {
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        if !metadata.is_file() {
+            continue;
+        }
+        let path = entry.path();
+        if path.extension().is_some_and(|ext| ext == "tmp") {
+            let abandoned = metadata
+                .modified()
+                .ok()
+                .and_then(|m| now.duration_since(m).ok())
+                .is_some_and(|age| age > ABANDONED_TEMP_AGE);
+            if abandoned && fs::remove_file(&path).is_ok() {
+                removed += 1;
+            }
+            continue;
+        }
+        let last_read = metadata.accessed().or_else(|_| metadata.modified()).unwrap_or(SystemTime::UNIX_EPOCH);
+        total += metadata.len();
+        copies.push((last_read, metadata.len(), path));
+    }
+
+    copies.sort_by_key(|(last_read, _, _)| *last_read);
+    for (_, len, path) in copies {
+        if total <= max_size as u64 {
+            break;
+        }
+        if fs::remove_file(&path).is_ok() {
+            total -= len;
+            removed += 1;
+        }
+    }
+
+    removed
+}
+
+/// A compressed copy being written as the response streams out. It only
+/// takes its place in the cache once complete; a copy abandoned halfway (the
+/// client went away, the disk is full) is removed.
+pub struct CacheWriter {
+    file: Option<File>,
+    temp: PathBuf,
+    path: PathBuf,
+    modified: SystemTime,
+}
+
+impl CacheWriter {
+    /// Start the `coding` copy of `source`, whose modification time was `modified`
+    pub fn create(dir: &str, source: &Path, modified: SystemTime, coding: &str) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let path = entry_path(dir, source, coding);
+        let temp = path.with_extension(format!(
+            "{}.{}-{}.tmp",
+            coding,
+            std::process::id(),
+            NEXT_TEMP.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = File::create(&temp)?;
+        Ok(Self {
+            file: Some(file),
+            temp,
+            path,
+            modified,
+        })
+    }
+
+    /// Append compressed bytes. A failed write gives up on this copy, the
+    /// response itself goes on.
+    pub fn write(&mut self, data: &[u8]) {
+        if let Some(file) = &mut self.file
+            && let Err(e) = file.write_all(data)
+        {
+            error!("Compression cache: cannot write {}: {}", self.temp.display(), e);
+            self.file = None;
+        }
+    }
+
+    /// Put the finished copy in place, stamped with its source's modification time
+    pub fn commit(mut self) {
+        let Some(file) = self.file.take() else {
+            return;
+        };
+        let done = file.set_modified(self.modified).and_then(|_| {
+            drop(file);
+            fs::rename(&self.temp, &self.path)
+        });
+        if let Err(e) = done {
+            error!("Compression cache: cannot store {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+impl Drop for CacheWriter {
+    fn drop(&mut self) {
+        // Gone once renamed, so this only removes abandoned copies
+        let _ = fs::remove_file(&self.temp);
+    }
+}
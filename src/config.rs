@@ -1,9 +1,39 @@
 use std::fs;
+use std::net::{IpAddr, Ipv6Addr};
 use std::error::Error;
+use std::time::Duration;
+
+use crate::auth::{AUTH_SCHEMES, valid_password_format};
+use crate::dav;
+use crate::filters::{CODINGS, FILTER_NAMES};
 
 #[derive(Debug, Clone)]
 pub struct Config {
     pub servers: Vec<ServerConfig>,
+    pub tasks: Vec<TaskConfig>,
+    pub upstreams: Vec<UpstreamConfig>, // Backend groups routes can proxy_pass to
+    pub heavy_handlers_per_tick: usize, // CGI runs started per event loop iteration
+    pub memory_watermark: Option<usize>, // Buffered bytes before new connections are refused and bodies spill to disk
+    pub workers: usize, // Event loop threads sharing the listeners
+    pub accept_strategy: AcceptStrategy, // How workers share incoming connections
+    pub worker_cpu_affinity: CpuAffinity, // Cores the worker threads are pinned to
+    pub read_only: bool, // Refuse mutating methods everywhere, toggleable at runtime
+    pub admin_path: Option<String>, // Path prefix of the admin API, on every server
+    pub admin_allow: Vec<IpAddr>, // Client addresses allowed to use the admin API
+    pub loop_stall_threshold: Duration, // Event loop iterations slower than this are logged
+    pub crash_dir: Option<String>, // Where crash reports are written; none are without it
+    pub crash_log_lines: usize,    // Output lines kept for crash reports
+    pub tls_session_cache: usize,  // TLS sessions remembered by ID for resumption, 0 disables
+    pub tls_session_tickets: bool, // Let TLS clients resume from an encrypted ticket
+    pub tls_ticket_rotation: Duration, // How long one ticket key encrypts before being replaced
+    pub auth_users: Vec<(String, String)>, // Basic auth users and their `sha256:`/`plain:` passwords
+    pub auth_tokens: Vec<(String, String)>, // Bearer tokens and the user each belongs to
+    pub auth_file: Option<String>, // Users and tokens read from this file instead
+    pub stats_file: Option<String>, // JSON run summary written here on shutdown, besides the log
+    pub drain_timeout: Duration,    // On SIGINT/SIGTERM, in-flight requests get this long to finish
+    pub pidfile: Option<String>,    // Our pid is written here while the server runs
+    pub log_file: Option<String>,   // With --daemon, stdout is appended here, or dropped when unset
+    pub error_log: Option<String>,  // ...and stderr here, or to log_file when unset
 }
 
 #[derive(Debug, Clone)]
@@ -14,27 +44,251 @@ pub struct ServerConfig {
     pub default_server: bool,       // NEW: Mark as default for this (host, port)
     pub error_pages: Vec<ErrorPage>,
     pub client_max_body_size: usize,
+    pub upload_tmp_dir: Option<String>, // Where large request bodies are spilled
+    pub upload_spill_threshold: usize,  // Bodies above this many bytes go to upload_tmp_dir
+    pub header_timeout: Duration,       // Time allowed to receive the full request head
+    pub max_request_line: usize,        // Longer request lines get a 414
+    pub max_header_size: usize,         // Header sections larger than this get a 431...
+    pub max_header_count: usize,        // ...as do those with more fields
+    pub strict: bool,                   // Refuse heads that bend RFC 9112 instead of making sense of them
+    pub body_timeout: Duration,         // Longest pause allowed while receiving the body
+    pub write_timeout: Duration,        // Longest pause allowed while the client reads the response
+    pub request_timeout: Option<Duration>, // Total budget from first byte to last byte sent
+    pub max_connection_age: Option<Duration>, // Keep-alive connections are closed once this old
+    pub max_connection_requests: Option<usize>, // ...or after serving this many requests
+    pub tls: Option<TlsConfig>,         // Serve HTTPS with this certificate
+    pub error_handler: Option<String>,  // CGI path rendering every 4xx/5xx response
+    pub force_https: bool,              // Redirect plain HTTP requests to https_port
+    pub method_override: bool,          // Honour X-HTTP-Method-Override / _method on POST
+    pub https_port: Option<u16>,        // Port plain requests are redirected to
+    pub listen_error: ListenErrorPolicy, // What to do when a port can't be bound or fails
+    pub proxy_protocol: bool,           // Connections start with a PROXY header naming the real client
+    pub max_bandwidth: Option<u64>,     // Bytes per second sent by all of the server's connections
+    pub proxy_max_response_size: Option<usize>, // Larger upstream response bodies are cut off with a 502
+    pub gzip: bool,                     // Gzip responses on routes without their own `compression` list
+    pub compress_min_size: usize,       // Smaller bodies are sent as is
+    pub compress_types: Vec<String>,    // MIME types worth compressing ("text/*" matches a family)
+    pub compress_exclude: Vec<String>,  // Already-compressed types, never compressed even if listed above
+    pub compress_cache_dir: Option<String>, // Compressed copies of static files, reused until the file changes
     pub root: String,       // NEW: Server-level root directory
     pub routes: Vec<Route>,
 }
 
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    pub cert: String, // PEM certificate chain
+    pub key: String,  // PEM private key
+}
+
 #[derive(Debug, Clone)]
 pub struct ErrorPage {
     pub code: u16,
     pub path: String,
 }
 
+/// One arm of a route's A/B split
+#[derive(Debug, Clone)]
+pub struct SplitVariant {
+    pub weight: u32,
+    pub root: String, // Used in place of the route's root, under the server root
+}
+
+/// What keeps a client on the same split variant
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SplitKey {
+    Session, // The session cookie, issued with the first response
+    Ip,      // The client address, for clients that drop cookies
+}
+
+impl SplitKey {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SplitKey::Session => "session",
+            SplitKey::Ip => "ip",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CgiHandler {
+    pub extension: String,           // e.g. ".py", ".php"
+    pub interpreter: Option<String>, // Binary scripts are passed to; None picks the usual one for the extension
+}
+
 #[derive(Debug, Clone)]
 pub struct Route {
     pub path: String,
     pub methods: Vec<String>,
     pub root: String,
+    pub fallback_root: Option<String>, // Served from while `root` is missing or unreadable
     pub default_file: Option<String>,
+    pub not_found: Option<String>,  // Served with 200 in place of a 404 (single-page apps)
     pub redirect: Option<String>,   // NEW: HTTP redirect
-    pub cgi: Option<String>,        // NEW: CGI extension (e.g., ".py", ".php")
+    pub cgi: Vec<CgiHandler>,       // Script extensions run as CGI, each with its interpreter
+    pub cgi_scripts: Option<Vec<String>>, // Only these scripts, relative to the route, are ever run
     pub list_directory: Option<bool>, // NEW: Enable/disable directory listing
+    pub bundle: Option<String>,     // Tar archive the route's content is served from
+    pub read_only: Option<bool>,    // Refuse mutating methods on this route
+    pub force_https: Option<bool>,  // Redirect plain HTTP requests for this route
+    pub proxy_pass: Option<String>, // Upstream name or http://host:port to forward requests to
+    pub fastcgi_pass: Option<String>, // host:port or unix:/path of the app running `cgi` scripts
+    pub cgi_timeout: Option<Duration>, // Scripts still running after this are killed with a 504
+    pub cgi_buffer_size: Option<usize>, // Buffered script output past this is streamed chunked instead (default 1M)
+    pub cgi_max_output: Option<usize>, // Scripts writing more than this are killed
+    pub cgi_headers_allow: Option<Vec<String>>, // Only these request headers become HTTP_* variables
+    pub cgi_headers_deny: Vec<String>, // Request headers never passed to scripts (authorization, cookie...)
+    pub cgi_env: Vec<(String, String)>, // Fixed variables set for every script of the route
+    pub cgi_session: Option<bool>,  // Scripts read the session as SESSION_* and change it with X-Session-Set
+    pub batch: Option<bool>,        // Accept JSON batch file operations on POST ?batch
+    pub stat: Option<bool>,         // Answer GET ?stat=1 with a JSON summary of the directory
+    pub webdav: Option<bool>,       // Serve the root as a WebDAV share (PROPFIND, MKCOL, MOVE, COPY)
+    pub filters: Option<Vec<String>>, // Response filters in the order they run (default: headers, compress)
+    pub add_headers: Vec<(String, String)>, // Set on every response by the `headers` filter
+    pub remove_headers: Vec<String>,  // Dropped from every response by the `headers` filter
+    pub compression: Option<Vec<String>>, // Codings offered to clients, preferred first (gzip, br, zstd)
+    pub precompressed: Option<bool>, // Serve foo.css.br / .zst / .gz in place of foo.css when accepted
+    pub case_insensitive: Option<bool>, // Match the path and find files on disk ignoring case
+    pub cache_control: Option<String>, // Cache-Control sent with the route's static files
+    pub cache_control_by_extension: Vec<(String, String)>, // Overrides by file extension, without the dot
+    pub split: Vec<SplitVariant>, // Roots picked by weight, sticky per session or client address
+    pub split_by: SplitKey,
+    pub auth: Option<Vec<String>>, // Schemes a request may authenticate with (basic, bearer, session)
+    pub auth_realm: Option<String>, // Realm named in the 401 challenge
+}
+
+#[derive(Debug, Clone)]
+pub struct TaskConfig {
+    pub name: String,
+    pub every: Duration,
+    pub jitter: Duration,
+    pub action: TaskAction,
+}
+
+#[derive(Debug, Clone)]
+pub enum TaskAction {
+    SessionCleanup,
+    TempCleanup { dir: String, max_age: Duration },
+    CachePrune { dir: String, max_size: usize }, // Least recently read copies go first
+    LogRotate { max_size: usize, keep: usize },  // The --daemon log files, once past max_size
+    Command(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct UpstreamConfig {
+    pub name: String,
+    pub servers: Vec<String>, // Backend host:port addresses
+    pub strategy: BalanceStrategy,
+}
+
+/// How a request picks a backend within an upstream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BalanceStrategy {
+    RoundRobin, // Each backend in turn
+    LeastConn,  // Backend with the fewest requests in flight
+    IpHash,     // Same client address, same backend
+}
+
+impl BalanceStrategy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BalanceStrategy::RoundRobin => "round_robin",
+            BalanceStrategy::LeastConn => "least_conn",
+            BalanceStrategy::IpHash => "ip_hash",
+        }
+    }
+}
+
+/// Reaction to a listener that fails to bind or dies at runtime
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListenErrorPolicy {
+    Abort, // Stop the server
+    Skip,  // Warn and keep serving the other ports
+    Retry, // Warn and rebind with exponential backoff
+}
+
+impl ListenErrorPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ListenErrorPolicy::Abort => "abort",
+            ListenErrorPolicy::Skip => "skip",
+            ListenErrorPolicy::Retry => "retry",
+        }
+    }
+}
+
+/// How several workers split the connections arriving on one port
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AcceptStrategy {
+    ReusePort, // One SO_REUSEPORT socket per worker, the kernel balances
+    Leader,    // One shared socket, whichever idle worker leads accepts
+}
+
+impl AcceptStrategy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AcceptStrategy::ReusePort => "reuseport",
+            AcceptStrategy::Leader => "leader_follower",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CpuAffinity {
+    Off,               // Let the scheduler move workers around
+    Auto,              // Worker N on the Nth core the process may use
+    Cores(Vec<usize>), // Worker N on the Nth listed core, wrapping around
+}
+
+impl CpuAffinity {
+    /// Core worker `worker` is pinned to, picked among the `allowed` ones for `auto`
+    pub fn core_for(&self, worker: usize, allowed: &[usize]) -> Option<usize> {
+        let cores = match self {
+            CpuAffinity::Off => return None,
+            CpuAffinity::Auto => allowed,
+            CpuAffinity::Cores(cores) => cores,
+        };
+        if cores.is_empty() {
+            return None;
+        }
+        Some(cores[worker % cores.len()])
+    }
 }
 
+pub const DEFAULT_PHASE_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub const DEFAULT_MAX_REQUEST_LINE: usize = 8 * 1024;
+pub const DEFAULT_MAX_HEADER_SIZE: usize = 32 * 1024;
+pub const DEFAULT_MAX_HEADER_COUNT: usize = 100;
+
+/// Longest `tls_ticket_rotation`: the keys rustls hands out rotate on their own after that
+const MAX_TICKET_ROTATION: Duration = Duration::from_secs(6 * 3600);
+
+/// Below this, gzip framing costs about as much as it saves
+const DEFAULT_COMPRESS_MIN_SIZE: usize = 1024;
+
+const DEFAULT_COMPRESS_TYPES: &[&str] = &[
+    "text/*",
+    "application/json",
+    "application/javascript",
+    "application/xml",
+    "image/svg+xml",
+];
+
+/// Formats that are compressed already; gzip would only burn CPU on them
+const DEFAULT_COMPRESS_EXCLUDE: &[&str] = &[
+    "image/png",
+    "image/jpeg",
+    "image/gif",
+    "image/webp",
+    "video/*",
+    "audio/*",
+    "font/woff",
+    "font/woff2",
+    "application/zip",
+    "application/gzip",
+    "application/pdf",
+];
+
 fn indent_level(line: &str) -> usize {
     line.chars().take_while(|c| *c == ' ').count()
 }
@@ -52,8 +306,7 @@ fn parse_ports(lines: &[String], start: usize) -> Result<(Vec<u16>, usize), Box<
         let lvl = indent_level(&lines[i]);
         let line = lines[i].trim();
         if lvl == 6 && line.starts_with("-") {
-            let port = line[1..].trim().parse::<u16>()?;
-            ports.push(port);
+            ports.extend(parse_port_range(&line[1..])?);
             i += 1;
         } else {
             break;
@@ -67,14 +320,87 @@ fn parse_ports(lines: &[String], start: usize) -> Result<(Vec<u16>, usize), Box<
     Ok((ports, i))
 }
 
+/// Parse a single port ("8080") or an inclusive range ("8000-8005")
+fn parse_port_range(value: &str) -> Result<Vec<u16>, Box<dyn Error>> {
+    let value = value.trim().trim_matches('"');
+    match value.split_once('-') {
+        Some((first, last)) => {
+            let first = first.trim().parse::<u16>()?;
+            let last = last.trim().parse::<u16>()?;
+            if first > last {
+                return Err(format!("Invalid port range: {}", value).into());
+            }
+            Ok((first..=last).collect())
+        }
+        None => Ok(vec![value.parse::<u16>()?]),
+    }
+}
+
+/// Parse `tls:` as a block of `cert:`/`key:` lines or inline `{ cert: ..., key: ... }`
+fn parse_tls(lines: &[String], start: usize) -> Result<(TlsConfig, usize), Box<dyn Error>> {
+    let mut cert = None;
+    let mut key = None;
+    let mut set = |field: &str, value: &str| -> Result<(), Box<dyn Error>> {
+        let value = value.trim().trim_matches('"').to_string();
+        match field.trim() {
+            "cert" => cert = Some(value),
+            "key" => key = Some(value),
+            other => return Err(format!("Unknown tls field: {}", other).into()),
+        }
+        Ok(())
+    };
+
+    let mut i = start;
+    let inline = lines[i].trim()["tls:".len()..].trim();
+    i += 1;
+    if !inline.is_empty() {
+        let inner = inline
+            .strip_prefix('{')
+            .and_then(|v| v.strip_suffix('}'))
+            .ok_or("tls inline value must be a { cert: ..., key: ... } map")?;
+        for entry in inner.split(',').filter(|e| !e.trim().is_empty()) {
+            let (field, value) = entry.split_once(':').ok_or("Invalid tls entry")?;
+            set(field, value)?;
+        }
+    } else {
+        while i < lines.len() && indent_level(&lines[i]) == 6 {
+            let (field, value) = lines[i].trim().split_once(':').ok_or("Invalid tls entry")?;
+            set(field, value)?;
+            i += 1;
+        }
+    }
+
+    Ok((
+        TlsConfig {
+            cert: cert.ok_or("tls missing 'cert'")?,
+            key: key.ok_or("tls missing 'key'")?,
+        },
+        i,
+    ))
+}
+
 fn parse_error_pages(lines: &[String], start: usize) -> Result<(Vec<ErrorPage>, usize), Box<dyn Error>> {
     let mut pages = Vec::new();
     let mut i = start;
 
-    if indent_level(&lines[i]) != 4 || lines[i].trim() != "error_pages:" {
+    if indent_level(&lines[i]) != 4 || !lines[i].trim().starts_with("error_pages:") {
         return Err("Expected 'error_pages:'".into());
     }
+
+    // Inline form: error_pages: { "404 403 410": "./errors/missing.html", 500: "./500.html" }
+    let inline = lines[i].trim()["error_pages:".len()..].trim();
     i += 1;
+    if !inline.is_empty() {
+        let inner = inline
+            .strip_prefix('{')
+            .and_then(|v| v.strip_suffix('}'))
+            .ok_or("error_pages inline value must be a { code: path } map")?;
+        for entry in inner.split(',').filter(|e| !e.trim().is_empty()) {
+            let (codes, path) = entry.split_once(':').ok_or("Invalid error_pages entry")?;
+            add_error_pages(&mut pages, codes, path)?;
+        }
+        return Ok((pages, i));
+    }
 
     // Support multiple error pages
     while i < lines.len() {
@@ -84,11 +410,8 @@ fn parse_error_pages(lines: &[String], start: usize) -> Result<(Vec<ErrorPage>,
         }
 
         let line = lines[i].trim();
-        if let Some((code, path)) = line.split_once(':') {
-            pages.push(ErrorPage {
-                code: code.trim().parse::<u16>()?,
-                path: path.trim().trim_matches('"').to_string(),
-            });
+        if let Some((codes, path)) = line.split_once(':') {
+            add_error_pages(&mut pages, codes, path)?;
             i += 1;
         } else {
             break;
@@ -98,15 +421,60 @@ fn parse_error_pages(lines: &[String], start: usize) -> Result<(Vec<ErrorPage>,
     Ok((pages, i))
 }
 
+/// Expand a `"404 403 410": path` entry into one ErrorPage per status code
+fn add_error_pages(pages: &mut Vec<ErrorPage>, codes: &str, path: &str) -> Result<(), Box<dyn Error>> {
+    let path = path.trim().trim_matches('"').to_string();
+    for code in codes.trim().trim_matches('"').split_whitespace() {
+        let code = code.parse::<u16>()?;
+        // Later entries win, so a grouped default can be refined line by line
+        pages.retain(|p| p.code != code);
+        pages.push(ErrorPage {
+            code,
+            path: path.clone(),
+        });
+    }
+    Ok(())
+}
+
 fn parse_route(lines: &[String], start: usize) -> Result<(Route, usize), Box<dyn Error>> {
     let mut route = Route {
         path: String::new(),
         methods: Vec::new(),
         root: "".to_string(),
+        fallback_root: None,
         default_file: None,
+        not_found: None,
         redirect: None,
-        cgi: None,
+        cgi: Vec::new(),
+        cgi_scripts: None,
         list_directory: None,
+        bundle: None,
+        read_only: None,
+        force_https: None,
+        proxy_pass: None,
+        fastcgi_pass: None,
+        cgi_timeout: None,
+        cgi_buffer_size: None,
+        cgi_session: None,
+        cgi_max_output: None,
+        cgi_headers_allow: None,
+        cgi_headers_deny: Vec::new(),
+        cgi_env: Vec::new(),
+        batch: None,
+        stat: None,
+        webdav: None,
+        filters: None,
+        add_headers: Vec::new(),
+        remove_headers: Vec::new(),
+        compression: None,
+        precompressed: None,
+        case_insensitive: None,
+        cache_control: None,
+        cache_control_by_extension: Vec::new(),
+        split: Vec::new(),
+        split_by: SplitKey::Session,
+        auth: None,
+        auth_realm: None,
     };
 
     let mut i = start;
@@ -117,10 +485,10 @@ fn parse_route(lines: &[String], start: usize) -> Result<(Route, usize), Box<dyn
 
     // Parse first line (may contain inline key-value)
     let first_line = lines[i].trim()[1..].trim();
-    if !first_line.is_empty() {
-        if let Some((key, value)) = first_line.split_once(':') {
-            parse_route_field(&mut route, key, value)?;
-        }
+    if !first_line.is_empty()
+        && let Some((key, value)) = first_line.split_once(':')
+    {
+        parse_route_field(&mut route, key, value)?;
     }
     i += 1;
 
@@ -140,13 +508,73 @@ fn parse_route(lines: &[String], start: usize) -> Result<(Route, usize), Box<dyn
     if route.methods.is_empty() {
         return Err("Route missing 'methods'".into());
     }
-    if route.root.is_empty() {
+    // Proxied routes have nothing on disk, split ones take their root from the variants
+    if route.root.is_empty() && route.proxy_pass.is_none() && route.split.is_empty() {
         return Err("Route missing 'root'".into());
     }
+    // Variants only swap the directory static files come from
+    if !route.split.is_empty()
+        && (!route.cgi.is_empty()
+            || route.proxy_pass.is_some()
+            || route.bundle.is_some()
+            || route.batch == Some(true))
+    {
+        return Err(format!(
+            "Route '{}': split only serves static files, not cgi, proxy_pass, bundle or batch",
+            route.path
+        )
+        .into());
+    }
+    if route.webdav == Some(true) {
+        // The share is files on disk under one root
+        if !route.cgi.is_empty()
+            || route.proxy_pass.is_some()
+            || route.bundle.is_some()
+            || route.redirect.is_some()
+            || !route.split.is_empty()
+        {
+            return Err(format!(
+                "Route '{}': webdav serves files from the root, not cgi, proxy_pass, bundle, redirect or split",
+                route.path
+            )
+            .into());
+        }
+        // The flag is enough to turn the WebDAV methods on
+        for method in dav::METHODS {
+            if !route.methods.iter().any(|m| m == method) {
+                route.methods.push(method.to_string());
+            }
+        }
+    }
 
     Ok((route, i))
 }
 
+/// Parse a single route written the way it is under a server's `routes:`,
+/// for routes changed at runtime. The leading `-` is optional and the
+/// fields only need one per line, whatever their indentation.
+pub fn parse_route_entry(text: &str) -> Result<Route, Box<dyn Error>> {
+    let mut lines = Vec::new();
+    for raw in text.lines() {
+        let clean = raw.split('#').next().unwrap().trim();
+        if clean.is_empty() {
+            continue;
+        }
+        if lines.is_empty() {
+            let first = clean.strip_prefix('-').unwrap_or(clean).trim();
+            lines.push(format!("      - {}", first));
+        } else if clean.starts_with('-') {
+            return Err("Expected a single route entry".into());
+        } else {
+            lines.push(format!("        {}", clean));
+        }
+    }
+    if lines.is_empty() {
+        return Err("Empty route entry".into());
+    }
+    parse_route(&lines, 0).map(|(route, _)| route)
+}
+
 fn parse_route_field(route: &mut Route, key: &str, value: &str) -> Result<(), Box<dyn Error>> {
     match key.trim() {
         "path" => route.path = value.trim().trim_matches('"').to_string(),
@@ -161,23 +589,257 @@ fn parse_route_field(route: &mut Route, key: &str, value: &str) -> Result<(), Bo
                 .collect();
         }
         "root" => route.root = value.trim().trim_matches('"').to_string(),
+        "fallback_root" => route.fallback_root = Some(value.trim().trim_matches('"').to_string()),
         "default_file" => route.default_file = Some(value.trim().trim_matches('"').to_string()),
+        "not_found" => route.not_found = Some(value.trim().trim_matches('"').to_string()),
         "redirect" => route.redirect = Some(value.trim().trim_matches('"').to_string()),
-        "cgi" => route.cgi = Some(value.trim().trim_matches('"').to_string()),
+        "cgi" => route.cgi = parse_cgi_handlers(value)?,
+        "cgi_scripts" => {
+            let scripts: Vec<String> = parse_list(value)
+                .iter()
+                .map(|script| script.trim_start_matches('/').to_string())
+                .collect();
+            if let Some(bad) = scripts.iter().find(|s| s.split('/').any(|segment| segment == ".." || segment.is_empty())) {
+                return Err(format!("Invalid cgi_scripts entry '{}'", bad).into());
+            }
+            route.cgi_scripts = Some(scripts);
+        }
+        "bundle" => route.bundle = Some(value.trim().trim_matches('"').to_string()),
+        "proxy_pass" => route.proxy_pass = Some(value.trim().trim_matches('"').to_string()),
+        "fastcgi_pass" => route.fastcgi_pass = Some(value.trim().trim_matches('"').to_string()),
+        "cgi_timeout" => route.cgi_timeout = Some(parse_duration(value)?),
+        "cgi_buffer_size" => route.cgi_buffer_size = Some(parse_size(value)?),
+        "cgi_max_output" => route.cgi_max_output = Some(parse_size(value)?),
         "list_directory" => {
             let val = value.trim().to_lowercase();
             route.list_directory = Some(val == "true" || val == "yes" || val == "1");
         }
+        "read_only" => {
+            let val = value.trim().to_lowercase();
+            route.read_only = Some(val == "true" || val == "yes" || val == "1");
+        }
+        "force_https" => {
+            let val = value.trim().to_lowercase();
+            route.force_https = Some(val == "true" || val == "yes" || val == "1");
+        }
+        "cgi_session" => {
+            let val = value.trim().to_lowercase();
+            route.cgi_session = Some(val == "true" || val == "yes" || val == "1");
+        }
+        "batch" => {
+            let val = value.trim().to_lowercase();
+            route.batch = Some(val == "true" || val == "yes" || val == "1");
+        }
+        "stat" => {
+            let val = value.trim().to_lowercase();
+            route.stat = Some(val == "true" || val == "yes" || val == "1");
+        }
+        "webdav" => {
+            let val = value.trim().to_lowercase();
+            route.webdav = Some(val == "true" || val == "yes" || val == "1");
+        }
+        "cgi_headers_allow" => route.cgi_headers_allow = Some(parse_header_names(value)),
+        "cgi_headers_deny" => route.cgi_headers_deny = parse_header_names(value),
+        "cgi_env" => {
+            let env = parse_map(value)?;
+            if let Some((name, _)) = env
+                .iter()
+                .find(|(name, _)| name.is_empty() || name.contains(['=', '\0']))
+            {
+                return Err(format!("Invalid cgi_env variable name '{}'", name).into());
+            }
+            route.cgi_env = env;
+        }
+        "case_insensitive" => {
+            let val = value.trim().to_lowercase();
+            route.case_insensitive = Some(val == "true" || val == "yes" || val == "1");
+        }
+        "precompressed" => {
+            let val = value.trim().to_lowercase();
+            route.precompressed = Some(val == "true" || val == "yes" || val == "1");
+        }
+        "filters" => {
+            let names = parse_list(value);
+            if let Some(unknown) = names.iter().find(|n| !FILTER_NAMES.contains(&n.as_str())) {
+                return Err(format!(
+                    "Unknown filter '{}', expected one of: {}",
+                    unknown,
+                    FILTER_NAMES.join(", ")
+                )
+                .into());
+            }
+            route.filters = Some(names);
+        }
+        "add_headers" => route.add_headers = parse_map(value)?,
+        "cache_control" => route.cache_control = Some(value.trim().trim_matches('"').to_string()),
+        "cache_control_by_extension" => {
+            let mut overrides = parse_map(value)?;
+            for (ext, _) in &mut overrides {
+                *ext = ext.trim_start_matches('.').to_ascii_lowercase();
+                if ext.is_empty() {
+                    return Err("cache_control_by_extension needs an extension for every entry".into());
+                }
+            }
+            route.cache_control_by_extension = overrides;
+        }
+        "remove_headers" => route.remove_headers = parse_list(value),
+        "split" => route.split = parse_split(value)?,
+        "auth" => {
+            let schemes: Vec<String> = parse_list(value).iter().map(|s| s.to_ascii_lowercase()).collect();
+            if let Some(unknown) = schemes.iter().find(|s| !AUTH_SCHEMES.contains(&s.as_str())) {
+                return Err(format!(
+                    "Unknown auth scheme '{}', expected one of: {}",
+                    unknown,
+                    AUTH_SCHEMES.join(", ")
+                )
+                .into());
+            }
+            if schemes.is_empty() {
+                return Err("'auth' needs at least one scheme".into());
+            }
+            route.auth = Some(schemes);
+        }
+        "auth_realm" => route.auth_realm = Some(value.trim().trim_matches('"').to_string()),
+        "split_by" => {
+            route.split_by = match value.trim().trim_matches('"') {
+                "session" => SplitKey::Session,
+                "ip" => SplitKey::Ip,
+                other => return Err(format!("Invalid split_by '{}', expected session or ip", other).into()),
+            }
+        }
+        "compression" => {
+            let codings: Vec<String> = parse_list(value).iter().map(|c| c.to_ascii_lowercase()).collect();
+            if let Some(unknown) = codings.iter().find(|c| !CODINGS.contains(&c.as_str())) {
+                return Err(format!(
+                    "Unknown compression '{}', expected one of: {}",
+                    unknown,
+                    CODINGS.join(", ")
+                )
+                .into());
+            }
+            route.compression = Some(codings);
+        }
         _ => return Err(format!("Unknown route field: {}", key).into()),
     }
     Ok(())
 }
 
+/// `cgi: ".py"`, `cgi: [".py", ".sh"]` or, to pick the binaries,
+/// `cgi: { ".py": /usr/bin/python3, ".php": /usr/bin/php-cgi }`
+fn parse_cgi_handlers(value: &str) -> Result<Vec<CgiHandler>, Box<dyn Error>> {
+    let value = value.trim();
+    let unquote = |s: &str| s.trim().trim_matches('"').trim_matches('\'').to_string();
+
+    let handlers: Vec<CgiHandler> = if let Some(inner) = value.strip_prefix('{').and_then(|v| v.strip_suffix('}')) {
+        inner
+            .split(',')
+            .filter(|entry| !entry.trim().is_empty())
+            .map(|entry| {
+                let (extension, interpreter) = entry
+                    .split_once(':')
+                    .ok_or_else(|| format!("Invalid cgi entry '{}', expected \".ext\": interpreter", entry.trim()))?;
+                let interpreter = unquote(interpreter);
+                if interpreter.is_empty() {
+                    return Err(format!("Missing interpreter for cgi extension {}", extension.trim()));
+                }
+                Ok(CgiHandler {
+                    extension: unquote(extension),
+                    interpreter: Some(interpreter),
+                })
+            })
+            .collect::<Result<_, String>>()?
+    } else {
+        let list = value.strip_prefix('[').and_then(|v| v.strip_suffix(']')).unwrap_or(value);
+        list.split(',')
+            .map(unquote)
+            .filter(|extension| !extension.is_empty())
+            .map(|extension| CgiHandler {
+                extension,
+                interpreter: None,
+            })
+            .collect()
+    };
+
+    if handlers.is_empty() {
+        return Err("'cgi' needs at least one extension".into());
+    }
+    if let Some(bad) = handlers.iter().find(|h| !h.extension.starts_with('.') || h.extension.len() < 2) {
+        return Err(format!("Invalid cgi extension '{}', expected e.g. \".py\"", bad.extension).into());
+    }
+    Ok(handlers)
+}
+
+/// `[{weight: 90, root: siteA}, {weight: 10, root: siteB}]`
+fn parse_split(value: &str) -> Result<Vec<SplitVariant>, Box<dyn Error>> {
+    let v = value.trim();
+    let mut rest = v
+        .strip_prefix('[')
+        .and_then(|v| v.strip_suffix(']'))
+        .ok_or_else(|| format!("Expected [{{ weight: N, root: dir }}, ...], got '{}'", v))?
+        .trim();
+
+    let mut variants = Vec::new();
+    while !rest.is_empty() {
+        let end = rest
+            .find('}')
+            .ok_or_else(|| format!("Unterminated split entry '{}'", rest))?;
+        let mut weight = None;
+        let mut root = None;
+        for (key, val) in parse_map(&rest[..=end])? {
+            match key.as_str() {
+                "weight" => {
+                    let parsed = val.parse::<u32>().map_err(|_| format!("Invalid split weight: {}", val))?;
+                    weight = Some(parsed);
+                }
+                "root" => root = Some(val),
+                _ => return Err(format!("Unknown split field: {}", key).into()),
+            }
+        }
+        let root = root.filter(|r| !r.is_empty()).ok_or("Every split entry needs a 'root'")?;
+        variants.push(SplitVariant {
+            weight: weight.ok_or_else(|| format!("Split entry '{}' needs a 'weight'", root))?,
+            root,
+        });
+        rest = rest[end + 1..].trim_start().trim_start_matches(',').trim_start();
+    }
+
+    if variants.iter().map(|v| v.weight as u64).sum::<u64>() == 0 {
+        return Err("'split' needs at least one entry with a weight above 0".into());
+    }
+    Ok(variants)
+}
+
 fn parse_server(lines: &[String], start: usize) -> Result<(ServerConfig, usize), Box<dyn Error>> {
     let mut server_name = None;
     let mut host = None;
     let mut default_server = false;
     let mut client_max_body_size = None;
+    let mut upload_tmp_dir = None;
+    let mut upload_spill_threshold = None;
+    let mut header_timeout = None;
+    let mut max_request_line = None;
+    let mut max_header_size = None;
+    let mut max_header_count = None;
+    let mut strict = false;
+    let mut body_timeout = None;
+    let mut write_timeout = None;
+    let mut request_timeout = None;
+    let mut max_connection_age = None;
+    let mut max_connection_requests = None;
+    let mut tls = None;
+    let mut error_handler = None;
+    let mut force_https = false;
+    let mut method_override = false;
+    let mut https_port = None;
+    let mut max_bandwidth = None;
+    let mut proxy_max_response_size = None;
+    let mut gzip = false;
+    let mut compress_min_size = None;
+    let mut compress_types = None;
+    let mut compress_exclude = None;
+    let mut compress_cache_dir = None;
+    let mut listen_error = ListenErrorPolicy::Abort;
+    let mut proxy_protocol = false;
     let mut root = String::from(".");
     let mut ports = Vec::new();
     let mut error_pages = Vec::new();
@@ -218,23 +880,156 @@ fn parse_server(lines: &[String], start: usize) -> Result<(ServerConfig, usize),
             }
             _ if lvl == 4 && line == "ports:" => {
                 let (p, ni) = parse_ports(lines, i)?;
-                ports = p;
+                ports.extend(p);
                 i = ni;
             }
+            _ if lvl == 4 && line.starts_with("port_range:") => {
+                ports.extend(parse_port_range(&line[11..])?);
+                i += 1;
+            }
             _ if lvl == 4 && line.starts_with("default_server:") => {
                 let val = line[15..].trim().to_lowercase();
                 default_server = val == "true" || val == "yes" || val == "1";
                 i += 1;
             }
-            _ if lvl == 4 && line == "error_pages:" => {
+            _ if lvl == 4 && line.starts_with("error_pages:") => {
                 let (ep, ni) = parse_error_pages(lines, i)?;
                 error_pages = ep;
                 i = ni;
             }
             _ if lvl == 4 && line.starts_with("client_max_body_size:") => {
-                client_max_body_size = Some(line[21..].trim().parse::<usize>()?);
+                client_max_body_size = Some(parse_size(&line[21..])?);
+                i += 1;
+            }
+            _ if lvl == 4 && line.starts_with("upload_tmp_dir:") => {
+                upload_tmp_dir = Some(line[15..].trim().trim_matches('"').to_string());
+                i += 1;
+            }
+            _ if lvl == 4 && line.starts_with("max_request_line:") => {
+                max_request_line = Some(parse_size(&line[17..])?);
+                i += 1;
+            }
+            _ if lvl == 4 && line.starts_with("max_header_size:") => {
+                max_header_size = Some(parse_size(&line[16..])?);
+                i += 1;
+            }
+            _ if lvl == 4 && line.starts_with("max_header_count:") => {
+                max_header_count = Some(line[17..].trim().parse::<usize>()?);
+                i += 1;
+            }
+            _ if lvl == 4 && line.starts_with("strict:") => {
+                let val = line[7..].trim().to_lowercase();
+                strict = val == "true" || val == "yes" || val == "1";
+                i += 1;
+            }
+            _ if lvl == 4 && line.starts_with("upload_spill_threshold:") => {
+                upload_spill_threshold = Some(parse_size(&line[23..])?);
                 i += 1;
             }
+            _ if lvl == 4 && line.starts_with("header_timeout:") => {
+                header_timeout = Some(parse_duration(&line[15..])?);
+                i += 1;
+            }
+            _ if lvl == 4 && line.starts_with("body_timeout:") => {
+                body_timeout = Some(parse_duration(&line[13..])?);
+                i += 1;
+            }
+            _ if lvl == 4 && line.starts_with("write_timeout:") => {
+                write_timeout = Some(parse_duration(&line[14..])?);
+                i += 1;
+            }
+            _ if lvl == 4 && line.starts_with("request_timeout:") => {
+                request_timeout = Some(parse_duration(&line[16..])?);
+                i += 1;
+            }
+            _ if lvl == 4 && line.starts_with("max_connection_age:") => {
+                max_connection_age = Some(parse_duration(&line[19..])?);
+                i += 1;
+            }
+            _ if lvl == 4 && line.starts_with("max_connection_requests:") => {
+                let max = line[24..].trim().parse::<usize>()?;
+                if max == 0 {
+                    return Err("max_connection_requests must be at least 1".into());
+                }
+                max_connection_requests = Some(max);
+                i += 1;
+            }
+            _ if lvl == 4 && line.starts_with("error_handler:") => {
+                error_handler = Some(line[14..].trim().trim_matches('"').to_string());
+                i += 1;
+            }
+            _ if lvl == 4 && line.starts_with("force_https:") => {
+                let val = line[12..].trim().to_lowercase();
+                force_https = val == "true" || val == "yes" || val == "1";
+                i += 1;
+            }
+            _ if lvl == 4 && line.starts_with("method_override:") => {
+                let val = line[16..].trim().to_lowercase();
+                method_override = val == "true" || val == "yes" || val == "1";
+                i += 1;
+            }
+            _ if lvl == 4 && line.starts_with("https_port:") => {
+                https_port = Some(line[11..].trim().parse::<u16>()?);
+                i += 1;
+            }
+            _ if lvl == 4 && line.starts_with("max_bandwidth:") => {
+                let rate = parse_size(&line[14..])?;
+                if rate == 0 {
+                    return Err("max_bandwidth must be greater than 0".into());
+                }
+                max_bandwidth = Some(rate as u64);
+                i += 1;
+            }
+            _ if lvl == 4 && line.starts_with("proxy_max_response_size:") => {
+                proxy_max_response_size = Some(parse_size(&line[24..])?);
+                i += 1;
+            }
+            _ if lvl == 4 && line.starts_with("gzip:") => {
+                let val = line[5..].trim().to_lowercase();
+                gzip = val == "true" || val == "yes" || val == "1";
+                i += 1;
+            }
+            _ if lvl == 4 && line.starts_with("compress_min_size:") => {
+                compress_min_size = Some(parse_size(&line[18..])?);
+                i += 1;
+            }
+            _ if lvl == 4 && line.starts_with("compress_types:") => {
+                compress_types = Some(parse_list(&line[15..]));
+                i += 1;
+            }
+            _ if lvl == 4 && line.starts_with("compress_exclude:") => {
+                compress_exclude = Some(parse_list(&line[17..]));
+                i += 1;
+            }
+            _ if lvl == 4 && line.starts_with("compress_cache_dir:") => {
+                compress_cache_dir = Some(line[19..].trim().trim_matches('"').to_string());
+                i += 1;
+            }
+            _ if lvl == 4 && line.starts_with("listen_error:") => {
+                listen_error = match line[13..].trim().trim_matches('"') {
+                    "abort" => ListenErrorPolicy::Abort,
+                    "skip" => ListenErrorPolicy::Skip,
+                    "retry" => ListenErrorPolicy::Retry,
+                    other => {
+                        return Err(format!(
+                            "Invalid listen_error '{}', expected abort, skip or retry",
+                            other
+                        )
+                        .into());
+                    }
+                };
+                i += 1;
+            }
+            _ if lvl == 4 && line.starts_with("proxy_protocol:") => {
+                let val = line[15..].trim().to_lowercase();
+                proxy_protocol = val == "true" || val == "yes" || val == "1";
+                i += 1;
+            }
+            _ if lvl == 4 && line.starts_with("tls:") => {
+                let (t, ni) = parse_tls(lines, i)?;
+                tls = Some(t);
+                i = ni;
+            }
             _ if lvl == 4 && line.starts_with("root:") => {
                 root = line[5..].trim().trim_matches('"').to_string();
                 i += 1;
@@ -257,6 +1052,10 @@ fn parse_server(lines: &[String], start: usize) -> Result<(ServerConfig, usize),
         }
     }
 
+    // Ranges and lists may overlap; keep the first occurrence of each port
+    let mut seen = std::collections::HashSet::new();
+    ports.retain(|p| seen.insert(*p));
+
     // Build server config with defaults
     Ok((
         ServerConfig {
@@ -266,6 +1065,34 @@ fn parse_server(lines: &[String], start: usize) -> Result<(ServerConfig, usize),
             default_server,
             error_pages,
             client_max_body_size: client_max_body_size.unwrap_or(1_000_000), // 1MB default
+            upload_tmp_dir,
+            upload_spill_threshold: upload_spill_threshold.unwrap_or(1024 * 1024),
+            header_timeout: header_timeout.unwrap_or(DEFAULT_PHASE_TIMEOUT),
+            max_request_line: max_request_line.unwrap_or(DEFAULT_MAX_REQUEST_LINE),
+            max_header_size: max_header_size.unwrap_or(DEFAULT_MAX_HEADER_SIZE),
+            max_header_count: max_header_count.unwrap_or(DEFAULT_MAX_HEADER_COUNT),
+            strict,
+            body_timeout: body_timeout.unwrap_or(DEFAULT_PHASE_TIMEOUT),
+            write_timeout: write_timeout.unwrap_or(DEFAULT_PHASE_TIMEOUT),
+            request_timeout,
+            max_connection_age,
+            max_connection_requests,
+            tls,
+            error_handler,
+            force_https,
+            method_override,
+            https_port,
+            listen_error,
+            proxy_protocol,
+            max_bandwidth,
+            proxy_max_response_size,
+            gzip,
+            compress_min_size: compress_min_size.unwrap_or(DEFAULT_COMPRESS_MIN_SIZE),
+            compress_types: compress_types
+                .unwrap_or_else(|| DEFAULT_COMPRESS_TYPES.iter().map(|t| t.to_string()).collect()),
+            compress_exclude: compress_exclude
+                .unwrap_or_else(|| DEFAULT_COMPRESS_EXCLUDE.iter().map(|t| t.to_string()).collect()),
+            compress_cache_dir,
             root,
             routes,
         },
@@ -273,14 +1100,282 @@ fn parse_server(lines: &[String], start: usize) -> Result<(ServerConfig, usize),
     ))
 }
 
+/// `{ Name: value, Other: "quoted, with commas" }`
+/// Header names as they are compared: lowercase, `_` read as `-` so that
+/// `X_Api_Key` and `x-api-key` (both HTTP_X_API_KEY to a script) are one
+fn parse_header_names(value: &str) -> Vec<String> {
+    parse_list(value)
+        .iter()
+        .map(|name| name.to_ascii_lowercase().replace('_', "-"))
+        .collect()
+}
+
+fn parse_map(value: &str) -> Result<Vec<(String, String)>, Box<dyn Error>> {
+    let v = value.trim();
+    let inner = v
+        .strip_prefix('{')
+        .and_then(|v| v.strip_suffix('}'))
+        .ok_or_else(|| format!("Expected {{ key: value, ... }}, got '{}'", v))?;
+
+    // Split on commas outside quotes
+    let mut entries = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    for c in inner.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                current.push(c);
+            }
+            ',' if !quoted => entries.push(std::mem::take(&mut current)),
+            _ => current.push(c),
+        }
+    }
+    entries.push(current);
+
+    entries
+        .iter()
+        .filter(|e| !e.trim().is_empty())
+        .map(|entry| {
+            let (key, val) = entry
+                .split_once(':')
+                .ok_or_else(|| format!("Invalid map entry '{}', expected key: value", entry.trim()))?;
+            Ok((
+                key.trim().trim_matches('"').to_string(),
+                val.trim().trim_matches('"').to_string(),
+            ))
+        })
+        .collect()
+}
+
+/// `[a, "b", c]` or `a, b, c`
+fn parse_list(value: &str) -> Vec<String> {
+    let v = value.trim();
+    let v = v.strip_prefix('[').and_then(|v| v.strip_suffix(']')).unwrap_or(v);
+    v.split(',')
+        .map(|s| s.trim().trim_matches('"').to_string())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+/// Parse sizes like "1000", "512K", "10M" or "1G" into bytes
+pub fn parse_size(value: &str) -> Result<usize, Box<dyn Error>> {
+    let v = value.trim().trim_matches('"');
+    let (num, mult) = match v.chars().last() {
+        Some('K') | Some('k') => (&v[..v.len() - 1], 1024),
+        Some('M') | Some('m') => (&v[..v.len() - 1], 1024 * 1024),
+        Some('G') | Some('g') => (&v[..v.len() - 1], 1024 * 1024 * 1024),
+        _ => (v, 1),
+    };
+    let n = num
+        .trim()
+        .parse::<usize>()
+        .map_err(|_| format!("Invalid size: {}", value))?;
+    Ok(n * mult)
+}
+
+/// Replace `${VAR}` references with the value of the environment variable
+fn expand_env(line: &str) -> Result<String, Box<dyn Error>> {
+    let mut out = String::new();
+    let mut rest = line;
+
+    while let Some(start) = rest.find("${") {
+        out.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| format!("Unterminated variable in: {}", line))?;
+        let name = &rest[start + 2..start + end];
+        let value = std::env::var(name)
+            .map_err(|_| format!("Environment variable '{}' is not set", name))?;
+        out.push_str(&value);
+        rest = &rest[start + end + 1..];
+    }
+
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Parse durations like "30", "30s", "5m", "2h" or "1d" (plain numbers are seconds)
+pub fn parse_duration(value: &str) -> Result<Duration, Box<dyn Error>> {
+    let v = value.trim().trim_matches('"');
+    let (num, unit) = match v.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => (&v[..i], &v[i..]),
+        None => (v, "s"),
+    };
+    let n = num.parse::<u64>()?;
+    let secs = match unit {
+        "ms" => return Ok(Duration::from_millis(n)),
+        "s" => n,
+        "m" => n * 60,
+        "h" => n * 3600,
+        "d" => n * 86400,
+        _ => return Err(format!("Invalid duration: {}", value).into()),
+    };
+    Ok(Duration::from_secs(secs))
+}
+
+fn parse_task(lines: &[String], start: usize) -> Result<(TaskConfig, usize), Box<dyn Error>> {
+    let mut fields: Vec<(String, String)> = Vec::new();
+    let mut i = start;
+
+    if indent_level(&lines[i]) != 2 || !lines[i].trim().starts_with("-") {
+        return Err("Expected task entry".into());
+    }
+
+    let first_line = lines[i].trim()[1..].trim();
+    if let Some((key, value)) = first_line.split_once(':') {
+        fields.push((key.trim().to_string(), value.trim().trim_matches('"').to_string()));
+    }
+    i += 1;
+
+    while i < lines.len() && indent_level(&lines[i]) == 4 {
+        let line = lines[i].trim();
+        if let Some((key, value)) = line.split_once(':') {
+            fields.push((key.trim().to_string(), value.trim().trim_matches('"').to_string()));
+        }
+        i += 1;
+    }
+
+    let get = |name: &str| {
+        fields
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.clone())
+    };
+
+    for (key, _) in &fields {
+        if !matches!(
+            key.as_str(),
+            "name" | "every" | "jitter" | "action" | "dir" | "max_age" | "max_size" | "keep" | "command"
+        ) {
+            return Err(format!("Unknown task field: {}", key).into());
+        }
+    }
+
+    let name = get("name").ok_or("Task missing 'name'")?;
+    let every = parse_duration(&get("every").ok_or("Task missing 'every'")?)?;
+    if every.is_zero() {
+        return Err(format!("Task '{}' must have a non-zero 'every'", name).into());
+    }
+    let jitter = match get("jitter") {
+        Some(j) => parse_duration(&j)?,
+        None => Duration::ZERO,
+    };
+
+    let action = match get("action").ok_or("Task missing 'action'")?.as_str() {
+        "session_cleanup" => TaskAction::SessionCleanup,
+        "temp_cleanup" => TaskAction::TempCleanup {
+            dir: get("dir").ok_or("temp_cleanup task missing 'dir'")?,
+            max_age: parse_duration(&get("max_age").unwrap_or_else(|| "1h".to_string()))?,
+        },
+        "cache_prune" => TaskAction::CachePrune {
+            dir: get("dir").ok_or("cache_prune task missing 'dir'")?,
+            max_size: parse_size(&get("max_size").ok_or("cache_prune task missing 'max_size'")?)?,
+        },
+        "log_rotate" => {
+            let keep = match get("keep") {
+                Some(keep) => keep.parse::<usize>().map_err(|_| format!("Invalid keep: {}", keep))?,
+                None => 5,
+            };
+            if keep == 0 {
+                return Err(format!("Task '{}' must keep at least one rotated log", name).into());
+            }
+            TaskAction::LogRotate {
+                max_size: parse_size(&get("max_size").ok_or("log_rotate task missing 'max_size'")?)?,
+                keep,
+            }
+        }
+        "command" => TaskAction::Command(get("command").ok_or("command task missing 'command'")?),
+        other => return Err(format!("Unknown task action: {}", other).into()),
+    };
+
+    Ok((
+        TaskConfig {
+            name,
+            every,
+            jitter,
+            action,
+        },
+        i,
+    ))
+}
+
+fn parse_upstream(lines: &[String], start: usize) -> Result<(UpstreamConfig, usize), Box<dyn Error>> {
+    let mut fields: Vec<(String, String)> = Vec::new();
+    let mut i = start;
+
+    if indent_level(&lines[i]) != 2 || !lines[i].trim().starts_with("-") {
+        return Err("Expected upstream entry".into());
+    }
+
+    let first_line = lines[i].trim()[1..].trim();
+    if let Some((key, value)) = first_line.split_once(':') {
+        fields.push((key.trim().to_string(), value.trim().trim_matches('"').to_string()));
+    }
+    i += 1;
+
+    while i < lines.len() && indent_level(&lines[i]) == 4 {
+        let line = lines[i].trim();
+        if let Some((key, value)) = line.split_once(':') {
+            fields.push((key.trim().to_string(), value.trim().trim_matches('"').to_string()));
+        }
+        i += 1;
+    }
+
+    let get = |name: &str| {
+        fields
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.clone())
+    };
+
+    for (key, _) in &fields {
+        if !matches!(key.as_str(), "name" | "servers" | "strategy") {
+            return Err(format!("Unknown upstream field: {}", key).into());
+        }
+    }
+
+    let name = get("name").ok_or("Upstream missing 'name'")?;
+    let servers: Vec<String> = get("servers")
+        .ok_or("Upstream missing 'servers'")?
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .split(',')
+        .map(|s| s.trim().trim_matches('"').to_string())
+        .filter(|s| !s.is_empty())
+        .collect();
+    if servers.is_empty() {
+        return Err(format!("Upstream '{}' must list at least one server", name).into());
+    }
+
+    let strategy = match get("strategy").as_deref().unwrap_or("round_robin") {
+        "round_robin" => BalanceStrategy::RoundRobin,
+        "least_conn" => BalanceStrategy::LeastConn,
+        "ip_hash" => BalanceStrategy::IpHash,
+        other => return Err(format!("Unknown upstream strategy: {}", other).into()),
+    };
+
+    Ok((
+        UpstreamConfig {
+            name,
+            servers,
+            strategy,
+        },
+        i,
+    ))
+}
+
 pub fn load_config(path: &str) -> Result<Config, Box<dyn Error>> {
-    let content = fs::read_to_string(path)?;
+    parse_config(&fs::read_to_string(path)?)
+}
 
+/// Parse a config from its YAML text
+pub fn parse_config(content: &str) -> Result<Config, Box<dyn Error>> {
     let mut lines = Vec::new();
     for raw in content.lines() {
         let clean = raw.split('#').next().unwrap();
         if !clean.trim().is_empty() {
-            lines.push(clean.to_string());
+            lines.push(expand_env(clean)?);
         }
     }
 
@@ -292,22 +1387,601 @@ pub fn load_config(path: &str) -> Result<Config, Box<dyn Error>> {
         return Err("Config must start with 'servers:'".into());
     }
 
-    let mut servers = Vec::new();
+    let mut config = Config {
+        servers: Vec::new(),
+        tasks: Vec::new(),
+        upstreams: Vec::new(),
+        heavy_handlers_per_tick: 4,
+        memory_watermark: None,
+        workers: 1,
+        accept_strategy: AcceptStrategy::ReusePort,
+        worker_cpu_affinity: CpuAffinity::Off,
+        read_only: false,
+        admin_path: None,
+        admin_allow: vec![IpAddr::from([127, 0, 0, 1]), IpAddr::from(Ipv6Addr::LOCALHOST)],
+        loop_stall_threshold: Duration::from_millis(100),
+        crash_dir: None,
+        crash_log_lines: 100,
+        stats_file: None,
+        drain_timeout: Duration::from_secs(10),
+        pidfile: None,
+        log_file: None,
+        error_log: None,
+        tls_session_cache: 256,
+        tls_session_tickets: true,
+        tls_ticket_rotation: Duration::from_secs(3600),
+        auth_users: Vec::new(),
+        auth_tokens: Vec::new(),
+        auth_file: None,
+    };
     let mut i = 1;
 
     while i < lines.len() {
         if indent_level(&lines[i]) == 2 && lines[i].trim().starts_with("-") {
             let (server, ni) = parse_server(&lines, i)?;
-            servers.push(server);
+            config.servers.push(server);
             i = ni;
+        } else if indent_level(&lines[i]) == 0 && lines[i].trim() == "tasks:" {
+            i += 1;
+            while i < lines.len() && indent_level(&lines[i]) == 2 && lines[i].trim().starts_with("-") {
+                let (task, ni) = parse_task(&lines, i)?;
+                config.tasks.push(task);
+                i = ni;
+            }
+        } else if indent_level(&lines[i]) == 0 && lines[i].trim() == "upstreams:" {
+            i += 1;
+            while i < lines.len() && indent_level(&lines[i]) == 2 && lines[i].trim().starts_with("-") {
+                let (upstream, ni) = parse_upstream(&lines, i)?;
+                config.upstreams.push(upstream);
+                i = ni;
+            }
+        } else if indent_level(&lines[i]) == 0
+            && let Some((key, value)) = lines[i].split_once(':')
+            && !value.trim().is_empty()
+        {
+            parse_global_field(&mut config, key, value)?;
+            i += 1;
         } else {
             return Err(format!("Expected server list item at line {}: {}", i + 1, lines[i]).into());
         }
     }
 
-    if servers.is_empty() {
+    if config.servers.is_empty() {
         return Err("Config must contain at least one server".into());
     }
 
-    Ok(Config { servers })
-}
\ No newline at end of file
+    resolve_https_ports(&mut config.servers);
+
+    Ok(config)
+}
+
+/// Redirect targets default to the first port of the TLS server with the same
+/// name, or 443 when the name is only served over plain HTTP
+fn resolve_https_ports(servers: &mut [ServerConfig]) {
+    let tls_ports: Vec<(String, u16)> = servers
+        .iter()
+        .filter(|s| s.tls.is_some())
+        .filter_map(|s| Some((s.server_name.to_ascii_lowercase(), *s.ports.first()?)))
+        .collect();
+
+    let redirects_plain = |s: &ServerConfig| {
+        s.force_https || s.routes.iter().any(|r| r.force_https == Some(true))
+    };
+    for server in servers.iter_mut().filter(|s| s.https_port.is_none() && redirects_plain(s)) {
+        let name = server.server_name.to_ascii_lowercase();
+        server.https_port = Some(
+            tls_ports
+                .iter()
+                .find(|(n, _)| *n == name)
+                .map(|(_, port)| *port)
+                .unwrap_or(443),
+        );
+    }
+}
+
+fn parse_global_field(config: &mut Config, key: &str, value: &str) -> Result<(), Box<dyn Error>> {
+    match key.trim() {
+        "heavy_handlers_per_tick" => {
+            config.heavy_handlers_per_tick = value.trim().parse::<usize>()?;
+            if config.heavy_handlers_per_tick == 0 {
+                return Err("heavy_handlers_per_tick must be at least 1".into());
+            }
+        }
+        "memory_watermark" => config.memory_watermark = Some(parse_size(value)?),
+        "workers" => {
+            config.workers = value.trim().parse::<usize>()?;
+            if config.workers == 0 {
+                return Err("workers must be at least 1".into());
+            }
+        }
+        "accept_strategy" => {
+            config.accept_strategy = match value.trim().trim_matches('"') {
+                "reuseport" => AcceptStrategy::ReusePort,
+                "leader_follower" => AcceptStrategy::Leader,
+                other => {
+                    return Err(format!(
+                        "Invalid accept_strategy '{}', expected reuseport or leader_follower",
+                        other
+                    )
+                    .into());
+                }
+            };
+        }
+        "worker_cpu_affinity" => {
+            config.worker_cpu_affinity = match value.trim().trim_matches('"') {
+                "off" | "false" | "no" => CpuAffinity::Off,
+                "auto" => CpuAffinity::Auto,
+                _ => {
+                    let cores = parse_list(value)
+                        .iter()
+                        .map(|core| core.parse::<usize>())
+                        .collect::<Result<Vec<_>, _>>()
+                        .map_err(|_| "worker_cpu_affinity must be off, auto or a list of core numbers")?;
+                    if cores.is_empty() {
+                        return Err("worker_cpu_affinity lists no core".into());
+                    }
+                    CpuAffinity::Cores(cores)
+                }
+            };
+        }
+        "loop_stall_threshold" => config.loop_stall_threshold = parse_duration(value)?,
+        "auth_users" => {
+            let users = parse_map(value)?;
+            if let Some((name, _)) = users.iter().find(|(_, stored)| !valid_password_format(stored)) {
+                return Err(format!(
+                    "Invalid password for auth user '{}', expected sha256:<64 hex digits> or plain:<password>",
+                    name
+                )
+                .into());
+            }
+            config.auth_users = users;
+        }
+        "auth_tokens" => config.auth_tokens = parse_map(value)?,
+        "auth_file" => config.auth_file = Some(value.trim().trim_matches('"').to_string()),
+        "crash_dir" => config.crash_dir = Some(value.trim().trim_matches('"').to_string()),
+        "stats_file" => config.stats_file = Some(value.trim().trim_matches('"').to_string()),
+        "drain_timeout" => config.drain_timeout = parse_duration(value)?,
+        "pidfile" => config.pidfile = Some(value.trim().trim_matches('"').to_string()),
+        "log_file" => config.log_file = Some(value.trim().trim_matches('"').to_string()),
+        "error_log" => config.error_log = Some(value.trim().trim_matches('"').to_string()),
+        "crash_log_lines" => {
+            config.crash_log_lines = value.trim().parse::<usize>()?;
+            if config.crash_log_lines == 0 {
+                return Err("crash_log_lines must be at least 1".into());
+            }
+        }
+        "tls_session_cache" => config.tls_session_cache = value.trim().parse::<usize>()?,
+        "tls_session_tickets" => {
+            let val = value.trim().to_lowercase();
+            config.tls_session_tickets = val == "true" || val == "yes" || val == "1";
+        }
+        "tls_ticket_rotation" => {
+            config.tls_ticket_rotation = parse_duration(value)?;
+            if config.tls_ticket_rotation < Duration::from_secs(1) || config.tls_ticket_rotation > MAX_TICKET_ROTATION {
+                return Err("tls_ticket_rotation must be between 1s and 6h".into());
+            }
+        }
+        "read_only" => {
+            let val = value.trim().to_lowercase();
+            config.read_only = val == "true" || val == "yes" || val == "1";
+        }
+        "admin_path" => {
+            let path = value.trim().trim_matches('"').trim_end_matches('/');
+            if !path.starts_with('/') {
+                return Err("admin_path must start with '/'".into());
+            }
+            config.admin_path = Some(path.to_string());
+        }
+        "admin_allow" => {
+            let list = value.trim().trim_start_matches('[').trim_end_matches(']');
+            config.admin_allow = list
+                .split(',')
+                .map(|s| s.trim().trim_matches('"'))
+                .filter(|s| !s.is_empty())
+                .map(|s| s.parse::<IpAddr>())
+                .collect::<Result<_, _>>()?;
+        }
+        _ => return Err(format!("Unknown top-level field: {}", key).into()),
+    }
+    Ok(())
+}
+
+pub(crate) fn format_duration(d: Duration) -> String {
+    if d.subsec_millis() != 0 {
+        format!("{}ms", d.as_millis())
+    } else {
+        format!("{}s", d.as_secs())
+    }
+}
+
+/// Inverse of `parse_cgi_handlers`, in its shortest form
+fn format_cgi_handlers(handlers: &[CgiHandler]) -> Option<String> {
+    if handlers.is_empty() {
+        return None;
+    }
+    let extensions: Vec<String> = handlers.iter().map(|h| format!("\"{}\"", h.extension)).collect();
+    if handlers.iter().all(|h| h.interpreter.is_none()) {
+        return Some(if let [single] = extensions.as_slice() {
+            single.clone()
+        } else {
+            format!("[{}]", extensions.join(", "))
+        });
+    }
+    let entries: Vec<String> = handlers
+        .iter()
+        .zip(&extensions)
+        .map(|(h, ext)| format!("{}: {}", ext, h.interpreter.as_deref().unwrap_or_default()))
+        .collect();
+    Some(format!("{{ {} }}", entries.join(", ")))
+}
+
+impl Route {
+    /// Render the route the way it is written under a server's `routes:`,
+    /// as the entry of `Config::dump` and of the admin route table.
+    pub fn dump(&self) -> String {
+        let mut out = String::new();
+        out.push_str(&format!("      - path: \"{}\"\n", self.path));
+        let methods: Vec<String> =
+            self.methods.iter().map(|m| format!("\"{}\"", m)).collect();
+        out.push_str(&format!("        methods: [{}]\n", methods.join(", ")));
+        out.push_str(&format!("        root: \"{}\"\n", self.root));
+        if let Some(fallback_root) = &self.fallback_root {
+            out.push_str(&format!("        fallback_root: \"{}\"\n", fallback_root));
+        }
+        if let Some(default_file) = &self.default_file {
+            out.push_str(&format!("        default_file: \"{}\"\n", default_file));
+        }
+        if let Some(not_found) = &self.not_found {
+            out.push_str(&format!("        not_found: \"{}\"\n", not_found));
+        }
+        if let Some(redirect) = &self.redirect {
+            out.push_str(&format!("        redirect: \"{}\"\n", redirect));
+        }
+        if let Some(cgi) = format_cgi_handlers(&self.cgi) {
+            out.push_str(&format!("        cgi: {}\n", cgi));
+        }
+        if let Some(scripts) = &self.cgi_scripts {
+            out.push_str(&format!("        cgi_scripts: [{}]\n", scripts.join(", ")));
+        }
+        if let Some(bundle) = &self.bundle {
+            out.push_str(&format!("        bundle: \"{}\"\n", bundle));
+        }
+        if let Some(proxy_pass) = &self.proxy_pass {
+            out.push_str(&format!("        proxy_pass: \"{}\"\n", proxy_pass));
+        }
+        if let Some(fastcgi_pass) = &self.fastcgi_pass {
+            out.push_str(&format!("        fastcgi_pass: \"{}\"\n", fastcgi_pass));
+        }
+        if let Some(filters) = &self.filters {
+            out.push_str(&format!("        filters: [{}]\n", filters.join(", ")));
+        }
+        if !self.add_headers.is_empty() {
+            let entries: Vec<String> = self
+                .add_headers
+                .iter()
+                .map(|(name, value)| format!("{}: \"{}\"", name, value))
+                .collect();
+            out.push_str(&format!("        add_headers: {{ {} }}\n", entries.join(", ")));
+        }
+        if let Some(cache_control) = &self.cache_control {
+            out.push_str(&format!("        cache_control: \"{}\"\n", cache_control));
+        }
+        if !self.cache_control_by_extension.is_empty() {
+            let entries: Vec<String> = self
+                .cache_control_by_extension
+                .iter()
+                .map(|(ext, value)| format!("\"{}\": \"{}\"", ext, value))
+                .collect();
+            out.push_str(&format!(
+                "        cache_control_by_extension: {{ {} }}\n",
+                entries.join(", ")
+            ));
+        }
+        if let Some(schemes) = &self.auth {
+            out.push_str(&format!("        auth: [{}]\n", schemes.join(", ")));
+        }
+        if let Some(realm) = &self.auth_realm {
+            out.push_str(&format!("        auth_realm: \"{}\"\n", realm));
+        }
+        if !self.split.is_empty() {
+            let entries: Vec<String> = self
+                .split
+                .iter()
+                .map(|v| format!("{{ weight: {}, root: \"{}\" }}", v.weight, v.root))
+                .collect();
+            out.push_str(&format!("        split: [{}]\n", entries.join(", ")));
+            out.push_str(&format!("        split_by: {}\n", self.split_by.as_str()));
+        }
+        if let Some(codings) = &self.compression {
+            out.push_str(&format!("        compression: [{}]\n", codings.join(", ")));
+        }
+        if let Some(case_insensitive) = self.case_insensitive {
+            out.push_str(&format!("        case_insensitive: {}\n", case_insensitive));
+        }
+        if let Some(precompressed) = self.precompressed {
+            out.push_str(&format!("        precompressed: {}\n", precompressed));
+        }
+        if !self.remove_headers.is_empty() {
+            out.push_str(&format!("        remove_headers: [{}]\n", self.remove_headers.join(", ")));
+        }
+        if let Some(cgi_timeout) = self.cgi_timeout {
+            out.push_str(&format!("        cgi_timeout: {}\n", format_duration(cgi_timeout)));
+        }
+        if let Some(cgi_session) = self.cgi_session {
+            out.push_str(&format!("        cgi_session: {}\n", cgi_session));
+        }
+        if let Some(size) = self.cgi_buffer_size {
+            out.push_str(&format!("        cgi_buffer_size: {}\n", size));
+        }
+        if let Some(max) = self.cgi_max_output {
+            out.push_str(&format!("        cgi_max_output: {}\n", max));
+        }
+        if let Some(allow) = &self.cgi_headers_allow {
+            out.push_str(&format!("        cgi_headers_allow: [{}]\n", allow.join(", ")));
+        }
+        if !self.cgi_headers_deny.is_empty() {
+            out.push_str(&format!("        cgi_headers_deny: [{}]\n", self.cgi_headers_deny.join(", ")));
+        }
+        if !self.cgi_env.is_empty() {
+            let entries: Vec<String> = self
+                .cgi_env
+                .iter()
+                .map(|(name, value)| format!("{}: \"{}\"", name, value))
+                .collect();
+            out.push_str(&format!("        cgi_env: {{ {} }}\n", entries.join(", ")));
+        }
+        out.push_str(&format!(
+            "        list_directory: {}\n",
+            self.list_directory.unwrap_or(false)
+        ));
+        if let Some(read_only) = self.read_only {
+            out.push_str(&format!("        read_only: {}\n", read_only));
+        }
+        if let Some(force_https) = self.force_https {
+            out.push_str(&format!("        force_https: {}\n", force_https));
+        }
+        if let Some(batch) = self.batch {
+            out.push_str(&format!("        batch: {}\n", batch));
+        }
+        if let Some(stat) = self.stat {
+            out.push_str(&format!("        stat: {}\n", stat));
+        }
+        if let Some(webdav) = self.webdav {
+            out.push_str(&format!("        webdav: {}\n", webdav));
+        }
+        out
+    }
+}
+
+impl Config {
+    /// Move every server to `host` and/or a single `port`, for a quick run
+    /// with `--host` / `--port` without editing the file
+    pub fn override_listen(&mut self, host: Option<&str>, port: Option<u16>) {
+        for server in &mut self.servers {
+            if let Some(host) = host {
+                server.host = host.to_string();
+            }
+            if let Some(port) = port {
+                server.ports = vec![port];
+            }
+        }
+    }
+
+    /// Render the effective configuration in the same format `load_config`
+    /// reads, with every default filled in and sizes/durations normalized.
+    pub fn dump(&self) -> String {
+        let mut out = String::from("servers:\n");
+
+        for server in &self.servers {
+            out.push_str(&format!("  - server_name: \"{}\"\n", server.server_name));
+            out.push_str(&format!("    host: {}\n", server.host));
+            out.push_str("    ports:\n");
+            for port in &server.ports {
+                out.push_str(&format!("      - {}\n", port));
+            }
+            out.push_str(&format!("    default_server: {}\n", server.default_server));
+            out.push_str(&format!("    root: \"{}\"\n", server.root));
+            out.push_str(&format!(
+                "    client_max_body_size: {}\n",
+                server.client_max_body_size
+            ));
+            if let Some(dir) = &server.upload_tmp_dir {
+                out.push_str(&format!("    upload_tmp_dir: \"{}\"\n", dir));
+            }
+            out.push_str(&format!(
+                "    upload_spill_threshold: {}\n",
+                server.upload_spill_threshold
+            ));
+            out.push_str(&format!(
+                "    header_timeout: {}\n",
+                format_duration(server.header_timeout)
+            ));
+            out.push_str(&format!("    max_request_line: {}\n", server.max_request_line));
+            out.push_str(&format!("    max_header_size: {}\n", server.max_header_size));
+            out.push_str(&format!("    max_header_count: {}\n", server.max_header_count));
+            if server.strict {
+                out.push_str("    strict: true\n");
+            }
+            out.push_str(&format!(
+                "    body_timeout: {}\n",
+                format_duration(server.body_timeout)
+            ));
+            out.push_str(&format!(
+                "    write_timeout: {}\n",
+                format_duration(server.write_timeout)
+            ));
+            if let Some(timeout) = server.request_timeout {
+                out.push_str(&format!("    request_timeout: {}\n", format_duration(timeout)));
+            }
+            if let Some(age) = server.max_connection_age {
+                out.push_str(&format!("    max_connection_age: {}\n", format_duration(age)));
+            }
+            if let Some(max) = server.max_connection_requests {
+                out.push_str(&format!("    max_connection_requests: {}\n", max));
+            }
+            if let Some(handler) = &server.error_handler {
+                out.push_str(&format!("    error_handler: \"{}\"\n", handler));
+            }
+            out.push_str(&format!("    listen_error: {}\n", server.listen_error.as_str()));
+            if server.proxy_protocol {
+                out.push_str("    proxy_protocol: true\n");
+            }
+            if server.force_https {
+                out.push_str("    force_https: true\n");
+            }
+            if server.method_override {
+                out.push_str("    method_override: true\n");
+            }
+            if let Some(port) = server.https_port {
+                out.push_str(&format!("    https_port: {}\n", port));
+            }
+            if let Some(rate) = server.max_bandwidth {
+                out.push_str(&format!("    max_bandwidth: {}\n", rate));
+            }
+            if let Some(max) = server.proxy_max_response_size {
+                out.push_str(&format!("    proxy_max_response_size: {}\n", max));
+            }
+            let compresses = server.gzip || server.routes.iter().any(|r| r.compression.is_some());
+            if compresses {
+                let quoted = |list: &[String]| {
+                    list.iter().map(|t| format!("\"{}\"", t)).collect::<Vec<_>>().join(", ")
+                };
+                if server.gzip {
+                    out.push_str("    gzip: true\n");
+                }
+                out.push_str(&format!("    compress_min_size: {}\n", server.compress_min_size));
+                out.push_str(&format!("    compress_types: [{}]\n", quoted(&server.compress_types)));
+                out.push_str(&format!("    compress_exclude: [{}]\n", quoted(&server.compress_exclude)));
+            }
+            if let Some(dir) = &server.compress_cache_dir {
+                out.push_str(&format!("    compress_cache_dir: \"{}\"\n", dir));
+            }
+            if let Some(tls) = &server.tls {
+                out.push_str("    tls:\n");
+                out.push_str(&format!("      cert: \"{}\"\n", tls.cert));
+                out.push_str(&format!("      key: \"{}\"\n", tls.key));
+            }
+            if !server.error_pages.is_empty() {
+                out.push_str("    error_pages:\n");
+                for page in &server.error_pages {
+                    out.push_str(&format!("      {}: \"{}\"\n", page.code, page.path));
+                }
+            }
+            if !server.routes.is_empty() {
+                out.push_str("    routes:\n");
+                for route in &server.routes {
+                    out.push_str(&route.dump());
+                }
+            }
+        }
+
+        out.push_str(&format!(
+            "heavy_handlers_per_tick: {}\n",
+            self.heavy_handlers_per_tick
+        ));
+        if let Some(watermark) = self.memory_watermark {
+            out.push_str(&format!("memory_watermark: {}\n", watermark));
+        }
+        out.push_str(&format!("workers: {}\n", self.workers));
+        out.push_str(&format!("accept_strategy: {}\n", self.accept_strategy.as_str()));
+        let affinity = match &self.worker_cpu_affinity {
+            CpuAffinity::Off => "off".to_string(),
+            CpuAffinity::Auto => "auto".to_string(),
+            CpuAffinity::Cores(cores) => {
+                let cores: Vec<String> = cores.iter().map(|c| c.to_string()).collect();
+                format!("[{}]", cores.join(", "))
+            }
+        };
+        out.push_str(&format!("worker_cpu_affinity: {}\n", affinity));
+        out.push_str(&format!(
+            "loop_stall_threshold: {}\n",
+            format_duration(self.loop_stall_threshold)
+        ));
+        out.push_str(&format!("tls_session_cache: {}\n", self.tls_session_cache));
+        out.push_str(&format!("tls_session_tickets: {}\n", self.tls_session_tickets));
+        out.push_str(&format!(
+            "tls_ticket_rotation: {}\n",
+            format_duration(self.tls_ticket_rotation)
+        ));
+        let quoted_map = |entries: &[(String, String)]| {
+            let entries: Vec<String> = entries.iter().map(|(k, v)| format!("\"{}\": \"{}\"", k, v)).collect();
+            entries.join(", ")
+        };
+        if !self.auth_users.is_empty() {
+            out.push_str(&format!("auth_users: {{ {} }}\n", quoted_map(&self.auth_users)));
+        }
+        if !self.auth_tokens.is_empty() {
+            out.push_str(&format!("auth_tokens: {{ {} }}\n", quoted_map(&self.auth_tokens)));
+        }
+        if let Some(auth_file) = &self.auth_file {
+            out.push_str(&format!("auth_file: \"{}\"\n", auth_file));
+        }
+        if let Some(crash_dir) = &self.crash_dir {
+            out.push_str(&format!("crash_dir: \"{}\"\n", crash_dir));
+            out.push_str(&format!("crash_log_lines: {}\n", self.crash_log_lines));
+        }
+        if let Some(stats_file) = &self.stats_file {
+            out.push_str(&format!("stats_file: \"{}\"\n", stats_file));
+        }
+        out.push_str(&format!("drain_timeout: {}\n", format_duration(self.drain_timeout)));
+        if let Some(pidfile) = &self.pidfile {
+            out.push_str(&format!("pidfile: \"{}\"\n", pidfile));
+        }
+        if let Some(log_file) = &self.log_file {
+            out.push_str(&format!("log_file: \"{}\"\n", log_file));
+        }
+        if let Some(error_log) = &self.error_log {
+            out.push_str(&format!("error_log: \"{}\"\n", error_log));
+        }
+        out.push_str(&format!("read_only: {}\n", self.read_only));
+        if let Some(admin_path) = &self.admin_path {
+            out.push_str(&format!("admin_path: \"{}\"\n", admin_path));
+        }
+        let allow: Vec<String> = self.admin_allow.iter().map(|ip| format!("\"{}\"", ip)).collect();
+        out.push_str(&format!("admin_allow: [{}]\n", allow.join(", ")));
+
+        if !self.tasks.is_empty() {
+            out.push_str("tasks:\n");
+            for task in &self.tasks {
+                out.push_str(&format!("  - name: \"{}\"\n", task.name));
+                out.push_str(&format!("    every: {}\n", format_duration(task.every)));
+                out.push_str(&format!("    jitter: {}\n", format_duration(task.jitter)));
+                match &task.action {
+                    TaskAction::SessionCleanup => out.push_str("    action: session_cleanup\n"),
+                    TaskAction::TempCleanup { dir, max_age } => {
+                        out.push_str("    action: temp_cleanup\n");
+                        out.push_str(&format!("    dir: \"{}\"\n", dir));
+                        out.push_str(&format!("    max_age: {}\n", format_duration(*max_age)));
+                    }
+                    TaskAction::CachePrune { dir, max_size } => {
+                        out.push_str("    action: cache_prune\n");
+                        out.push_str(&format!("    dir: \"{}\"\n", dir));
+                        out.push_str(&format!("    max_size: {}\n", max_size));
+                    }
+                    TaskAction::LogRotate { max_size, keep } => {
+                        out.push_str("    action: log_rotate\n");
+                        out.push_str(&format!("    max_size: {}\n", max_size));
+                        out.push_str(&format!("    keep: {}\n", keep));
+                    }
+                    TaskAction::Command(command) => {
+                        out.push_str("    action: command\n");
+                        out.push_str(&format!("    command: \"{}\"\n", command));
+                    }
+                }
+            }
+        }
+
+        if !self.upstreams.is_empty() {
+            out.push_str("upstreams:\n");
+            for upstream in &self.upstreams {
+                out.push_str(&format!("  - name: \"{}\"\n", upstream.name));
+                let servers: Vec<String> =
+                    upstream.servers.iter().map(|s| format!("\"{}\"", s)).collect();
+                out.push_str(&format!("    servers: [{}]\n", servers.join(", ")));
+                out.push_str(&format!("    strategy: {}\n", upstream.strategy.as_str()));
+            }
+        }
+
+        out
+    }
+}
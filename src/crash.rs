@@ -0,0 +1,213 @@
+use std::backtrace::Backtrace;
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config::Config;
+use crate::metrics;
+
+/// How long a report waits for captured output still in the pipes
+#[cfg(unix)]
+const DRAIN_TIMEOUT: std::time::Duration = std::time::Duration::from_millis(200);
+
+/// What a report needs, fixed once the server starts
+struct CrashSettings {
+    dir: PathBuf,
+    config_dump: String,
+    log_lines: usize,
+}
+
+static SETTINGS: OnceLock<CrashSettings> = OnceLock::new();
+
+/// Last lines written to stdout and stderr, oldest first
+static RECENT_LINES: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+
+/// Read ends of the capture pipes, checked for unread output before a report
+#[cfg(unix)]
+static CAPTURE_FDS: Mutex<Vec<i32>> = Mutex::new(Vec::new());
+
+/// With `crash_dir` set, keep the last log lines and write a report on panics
+/// and fatal errors. Does nothing otherwise.
+pub fn init(config: &Config) {
+    let Some(dir) = &config.crash_dir else {
+        return;
+    };
+    let settings = CrashSettings {
+        dir: PathBuf::from(dir),
+        config_dump: config.dump(),
+        log_lines: config.crash_log_lines,
+    };
+    if SETTINGS.set(settings).is_err() {
+        return;
+    }
+
+    #[cfg(unix)]
+    for fd in [libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        if let Err(e) = capture(fd) {
+            eprintln!("Crash reports: cannot capture output of fd {}: {}", fd, e);
+        }
+    }
+
+    // The default hook prints the message first, so the report captures it
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        let thread = thread::current();
+        report(&format!("thread '{}' {}", thread.name().unwrap_or("unnamed"), info));
+    }));
+}
+
+/// Write a crash report for `reason`, if crash reports are on. Returns the
+/// file written so the caller can mention it.
+pub fn report(reason: &str) -> Option<PathBuf> {
+    let settings = SETTINGS.get()?;
+    let backtrace = Backtrace::force_capture();
+    wait_for_output();
+
+    let now = SystemTime::now();
+    let mut out = String::new();
+    let _ = writeln!(out, "localserver crash report");
+    let _ = writeln!(out, "time: {}", httpdate::fmt_http_date(now));
+    let _ = writeln!(out, "pid: {}", std::process::id());
+    let _ = writeln!(out, "reason: {}", reason);
+
+    let _ = writeln!(out, "\n== connections ==");
+    let mut index = 0;
+    while let Some(stats) = metrics::worker(index) {
+        let _ = writeln!(
+            out,
+            "worker {}: {} open, {} accepted",
+            index,
+            stats.connections.load(Ordering::Relaxed),
+            stats.accepted.load(Ordering::Relaxed)
+        );
+        index += 1;
+    }
+
+    let _ = writeln!(out, "\n== last {} log lines ==", settings.log_lines);
+    if cfg!(unix) {
+        for line in RECENT_LINES.lock().unwrap_or_else(|e| e.into_inner()).iter() {
+            let _ = writeln!(out, "{}", line);
+        }
+    } else {
+        let _ = writeln!(out, "(output capture is not available on this platform)");
+    }
+
+    let _ = writeln!(out, "\n== backtrace ==\n{}", backtrace);
+    let _ = writeln!(out, "\n== config ==\n{}", settings.config_dump);
+
+    let secs = now.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let path = settings.dir.join(format!("crash-{}-{}.txt", secs, std::process::id()));
+    match write_report(&settings.dir, &path, &out) {
+        Ok(()) => {
+            eprintln!("Crash report written to {}", path.display());
+            wait_for_output();
+            Some(path)
+        }
+        Err(e) => {
+            eprintln!("Cannot write crash report to {}: {}", path.display(), e);
+            wait_for_output();
+            None
+        }
+    }
+}
+
+fn write_report(dir: &Path, path: &Path, report: &str) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    fs::write(path, report)
+}
+
+#[cfg(unix)]
+fn remember(lines: &str, limit: usize) {
+    let mut recent = RECENT_LINES.lock().unwrap_or_else(|e| e.into_inner());
+    for line in lines.lines() {
+        if recent.len() == limit {
+            recent.pop_front();
+        }
+        recent.push_back(line.to_string());
+    }
+}
+
+/// Point `fd` at a pipe whose reader thread copies everything to where `fd`
+/// went before and remembers the lines
+#[cfg(unix)]
+fn capture(fd: i32) -> io::Result<()> {
+    use std::fs::File;
+    use std::io::Read;
+    use std::os::fd::FromRawFd;
+
+    let limit = SETTINGS.get().map_or(0, |s| s.log_lines);
+    let mut fds = [0; 2];
+    // SAFETY: plain descriptor calls; each new descriptor is owned by one File
+    let (mut reader, mut original) = unsafe {
+        if libc::pipe(fds.as_mut_ptr()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let original = libc::dup(fd);
+        if original < 0 || libc::dup2(fds[1], fd) < 0 {
+            let error = io::Error::last_os_error();
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+            return Err(error);
+        }
+        libc::close(fds[1]);
+        (File::from_raw_fd(fds[0]), File::from_raw_fd(original))
+    };
+    CAPTURE_FDS.lock().unwrap_or_else(|e| e.into_inner()).push(fds[0]);
+
+    thread::Builder::new()
+        .name(format!("capture-{}", fd))
+        .spawn(move || {
+            let mut buf = [0u8; 8192];
+            let mut partial = String::new();
+            loop {
+                let n = match reader.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(n) => n,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(_) => break,
+                };
+                let _ = original.write_all(&buf[..n]);
+                partial.push_str(&String::from_utf8_lossy(&buf[..n]));
+                // Only whole lines are kept, the rest waits for its newline
+                if let Some(end) = partial.rfind('\n') {
+                    remember(&partial[..end], limit);
+                    partial.drain(..=end);
+                }
+            }
+        })?;
+    Ok(())
+}
+
+/// Let the capture threads forward what was just printed, so it reaches the
+/// terminal and the report before the process goes away
+fn wait_for_output() {
+    let _ = io::stdout().flush();
+    let _ = io::stderr().flush();
+    #[cfg(unix)]
+    {
+        use std::time::{Duration, Instant};
+
+        let fds = CAPTURE_FDS.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        let unread = |fd: i32| {
+            let mut pending: libc::c_int = 0;
+            // SAFETY: FIONREAD only writes the count of unread bytes
+            let ok = unsafe { libc::ioctl(fd, libc::FIONREAD, &mut pending) } == 0;
+            ok && pending > 0
+        };
+        let started = Instant::now();
+        while fds.iter().any(|&fd| unread(fd)) && started.elapsed() < DRAIN_TIMEOUT {
+            thread::sleep(Duration::from_millis(5));
+        }
+        // The last chunk read may still be on its way to the ring
+        if !fds.is_empty() {
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
@@ -0,0 +1,197 @@
+use std::fs;
+#[cfg(unix)]
+use std::fs::{File, OpenOptions};
+use std::io::{self, PipeWriter, Write};
+use std::sync::Mutex;
+
+use crate::config::Config;
+
+/// Write end of the pipe the process started from the terminal waits on
+static READY: Mutex<Option<PipeWriter>> = Mutex::new(None);
+
+/// The log files output was sent to, with the descriptors writing to each,
+/// for `rotate_logs` to point at fresh files
+#[cfg(unix)]
+static LOGS: Mutex<Vec<(String, Vec<i32>)>> = Mutex::new(Vec::new());
+
+/// `--daemon`: fork into the background, leaving the terminal's session,
+/// with stdout appended to `log_file` and stderr to `error_log` (both
+/// dropped when unset). The files are opened before forking, so a bad path
+/// is still reported on the terminal. Call before any thread is started.
+///
+/// The command only returns once the server calls `ready`: with status 0,
+/// or 1 when the daemon dies before then.
+#[cfg(unix)]
+pub fn daemonize(config: &Config) -> io::Result<()> {
+    use std::io::Read;
+    use std::os::fd::AsRawFd;
+
+    let stdin = File::open("/dev/null")?;
+    let stdout = open_log(config.log_file.as_deref())?;
+    let stderr = match &config.error_log {
+        Some(_) => open_log(config.error_log.as_deref())?,
+        None => stdout.try_clone()?,
+    };
+    let errors = config.error_log.clone().or_else(|| config.log_file.clone());
+    let (mut reader, writer) = io::pipe()?;
+    *READY.lock().unwrap_or_else(|e| e.into_inner()) = Some(writer);
+    io::stdout().flush()?;
+
+    unsafe {
+        fork_and_leave(|| {
+            // The wait only ends on EOF if the daemon holds the sole write end
+            READY.lock().unwrap_or_else(|e| e.into_inner()).take();
+            let mut status = [1u8];
+            let _ = reader.read_exact(&mut status);
+            if status[0] != 0 {
+                match &errors {
+                    Some(path) => eprintln!("Server failed to start in the background, see {}", path),
+                    None => eprintln!("Server failed to start in the background"),
+                }
+            }
+            i32::from(status[0])
+        })?;
+        if libc::setsid() == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        // No longer a session leader, so no terminal can become ours again
+        fork_and_leave(|| 0)?;
+        for (file, fd) in [(&stdin, libc::STDIN_FILENO), (&stdout, libc::STDOUT_FILENO), (&stderr, libc::STDERR_FILENO)] {
+            if libc::dup2(file.as_raw_fd(), fd) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+    }
+    drop(reader);
+
+    let mut logs = LOGS.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(log_file) = &config.log_file {
+        let fds = match config.error_log {
+            Some(_) => vec![libc::STDOUT_FILENO],
+            None => vec![libc::STDOUT_FILENO, libc::STDERR_FILENO],
+        };
+        logs.push((log_file.clone(), fds));
+    }
+    if let Some(error_log) = &config.error_log {
+        logs.push((error_log.clone(), vec![libc::STDERR_FILENO]));
+    }
+    Ok(())
+}
+
+/// Rotate the `--daemon` log files grown past `max_size`: `file` becomes
+/// `file.1`, the older ones move up to `file.<keep>` and the oldest is
+/// dropped, and output goes on into a new `file`. Returns the files rotated.
+#[cfg(unix)]
+pub fn rotate_logs(max_size: usize, keep: usize) -> Vec<String> {
+    use std::os::fd::AsRawFd;
+
+    let logs = LOGS.lock().unwrap_or_else(|e| e.into_inner());
+    let mut rotated = Vec::new();
+
+    for (path, fds) in logs.iter() {
+        let grown = fs::metadata(path).is_ok_and(|m| m.len() > max_size as u64);
+        if !grown {
+            continue;
+        }
+        for n in (1..keep).rev() {
+            let _ = fs::rename(format!("{}.{}", path, n), format!("{}.{}", path, n + 1));
+        }
+        let reopened = fs::rename(path, format!("{}.1", path)).and_then(|_| open_log(Some(path)));
+        let file = match reopened {
+            Ok(file) => file,
+            Err(e) => {
+                error!("Cannot rotate log {}: {}", path, e);
+                continue;
+            }
+        };
+        for fd in fds {
+            // Until then, output still lands at the end of the renamed file
+            if unsafe { libc::dup2(file.as_raw_fd(), *fd) } == -1 {
+                error!("Cannot rotate log {}: {}", path, io::Error::last_os_error());
+            }
+        }
+        rotated.push(path.clone());
+    }
+
+    rotated
+}
+
+/// Only `--daemon` writes to log files
+#[cfg(not(unix))]
+pub fn rotate_logs(_max_size: usize, _keep: usize) -> Vec<String> {
+    Vec::new()
+}
+
+#[cfg(not(unix))]
+pub fn daemonize(_config: &Config) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "--daemon is only available on Unix"))
+}
+
+/// Fork, the parent exits with the status `parent` returns and the child
+/// goes on
+#[cfg(unix)]
+unsafe fn fork_and_leave(parent: impl FnOnce() -> i32) -> io::Result<()> {
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => Ok(()),
+        _ => unsafe { libc::_exit(parent()) },
+    }
+}
+
+/// The pidfile is written and the listeners are set up: let the command
+/// that started the daemon exit successfully. A no-op without `--daemon`.
+pub fn ready() {
+    if let Some(mut writer) = READY.lock().unwrap_or_else(|e| e.into_inner()).take() {
+        let _ = writer.write_all(&[0]);
+    }
+}
+
+#[cfg(unix)]
+fn open_log(path: Option<&str>) -> io::Result<File> {
+    match path {
+        Some(path) => OpenOptions::new().create(true).append(true).open(path),
+        None => OpenOptions::new().write(true).open("/dev/null"),
+    }
+}
+
+/// Write our pid to `pidfile`, unless it names a server that is still
+/// running; one left behind by a crash is replaced
+pub fn write_pidfile(path: Option<&str>) -> Result<(), String> {
+    let Some(path) = path else {
+        return Ok(());
+    };
+    if let Some(pid) = fs::read_to_string(path).ok().and_then(|s| s.trim().parse::<u32>().ok())
+        && pid != std::process::id()
+        && is_running(pid)
+    {
+        return Err(format!("pidfile {}: already running as pid {}", path, pid));
+    }
+    fs::write(path, format!("{}\n", std::process::id())).map_err(|e| format!("pidfile {}: {}", path, e))
+}
+
+/// Remove the pidfile on the way out, if it is still ours
+pub fn remove_pidfile(path: Option<&str>) {
+    let Some(path) = path else {
+        return;
+    };
+    let ours = fs::read_to_string(path).is_ok_and(|s| s.trim() == std::process::id().to_string());
+    if ours && let Err(e) = fs::remove_file(path) {
+        warn!("Cannot remove pidfile {}: {}", path, e);
+    }
+}
+
+#[cfg(unix)]
+fn is_running(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    // Signal 0 only checks; EPERM means it exists under another user
+    let alive = unsafe { libc::kill(pid, 0) } == 0;
+    alive || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// Without a cheap way to ask, assume the pid was reused
+#[cfg(not(unix))]
+fn is_running(_pid: u32) -> bool {
+    false
+}
@@ -0,0 +1,470 @@
+use std::fs::{self, Metadata};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::config::{Route, ServerConfig};
+use crate::handler::route_methods;
+use crate::models::weak_etag;
+use crate::read::{find_matching_route, resolve_file_path, strip_route_prefix};
+use crate::request::{HttpRequest, normalize_path, percent_decode};
+use crate::response::{HttpResponseBuilder, detect_content_type, escape_html, reason_phrase};
+use crate::utils::cookie::Cookie;
+
+/// Methods a route with `webdav: true` answers on top of GET, POST and DELETE
+pub const METHODS: [&str; 4] = ["PROPFIND", "MKCOL", "MOVE", "COPY"];
+
+/// Answer a WebDAV method on a `webdav` route, enough of RFC 4918 for the
+/// network drives of Finder and Explorer: listing with PROPFIND, folders with
+/// MKCOL, renames with MOVE and copies with COPY. `file_path` is the request
+/// path resolved under the route root, empty when it can't be.
+pub fn handle(
+    method: &str,
+    file_path: &str,
+    server: &ServerConfig,
+    route: &Route,
+    request: &HttpRequest,
+    cookie: &Cookie,
+) -> Vec<u8> {
+    if file_path.is_empty() {
+        return match (escapes(&request.path), method) {
+            (true, _) => plain(403, "Path is outside the share", cookie),
+            (false, "MKCOL") => plain(409, "Parent collection does not exist", cookie),
+            (false, _) => plain(404, "Not Found", cookie),
+        };
+    }
+    match method {
+        "PROPFIND" => propfind(file_path, server, route, request, cookie),
+        "MKCOL" => mkcol(file_path, route, request, cookie),
+        "MOVE" => transfer(file_path, server, route, request, cookie, true),
+        "COPY" => transfer(file_path, server, route, request, cookie, false),
+        _ => plain(405, "Not a WebDAV method", cookie),
+    }
+}
+
+/// Properties of the resource and, with `Depth: 1`, of a collection's
+/// members. The body isn't parsed: every resource comes with the same live
+/// properties, which covers `allprop` and the named ones drives ask for.
+/// `Depth: infinity` (also the default) is refused, as RFC 4918 allows.
+/// Members that are symlinks are listed only when they resolve inside the
+/// share, as GET would serve them.
+fn propfind(file_path: &str, server: &ServerConfig, route: &Route, request: &HttpRequest, cookie: &Cookie) -> Vec<u8> {
+    let depth = match request.headers.get("depth").map(|v| v.trim()) {
+        Some("0") => 0,
+        Some("1") => 1,
+        _ => {
+            return HttpResponseBuilder::new(403, "Forbidden")
+                .header("Content-Type", "application/xml; charset=utf-8")
+                .body(xml_error("propfind-finite-depth").into_bytes())
+                .cookie(cookie)
+                .build();
+        }
+    };
+    let Ok(metadata) = fs::metadata(file_path) else {
+        return plain(404, "Not Found", cookie);
+    };
+
+    let mut href = encode_path(&request.path);
+    if metadata.is_dir() && !href.ends_with('/') {
+        href.push('/');
+    }
+    let name = request.path.trim_end_matches('/').rsplit('/').next().unwrap_or("");
+    let mut body = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:multistatus xmlns:D=\"DAV:\">\n");
+    push_response(&mut body, &href, name, &metadata, file_path);
+
+    if depth == 1 && metadata.is_dir() {
+        let mut members: Vec<_> = match fs::read_dir(file_path) {
+            Ok(entries) => entries.flatten().collect(),
+            Err(e) => return plain(500, &e.to_string(), cookie),
+        };
+        members.sort_by_key(|entry| entry.file_name());
+        let root = share_root(server, route);
+        for entry in members {
+            let Ok(member_name) = entry.file_name().into_string() else {
+                continue;
+            };
+            let member_path = entry.path();
+            let member = match fs::symlink_metadata(&member_path) {
+                Ok(link) if link.file_type().is_symlink() => match (&root, member_path.canonicalize()) {
+                    (Some(root), Ok(real)) if real.starts_with(root) => fs::metadata(real),
+                    _ => continue,
+                },
+                other => other,
+            };
+            let Ok(member) = member else {
+                continue;
+            };
+            let mut member_href = format!("{}{}", href, urlencoding::encode(&member_name));
+            if member.is_dir() {
+                member_href.push('/');
+            }
+            push_response(&mut body, &member_href, &member_name, &member, &member_path.to_string_lossy());
+        }
+    }
+    body.push_str("</D:multistatus>\n");
+
+    debug!("PROPFIND: {} (depth {})", file_path, depth);
+    HttpResponseBuilder::new(207, reason_phrase(207))
+        .header("Content-Type", "application/xml; charset=utf-8")
+        .body(body.into_bytes())
+        .cookie(cookie)
+        .build()
+}
+
+/// One `<D:response>` of a multistatus listing
+fn push_response(out: &mut String, href: &str, name: &str, metadata: &Metadata, path: &str) {
+    out.push_str("<D:response>\n");
+    out.push_str(&format!("<D:href>{}</D:href>\n", escape_html(href)));
+    out.push_str("<D:propstat>\n<D:prop>\n");
+    out.push_str(&format!("<D:displayname>{}</D:displayname>\n", escape_html(name)));
+    if metadata.is_dir() {
+        out.push_str("<D:resourcetype><D:collection/></D:resourcetype>\n");
+    } else {
+        out.push_str("<D:resourcetype/>\n");
+        out.push_str(&format!("<D:getcontentlength>{}</D:getcontentlength>\n", metadata.len()));
+        out.push_str(&format!("<D:getcontenttype>{}</D:getcontenttype>\n", detect_content_type(path)));
+        out.push_str(&format!(
+            "<D:getetag>{}</D:getetag>\n",
+            escape_html(&weak_etag(metadata.len(), metadata.modified().ok()))
+        ));
+    }
+    if let Ok(modified) = metadata.modified() {
+        out.push_str(&format!(
+            "<D:getlastmodified>{}</D:getlastmodified>\n",
+            httpdate::fmt_http_date(modified)
+        ));
+    }
+    out.push_str("</D:prop>\n<D:status>HTTP/1.1 200 OK</D:status>\n</D:propstat>\n</D:response>\n");
+}
+
+/// Create a directory. Its parent must exist (409 otherwise) and nothing may
+/// be there already (405). Bodies would describe the new collection's
+/// contents, which isn't supported, so they get a 415.
+fn mkcol(file_path: &str, route: &Route, request: &HttpRequest, cookie: &Cookie) -> Vec<u8> {
+    let has_body = request.body.as_ref().is_some_and(|body| !body.is_empty()) || request.body_file.is_some();
+    if has_body {
+        return plain(415, "MKCOL takes no body", cookie);
+    }
+    let path = Path::new(file_path);
+    if path.symlink_metadata().is_ok() {
+        let allowed: Vec<String> = route_methods(route).into_iter().filter(|m| m != "MKCOL").collect();
+        return HttpResponseBuilder::new(405, reason_phrase(405))
+            .header("Allow", &allowed.join(", "))
+            .body(b"Already exists".to_vec())
+            .cookie(cookie)
+            .build();
+    }
+    if !path.parent().is_some_and(Path::is_dir) {
+        return plain(409, "Parent collection does not exist", cookie);
+    }
+    match fs::create_dir(path) {
+        Ok(()) => {
+            info!("MKCOL: Created {}", file_path);
+            HttpResponseBuilder::created().cookie(cookie).build()
+        }
+        Err(e) => plain(500, &e.to_string(), cookie),
+    }
+}
+
+/// MOVE or COPY to the `Destination` header, which must stay in the same
+/// route. An existing destination is replaced (204) unless `Overwrite: F`
+/// asks for a 412; a new one gets a 201. Collections are copied with their
+/// contents, or empty with `Depth: 0`.
+fn transfer(
+    file_path: &str,
+    server: &ServerConfig,
+    route: &Route,
+    request: &HttpRequest,
+    cookie: &Cookie,
+    is_move: bool,
+) -> Vec<u8> {
+    let method = if is_move { "MOVE" } else { "COPY" };
+    let source = Path::new(file_path);
+    let Ok(metadata) = fs::metadata(source) else {
+        return plain(404, "Not Found", cookie);
+    };
+    let Some(destination) = request.headers.get("destination").and_then(|v| destination_path(v)) else {
+        return plain(400, "Missing or malformed Destination header", cookie);
+    };
+    // Other routes have their own roots, methods and auth
+    if find_matching_route(server, &destination).is_none_or(|r| r.path != route.path) {
+        return plain(502, "Destination is outside this share", cookie);
+    }
+    let is_share_root = |path: &str| strip_route_prefix(route, path).is_none_or(|rest| rest.trim_matches('/').is_empty());
+    if is_share_root(&request.path) || is_share_root(&destination) {
+        return plain(403, "The share itself cannot be moved or replaced", cookie);
+    }
+    let Some(target) = resolve_file_path(server, route, &destination) else {
+        return match escapes(&destination) {
+            true => plain(403, "Destination is outside the share", cookie),
+            false => plain(409, "Destination collection does not exist", cookie),
+        };
+    };
+    let target = Path::new(&target);
+    if target == source || (metadata.is_dir() && target.starts_with(source)) {
+        return plain(403, "Destination is the source or inside it", cookie);
+    }
+    if !target.parent().is_some_and(Path::is_dir) {
+        return plain(409, "Destination collection does not exist", cookie);
+    }
+
+    let existed = target.symlink_metadata().is_ok();
+    let overwrite = request.headers.get("overwrite").is_none_or(|v| !v.trim().eq_ignore_ascii_case("F"));
+    if existed && !overwrite {
+        return plain(412, "Destination exists and Overwrite is F", cookie);
+    }
+    let recursive = is_move || request.headers.get("depth").is_none_or(|v| v.trim() != "0");
+
+    let result = (|| {
+        if existed {
+            remove(target)?;
+        }
+        if is_move {
+            // Renames fail across filesystems: copy, then delete the source
+            if fs::rename(source, target).is_err() {
+                copy_tree(source, target, true)?;
+                remove(source)?;
+            }
+            Ok(())
+        } else {
+            copy_tree(source, target, recursive)
+        }
+    })();
+
+    match result {
+        Ok(()) => {
+            info!("{}: {} to {}", method, request.path, destination);
+            if existed {
+                HttpResponseBuilder::no_content().cookie(cookie).build()
+            } else {
+                HttpResponseBuilder::created().cookie(cookie).build()
+            }
+        }
+        Err(e) => plain(500, &e.to_string(), cookie),
+    }
+}
+
+/// The request path a `Destination` names: an absolute URI or an absolute
+/// path, percent-decoded and normalized like request targets. The host is
+/// not looked at.
+fn destination_path(value: &str) -> Option<String> {
+    let value = value.trim();
+    let path = match value.split_once("://") {
+        Some((_, rest)) => &rest[rest.find('/')?..],
+        None => value,
+    };
+    let path = path.split(['?', '#']).next().unwrap_or("");
+    let path = String::from_utf8(percent_decode(path, false)).ok().filter(|p| p.starts_with('/'))?;
+    normalize_path(&path).ok()
+}
+
+/// Whether a path that didn't resolve tried to climb out of the root; if
+/// not, a directory on the way is missing
+fn escapes(request_path: &str) -> bool {
+    request_path.split('/').any(|segment| segment == "..")
+}
+
+/// The route root with symlinks resolved, what request paths must stay under
+fn share_root(server: &ServerConfig, route: &Route) -> Option<PathBuf> {
+    Path::new(&format!("{}/{}", server.root, route.root)).canonicalize().ok()
+}
+
+/// Copy a file, or a collection with its contents when `recursive`.
+/// Symlinks are left out: following them could leave the share, or loop.
+fn copy_tree(source: &Path, target: &Path, recursive: bool) -> io::Result<()> {
+    let metadata = fs::symlink_metadata(source)?;
+    if metadata.file_type().is_symlink() {
+        debug!("Not copying symlink {}", source.display());
+        return Ok(());
+    }
+    if !metadata.is_dir() {
+        return fs::copy(source, target).map(|_| ());
+    }
+    fs::create_dir(target)?;
+    if recursive {
+        for entry in fs::read_dir(source)? {
+            let entry = entry?;
+            copy_tree(&entry.path(), &target.join(entry.file_name()), true)?;
+        }
+    }
+    Ok(())
+}
+
+fn remove(path: &Path) -> io::Result<()> {
+    if path.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+}
+
+/// Percent-encode each segment of a decoded request path for an `href`
+fn encode_path(path: &str) -> String {
+    path.split('/').map(|segment| urlencoding::encode(segment).into_owned()).collect::<Vec<_>>().join("/")
+}
+
+/// Body of an error carrying a precondition code
+fn xml_error(condition: &str) -> String {
+    format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:error xmlns:D=\"DAV:\"><D:{}/></D:error>\n",
+        condition
+    )
+}
+
+fn plain(status: u16, message: &str, cookie: &Cookie) -> Vec<u8> {
+    HttpResponseBuilder::new(status, reason_phrase(status))
+        .header("Content-Type", "text/plain")
+        .body(message.as_bytes().to_vec())
+        .cookie(cookie)
+        .build()
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::config::parse_config;
+    use crate::request::{HeadLimits, HttpRequestBuilder};
+    use std::os::unix::fs::symlink;
+
+    const LIMITS: HeadLimits = HeadLimits {
+        max_request_line: 8 * 1024,
+        max_header_size: 32 * 1024,
+        max_header_count: 100,
+        strict: false,
+    };
+
+    /// A share at `/dav` over a fresh directory, and that directory
+    fn share() -> (ServerConfig, PathBuf) {
+        let root = std::env::temp_dir().join(format!("dav-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&root).unwrap();
+        let config = format!(
+            "servers:
+  - server_name: \"test\"
+    host: 127.0.0.1
+    ports:
+      - 8080
+    default_server: true
+    root: \"{}\"
+    routes:
+      - path: \"/dav\"
+        methods: [\"GET\", \"POST\", \"DELETE\"]
+        root: \".\"
+        webdav: true
+",
+            root.display()
+        );
+        let server = parse_config(&config).expect("test config").servers.remove(0);
+        (server, root.canonicalize().unwrap())
+    }
+
+    fn request(head: &str) -> HttpRequest {
+        let mut builder = HttpRequestBuilder::new();
+        builder.append(format!("{}\r\n\r\n", head).into_bytes(), &LIMITS).unwrap();
+        builder.get().unwrap().clone()
+    }
+
+    fn run(server: &ServerConfig, method: &str, head: &str) -> String {
+        let request = request(head);
+        let route = find_matching_route(server, &request.path).unwrap();
+        let file_path = resolve_file_path(server, route, &request.path).unwrap_or_default();
+        let response = handle(method, &file_path, server, route, &request, &Cookie::new("id", "1"));
+        String::from_utf8_lossy(&response).into_owned()
+    }
+
+    #[test]
+    fn propfind_lists_only_symlinks_inside_the_share() {
+        let (server, root) = share();
+        let outside = std::env::temp_dir().join(format!("dav-outside-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&outside).unwrap();
+        fs::write(root.join("a.txt"), "a").unwrap();
+        symlink(root.join("a.txt"), root.join("inside")).unwrap();
+        symlink(&outside, root.join("outside")).unwrap();
+
+        let listing = run(&server, "PROPFIND", "PROPFIND /dav/ HTTP/1.1\r\nHost: test\r\nDepth: 1");
+
+        assert!(listing.starts_with("HTTP/1.1 207"));
+        assert!(listing.contains("<D:href>/dav/a.txt</D:href>"));
+        assert!(listing.contains("<D:href>/dav/inside</D:href>"));
+        assert!(!listing.contains("outside"));
+        let _ = fs::remove_dir_all(&root);
+        let _ = fs::remove_dir_all(&outside);
+    }
+
+    #[test]
+    fn copy_leaves_symlinks_out() {
+        let (server, root) = share();
+        fs::create_dir_all(root.join("src")).unwrap();
+        fs::write(root.join("src/a.txt"), "a").unwrap();
+        symlink("/etc/passwd", root.join("src/passwd")).unwrap();
+        symlink(root.join("src"), root.join("src/loop")).unwrap();
+
+        let response = run(
+            &server,
+            "COPY",
+            "COPY /dav/src HTTP/1.1\r\nHost: test\r\nDestination: /dav/copy",
+        );
+
+        assert!(response.starts_with("HTTP/1.1 201"));
+        assert_eq!(fs::read_to_string(root.join("copy/a.txt")).unwrap(), "a");
+        assert!(fs::symlink_metadata(root.join("copy/passwd")).is_err());
+        assert!(fs::symlink_metadata(root.join("copy/loop")).is_err());
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn propfind_refuses_infinite_depth() {
+        let (server, root) = share();
+        let response = run(&server, "PROPFIND", "PROPFIND /dav/ HTTP/1.1\r\nHost: test");
+        assert!(response.starts_with("HTTP/1.1 403"));
+        assert!(response.contains("propfind-finite-depth"));
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn mkcol_creates_a_collection_once() {
+        let (server, root) = share();
+        let created = run(&server, "MKCOL", "MKCOL /dav/new HTTP/1.1\r\nHost: test");
+        assert!(created.starts_with("HTTP/1.1 201"));
+        assert!(root.join("new").is_dir());
+
+        let again = run(&server, "MKCOL", "MKCOL /dav/new HTTP/1.1\r\nHost: test");
+        assert!(again.starts_with("HTTP/1.1 405"));
+        let allow = again.lines().find(|l| l.to_ascii_lowercase().starts_with("allow:")).unwrap();
+        assert!(allow.contains("PROPFIND") && !allow.contains("MKCOL"), "{}", allow);
+
+        let orphan = run(&server, "MKCOL", "MKCOL /dav/missing/new HTTP/1.1\r\nHost: test");
+        assert!(orphan.starts_with("HTTP/1.1 409"));
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn move_honours_overwrite_and_stays_in_the_share() {
+        let (server, root) = share();
+        fs::write(root.join("a.txt"), "a").unwrap();
+        fs::write(root.join("b.txt"), "b").unwrap();
+
+        let refused = run(
+            &server,
+            "MOVE",
+            "MOVE /dav/a.txt HTTP/1.1\r\nHost: test\r\nDestination: /dav/b.txt\r\nOverwrite: F",
+        );
+        assert!(refused.starts_with("HTTP/1.1 412"));
+        let outside = run(&server, "MOVE", "MOVE /dav/a.txt HTTP/1.1\r\nHost: test\r\nDestination: /elsewhere/a.txt");
+        assert!(outside.starts_with("HTTP/1.1 502"));
+        assert_eq!(fs::read_to_string(root.join("b.txt")).unwrap(), "b");
+
+        let replaced = run(&server, "MOVE", "MOVE /dav/a.txt HTTP/1.1\r\nHost: test\r\nDestination: /dav/b.txt");
+        assert!(replaced.starts_with("HTTP/1.1 204"));
+        assert!(!root.join("a.txt").exists());
+        assert_eq!(fs::read_to_string(root.join("b.txt")).unwrap(), "a");
+
+        // Absolute URIs name the destination too, percent-encoded
+        let renamed = run(
+            &server,
+            "MOVE",
+            "MOVE /dav/b.txt HTTP/1.1\r\nHost: test\r\nDestination: http://test/dav/c%20d.txt",
+        );
+        assert!(renamed.starts_with("HTTP/1.1 201"));
+        assert_eq!(fs::read_to_string(root.join("c d.txt")).unwrap(), "a");
+        let _ = fs::remove_dir_all(&root);
+    }
+}
@@ -0,0 +1,129 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use crate::config::ServerConfig;
+use crate::error::get_error_page_path;
+use crate::request::HttpRequest;
+use crate::response::HttpResponseBuilder;
+use crate::utils::cookie::Cookie;
+use crate::utils::json;
+
+/// How long a computed summary is served before the subtree is walked again
+const STAT_CACHE_TTL: Duration = Duration::from_secs(10);
+
+/// Summaries cached by directory, shared by every worker so a dashboard
+/// polling several workers doesn't walk the tree once per worker
+static CACHE: OnceLock<Mutex<HashMap<PathBuf, (Instant, DirStat)>>> = OnceLock::new();
+
+/// Totals for a directory subtree
+#[derive(Clone, Copy, Default)]
+struct DirStat {
+    total_size: u64,
+    file_count: u64,
+    newest_mtime: Option<u64>, // Seconds since the epoch
+}
+
+/// Whether the query asks for a subtree summary (`?stat=1`)
+pub fn wants_stat(query_string: &str) -> bool {
+    query_string.split('&').any(|param| param == "stat=1")
+}
+
+/// Answer `GET <dir>?stat=1` with the total size, file count and newest
+/// modification time of the subtree under `file_path`, as JSON
+pub fn handle_stat(file_path: &str, request: &HttpRequest, server: &ServerConfig, cookie: &Cookie) -> Vec<u8> {
+    let path = Path::new(file_path);
+    if !path.exists() {
+        let not_found = get_error_page_path(server, 404);
+        return HttpResponseBuilder::serve_error_page(&not_found, 404, "Not Found", cookie);
+    }
+    if request.cache_bypass {
+        purge(path);
+    }
+
+    let (stat, age) = match cached_stat(path) {
+        Ok(found) => found,
+        Err(e) => {
+            error!("STAT: cannot walk {}: {}", path.display(), e);
+            let error_path = get_error_page_path(server, 500);
+            return HttpResponseBuilder::serve_error_page(&error_path, 500, "Internal Server Error", cookie);
+        }
+    };
+
+    let newest = match stat.newest_mtime {
+        Some(mtime) => mtime.to_string(),
+        None => "null".to_string(),
+    };
+    let body = format!(
+        "{{\"path\": \"{}\", \"total_size\": {}, \"file_count\": {}, \"newest_mtime\": {}}}\n",
+        json::escape(&request.path),
+        stat.total_size,
+        stat.file_count,
+        newest
+    );
+    HttpResponseBuilder::new(200, "OK")
+        .header("Content-Type", "application/json")
+        .header("Cache-Control", &format!("max-age={}", STAT_CACHE_TTL.saturating_sub(age).as_secs()))
+        .body(body.into_bytes())
+        .cookie(cookie)
+        .build()
+}
+
+/// The cached summary for `path` and its age, walking the tree when it
+/// is missing or older than the TTL
+fn cached_stat(path: &Path) -> io::Result<(DirStat, Duration)> {
+    let cache = CACHE.get_or_init(|| Mutex::new(HashMap::new()));
+    let now = Instant::now();
+    {
+        let mut entries = cache.lock().unwrap_or_else(|e| e.into_inner());
+        entries.retain(|_, (computed, _)| now.duration_since(*computed) < STAT_CACHE_TTL);
+        if let Some((computed, stat)) = entries.get(path) {
+            return Ok((*stat, now.duration_since(*computed)));
+        }
+    }
+
+    // Walk without holding the lock, other directories stay answerable
+    let mut stat = DirStat::default();
+    walk(path, &mut stat)?;
+    cache
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(path.to_path_buf(), (now, stat));
+    Ok((stat, Duration::ZERO))
+}
+
+/// Forget the summary of `path`, if one is cached
+pub fn purge(path: &Path) -> bool {
+    CACHE
+        .get()
+        .is_some_and(|cache| cache.lock().unwrap_or_else(|e| e.into_inner()).remove(path).is_some())
+}
+
+/// Add up regular files under `path`. Symlinks are not followed, so a link
+/// can neither loop nor pull in files from outside the root.
+fn walk(path: &Path, stat: &mut DirStat) -> io::Result<()> {
+    let metadata = fs::symlink_metadata(path)?;
+    if metadata.is_file() {
+        stat.total_size += metadata.len();
+        stat.file_count += 1;
+        let mtime = metadata
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs());
+        stat.newest_mtime = stat.newest_mtime.max(mtime);
+    } else if metadata.is_dir() {
+        for entry in fs::read_dir(path)? {
+            let entry = entry?;
+            // Entries removed while walking are not an error
+            match walk(&entry.path(), stat) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                other => other?,
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,640 @@
+use crate::{
+    cgi::{
+        CgiContext, CgiSession, cgi_variables, error_page, send_error_response, set_variable, split_cgi_output,
+        apply_session_headers, take_cgi_status, timeout_response, verify_content_length, with_session_cookie,
+    },
+    client::spawn_lookup,
+    metrics,
+    models::{HttpResponseCommon, SimpleResponse},
+    response::{HttpResponseBuilder, status_code},
+    server::{SocketData, Status},
+};
+use mio::{Interest, Registry, Token};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::time::{Duration, Instant};
+
+/// Temps accordé à l'application quand la requête n'a pas d'échéance
+const FASTCGI_TIMEOUT: Duration = Duration::from_secs(60);
+
+const VERSION: u8 = 1;
+const BEGIN_REQUEST: u8 = 1;
+const END_REQUEST: u8 = 3;
+const PARAMS: u8 = 4;
+const STDIN: u8 = 5;
+const STDOUT: u8 = 6;
+const STDERR: u8 = 7;
+const RESPONDER: u16 = 1;
+/// Une seule requête par connexion, l'id est toujours le même
+const REQUEST_ID: u16 = 1;
+const MAX_RECORD: usize = 65535;
+
+/// Connexion non bloquante vers l'application : `host:port` ou
+/// `unix:/chemin/du/socket`
+enum Connection {
+    /// Nom d'hôte résolu par un thread à part, un DNS lent ne bloque pas la boucle
+    Resolving(Receiver<io::Result<SocketAddr>>),
+    Tcp(mio::net::TcpStream),
+    #[cfg(unix)]
+    Unix(mio::net::UnixStream),
+}
+
+impl Connection {
+    /// Lance la connexion sans l'attendre ; elle aboutit (ou échoue) plus tard
+    fn open(address: &str) -> io::Result<Self> {
+        if let Some(path) = address.strip_prefix("unix:") {
+            #[cfg(unix)]
+            return Ok(Connection::Unix(mio::net::UnixStream::connect(path)?));
+            #[cfg(not(unix))]
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("unix sockets are not supported: {}", path),
+            ));
+        }
+
+        if let Ok(addr) = address.parse::<SocketAddr>() {
+            return Ok(Connection::Tcp(mio::net::TcpStream::connect(addr)?));
+        }
+        let (host, port) = address
+            .rsplit_once(':')
+            .and_then(|(host, port)| Some((host.trim_matches(['[', ']']), port.parse::<u16>().ok()?)))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("invalid address {}", address)))?;
+        let (sender, receiver) = mpsc::channel();
+        spawn_lookup(host.to_string(), port, sender)?;
+        Ok(Connection::Resolving(receiver))
+    }
+
+    fn is_resolving(&self) -> bool {
+        matches!(self, Connection::Resolving(_))
+    }
+
+    /// Vrai une fois la connexion établie, une erreur si elle a échoué.
+    /// L'adresse résolue, la connexion est lancée.
+    fn connected(&mut self) -> io::Result<bool> {
+        let (error, peer) = match self {
+            Connection::Resolving(receiver) => {
+                let addr = match receiver.try_recv() {
+                    Ok(result) => result?,
+                    Err(TryRecvError::Empty) => return Ok(false),
+                    Err(TryRecvError::Disconnected) => return Err(io::Error::other("resolver thread exited")),
+                };
+                *self = Connection::Tcp(mio::net::TcpStream::connect(addr)?);
+                return Ok(false);
+            }
+            Connection::Tcp(s) => (s.take_error()?, s.peer_addr().map(|_| ())),
+            #[cfg(unix)]
+            Connection::Unix(s) => (s.take_error()?, s.peer_addr().map(|_| ())),
+        };
+        if let Some(e) = error {
+            return Err(e);
+        }
+        match peer {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::NotConnected => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn register(&mut self, registry: &Registry, token: Token) -> io::Result<()> {
+        let interest = Interest::READABLE.add(Interest::WRITABLE);
+        match self {
+            Connection::Resolving(_) => Err(io::ErrorKind::NotConnected.into()),
+            Connection::Tcp(s) => registry.register(s, token, interest),
+            #[cfg(unix)]
+            Connection::Unix(s) => registry.register(s, token, interest),
+        }
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Connection::Resolving(_) => Err(io::ErrorKind::WouldBlock.into()),
+            Connection::Tcp(s) => s.read(buf),
+            #[cfg(unix)]
+            Connection::Unix(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Connection::Resolving(_) => Err(io::ErrorKind::WouldBlock.into()),
+            Connection::Tcp(s) => s.write(buf),
+            #[cfg(unix)]
+            Connection::Unix(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Connection::Resolving(_) => Ok(()),
+            Connection::Tcp(s) => s.flush(),
+            #[cfg(unix)]
+            Connection::Unix(s) => s.flush(),
+        }
+    }
+}
+
+/// Exécute le script par l'application FastCGI à `address` (php-fpm…)
+/// au lieu de lancer un processus ; même contrat que `run_cgi`. L'échange
+/// avance au rythme du socket, sans jamais bloquer la boucle d'événements.
+pub fn run_fastcgi(
+    address: &str,
+    mut context: CgiContext,
+    script_path: &str,
+    socket_data: &mut SocketData,
+) -> bool {
+    debug!(
+        "Executing FastCGI: {} via {} with query: {}",
+        script_path, address, context.query_string
+    );
+
+    let deadline = context.deadline.unwrap_or_else(|| Instant::now() + FASTCGI_TIMEOUT);
+    if deadline <= Instant::now() {
+        send_timeout_response(socket_data, &context);
+        return false;
+    }
+
+    let params = build_params(&context, script_path, socket_data);
+    let conn = match Connection::open(address) {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!("FastCGI exchange with {} failed: {:?}", address, e);
+            send_error_response(socket_data, 502, "Bad Gateway");
+            return false;
+        }
+    };
+    // Un body spillé sur disque est envoyé par morceaux, sans tout charger
+    let body_file = match context.body_file.as_ref().map(|spooled| spooled.open()).transpose() {
+        Ok(file) => file,
+        Err(e) => {
+            error!("FastCGI: cannot read spooled request body: {:?}", e);
+            send_error_response(socket_data, 500, "Internal Server Error");
+            return false;
+        }
+    };
+
+    let mut outgoing = Vec::new();
+    // keep_conn à 0 : l'application ferme la connexion après la réponse
+    let mut begin = RESPONDER.to_be_bytes().to_vec();
+    begin.extend_from_slice(&[0; 6]);
+    write_record(&mut outgoing, BEGIN_REQUEST, &begin);
+    let mut encoded = Vec::new();
+    for (name, value) in &params {
+        encode_length(&mut encoded, name.len());
+        encode_length(&mut encoded, value.len());
+        encoded.extend_from_slice(name.as_bytes());
+        encoded.extend_from_slice(value.as_bytes());
+    }
+    write_stream(&mut outgoing, PARAMS, &encoded);
+    write_record(&mut outgoing, PARAMS, &[]);
+    if body_file.is_none() {
+        write_stream(&mut outgoing, STDIN, &context.body);
+    }
+
+    let range = context
+        .headers
+        .iter()
+        .find(|(k, _)| k == "range")
+        .map(|(_, v)| v.clone());
+    socket_data.status.response = Some(Box::new(FastCgiResponse {
+        conn,
+        address: address.to_string(),
+        connected: false,
+        outgoing,
+        sent: 0,
+        body_file,
+        stdin_done: false,
+        incoming: Vec::new(),
+        stdout: Vec::new(),
+        pending: Vec::new(),
+        index: 0,
+        done: false,
+        status: None,
+        default_status: context.status,
+        range,
+        deadline,
+        timeout_page: context.timeout_page.take(),
+        max_output: context.max_output,
+        session: context.session.take(),
+        registered: false,
+    }));
+    socket_data.status.status = Status::Write;
+    true
+}
+
+/// Réponse d'une application FastCGI : les records partent et arrivent quand
+/// le socket est prêt, la réponse est construite une fois END_REQUEST reçu
+pub struct FastCgiResponse {
+    conn: Connection,
+    address: String,
+    connected: bool,
+    outgoing: Vec<u8>, // Records pas encore écrits
+    sent: usize,
+    body_file: Option<File>, // Reste du body spillé, lu au fur et à mesure
+    stdin_done: bool,        // Le record STDIN vide de fin est parti dans `outgoing`
+    incoming: Vec<u8>,       // Records reçus pas encore décodés
+    stdout: Vec<u8>,
+    pending: Vec<u8>, // Réponse prête pour le client
+    index: usize,
+    done: bool,
+    status: Option<u16>, // Connu une fois la réponse complète
+    default_status: u16,
+    range: Option<String>,
+    deadline: Instant,
+    timeout_page: Option<String>,
+    max_output: Option<usize>,
+    session: Option<CgiSession>,
+    registered: bool,
+}
+
+impl FastCgiResponse {
+    /// Fait avancer l'échange autant que possible sans bloquer
+    fn pump(&mut self) {
+        if self.done {
+            return;
+        }
+        if Instant::now() >= self.deadline {
+            warn!("FastCGI application ran past its deadline");
+            self.finish_with(timeout_response(self.timeout_page.as_deref()));
+            return;
+        }
+        if let Err(e) = self.exchange() {
+            error!("FastCGI exchange with {} failed: {:?}", self.address, e);
+            self.finish_with(error_page(502, "Bad Gateway"));
+        }
+    }
+
+    fn exchange(&mut self) -> io::Result<()> {
+        if !self.connected {
+            self.connected = self.conn.connected()?;
+            if !self.connected {
+                return Ok(());
+            }
+        }
+
+        loop {
+            if self.sent == self.outgoing.len() {
+                self.outgoing.clear();
+                self.sent = 0;
+                if !self.queue_stdin()? {
+                    break;
+                }
+            }
+            match self.conn.write(&self.outgoing[self.sent..]) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => self.sent += n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        // L'application peut répondre avant d'avoir tout lu
+        let mut buf = [0u8; 8192];
+        loop {
+            match self.conn.read(&mut buf) {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "connection closed before END_REQUEST",
+                    ));
+                }
+                Ok(n) => {
+                    self.incoming.extend_from_slice(&buf[..n]);
+                    self.decode_records();
+                    if self.done {
+                        return Ok(());
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Met le morceau suivant du body spillé dans `outgoing`, puis le record
+    /// STDIN vide qui le termine ; faux quand tout est parti
+    fn queue_stdin(&mut self) -> io::Result<bool> {
+        if let Some(file) = self.body_file.as_mut() {
+            let mut buf = vec![0u8; MAX_RECORD];
+            let n = file.read(&mut buf)?;
+            if n > 0 {
+                write_record(&mut self.outgoing, STDIN, &buf[..n]);
+                return Ok(true);
+            }
+            self.body_file = None;
+        }
+        if self.stdin_done {
+            return Ok(false);
+        }
+        write_record(&mut self.outgoing, STDIN, &[]);
+        self.stdin_done = true;
+        Ok(true)
+    }
+
+    /// Décode les records complets reçus
+    fn decode_records(&mut self) {
+        let mut pos = 0;
+        while self.incoming.len() - pos >= 8 {
+            let header = &self.incoming[pos..pos + 8];
+            let kind = header[1];
+            let content_len = u16::from_be_bytes([header[4], header[5]]) as usize;
+            let total = 8 + content_len + header[6] as usize;
+            if self.incoming.len() - pos < total {
+                break;
+            }
+            let content = &self.incoming[pos + 8..pos + 8 + content_len];
+            pos += total;
+
+            match kind {
+                STDOUT => {
+                    self.stdout.extend_from_slice(content);
+                    if let Some(max) = self.max_output
+                        && self.stdout.len() > max
+                    {
+                        warn!("FastCGI application wrote more than cgi_max_output ({} bytes), aborted", max);
+                        metrics::CGI_OUTPUT_OVERFLOWS.fetch_add(1, Ordering::Relaxed);
+                        self.finish_with(error_page(502, "FastCGI output too large"));
+                        return;
+                    }
+                }
+                STDERR => {
+                    // Une ligne de log par ligne écrite par l'application
+                    for line in String::from_utf8_lossy(content).lines().filter(|line| !line.trim().is_empty()) {
+                        warn!("FastCGI stderr from {}: {}", self.address, line);
+                    }
+                }
+                END_REQUEST => {
+                    self.finish();
+                    return;
+                }
+                _ => {}
+            }
+        }
+        self.incoming.drain(..pos);
+    }
+
+    /// Construit la réponse à partir de la sortie complète de l'application
+    fn finish(&mut self) {
+        let (mut headers, body) = split_cgi_output(&self.stdout);
+        let (status_code, status_text) = take_cgi_status(&mut headers, self.default_status);
+        apply_session_headers(&mut headers, self.session.as_ref());
+        let body = verify_content_length(&mut headers, body.to_vec());
+
+        debug!("FastCGI execution successful");
+        let builder = HttpResponseBuilder::new(status_code, &status_text).headers(headers);
+        let response = with_session_cookie(builder, self.session.as_ref())
+            .body(body)
+            .range(self.range.as_ref())
+            .build();
+        self.finish_with(response);
+    }
+
+    fn finish_with(&mut self, response: Vec<u8>) {
+        self.status = status_code(&response);
+        self.pending = response;
+        self.index = 0;
+        self.outgoing = Vec::new();
+        self.incoming = Vec::new();
+        self.stdout = Vec::new();
+        self.body_file = None;
+        self.done = true;
+    }
+}
+
+impl HttpResponseCommon for FastCgiResponse {
+    fn peek(&self) -> &[u8] {
+        &self.pending[self.index..]
+    }
+
+    fn next(&mut self, n: usize) {
+        self.index += n;
+    }
+
+    fn is_finished(&self) -> bool {
+        self.done && self.index >= self.pending.len()
+    }
+
+    fn fill_if_needed(&mut self) -> io::Result<()> {
+        self.pump();
+        Ok(())
+    }
+
+    fn status(&self) -> Option<u16> {
+        self.status
+    }
+
+    fn buffered_len(&self) -> usize {
+        self.pending.capacity() + self.outgoing.capacity() + self.incoming.capacity() + self.stdout.capacity()
+    }
+
+    fn is_waiting(&self) -> bool {
+        !self.done
+    }
+
+    fn poll_producer(&mut self) -> bool {
+        let resolving = self.conn.is_resolving();
+        self.pump();
+        // Le socket ouvert une fois l'adresse résolue est enregistré au réveil
+        self.done || (resolving && !self.conn.is_resolving())
+    }
+
+    fn register_sources(&mut self, registry: &Registry, next_token: &mut usize) -> io::Result<Vec<Token>> {
+        if self.registered || self.done || self.conn.is_resolving() {
+            return Ok(Vec::new());
+        }
+        self.registered = true;
+        let token = Token(*next_token);
+        *next_token += 1;
+        self.conn.register(registry, token)?;
+        Ok(vec![token])
+    }
+}
+
+fn send_timeout_response(socket_data: &mut SocketData, context: &CgiContext) {
+    let response = timeout_response(context.timeout_page.as_deref());
+    socket_data.status.response = Some(Box::new(SimpleResponse::new(response)));
+    socket_data.status.status = Status::Write;
+}
+
+/// Variables CGI standard, plus ce que php-fpm attend en général
+fn build_params(context: &CgiContext, script_path: &str, socket_data: &SocketData) -> Vec<(String, String)> {
+    // L'application tourne dans un autre processus, souvent un autre répertoire
+    let script_filename = fs::canonicalize(script_path)
+        .map(|p| p.to_string_lossy().into_owned())
+        .unwrap_or_else(|_| script_path.to_string());
+
+    let mut params = cgi_variables(context, &script_filename, socket_data);
+    set_variable(&mut params, "DOCUMENT_URI", &context.path);
+    params
+}
+
+fn write_stream(out: &mut Vec<u8>, kind: u8, data: &[u8]) {
+    for chunk in data.chunks(MAX_RECORD) {
+        write_record(out, kind, chunk);
+    }
+}
+
+fn write_record(out: &mut Vec<u8>, kind: u8, content: &[u8]) {
+    // Le contenu est aligné sur 8 octets, comme le recommande la spécification
+    let padding = (8 - content.len() % 8) % 8;
+    out.push(VERSION);
+    out.push(kind);
+    out.extend_from_slice(&REQUEST_ID.to_be_bytes());
+    out.extend_from_slice(&(content.len() as u16).to_be_bytes());
+    out.push(padding as u8);
+    out.push(0);
+    out.extend_from_slice(content);
+    out.resize(out.len() + padding, 0);
+}
+
+/// Longueur d'un nom ou d'une valeur : 1 octet sous 128, sinon 4 octets avec le bit de poids fort
+fn encode_length(out: &mut Vec<u8>, len: usize) {
+    if len < 128 {
+        out.push(len as u8);
+    } else {
+        out.extend_from_slice(&(len as u32 | 0x8000_0000).to_be_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn records_are_padded_and_split() {
+        let mut out = Vec::new();
+        write_record(&mut out, STDOUT, b"hello");
+        assert_eq!(out, [VERSION, STDOUT, 0, 1, 0, 5, 3, 0, b'h', b'e', b'l', b'l', b'o', 0, 0, 0]);
+
+        let mut out = Vec::new();
+        write_stream(&mut out, STDIN, &vec![b'x'; MAX_RECORD + 1]);
+        let first = 8 + MAX_RECORD + 1; // 65535 octets plus 1 de bourrage
+        assert_eq!(u16::from_be_bytes([out[4], out[5]]) as usize, MAX_RECORD);
+        assert_eq!(u16::from_be_bytes([out[first + 4], out[first + 5]]), 1);
+        assert_eq!(out.len(), first + 16);
+    }
+
+    #[test]
+    fn long_lengths_take_four_bytes() {
+        let mut out = Vec::new();
+        encode_length(&mut out, 127);
+        encode_length(&mut out, 300);
+        assert_eq!(out, [127, 0x80, 0, 1, 44]);
+    }
+
+    /// Lit un record entier : (type, contenu)
+    fn read_record(stream: &mut std::net::TcpStream) -> (u8, Vec<u8>) {
+        let mut header = [0u8; 8];
+        stream.read_exact(&mut header).unwrap();
+        let len = u16::from_be_bytes([header[4], header[5]]) as usize;
+        let mut content = vec![0u8; len + header[6] as usize];
+        stream.read_exact(&mut content).unwrap();
+        content.truncate(len);
+        (header[1], content)
+    }
+
+    fn response_to(address: &str, outgoing: Vec<u8>) -> FastCgiResponse {
+        FastCgiResponse {
+            conn: Connection::open(address).unwrap(),
+            address: address.to_string(),
+            connected: false,
+            outgoing,
+            sent: 0,
+            body_file: None,
+            stdin_done: false,
+            incoming: Vec::new(),
+            stdout: Vec::new(),
+            pending: Vec::new(),
+            index: 0,
+            done: false,
+            status: None,
+            default_status: 200,
+            range: None,
+            deadline: Instant::now() + Duration::from_secs(5),
+            timeout_page: None,
+            max_output: None,
+            session: None,
+            registered: false,
+        }
+    }
+
+    #[test]
+    fn exchange_with_an_application() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let app = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut params = Vec::new();
+            let mut stdin = Vec::new();
+            loop {
+                match read_record(&mut stream) {
+                    (PARAMS, content) => params.extend(content),
+                    (STDIN, content) if content.is_empty() => break,
+                    (STDIN, content) => stdin.extend(content),
+                    _ => {}
+                }
+            }
+            let mut out = Vec::new();
+            // La sortie arrive en plusieurs records, entrecoupés de stderr
+            write_record(&mut out, STDOUT, b"Status: 201 Created\r\nContent-Type: text/plain\r\n");
+            write_record(&mut out, STDERR, b"notice\n");
+            write_record(&mut out, STDOUT, format!("\r\nreceived {} bytes", stdin.len()).as_bytes());
+            write_record(&mut out, END_REQUEST, &[0; 8]);
+            stream.write_all(&out).unwrap();
+            params
+        });
+
+        let mut outgoing = Vec::new();
+        write_record(&mut outgoing, BEGIN_REQUEST, &[0, 1, 0, 0, 0, 0, 0, 0]);
+        let mut encoded = Vec::new();
+        encode_length(&mut encoded, 15);
+        encode_length(&mut encoded, 6);
+        encoded.extend_from_slice(b"SCRIPT_FILENAME/a.php");
+        write_stream(&mut outgoing, PARAMS, &encoded);
+        write_record(&mut outgoing, PARAMS, &[]);
+        write_stream(&mut outgoing, STDIN, &vec![b'x'; 100_000]);
+
+        let mut response = response_to(&address, outgoing);
+        while !response.poll_producer() {
+            assert!(Instant::now() < response.deadline, "no answer from the application");
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        assert_eq!(app.join().unwrap(), encoded);
+        assert_eq!(response.status(), Some(201));
+        let sent = String::from_utf8_lossy(response.peek()).into_owned();
+        assert!(sent.starts_with("HTTP/1.1 201 Created\r\n"), "{}", sent);
+        assert!(sent.ends_with("\r\n\r\nreceived 100000 bytes"), "{}", sent);
+    }
+
+    #[test]
+    fn closing_before_end_request_is_a_bad_gateway() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let app = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            while !matches!(read_record(&mut stream), (STDIN, content) if content.is_empty()) {}
+            let mut out = Vec::new();
+            write_record(&mut out, STDOUT, b"Content-Type: text/plain\r\n\r\npartial");
+            stream.write_all(&out).unwrap();
+        });
+
+        let mut response = response_to(&address, Vec::new());
+        while !response.poll_producer() {
+            assert!(Instant::now() < response.deadline, "no answer from the application");
+            thread::sleep(Duration::from_millis(5));
+        }
+        app.join().unwrap();
+        assert_eq!(response.status(), Some(502));
+    }
+}
@@ -0,0 +1,785 @@
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use flate2::{Compression, write::GzEncoder};
+
+use crate::compress_cache::CacheWriter;
+use crate::config::{Route, ServerConfig};
+use crate::models::HttpResponseCommon;
+use crate::request::HttpRequest;
+use crate::response::find_bytes;
+
+/// Heads longer than this are passed through unfiltered
+const MAX_FILTER_HEAD: usize = 16 * 1024;
+
+/// Filter names accepted in a route's `filters:` list
+pub const FILTER_NAMES: &[&str] = &["headers", "compress"];
+
+/// Order used by routes without a `filters:` list
+const DEFAULT_ORDER: &[&str] = &["headers", "compress"];
+
+/// Status line and headers of a response going through the chain
+pub struct ResponseHead {
+    pub status_line: String,
+    pub status: u16,
+    pub headers: Vec<(String, String)>, // Original names and order, repeats kept
+    pub source: Option<(PathBuf, SystemTime)>, // File on disk sent whole as the body, and its modification time
+}
+
+impl ResponseHead {
+    fn parse(bytes: &[u8]) -> Self {
+        let text = String::from_utf8_lossy(bytes);
+        let mut lines = text.split("\r\n").filter(|l| !l.is_empty());
+        let status_line = lines.next().unwrap_or("").to_string();
+        let status = status_line
+            .split(' ')
+            .nth(1)
+            .and_then(|c| c.parse::<u16>().ok())
+            .unwrap_or(0);
+        let headers = lines
+            .filter_map(|l| l.split_once(':'))
+            .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+            .collect();
+        Self {
+            status_line,
+            status,
+            headers,
+            source: None,
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Replace every value of `name` with `value`
+    pub fn set(&mut self, name: &str, value: &str) {
+        self.remove(name);
+        self.headers.push((name.to_string(), value.to_string()));
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.headers.retain(|(k, _)| !k.eq_ignore_ascii_case(name));
+    }
+
+    /// Add `token` to the Vary header, creating it if needed
+    pub fn vary(&mut self, token: &str) {
+        match self.headers.iter_mut().find(|(k, _)| k.eq_ignore_ascii_case("vary")) {
+            Some((_, vary)) if vary.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)) => {}
+            Some((_, vary)) => vary.push_str(&format!(", {}", token)),
+            None => self.headers.push(("Vary".to_string(), token.to_string())),
+        }
+    }
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut out = format!("{}\r\n", self.status_line);
+        for (key, value) in &self.headers {
+            out.push_str(&format!("{}: {}\r\n", key, value));
+        }
+        out.push_str("\r\n");
+        out.into_bytes()
+    }
+}
+
+/// Edits the head only. Cheap, and applied whatever the body framing.
+pub trait HeaderFilter {
+    fn apply(&self, head: &mut ResponseHead);
+}
+
+/// Rewrites the body while it streams through. `start` sees the head as
+/// left by the filters before it and returns whether this filter takes
+/// part; bodies are only transformed when their length is known.
+pub trait BodyFilter {
+    fn start(&mut self, head: &mut ResponseHead) -> bool;
+    fn transform(&mut self, data: &[u8], out: &mut Vec<u8>) -> io::Result<()>;
+    fn finish(&mut self, out: &mut Vec<u8>) -> io::Result<()>;
+}
+
+pub enum Filter {
+    Header(Box<dyn HeaderFilter>),
+    Body(Box<dyn BodyFilter>),
+}
+
+/// Run `response` through the route's filters, in the route's order
+/// (`filters: [headers, compress]`). Responses with nothing to do are returned as is.
+pub fn apply(
+    response: Box<dyn HttpResponseCommon>,
+    server: &ServerConfig,
+    route: &Route,
+    request: &HttpRequest,
+) -> Box<dyn HttpResponseCommon> {
+    let order: Vec<&str> = match &route.filters {
+        Some(names) => names.iter().map(String::as_str).collect(),
+        None => DEFAULT_ORDER.to_vec(),
+    };
+
+    let mut chain = Vec::new();
+    for name in order {
+        match name {
+            "headers" if !route.add_headers.is_empty() || !route.remove_headers.is_empty() => {
+                chain.push(Filter::Header(Box::new(HeaderEdits {
+                    add: route.add_headers.clone(),
+                    remove: route.remove_headers.clone(),
+                })));
+            }
+            "compress" => {
+                let offered = offered_codings(server, route);
+                if !offered.is_empty() {
+                    chain.push(Filter::Body(Box::new(CompressFilter::new(
+                        server,
+                        &offered,
+                        request.headers.get_combined("accept-encoding").as_deref(),
+                    ))));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if chain.is_empty() {
+        return response;
+    }
+    Box::new(FilteredResponse::new(response, chain))
+}
+
+/// Codings the route compresses with: its own list, else gzip where the
+/// server enables it or the route lists the filter explicitly. None when the
+/// route's filters leave `compress` out.
+pub fn offered_codings(server: &ServerConfig, route: &Route) -> Vec<String> {
+    if route.filters.as_ref().is_some_and(|names| !names.iter().any(|n| n == "compress")) {
+        return Vec::new();
+    }
+    match &route.compression {
+        Some(codings) => codings.clone(),
+        None if server.gzip || route.filters.is_some() => vec!["gzip".to_string()],
+        None => Vec::new(),
+    }
+}
+
+/// Send `response` with `Connection: close`, for the last one on a connection
+pub fn close_connection(response: Box<dyn HttpResponseCommon>) -> Box<dyn HttpResponseCommon> {
+    Box::new(FilteredResponse::new(response, vec![Filter::Header(Box::new(ConnectionClose))]))
+}
+
+/// Send `response` to an HTTP/1.0 client: a 1.0 status line, and no
+/// chunked framing, which it doesn't know. A body of unknown length then
+/// ends with the connection, even if the client asked to keep it.
+pub fn http10(response: Box<dyn HttpResponseCommon>, keep_alive: bool) -> Box<dyn HttpResponseCommon> {
+    let mut response = FilteredResponse::new(response, Vec::new());
+    response.http10 = Some(keep_alive);
+    Box::new(response)
+}
+
+struct ConnectionClose;
+
+impl HeaderFilter for ConnectionClose {
+    fn apply(&self, head: &mut ResponseHead) {
+        head.set("Connection", "close");
+    }
+}
+
+/// `add_headers` / `remove_headers` of a route
+struct HeaderEdits {
+    add: Vec<(String, String)>,
+    remove: Vec<String>,
+}
+
+impl HeaderFilter for HeaderEdits {
+    fn apply(&self, head: &mut ResponseHead) {
+        for name in &self.remove {
+            head.remove(name);
+        }
+        for (name, value) in &self.add {
+            head.set(name, value);
+        }
+    }
+}
+
+/// Content codings the server can produce, in its default preference order
+pub const CODINGS: &[&str] = &["gzip", "br", "zstd"];
+
+/// Pick the coding to send: the highest q-value the client gives among
+/// `offered` (the route's list, whose order breaks ties). A coding the
+/// client doesn't name takes the q-value of `*`; q=0 refuses it.
+pub fn negotiate<'a>(accept_encoding: Option<&str>, offered: &'a [String]) -> Option<&'a str> {
+    let accepted: Vec<(String, f32)> = accept_encoding?
+        .split(',')
+        .filter_map(|item| {
+            let mut params = item.split(';');
+            let coding = params.next()?.trim().to_ascii_lowercase();
+            if coding.is_empty() {
+                return None;
+            }
+            let q = params
+                .filter_map(|p| p.trim().strip_prefix("q=").map(str::trim))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            Some((coding, q))
+        })
+        .collect();
+    let q_of = |coding: &str| {
+        let alias = if coding == "gzip" { "x-gzip" } else { coding };
+        accepted
+            .iter()
+            .find(|(c, _)| c == coding || c == alias)
+            .or_else(|| accepted.iter().find(|(c, _)| c == "*"))
+            .map(|&(_, q)| q)
+            .unwrap_or(0.0)
+    };
+
+    let mut best: Option<(&str, f32)> = None;
+    for coding in offered {
+        let q = q_of(coding);
+        if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) {
+            best = Some((coding, q));
+        }
+    }
+    best.map(|(coding, _)| coding)
+}
+
+/// Whether responses of this type are worth compressing: listed in
+/// `types` and not in `exclude`. `*` and `text/*` patterns match families.
+fn is_compressible(types: &[String], exclude: &[String], content_type: &str) -> bool {
+    let mime = content_type.split(';').next().unwrap_or("").trim();
+    let matches = |pattern: &String| {
+        if pattern == "*" {
+            return true;
+        }
+        match pattern.strip_suffix("/*") {
+            Some(family) => mime
+                .split_once('/')
+                .is_some_and(|(f, _)| f.eq_ignore_ascii_case(family)),
+            None => mime.eq_ignore_ascii_case(pattern),
+        }
+    };
+    types.iter().any(matches) && !exclude.iter().any(matches)
+}
+
+enum Encoder {
+    Gzip(GzEncoder<Vec<u8>>),
+    Brotli(Box<brotli::CompressorWriter<Vec<u8>>>),
+    Zstd(zstd::stream::write::Encoder<'static, Vec<u8>>),
+}
+
+impl Encoder {
+    fn new(coding: &str) -> io::Result<Self> {
+        Ok(match coding {
+            // Mid-range levels: most of the gain for a fraction of the CPU
+            "br" => Encoder::Brotli(Box::new(brotli::CompressorWriter::new(Vec::new(), 4096, 5, 22))),
+            "zstd" => Encoder::Zstd(zstd::stream::write::Encoder::new(Vec::new(), 3)?),
+            _ => Encoder::Gzip(GzEncoder::new(Vec::new(), Compression::default())),
+        })
+    }
+
+    /// Compress `data`, moving whatever output is ready into `out`
+    fn write(&mut self, data: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        match self {
+            Encoder::Gzip(e) => {
+                e.write_all(data)?;
+                out.append(e.get_mut());
+            }
+            Encoder::Brotli(e) => {
+                e.write_all(data)?;
+                out.append(e.get_mut());
+            }
+            Encoder::Zstd(e) => {
+                e.write_all(data)?;
+                out.append(e.get_mut());
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(self, out: &mut Vec<u8>) -> io::Result<()> {
+        let tail = match self {
+            Encoder::Gzip(e) => e.finish()?,
+            Encoder::Brotli(e) => e.into_inner(),
+            Encoder::Zstd(e) => e.finish()?,
+        };
+        out.extend_from_slice(&tail);
+        Ok(())
+    }
+}
+
+/// Compression in the best coding the client and the route share.
+/// Responses that would be compressed for some clients carry
+/// `Vary: Accept-Encoding` either way. With `compress_cache_dir`, whole
+/// files are also written to the cache as they are compressed, for the
+/// next requests to be served from.
+struct CompressFilter {
+    coding: Option<String>, // Negotiated for this request
+    min_size: usize,
+    types: Vec<String>,
+    exclude: Vec<String>,
+    cache_dir: Option<String>,
+    encoder: Option<Encoder>,
+    cache: Option<CacheWriter>,
+}
+
+impl CompressFilter {
+    fn new(server: &ServerConfig, offered: &[String], accept_encoding: Option<&str>) -> Self {
+        Self {
+            coding: negotiate(accept_encoding, offered).map(str::to_string),
+            min_size: server.compress_min_size,
+            types: server.compress_types.clone(),
+            exclude: server.compress_exclude.clone(),
+            cache_dir: server.compress_cache_dir.clone(),
+            encoder: None,
+            cache: None,
+        }
+    }
+}
+
+impl BodyFilter for CompressFilter {
+    fn start(&mut self, head: &mut ResponseHead) -> bool {
+        let length = head.get("content-length").and_then(|v| v.parse::<u64>().ok());
+        // Partial, empty and already-encoded bodies stay as they are
+        let eligible = !matches!(head.status, 0..=199 | 204 | 206 | 304)
+            && head.get("content-encoding").is_none()
+            && head.get("content-range").is_none()
+            && length.is_some_and(|len| len >= self.min_size as u64)
+            && head
+                .get("content-type")
+                .is_some_and(|ct| is_compressible(&self.types, &self.exclude, ct));
+        if !eligible {
+            return false;
+        }
+
+        head.vary("Accept-Encoding");
+        let Some(coding) = &self.coding else {
+            return false;
+        };
+        match Encoder::new(coding) {
+            Ok(encoder) => self.encoder = Some(encoder),
+            Err(e) => {
+                error!("Cannot start {} encoder: {}", coding, e);
+                return false;
+            }
+        }
+        head.set("Content-Encoding", coding);
+
+        if let (Some(dir), Some((path, modified))) = (&self.cache_dir, &head.source) {
+            match CacheWriter::create(dir, path, *modified, coding) {
+                Ok(cache) => self.cache = Some(cache),
+                Err(e) => error!("Compression cache: cannot write to {}: {}", dir, e),
+            }
+        }
+        true
+    }
+
+    fn transform(&mut self, data: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        let Some(encoder) = self.encoder.as_mut() else {
+            return Ok(());
+        };
+        let start = out.len();
+        encoder.write(data, out)?;
+        if let Some(cache) = &mut self.cache {
+            cache.write(&out[start..]);
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self, out: &mut Vec<u8>) -> io::Result<()> {
+        let Some(encoder) = self.encoder.take() else {
+            return Ok(());
+        };
+        let start = out.len();
+        encoder.finish(out)?;
+        if let Some(mut cache) = self.cache.take() {
+            cache.write(&out[start..]);
+            cache.commit();
+        }
+        Ok(())
+    }
+}
+
+enum FilterState {
+    Head,         // Collecting the inner response's head
+    Passthrough,  // Head sent, body forwarded as is
+    Transforming, // Body going through the active body filters, re-framed as chunks
+    Unchunking,   // Chunked body sent without its framing to an HTTP/1.0 client
+    Done,
+}
+
+/// A response behind a filter chain. The inner head is read first and run
+/// through the filters in order; when a body filter takes part, the body is
+/// streamed through the active ones and sent with chunked framing.
+pub struct FilteredResponse {
+    inner: Box<dyn HttpResponseCommon>,
+    chain: Vec<Filter>,
+    active: Vec<usize>, // Body filters transforming this response, in chain order
+    head: Vec<u8>,
+    out: Vec<u8>,
+    index: usize,
+    remaining: u64, // Inner body bytes still to transform
+    state: FilterState,
+    http10: Option<bool>, // Client speaks HTTP/1.0, and whether it asked for keep-alive
+    unchunker: Unchunker,
+    closes: bool, // Body delimited by the end of the connection
+}
+
+impl FilteredResponse {
+    pub fn new(inner: Box<dyn HttpResponseCommon>, chain: Vec<Filter>) -> Self {
+        Self {
+            inner,
+            chain,
+            active: Vec::new(),
+            head: Vec::new(),
+            out: Vec::new(),
+            index: 0,
+            remaining: 0,
+            state: FilterState::Head,
+            http10: None,
+            unchunker: Unchunker::default(),
+            closes: false,
+        }
+    }
+
+    /// Pull the inner head; false while more of it is needed
+    fn read_head(&mut self) -> io::Result<bool> {
+        loop {
+            self.inner.fill_if_needed()?;
+            let data = self.inner.peek();
+            if data.is_empty() {
+                if self.inner.is_finished() {
+                    // Not an HTTP head after all, send what there is
+                    self.out = std::mem::take(&mut self.head);
+                    self.state = FilterState::Passthrough;
+                    return Ok(true);
+                }
+                return Ok(false);
+            }
+
+            let before = self.head.len();
+            self.head.extend_from_slice(data);
+            if let Some(end) = find_bytes(&self.head, b"\r\n\r\n") {
+                let head_len = end + 4;
+                self.inner.next(head_len - before);
+                self.head.truncate(head_len);
+                self.start_body();
+                return Ok(true);
+            }
+            let taken = data.len();
+            self.inner.next(taken);
+            if self.head.len() > MAX_FILTER_HEAD {
+                self.out = std::mem::take(&mut self.head);
+                self.state = FilterState::Passthrough;
+                return Ok(true);
+            }
+        }
+    }
+
+    /// Run the head through the chain and queue it
+    fn start_body(&mut self) {
+        let mut head = ResponseHead::parse(&std::mem::take(&mut self.head));
+        head.source = self
+            .inner
+            .source_file()
+            .map(|(path, modified)| (path.to_path_buf(), modified));
+        // Chunked or unframed bodies only get header filters
+        let length = match head.get("transfer-encoding") {
+            Some(_) => None,
+            None => head.get("content-length").and_then(|v| v.parse::<u64>().ok()),
+        };
+
+        for (i, filter) in self.chain.iter_mut().enumerate() {
+            match filter {
+                Filter::Header(filter) => filter.apply(&mut head),
+                Filter::Body(filter) => {
+                    if length.is_some() && filter.start(&mut head) {
+                        self.active.push(i);
+                    }
+                }
+            }
+        }
+
+        let chunked = head
+            .get("transfer-encoding")
+            .is_some_and(|te| te.to_ascii_lowercase().contains("chunked"));
+        if self.active.is_empty() {
+            self.state = FilterState::Passthrough;
+            if self.http10.is_some() && chunked {
+                head.remove("Transfer-Encoding");
+                self.state = FilterState::Unchunking;
+            }
+        } else {
+            head.remove("Content-Length");
+            if self.http10.is_none() {
+                head.set("Transfer-Encoding", "chunked");
+            }
+            self.remaining = length.unwrap_or(0);
+            self.state = FilterState::Transforming;
+        }
+
+        if let Some(keep_alive) = self.http10 {
+            if let Some(rest) = head.status_line.strip_prefix("HTTP/1.1 ") {
+                head.status_line = format!("HTTP/1.0 {}", rest);
+            }
+            let framed = head.get("content-length").is_some() || matches!(head.status, 204 | 304);
+            self.closes = !framed;
+            let closing = head.get("connection").is_some_and(|c| c.eq_ignore_ascii_case("close"));
+            let persists = keep_alive && framed && !closing;
+            head.set("Connection", if persists { "keep-alive" } else { "close" });
+        }
+        self.out = head.serialize();
+    }
+
+    /// Pass `data` through every active body filter, or flush them at the end
+    fn run_body_filters(&mut self, data: &[u8], last: bool) -> io::Result<Vec<u8>> {
+        let mut data = data.to_vec();
+        for &i in &self.active {
+            let Filter::Body(filter) = &mut self.chain[i] else {
+                continue;
+            };
+            let mut out = Vec::new();
+            filter.transform(&data, &mut out)?;
+            if last {
+                filter.finish(&mut out)?;
+            }
+            data = out;
+        }
+        Ok(data)
+    }
+
+    /// Transform what the inner response has ready into the next chunks
+    fn transform_some(&mut self) -> io::Result<()> {
+        while self.remaining > 0 {
+            self.inner.fill_if_needed()?;
+            let data = self.inner.peek();
+            if data.is_empty() {
+                if self.inner.is_finished() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "body shorter than its Content-Length",
+                    ));
+                }
+                return Ok(());
+            }
+            let n = data.len().min(usize::try_from(self.remaining).unwrap_or(usize::MAX));
+            let data = data[..n].to_vec();
+            self.inner.next(n);
+            self.remaining -= n as u64;
+
+            let transformed = self.run_body_filters(&data, self.remaining == 0)?;
+            if self.http10.is_some() {
+                self.out.extend_from_slice(&transformed);
+            } else {
+                push_chunk(&mut self.out, &transformed);
+            }
+            if !self.out.is_empty() {
+                break;
+            }
+        }
+
+        if self.remaining == 0 {
+            if self.http10.is_none() {
+                self.out.extend_from_slice(b"0\r\n\r\n");
+            }
+            self.state = FilterState::Done;
+        }
+        Ok(())
+    }
+
+    /// Strip the chunk framing off what the inner response has ready
+    fn unchunk_some(&mut self) -> io::Result<()> {
+        loop {
+            self.inner.fill_if_needed()?;
+            let data = self.inner.peek();
+            if data.is_empty() {
+                if self.inner.is_finished() {
+                    self.state = FilterState::Done;
+                }
+                return Ok(());
+            }
+            let data = data.to_vec();
+            self.inner.next(data.len());
+            if self.unchunker.feed(&data, &mut self.out)? {
+                self.state = FilterState::Done;
+                return Ok(());
+            }
+            if !self.out.is_empty() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Decoder for the chunked bodies the server frames itself
+#[derive(Default)]
+struct Unchunker {
+    buffer: Vec<u8>,
+    left: usize,    // Data bytes of the current chunk still to come
+    trailers: bool, // Past the last chunk
+}
+
+impl Unchunker {
+    /// Move the body bytes of `data` to `out`; true once the body is complete
+    fn feed(&mut self, data: &[u8], out: &mut Vec<u8>) -> io::Result<bool> {
+        self.buffer.extend_from_slice(data);
+        let mut pos = 0;
+        let mut complete = false;
+        while pos < self.buffer.len() {
+            if self.left > 0 {
+                let n = self.left.min(self.buffer.len() - pos);
+                out.extend_from_slice(&self.buffer[pos..pos + n]);
+                pos += n;
+                self.left -= n;
+                continue;
+            }
+            let Some(end) = find_bytes(&self.buffer[pos..], b"\r\n") else {
+                break;
+            };
+            let line = &self.buffer[pos..pos + end];
+            pos += end + 2;
+            if self.trailers {
+                // Trailer fields are dropped; an empty line ends the body
+                if line.is_empty() {
+                    complete = true;
+                    break;
+                }
+            } else if !line.is_empty() {
+                // Empty lines are the ends of chunk data
+                let size = std::str::from_utf8(line)
+                    .ok()
+                    .and_then(|l| usize::from_str_radix(l.split(';').next()?.trim(), 16).ok())
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid chunk size"))?;
+                self.left = size;
+                self.trailers = size == 0;
+            }
+        }
+        self.buffer.drain(..pos);
+        Ok(complete)
+    }
+}
+
+fn push_chunk(out: &mut Vec<u8>, data: &[u8]) {
+    if data.is_empty() {
+        return;
+    }
+    out.extend_from_slice(format!("{:x}\r\n", data.len()).as_bytes());
+    out.extend_from_slice(data);
+    out.extend_from_slice(b"\r\n");
+}
+
+impl HttpResponseCommon for FilteredResponse {
+    fn peek(&self) -> &[u8] {
+        if self.index < self.out.len() {
+            return &self.out[self.index..];
+        }
+        match self.state {
+            FilterState::Passthrough => self.inner.peek(),
+            _ => &[],
+        }
+    }
+
+    fn next(&mut self, n: usize) {
+        if self.index < self.out.len() {
+            self.index += n;
+        } else if let FilterState::Passthrough = self.state {
+            self.inner.next(n);
+        }
+    }
+
+    fn is_finished(&self) -> bool {
+        self.index >= self.out.len()
+            && match self.state {
+                FilterState::Passthrough => self.inner.is_finished(),
+                FilterState::Done => true,
+                _ => false,
+            }
+    }
+
+    fn fill_if_needed(&mut self) -> io::Result<()> {
+        if self.index < self.out.len() {
+            return Ok(());
+        }
+        self.out.clear();
+        self.index = 0;
+        match self.state {
+            FilterState::Head => self.read_head().map(|_| ()),
+            FilterState::Passthrough => self.inner.fill_if_needed(),
+            FilterState::Transforming => self.transform_some(),
+            FilterState::Unchunking => self.unchunk_some(),
+            FilterState::Done => Ok(()),
+        }
+    }
+
+    fn status(&self) -> Option<u16> {
+        self.inner.status()
+    }
+
+    fn buffered_len(&self) -> usize {
+        self.head.capacity() + self.out.capacity() + self.inner.buffered_len()
+    }
+
+    fn is_waiting(&self) -> bool {
+        self.index >= self.out.len() && self.inner.is_waiting()
+    }
+
+    fn poll_producer(&mut self) -> bool {
+        self.inner.poll_producer()
+    }
+
+    fn closes_connection(&self) -> bool {
+        self.closes || self.inner.closes_connection()
+    }
+
+    fn register_sources(&mut self, registry: &mio::Registry, next_token: &mut usize) -> io::Result<Vec<mio::Token>> {
+        self.inner.register_sources(registry, next_token)
+    }
+
+    fn sendfile_len(&self) -> u64 {
+        match self.state {
+            // Once the filtered head is out, an untouched body can skip the buffer
+            FilterState::Passthrough if self.index >= self.out.len() => self.inner.sendfile_len(),
+            _ => 0,
+        }
+    }
+
+    fn sendfile(&mut self, fd: i32, max: usize) -> io::Result<usize> {
+        match self.state {
+            FilterState::Passthrough if self.index >= self.out.len() => self.inner.sendfile(fd, max),
+            _ => Ok(0),
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use crate::models::FileResponse;
+    use crate::utils::cookie::Cookie;
+    use std::io::Read;
+    use std::os::fd::AsRawFd;
+    use std::os::unix::net::UnixStream;
+
+    #[test]
+    fn passthrough_keeps_sendfile() {
+        let path = std::env::temp_dir().join(format!("filters-{}.txt", uuid::Uuid::new_v4()));
+        std::fs::write(&path, "file body").unwrap();
+        let file = FileResponse::new(path.to_str().unwrap(), &Cookie::new("id", "1")).unwrap();
+        let mut response = close_connection(Box::new(file));
+        let (mut client, server) = UnixStream::pair().unwrap();
+
+        // The head goes through the buffer, rewritten by the filter
+        assert_eq!(response.sendfile_len(), 0);
+        response.fill_if_needed().unwrap();
+        let head = String::from_utf8_lossy(response.peek()).to_ascii_lowercase();
+        assert!(head.contains("connection: close"));
+        let n = response.peek().len();
+        response.next(n);
+
+        // Then the body goes straight from the file
+        assert_eq!(response.sendfile_len(), 9);
+        assert_eq!(response.sendfile(server.as_raw_fd(), 64).unwrap(), 9);
+        assert!(response.is_finished());
+        let mut body = [0u8; 9];
+        client.read_exact(&mut body).unwrap();
+        assert_eq!(&body, b"file body");
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -1,33 +1,52 @@
+use crate::admin;
+use crate::compress_cache;
+use crate::dav;
+use crate::dirstat;
 use crate::error::get_error_page_path;
+use crate::filters::offered_codings;
 use crate::models::{FileResponse, HttpResponseCommon, SimpleResponse};
+use crate::read::{find_matching_route, resolve_file_path};
+use crate::storage::{ContentSource, content_source_for};
 use crate::utils::cookie::{ Cookie};
+use crate::utils::json;
+use crate::utils::range::parse_content_range;
 use crate::{
-    config::ServerConfig,
+    config::{Route, ServerConfig},
     request::HttpRequest,
-    response::{HttpResponseBuilder, extract_boundary, extract_multipart_files, write_file},
+    response::{HttpResponseBuilder, cache_control_for, MultipartUpload, extract_boundary, move_file, store_multipart, write_file},
 };
-use std::fs;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::UNIX_EPOCH;
 use uuid::Uuid;
 
 pub fn handle_get(
     request_path: &str,
     server: &ServerConfig,
+    matched_route: &Route,
     request: &HttpRequest,
     cookie: &Cookie,
 ) -> Box<dyn HttpResponseCommon> {
     let path = request.path.trim_matches('/');
 
-    if let Some(route) = server
-        .routes
-        .iter()
-        .find(|r| r.path.trim_matches('/') == path)
+    if let Some(route) = server.routes.iter().find(|r| {
+        let route_path = r.path.trim_matches('/');
+        route_path == path || (r.case_insensitive == Some(true) && route_path.eq_ignore_ascii_case(path))
+    })
     {
+        // Keep the root a split already picked for this request
+        let route = if route.path == matched_route.path { matched_route } else { route };
+        let source = content_source_for(server, route);
+
         if route.list_directory == Some(true) {
             let content = HttpResponseBuilder::serve_directory_listing(
+                source.as_ref(),
                 &server.root,
                 &route.root,
                 &route.path,
-                &cookie,
+                cookie,
+                request.headers.get("range"),
             );
             return Box::new(SimpleResponse::new(content));
         }
@@ -36,60 +55,351 @@ pub fn handle_get(
             let (_key, _value) = cookie.to_header_pair();
             let full_path = format!("{}/{}/{}", server.root, route.root, default_file);
 
-            return match FileResponse::new(&full_path , cookie) {
+            return match serve_file(source.as_ref(), &full_path, server, route, request, cookie) {
                 Ok(fr) => Box::new(fr),
-                Err(_) => {
-                    let not_found = get_error_page_path(server, 404);
-                    match FileResponse::new(&not_found , cookie) {
-                        Ok(fr) => Box::new(fr),
-                        Err(_) => Box::new(SimpleResponse::new(
-                            HttpResponseBuilder::not_found().build(),
-                        )),
-                    }
-                }
+                Err(_) => not_found(source.as_ref(), server, route, request, cookie),
             };
         }
     }
 
     // Fallback: try to serve requested file
     let (_key, _value) = cookie.to_header_pair();
-    match FileResponse::new(&request_path , cookie) {
+    let source = content_source_for(server, matched_route);
+    match serve_file(source.as_ref(), request_path, server, matched_route, request, cookie) {
         Ok(fr) => Box::new(fr),
-        Err(_) => {
-            let not_found = get_error_page_path(server, 404);
-            match FileResponse::new(&not_found , cookie) {
-                Ok(fr) => Box::new(fr),
-                Err(_) => Box::new(SimpleResponse::new(
-                    HttpResponseBuilder::not_found().cookie(cookie).build(),
-                )),
+        Err(_) => not_found(source.as_ref(), server, matched_route, request, cookie),
+    }
+}
+
+/// Answer for a missing file: the route's `not_found` file with a 200, so a
+/// single-page app can route the path itself, or the server's 404 page
+fn not_found(
+    source: &dyn ContentSource,
+    server: &ServerConfig,
+    route: &Route,
+    request: &HttpRequest,
+    cookie: &Cookie,
+) -> Box<dyn HttpResponseCommon> {
+    if let Some(fallback) = &route.not_found {
+        let full_path = format!("{}/{}/{}", server.root, route.root, fallback);
+        match serve_file(source, &full_path, server, route, request, cookie) {
+            Ok(fr) => {
+                info!("GET: {} not found, serving {}", request.path, fallback);
+                return Box::new(fr);
             }
+            Err(e) => warn!("not_found file {} cannot be served: {}", full_path, e),
+        }
+    }
+    let not_found = get_error_page_path(server, 404);
+    Box::new(SimpleResponse::new(HttpResponseBuilder::serve_error_page(
+        &not_found,
+        404,
+        "Not Found",
+        cookie,
+    )))
+}
+
+fn serve_file(
+    source: &dyn ContentSource,
+    file_path: &str,
+    server: &ServerConfig,
+    route: &Route,
+    request: &HttpRequest,
+    cookie: &Cookie,
+) -> std::io::Result<FileResponse> {
+    let precompressed = route.precompressed == Some(true);
+    // Copies compressed for earlier requests, for files on disk only
+    let cached = match &server.compress_cache_dir {
+        Some(dir) if route.bundle.is_none() && !request.cache_bypass => {
+            compress_cache::fresh_entries(dir, file_path, &offered_codings(server, route))
         }
+        _ => Vec::new(),
+    };
+    FileResponse::for_request(source, file_path, &request.headers, precompressed, cached, cookie)
+        .map(|response| response.cache_control(cache_control_for(route, file_path)))
+}
+
+/// Lost-update protection for clients that only track timestamps: a write
+/// carrying `If-Unmodified-Since` gets a 412 when the file changed after that
+/// date. Missing files and unparseable dates leave the request unconditional.
+pub fn check_unmodified_since(file_path: &str, request: &HttpRequest, cookie: &Cookie) -> Option<Vec<u8>> {
+    let since = httpdate::parse_http_date(request.headers.get("if-unmodified-since")?).ok()?;
+    let metadata = fs::metadata(file_path).ok().filter(|m| m.is_file())?;
+    let modified = metadata.modified().ok()?;
+
+    // HTTP dates have one-second resolution
+    let modified_secs = modified.duration_since(UNIX_EPOCH).ok()?.as_secs();
+    let since_secs = since.duration_since(UNIX_EPOCH).ok()?.as_secs();
+    if modified_secs <= since_secs {
+        return None;
     }
+
+    info!(
+        "{}: {} modified since {}, refusing",
+        request.method.to_str(),
+        file_path,
+        httpdate::fmt_http_date(since)
+    );
+    Some(
+        HttpResponseBuilder::precondition_failed()
+            .header("Last-Modified", &httpdate::fmt_http_date(modified))
+            .cookie(cookie)
+            .build(),
+    )
 }
 
 pub fn handle_delete(file_path: &str, error_page_path: &str, cookie: &Cookie) -> Vec<u8> {
     match fs::remove_file(file_path) {
         Ok(_) => {
-            println!("DELETE: Successfully deleted {}", file_path);
+            info!("DELETE: Successfully deleted {}", file_path);
             HttpResponseBuilder::no_content().build()
         }
         Err(_) => {
-            println!("DELETE: File not found {}", file_path);
+            info!("DELETE: File not found {}", file_path);
             HttpResponseBuilder::serve_error_page(error_page_path, 404, "Not Found", cookie)
         }
     }
 }
 
-pub fn handle_post(file_path: &str, request: &HttpRequest, cookie: &Cookie) -> Vec<u8> {
-    let body = match &request.body {
-        Some(b) => b,
-        None => {
-            return HttpResponseBuilder::bad_request()
-                .body(b"Empty body".to_vec())
-                .cookie(cookie)
-                .build();
+/// Media type of PATCH bodies, the raw bytes to write
+const PATCH_TYPE: &str = "application/octet-stream";
+
+/// Update part of an existing file instead of uploading it again. A body
+/// sent with `Content-Range: bytes a-b/*` overwrites those bytes, growing the
+/// file if the range runs past its end; a complete length in place of `*`
+/// also truncates the file to that size. A body without `Content-Range` is
+/// appended. Ranges starting past the end get a 416, they would leave a hole.
+pub fn handle_patch(file_path: &str, error_page_path: &str, request: &HttpRequest, cookie: &Cookie) -> Vec<u8> {
+    let media_type = request
+        .headers
+        .get("content-type")
+        .map(|v| v.split(';').next().unwrap_or("").trim().to_ascii_lowercase());
+    if media_type.as_deref() != Some(PATCH_TYPE) {
+        return HttpResponseBuilder::unsupported_media_type()
+            .header("Accept-Patch", PATCH_TYPE)
+            .body(b"PATCH takes an application/octet-stream body".to_vec())
+            .cookie(cookie)
+            .build();
+    }
+
+    let len = match fs::metadata(file_path) {
+        Ok(metadata) if metadata.is_file() => metadata.len(),
+        _ => {
+            info!("PATCH: File not found {}", file_path);
+            return HttpResponseBuilder::serve_error_page(error_page_path, 404, "Not Found", cookie);
         }
     };
+    let body_len = match (&request.body, &request.body_file) {
+        (_, Some(spooled)) => spooled.len() as u64,
+        (Some(body), None) => body.len() as u64,
+        (None, None) => 0,
+    };
+
+    let range = match request.headers.get("content-range") {
+        None => None,
+        Some(value) => match parse_content_range(value) {
+            Some((start, end, _)) if end - start + 1 != body_len => {
+                return HttpResponseBuilder::bad_request()
+                    .body(b"Content-Range does not match the body length".to_vec())
+                    .cookie(cookie)
+                    .build();
+            }
+            Some((start, _, _)) if start > len => {
+                return HttpResponseBuilder::new(416, "Range Not Satisfiable")
+                    .header("Content-Range", &format!("bytes */{}", len))
+                    .cookie(cookie)
+                    .build();
+            }
+            Some((_, end, complete)) if complete.is_some_and(|total| total > len.max(end + 1)) => {
+                return HttpResponseBuilder::bad_request()
+                    .body(format!("Complete length would leave a hole after byte {}", len.max(end + 1)).into_bytes())
+                    .cookie(cookie)
+                    .build();
+            }
+            Some((start, _, complete)) => Some((start, complete)),
+            None => {
+                return HttpResponseBuilder::bad_request()
+                    .body(b"Malformed Content-Range".to_vec())
+                    .cookie(cookie)
+                    .build();
+            }
+        },
+    };
+
+    let result = OpenOptions::new()
+        .write(true)
+        .append(range.is_none())
+        .open(file_path)
+        .and_then(|mut file| {
+            if let Some((start, _)) = range {
+                file.seek(SeekFrom::Start(start))?;
+            }
+            match (&request.body, &request.body_file) {
+                (_, Some(spooled)) => {
+                    io::copy(&mut spooled.open()?, &mut file)?;
+                }
+                (Some(body), None) => file.write_all(body)?,
+                (None, None) => {}
+            }
+            if let Some((_, Some(total))) = range {
+                file.set_len(total)?;
+            }
+            file.metadata()
+        });
+
+    match result {
+        Ok(metadata) => {
+            info!("PATCH: Wrote {} bytes to {}, now {} bytes", body_len, file_path, metadata.len());
+            let mut builder = HttpResponseBuilder::no_content().cookie(cookie);
+            if let Ok(modified) = metadata.modified() {
+                builder = builder.header("Last-Modified", &httpdate::fmt_http_date(modified));
+            }
+            builder.build()
+        }
+        Err(e) => HttpResponseBuilder::internal_error()
+            .body(e.to_string().into_bytes())
+            .cookie(cookie)
+            .build(),
+    }
+}
+
+/// Top-level media types stored as-is by `handle_post`; multipart/form-data
+/// is the only other type accepted, anything else gets a 415
+const RAW_UPLOAD_TYPES: [&str; 6] = ["application/", "image/", "audio/", "video/", "font/", "text/"];
+
+/// Methods with a handler of their own; the WebDAV ones also count on
+/// `webdav` routes
+const SERVED_METHODS: [&str; 5] = ["GET", "POST", "DELETE", "PATCH", "OPTIONS"];
+
+/// Whether `route` can answer `method` if allowed to: the server handles it
+/// itself, or the route passes its requests to a script or upstream
+pub fn route_implements(route: &Route, method: &str) -> bool {
+    SERVED_METHODS.contains(&method)
+        || (route.webdav == Some(true) && dav::METHODS.contains(&method))
+        || (route.methods.iter().any(|m| m == method) && (!route.cgi.is_empty() || route.proxy_pass.is_some()))
+}
+
+/// The 501 for a method the server has no way of answering, as opposed to
+/// the 405 for one a route doesn't allow
+pub fn handle_not_implemented(server: &ServerConfig, method: &str, cookie: &Cookie) -> Vec<u8> {
+    info!("{}: not implemented, sending 501", method);
+    let page = get_error_page_path(server, 501);
+    HttpResponseBuilder::serve_error_page(&page, 501, "Not Implemented", cookie)
+}
+
+/// Methods `route` accepts right now, read-only mode taken into account
+pub fn route_methods(route: &Route) -> Vec<String> {
+    if admin::read_only() || route.read_only == Some(true) {
+        admin::read_only_methods(route)
+    } else {
+        route.methods.clone()
+    }
+}
+
+/// Answer OPTIONS with 204 and the methods the route allows. Upload routes also
+/// advertise what `handle_post` accepts, so clients can check a file before
+/// sending it: `Accept-Post` lists the media types and `X-Upload-Max-Size`
+/// the largest body in bytes. Routes taking PATCH name its body type in
+/// `Accept-Patch`, and WebDAV routes announce class 1 compliance with `DAV`.
+pub fn handle_options(
+    server: &ServerConfig,
+    route: &Route,
+    allowed_methods: &[String],
+    cookie: &Cookie,
+) -> Vec<u8> {
+    let mut methods = allowed_methods.to_vec();
+    if !methods.iter().any(|m| m == "OPTIONS") {
+        methods.push("OPTIONS".to_string());
+    }
+
+    let mut builder = HttpResponseBuilder::no_content()
+        .header("Allow", &methods.join(", "))
+        .cookie(cookie);
+
+    let accepts_uploads = methods.iter().any(|m| m == "POST")
+        && route.cgi.is_empty()
+        && route.proxy_pass.is_none();
+    if accepts_uploads {
+        let mut types: Vec<String> = RAW_UPLOAD_TYPES.iter().map(|t| format!("{}*", t)).collect();
+        types.push("multipart/form-data".to_string());
+        builder = builder
+            .header("Accept-Post", &types.join(", "))
+            .header("X-Upload-Max-Size", &server.client_max_body_size.to_string());
+    }
+    let accepts_patches = methods.iter().any(|m| m == "PATCH")
+        && route.cgi.is_empty()
+        && route.proxy_pass.is_none();
+    if accepts_patches {
+        builder = builder.header("Accept-Patch", PATCH_TYPE);
+    }
+    if route.webdav == Some(true) {
+        // Explorer only treats the folder as a drive when told to use DAV
+        builder = builder.header("DAV", "1").header("MS-Author-Via", "DAV");
+    }
+
+    builder.build()
+}
+
+/// Answer `OPTIONS *` with 204 and every method some route of the server allows
+pub fn handle_server_options(server: &ServerConfig, cookie: &Cookie) -> Vec<u8> {
+    let mut methods: Vec<String> = Vec::new();
+    for method in server.routes.iter().flat_map(route_methods) {
+        if !methods.contains(&method) {
+            methods.push(method);
+        }
+    }
+    if !methods.iter().any(|m| m == "OPTIONS") {
+        methods.push("OPTIONS".to_string());
+    }
+
+    HttpResponseBuilder::no_content()
+        .header("Allow", &methods.join(", "))
+        .cookie(cookie)
+        .build()
+}
+
+/// `PURGE <path>` from an admin client: drop what the caches hold for the
+/// file or directory behind the path, its compressed copies and its `?stat=1`
+/// summary, so the next request computes them again. 404 when nothing was cached.
+pub fn handle_purge(server: &ServerConfig, request: &HttpRequest, cookie: &Cookie) -> Vec<u8> {
+    let file_path = find_matching_route(server, &request.path)
+        .and_then(|route| resolve_file_path(server, route, &request.path));
+    let Some(file_path) = file_path else {
+        let error_path = get_error_page_path(server, 404);
+        return HttpResponseBuilder::serve_error_page(&error_path, 404, "Not Found", cookie);
+    };
+
+    let copies = server
+        .compress_cache_dir
+        .as_deref()
+        .map_or(0, |dir| compress_cache::purge(dir, &file_path));
+    let summary = dirstat::purge(Path::new(&file_path));
+    info!("PURGE {}: {} compressed copies, summary {}", request.path, copies, summary);
+
+    let body = format!(
+        "{{\"path\": \"{}\", \"compressed_copies\": {}, \"stat_summary\": {}}}\n",
+        json::escape(&request.path),
+        copies,
+        summary
+    );
+    let (status, text) = match copies > 0 || summary {
+        true => (200, "OK"),
+        false => (404, "Not Found"),
+    };
+    HttpResponseBuilder::new(status, text)
+        .header("Content-Type", "application/json")
+        .body(body.into_bytes())
+        .cookie(cookie)
+        .build()
+}
+
+pub fn handle_post(file_path: &str, request: &HttpRequest, cookie: &Cookie) -> Vec<u8> {
+    if request.body.is_none() && request.body_file.is_none() {
+        return HttpResponseBuilder::bad_request()
+            .body(b"Empty body".to_vec())
+            .cookie(cookie)
+            .build();
+    }
 
     let content_type = match request.headers.get("content-type") {
         Some(v) => v,
@@ -100,19 +410,13 @@ pub fn handle_post(file_path: &str, request: &HttpRequest, cookie: &Cookie) -> V
         }
     };
 
-    if content_type.starts_with("application/")
-        || content_type.starts_with("image/")
-        || content_type.starts_with("audio/")
-        || content_type.starts_with("video/")
-        || content_type.starts_with("font/")
-        || content_type.starts_with("text/")
-    {
+    if RAW_UPLOAD_TYPES.iter().any(|t| content_type.starts_with(t)) {
         // get file extension from content type
         let b = content_type.split('/').nth(1).unwrap_or("dat");
         // For direct uploads, extract filename from the request path
 
         let filename: String = {
-            let last_segment = request.path.split('/').last().unwrap_or("");
+            let last_segment = request.path.split('/').next_back().unwrap_or("");
 
             if !last_segment.is_empty() {
                 "".to_string()
@@ -122,7 +426,10 @@ pub fn handle_post(file_path: &str, request: &HttpRequest, cookie: &Cookie) -> V
         };
         let save_path = format!("{}{}", file_path, filename);
 
-        return write_file(&save_path, body, cookie);
+        if let Some(spooled) = &request.body_file {
+            return move_file(&save_path, spooled, cookie);
+        }
+        return write_file(&save_path, request.body.as_deref().unwrap_or_default(), cookie);
     }
 
     if content_type.starts_with("multipart/form-data") {
@@ -135,49 +442,112 @@ pub fn handle_post(file_path: &str, request: &HttpRequest, cookie: &Cookie) -> V
             }
         };
 
-        println!("Extracted boundary: {}", boundary);
+        debug!("Extracted boundary: {}", boundary);
 
-        let files = extract_multipart_files(body, &boundary);
+        // Parts stream from the body straight to their destination
+        let dir = Path::new(file_path);
+        let stored = match (&request.body, &request.body_file) {
+            (Some(body), _) => store_multipart(body.as_slice(), &boundary, dir),
+            (None, Some(spooled)) => spooled.open().and_then(|file| store_multipart(file, &boundary, dir)),
+            (None, None) => Ok(MultipartUpload::default()),
+        };
+        let upload = match stored {
+            Ok(upload) => upload,
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                return HttpResponseBuilder::bad_request()
+                    .body(e.to_string().into_bytes())
+                    .build();
+            }
+            Err(e) if e.kind() == io::ErrorKind::FileTooLarge => {
+                return HttpResponseBuilder::new(413, "Payload Too Large")
+                    .body(e.to_string().into_bytes())
+                    .build();
+            }
+            Err(e) => {
+                return HttpResponseBuilder::internal_error()
+                    .body(e.to_string().into_bytes())
+                    .cookie(cookie)
+                    .build();
+            }
+        };
 
-        if files.is_empty() {
-            println!("No files extracted from multipart body");
+        if upload.files.is_empty() {
+            debug!("No files extracted from multipart body");
             return HttpResponseBuilder::bad_request()
                 .body(b"Invalid multipart body or no files found".to_vec())
                 .build();
         }
 
-        // Write each file with its extracted filename
-        let mut saved_files = Vec::new();
-        for (filename, file_bytes) in files.iter() {
-            // Combine the directory from file_path with the extracted filename
-            let save_path = if file_path.ends_with('/') {
-                format!("{}{}", file_path, filename)
-            } else {
-                format!("{}/{}", file_path, filename)
-            };
+        let wants_json = request
+            .headers
+            .get("accept")
+            .is_some_and(|v| v.contains("application/json"));
+        if wants_json {
+            return HttpResponseBuilder::created()
+                .header("Content-Type", "application/json")
+                .body(upload_manifest(&request.path, &upload).into_bytes())
+                .cookie(cookie)
+                .build();
+        }
 
-            let response = write_file(&save_path, file_bytes, cookie);
-            // Check if write failed
-            if response.starts_with(b"HTTP/1.1 500") || response.starts_with(b"HTTP/1.1 4") {
-                return response;
-            }
-            saved_files.push(filename.clone());
+        let names: Vec<&str> = upload.files.iter().map(|file| file.name.as_str()).collect();
+
+        // Metadata sent alongside the files (title, tags...)
+        let mut message = format!(
+            "Successfully uploaded {} file(s): {}",
+            names.len(),
+            names.join(", ")
+        );
+        if !upload.fields.is_empty() {
+            let fields: Vec<String> = upload
+                .fields
+                .iter()
+                .map(|(name, value)| format!("{}={}", name, String::from_utf8_lossy(value)))
+                .collect();
+            debug!("Upload form fields: {}", fields.join(", "));
+            message.push_str(&format!("\nFields: {}", fields.join(", ")));
         }
 
-        HttpResponseBuilder::created()
-            .body(
-                format!(
-                    "Successfully uploaded {} file(s): {}",
-                    saved_files.len(),
-                    saved_files.join(", ")
-                )
-                .into_bytes(),
-            )
-            .build()
+        HttpResponseBuilder::created().body(message.into_bytes()).build()
     } else {
-        println!("Unsupported Content-Type: {}", content_type);
+        info!("Unsupported Content-Type: {}", content_type);
         HttpResponseBuilder::unsupported_media_type()
             .body(b"Unsupported Content-Type".to_vec())
             .build()
     }
 }
+
+/// JSON description of a multipart upload, so a frontend can link to the
+/// stored files right away:
+///
+/// `{"files": [{"name": "a.png", "size": 1234, "sha256": "…", "url": "/uploads/a.png"}],
+///   "fields": [{"name": "title", "value": "Holidays"}]}`
+fn upload_manifest(request_path: &str, upload: &MultipartUpload) -> String {
+    let base = request_path.trim_end_matches('/');
+    let files: Vec<String> = upload
+        .files
+        .iter()
+        .map(|file| {
+            let url = format!("{}/{}", base, urlencoding::encode(&file.name));
+            format!(
+                "{{\"name\": \"{}\", \"size\": {}, \"sha256\": \"{}\", \"url\": \"{}\"}}",
+                json::escape(&file.name),
+                file.size,
+                file.sha256,
+                json::escape(&url)
+            )
+        })
+        .collect();
+    let fields: Vec<String> = upload
+        .fields
+        .iter()
+        .map(|(name, value)| {
+            format!(
+                "{{\"name\": \"{}\", \"value\": \"{}\"}}",
+                json::escape(name),
+                json::escape(&String::from_utf8_lossy(value))
+            )
+        })
+        .collect();
+    format!("{{\"files\": [{}], \"fields\": [{}]}}\n", files.join(", "), fields.join(", "))
+}
@@ -0,0 +1,53 @@
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// How much the running server reports, chosen with `--log-level`. Errors
+/// and warnings go to stderr, the rest to stdout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+pub enum Level {
+    Error, // Something failed: a worker, a listener, a script that didn't run
+    Warn,  // Input ignored or fixed up, a limit hit
+    Info,  // Lifecycle, refusals and changes made to files
+    Debug, // Every step of every request
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+pub fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn enabled(level: Level) -> bool {
+    level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
+
+macro_rules! error {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::Level::Error) {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::Level::Warn) {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::Level::Info) {
+            println!($($arg)*);
+        }
+    };
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::Level::Debug) {
+            println!($($arg)*);
+        }
+    };
+}
@@ -1,9 +1,35 @@
+#[macro_use]
+pub mod log;
+pub mod admin;
+pub mod auth;
+pub mod batch;
 pub mod cgi;
+pub mod check;
+pub mod client;
+pub mod compress_cache;
 pub mod config;
+pub mod crash;
+pub mod daemon;
+pub mod dav;
+pub mod dirstat;
 pub mod error;
+pub mod fastcgi;
+pub mod filters;
+pub mod metrics;
+pub mod pack;
+pub mod pacing;
+pub mod proxy_protocol;
 pub mod request;
+pub mod route_test;
 pub mod router;
+pub mod scheduler;
 pub mod server;
+pub mod shutdown;
+pub mod stats;
+pub mod storage;
+pub mod tls;
+pub mod transport;
+pub mod upstream;
 pub mod utils;
 pub(crate) mod response;
 pub mod handler;
@@ -11,31 +37,135 @@ pub mod models;
 pub mod read;
 pub mod write;
 
+use clap::{Parser, Subcommand};
+
+use log::Level;
 use server::Server;
 
+const CONFIG_PATH: &str = "config.yaml";
+
+#[derive(Parser)]
+#[command(version, about = "HTTP/1.1 server configured by a YAML file")]
+struct Cli {
+    /// Configuration file to load
+    #[arg(short, long, default_value = CONFIG_PATH)]
+    config: String,
+    /// Bind every server to this address instead of its configured host
+    #[arg(long)]
+    host: Option<String>,
+    /// Listen on this port only, instead of each server's configured ports
+    #[arg(long)]
+    port: Option<u16>,
+    /// Least severe messages to print
+    #[arg(long, value_enum, default_value_t = Level::Info, global = true)]
+    log_level: Level,
+    /// Print the effective configuration and exit
+    #[arg(long, global = true)]
+    dump_config: bool,
+    /// Check the configuration and bind every listener, then exit
+    #[arg(short, long, global = true)]
+    test: bool,
+    /// Run in the background, output going to log_file and error_log
+    #[arg(long, global = true)]
+    daemon: bool,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Store a static site in one archive
+    Pack {
+        dir: String,
+        #[arg(short, long)]
+        output: String,
+    },
+    /// Show how the configuration would route a request
+    RouteTest {
+        method: String,
+        path: String,
+        /// Host header of the request
+        #[arg(long)]
+        host: Option<String>,
+        /// Port the request arrives on
+        #[arg(long)]
+        port: Option<u16>,
+    },
+    /// Serve an archive made by `pack`, with no configuration file
+    ServeBundle {
+        bundle: String,
+        #[arg(long, default_value = pack::DEFAULT_LISTEN)]
+        listen: String,
+    },
+}
+
 fn main() {
-    println!("Starting server...");
+    let cli = Cli::parse();
+    log::set_level(cli.log_level);
+    if let Some(Command::Pack { dir, output }) = &cli.command {
+        std::process::exit(pack::run(dir, output));
+    }
+    let route_test = matches!(cli.command, Some(Command::RouteTest { .. }));
 
-    let config = match config::load_config("config.yaml") {
-        Ok(cfg) => {
-            println!("Configuration loaded successfully!");
-            cfg
-        }
+    if !cli.dump_config && !route_test && !cli.test {
+        info!("Starting server...");
+    }
+
+    let (loaded, source) = match &cli.command {
+        Some(Command::ServeBundle { bundle, listen }) => (pack::bundle_config(bundle, listen), bundle.as_str()),
+        _ => (config::load_config(&cli.config), cli.config.as_str()),
+    };
+    let mut config = match loaded {
+        Ok(cfg) => cfg,
         Err(e) => {
-            eprintln!("Failed to load configuration: {}", e);
-            return;
+            eprintln!("Failed to load configuration from {}: {}", source, e);
+            std::process::exit(1);
         }
     };
+    config.override_listen(cli.host.as_deref(), cli.port);
+
+    if cli.dump_config {
+        print!("{}", config.dump());
+        return;
+    }
+    if let Some(Command::RouteTest { method, path, host, port }) = &cli.command {
+        std::process::exit(route_test::run(&config, method, path, host.as_deref(), *port));
+    }
+    if cli.test {
+        std::process::exit(check::run_test(&config, source));
+    }
+    info!("Configuration loaded successfully!");
+
+    if let Err(problems) = check::self_check(&config) {
+        eprintln!("Startup self-check failed:");
+        for problem in &problems {
+            eprintln!("  - {}", problem);
+        }
+        std::process::exit(1);
+    }
+
+    if cli.daemon && let Err(e) = daemon::daemonize(&config) {
+        eprintln!("Cannot run in the background: {}", e);
+        std::process::exit(1);
+    }
+    let pidfile = config.pidfile.clone();
+    if let Err(e) = daemon::write_pidfile(pidfile.as_deref()) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
 
     let mut server = match Server::new() {
         Ok(srv) => srv,
         Err(e) => {
             eprintln!("Failed to initialize server: {}", e);
+            daemon::remove_pidfile(pidfile.as_deref());
             return;
         }
     };
 
     if let Err(e) = server.run(config) {
         eprintln!("Server error: {}", e);
+        crash::report(&format!("server stopped: {}", e));
     }
+    daemon::remove_pidfile(pidfile.as_deref());
 }
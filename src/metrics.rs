@@ -0,0 +1,248 @@
+use std::fmt::Write;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::request::LineRejection;
+use crate::server::CloseReason;
+
+/// Upper bounds of the latency buckets, in microseconds
+const BUCKETS_US: [u64; 10] = [
+    500, 1_000, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000, 500_000, 1_000_000,
+];
+
+/// Cumulative latency histogram in the Prometheus layout, shared by workers
+pub struct Histogram {
+    buckets: [AtomicU64; BUCKETS_US.len()],
+    count: AtomicU64,
+    sum_us: AtomicU64,
+}
+
+impl Histogram {
+    const fn new() -> Self {
+        Self {
+            buckets: [const { AtomicU64::new(0) }; BUCKETS_US.len()],
+            count: AtomicU64::new(0),
+            sum_us: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, elapsed: Duration) {
+        let us = elapsed.as_micros().min(u64::MAX as u128) as u64;
+        if let Some(i) = BUCKETS_US.iter().position(|&bound| us <= bound) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_us.fetch_add(us, Ordering::Relaxed);
+    }
+
+    fn render(&self, name: &str, help: &str, out: &mut String) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        let mut cumulative = 0;
+        for (bound, bucket) in BUCKETS_US.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let le = *bound as f64 / 1_000_000.0;
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, le, cumulative);
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+        let sum = self.sum_us.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let _ = writeln!(out, "{}_sum {}", name, sum);
+        let _ = writeln!(out, "{}_count {}", name, count);
+    }
+}
+
+/// Busy time of one event loop iteration, poll wait excluded
+pub static LOOP_ITERATION: Histogram = Histogram::new();
+/// Time spent in one handler invocation for a connection
+pub static HANDLER: Histogram = Histogram::new();
+/// Iterations that went over `loop_stall_threshold`
+pub static LOOP_STALLS: AtomicU64 = AtomicU64::new(0);
+/// CGI scripts killed for writing more than `cgi_max_output`
+pub static CGI_OUTPUT_OVERFLOWS: AtomicU64 = AtomicU64::new(0);
+/// TLS handshakes that went through the full key exchange
+pub static TLS_HANDSHAKES_FULL: AtomicU64 = AtomicU64::new(0);
+/// TLS handshakes resumed from a session ID or ticket
+pub static TLS_HANDSHAKES_RESUMED: AtomicU64 = AtomicU64::new(0);
+/// Times the session ticket key was replaced
+pub static TLS_TICKET_ROTATIONS: AtomicU64 = AtomicU64::new(0);
+
+/// Connection teardowns, in the order of `CloseReason::ALL`
+static CLOSES: [AtomicU64; CloseReason::ALL.len()] = [const { AtomicU64::new(0) }; CloseReason::ALL.len()];
+
+pub fn record_close(reason: CloseReason) {
+    if let Some(index) = CloseReason::ALL.iter().position(|r| *r == reason) {
+        CLOSES[index].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Request lines dropped unparsed, in the order of `LineRejection::ALL`
+static LINE_REJECTIONS: [AtomicU64; LineRejection::ALL.len()] =
+    [const { AtomicU64::new(0) }; LineRejection::ALL.len()];
+
+pub fn record_line_rejection(reason: LineRejection) {
+    if let Some(index) = LineRejection::ALL.iter().position(|r| *r == reason) {
+        LINE_REJECTIONS[index].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Counters kept by one worker, labelled by its index so imbalance shows
+pub struct WorkerStats {
+    pub accepted: AtomicU64,    // Connections this worker accepted
+    pub connections: AtomicU64, // Connections open at the end of the last iteration
+    pub busy_us: AtomicU64,     // Time spent working, poll wait excluded
+    pub cpu: AtomicI64,         // Core the worker is pinned to, -1 when not pinned
+}
+
+static WORKERS: OnceLock<Vec<WorkerStats>> = OnceLock::new();
+
+/// Allocate the per-worker counters, before the workers start
+pub fn init_workers(count: usize) {
+    let _ = WORKERS.set(
+        (0..count)
+            .map(|_| WorkerStats {
+                accepted: AtomicU64::new(0),
+                connections: AtomicU64::new(0),
+                busy_us: AtomicU64::new(0),
+                cpu: AtomicI64::new(-1),
+            })
+            .collect(),
+    );
+}
+
+pub fn worker(index: usize) -> Option<&'static WorkerStats> {
+    WORKERS.get()?.get(index)
+}
+
+/// Connections open across every worker, as of their last iteration
+pub fn open_connections() -> u64 {
+    WORKERS
+        .get()
+        .map_or(0, |workers| workers.iter().map(|w| w.connections.load(Ordering::Relaxed)).sum())
+}
+
+fn render_workers(out: &mut String) {
+    let Some(workers) = WORKERS.get() else {
+        return;
+    };
+    let mut series = |name: &str, kind: &str, help: &str, value: fn(&WorkerStats) -> String| {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+        for (index, stats) in workers.iter().enumerate() {
+            let _ = writeln!(out, "{}{{worker=\"{}\"}} {}", name, index, value(stats));
+        }
+    };
+    series(
+        "localserver_worker_accepted_total",
+        "counter",
+        "Connections accepted by each worker",
+        |w| w.accepted.load(Ordering::Relaxed).to_string(),
+    );
+    series(
+        "localserver_worker_connections",
+        "gauge",
+        "Connections currently handled by each worker",
+        |w| w.connections.load(Ordering::Relaxed).to_string(),
+    );
+    series(
+        "localserver_worker_busy_seconds_total",
+        "counter",
+        "Time each worker spent working, poll wait excluded",
+        |w| (w.busy_us.load(Ordering::Relaxed) as f64 / 1_000_000.0).to_string(),
+    );
+    series(
+        "localserver_worker_cpu",
+        "gauge",
+        "Core each worker is pinned to, -1 when not pinned",
+        |w| w.cpu.load(Ordering::Relaxed).to_string(),
+    );
+}
+
+/// All metrics in the Prometheus text exposition format
+pub fn render() -> String {
+    let mut out = String::new();
+    LOOP_ITERATION.render(
+        "localserver_loop_iteration_seconds",
+        "Time spent working in one event loop iteration",
+        &mut out,
+    );
+    HANDLER.render(
+        "localserver_handler_seconds",
+        "Time spent in one connection handler invocation",
+        &mut out,
+    );
+    let _ = writeln!(
+        out,
+        "# HELP localserver_loop_stalls_total Event loop iterations over the stall threshold"
+    );
+    let _ = writeln!(out, "# TYPE localserver_loop_stalls_total counter");
+    let _ = writeln!(
+        out,
+        "localserver_loop_stalls_total {}",
+        LOOP_STALLS.load(Ordering::Relaxed)
+    );
+    let _ = writeln!(
+        out,
+        "# HELP localserver_cgi_output_overflows_total CGI scripts killed for writing more than cgi_max_output"
+    );
+    let _ = writeln!(out, "# TYPE localserver_cgi_output_overflows_total counter");
+    let _ = writeln!(
+        out,
+        "localserver_cgi_output_overflows_total {}",
+        CGI_OUTPUT_OVERFLOWS.load(Ordering::Relaxed)
+    );
+    let _ = writeln!(
+        out,
+        "# HELP localserver_tls_handshakes_total Completed TLS handshakes, full or resumed"
+    );
+    let _ = writeln!(out, "# TYPE localserver_tls_handshakes_total counter");
+    let _ = writeln!(
+        out,
+        "localserver_tls_handshakes_total{{kind=\"full\"}} {}",
+        TLS_HANDSHAKES_FULL.load(Ordering::Relaxed)
+    );
+    let _ = writeln!(
+        out,
+        "localserver_tls_handshakes_total{{kind=\"resumed\"}} {}",
+        TLS_HANDSHAKES_RESUMED.load(Ordering::Relaxed)
+    );
+    let _ = writeln!(
+        out,
+        "# HELP localserver_tls_ticket_rotations_total Times the session ticket key was replaced"
+    );
+    let _ = writeln!(out, "# TYPE localserver_tls_ticket_rotations_total counter");
+    let _ = writeln!(
+        out,
+        "localserver_tls_ticket_rotations_total {}",
+        TLS_TICKET_ROTATIONS.load(Ordering::Relaxed)
+    );
+    let _ = writeln!(
+        out,
+        "# HELP localserver_connections_closed_total Connections closed, by reason"
+    );
+    let _ = writeln!(out, "# TYPE localserver_connections_closed_total counter");
+    for (reason, count) in CloseReason::ALL.iter().zip(&CLOSES) {
+        let _ = writeln!(
+            out,
+            "localserver_connections_closed_total{{reason=\"{}\"}} {}",
+            reason.as_str(),
+            count.load(Ordering::Relaxed)
+        );
+    }
+    let _ = writeln!(
+        out,
+        "# HELP localserver_request_lines_rejected_total Connections closed on a request line that isn't HTTP, by reason"
+    );
+    let _ = writeln!(out, "# TYPE localserver_request_lines_rejected_total counter");
+    for (reason, count) in LineRejection::ALL.iter().zip(&LINE_REJECTIONS) {
+        let _ = writeln!(
+            out,
+            "localserver_request_lines_rejected_total{{reason=\"{}\"}} {}",
+            reason.as_str(),
+            count.load(Ordering::Relaxed)
+        );
+    }
+    render_workers(&mut out);
+    out
+}
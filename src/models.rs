@@ -1,21 +1,76 @@
-use std::{fs::File, io::{self, BufReader, Read}};
+use std::fs::File;
+use std::io::{self, Read, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use crate::{response::detect_content_type, utils::cookie::Cookie};
+use mio::{Registry, Token};
+
+use crate::{
+    filters::negotiate,
+    response::{cache_headers, content_disposition, detect_content_type, status_code},
+    storage::{ContentMeta, ContentReader, ContentSource, LocalFs},
+    utils::{
+        HttpHeaders,
+        buffer::buffer_size_for,
+        cookie::Cookie,
+        range::{ByteRange, parse_range},
+        sendfile,
+    },
+};
 pub trait HttpResponseCommon {
     fn peek(&self) -> &[u8];
     fn next(&mut self, n: usize);
     fn is_finished(&self) -> bool;
     fn fill_if_needed(&mut self) -> io::Result<()>;
+    /// Status code of the response; None while a producer that answers
+    /// later (CGI, FastCGI, a backend) has not sent its head yet
+    fn status(&self) -> Option<u16>;
+    /// Bytes held in memory by this response, for memory accounting
+    fn buffered_len(&self) -> usize {
+        0
+    }
+    /// Nothing to send until a producer queues more (long-lived streams)
+    fn is_waiting(&self) -> bool {
+        false
+    }
+    /// Ask the producer for new data; true when bytes were queued
+    fn poll_producer(&mut self) -> bool {
+        false
+    }
+    /// The body runs until the connection closes, no response can follow it
+    fn closes_connection(&self) -> bool {
+        false
+    }
+    /// Register the pipes the producer reads from, so their readiness wakes
+    /// the connection. Returns the tokens newly registered (none once done).
+    fn register_sources(&mut self, _registry: &Registry, _next_token: &mut usize) -> io::Result<Vec<Token>> {
+        Ok(Vec::new())
+    }
+    /// The file on disk sent whole and unencoded as the body, with its
+    /// modification time when opened, so filters can keep what they make of it
+    fn source_file(&self) -> Option<(&Path, SystemTime)> {
+        None
+    }
+    /// Body bytes that can go straight from a file to the socket, bypassing
+    /// `peek`/`next`; zero when there are none right now
+    fn sendfile_len(&self) -> u64 {
+        0
+    }
+    /// Send up to `max` of those bytes to the socket `fd` with `sendfile`
+    fn sendfile(&mut self, _fd: i32, _max: usize) -> io::Result<usize> {
+        Ok(0)
+    }
 }
 
 pub struct SimpleResponse {
     data: Vec<u8>,
     index: usize,
+    status: Option<u16>,
 }
 
 impl SimpleResponse {
     pub fn new(data: Vec<u8>) -> Self {
-        Self { data, index: 0 }
+        Self { status: status_code(&data), data, index: 0 }
     }
 }
 
@@ -34,54 +89,449 @@ impl HttpResponseCommon for SimpleResponse {
     fn fill_if_needed(&mut self) -> io::Result<()> {
         Ok(())
     } // no-op
+
+    fn status(&self) -> Option<u16> {
+        self.status
+    }
+
+    fn buffered_len(&self) -> usize {
+        self.data.capacity()
+    }
+}
+
+/// One Server-Sent Event; multi-line data is split into several `data:` lines
+#[derive(Debug, Clone, Default)]
+pub struct SseEvent {
+    pub event: Option<String>,
+    pub id: Option<String>,
+    pub data: Option<String>,
+    pub comment: Option<String>, // Ignored by clients, keeps proxies from timing out
+}
+
+impl SseEvent {
+    pub fn new(data: &str) -> Self {
+        Self {
+            data: Some(data.to_string()),
+            ..Self::default()
+        }
+    }
+
+    pub fn comment(text: &str) -> Self {
+        Self {
+            comment: Some(text.to_string()),
+            ..Self::default()
+        }
+    }
+
+    pub fn event(mut self, name: &str) -> Self {
+        self.event = Some(name.to_string());
+        self
+    }
+
+    pub fn id(mut self, id: &str) -> Self {
+        self.id = Some(id.to_string());
+        self
+    }
+
+    fn encode(&self, out: &mut String) {
+        if let Some(comment) = &self.comment {
+            for line in comment.lines() {
+                out.push_str(&format!(": {}\n", line));
+            }
+        }
+        if let Some(event) = &self.event {
+            out.push_str(&format!("event: {}\n", event));
+        }
+        if let Some(id) = &self.id {
+            out.push_str(&format!("id: {}\n", id));
+        }
+        if let Some(data) = &self.data {
+            for line in data.split('\n') {
+                out.push_str(&format!("data: {}\n", line));
+            }
+        }
+        out.push('\n');
+    }
+}
+
+/// Called by the event loop while the stream waits: new events (possibly
+/// none), or None once the stream is over
+pub type SseProducer = Box<dyn FnMut() -> Option<Vec<SseEvent>>>;
+
+/// Long-lived `text/event-stream` response, framed with chunked encoding.
+///
+/// Once queued bytes are written the connection parks (registered for
+/// READABLE only) until the producer returns events.
+pub struct SseResponse {
+    pending: Vec<u8>,
+    index: usize,
+    status: Option<u16>,
+    producer: SseProducer,
+    done: bool,
+}
+
+impl SseResponse {
+    /// `head` is the serialized status line and headers, see
+    /// `HttpResponseBuilder::build_chunked_head`
+    pub fn new(head: Vec<u8>, producer: SseProducer) -> Self {
+        Self {
+            status: status_code(&head),
+            pending: head,
+            index: 0,
+            producer,
+            done: false,
+        }
+    }
+}
+
+impl HttpResponseCommon for SseResponse {
+    fn peek(&self) -> &[u8] {
+        &self.pending[self.index..]
+    }
+
+    fn next(&mut self, n: usize) {
+        self.index += n;
+    }
+
+    fn is_finished(&self) -> bool {
+        self.done && self.index >= self.pending.len()
+    }
+
+    fn fill_if_needed(&mut self) -> io::Result<()> {
+        if self.index >= self.pending.len() {
+            self.pending.clear();
+            self.index = 0;
+        }
+        Ok(())
+    }
+
+    fn status(&self) -> Option<u16> {
+        self.status
+    }
+
+    fn buffered_len(&self) -> usize {
+        self.pending.capacity()
+    }
+
+    fn is_waiting(&self) -> bool {
+        !self.done && self.index >= self.pending.len()
+    }
+
+    fn poll_producer(&mut self) -> bool {
+        if self.done {
+            return false;
+        }
+        match (self.producer)() {
+            Some(events) if events.is_empty() => false,
+            Some(events) => {
+                let mut text = String::new();
+                for event in &events {
+                    event.encode(&mut text);
+                }
+                self.pending
+                    .extend_from_slice(format!("{:x}\r\n", text.len()).as_bytes());
+                self.pending.extend_from_slice(text.as_bytes());
+                self.pending.extend_from_slice(b"\r\n");
+                true
+            }
+            None => {
+                self.pending.extend_from_slice(b"0\r\n\r\n");
+                self.done = true;
+                true
+            }
+        }
+    }
+}
+
+/// Whether a `Range` applies given the request's `If-Range`: only when the
+/// validator is the served copy's strong entity tag (bundled files have one)
+/// or its exact modification date. Weak tags never match, and anything else
+/// means the client's copy is stale, so the whole file is sent.
+fn if_range_matches(if_range: Option<&String>, metadata: &ContentMeta) -> bool {
+    let Some(if_range) = if_range.map(|v| v.trim()) else {
+        return true;
+    };
+    if if_range.starts_with("W/") {
+        return false;
+    }
+    if if_range.starts_with('"') {
+        return metadata.etag.as_deref() == Some(if_range);
+    }
+    match (httpdate::parse_http_date(if_range), metadata.modified) {
+        (Ok(date), Some(modified)) => httpdate::fmt_http_date(date) == httpdate::fmt_http_date(modified),
+        _ => false,
+    }
 }
 
+/// Weak validator from the size and modification time, cheap enough for
+/// every request; the bytes themselves are never hashed
+pub fn weak_etag(len: u64, modified: Option<SystemTime>) -> String {
+    let mtime = modified
+        .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_secs());
+    format!("W/\"{:x}-{:x}\"", len, mtime)
+}
+
+/// `If-None-Match` holds `*` or a list of tags, compared weakly
+fn none_match(if_none_match: &str, etag: &str) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    if_none_match.trim() == "*" || if_none_match.split(',').any(|tag| opaque(tag) == opaque(etag))
+}
+
+/// Precompressed siblings looked for next to a file, preferred first
+const PRECOMPRESSED: [(&str, &str); 3] = [("br", ".br"), ("zstd", ".zst"), ("gzip", ".gz")];
+
 pub struct FileResponse {
     headers: Vec<u8>,
     headers_index: usize,
     headers_sent: bool,
-    reader: BufReader<File>,
-    buffer: [u8; 8192],
+    reader: Box<dyn ContentReader>,
+    buffer: Vec<u8>,
     buf_len: usize,
     buf_index: usize,
+    remaining: u64,
     finished: bool,
+    direct: Option<(File, u64)>, // The file for sendfile, and the offset of its next unsent byte
+    source: Option<(PathBuf, SystemTime)>, // Set for a plain file sent whole, see `source_file`
 }
 
 impl FileResponse {
     pub fn new(file_path: &str, cookie: &Cookie) -> io::Result<Self> {
+        Self::from_source(&LocalFs, file_path, cookie)
+    }
+
+    pub fn from_source(
+        source: &dyn ContentSource,
+        file_path: &str,
+        cookie: &Cookie,
+    ) -> io::Result<Self> {
+        Self::build(source, file_path, None, false, None, None, cookie)
+    }
+
+    /// The response to a GET for a static file, honouring `If-None-Match`,
+    /// then `Range` (and `If-Range`) from `request_headers`. With `precompressed`, sends
+    /// `foo.css.br` (or `.zst`, `.gz`) for `foo.css` when the client accepts
+    /// that coding; siblings older than the file are ignored, a stale copy is
+    /// worse than compressing on the fly. `cached` lists other encoded copies
+    /// known to match the file, as (coding, path); siblings win over them.
+    pub fn for_request(
+        source: &dyn ContentSource,
+        file_path: &str,
+        request_headers: &HttpHeaders,
+        precompressed: bool,
+        cached: Vec<(String, String)>,
+        cookie: &Cookie,
+    ) -> io::Result<Self> {
+        let original = source.metadata(file_path)?;
+        // If-Range validates the copy that is served, encoded or not
+        let range_for = |served: &ContentMeta| {
+            request_headers
+                .get("range")
+                .filter(|_| if_range_matches(request_headers.get("if-range"), served))
+                .map(String::as_str)
+        };
+        let if_none_match = request_headers.get_combined("if-none-match");
+        let if_none_match = if_none_match.as_deref();
+
+        let mut available: Vec<(String, String)> = PRECOMPRESSED
+            .iter()
+            .filter(|_| precompressed && !original.is_dir)
+            .map(|(coding, ext)| (coding.to_string(), format!("{}{}", file_path, ext)))
+            .filter(|(_, path)| {
+                source
+                    .metadata(path)
+                    .is_ok_and(|m| !m.is_dir && m.modified >= original.modified)
+            })
+            .collect();
+        for (coding, path) in cached {
+            if !available.iter().any(|(c, _)| *c == coding) {
+                available.push((coding, path));
+            }
+        }
+        if available.is_empty() {
+            return Self::build(source, file_path, None, false, range_for(&original), if_none_match, cookie);
+        }
+        let codings: Vec<String> = available.iter().map(|(coding, _)| coding.clone()).collect();
+        let accept_encoding = request_headers.get_combined("accept-encoding");
+        let encoded = negotiate(accept_encoding.as_deref(), &codings)
+            .and_then(|coding| available.iter().find(|(c, _)| c == coding))
+            .map(|(coding, path)| (coding.as_str(), path.as_str()));
+        let range = match encoded {
+            Some((_, path)) => range_for(&source.metadata(path)?),
+            None => range_for(&original),
+        };
+        Self::build(source, file_path, encoded, true, range, if_none_match, cookie)
+    }
+
+    /// Response for `file_path`, read from the `encoded` (coding, path) copy
+    /// when set. A satisfiable `range` is answered with 206 and only those
+    /// bytes, one starting past the end with 416. A matching `if_none_match`
+    /// gets a bodiless 304 instead.
+    fn build(
+        source: &dyn ContentSource,
+        file_path: &str,
+        encoded: Option<(&str, &str)>,
+        varies: bool,
+        range: Option<&str>,
+        if_none_match: Option<&str>,
+        cookie: &Cookie,
+    ) -> io::Result<Self> {
         let content_type = detect_content_type(file_path);
-        let file = File::open(file_path)?;
-        let metadata = file.metadata()?;
+        let (coding, served_path) = match encoded {
+            Some((coding, path)) => (Some(coding), path),
+            None => (None, file_path),
+        };
+        let metadata = source.metadata(served_path)?;
+        if metadata.is_dir {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "is a directory"));
+        }
+        let file = source.open_file(served_path).transpose()?;
+        let mut reader: Box<dyn ContentReader> = match &file {
+            Some(file) => Box::new(file.try_clone()?),
+            None => source.open(served_path)?,
+        };
+
+        // Downloads keep their original (possibly non-ASCII) name when saved
+        let disposition = match std::path::Path::new(file_path).file_name() {
+            Some(name) if content_type == "application/octet-stream" => format!(
+                "Content-Disposition: {}\r\n",
+                content_disposition(&name.to_string_lossy())
+            ),
+            _ => String::new(),
+        };
+        // Lets clients send the date back in If-Unmodified-Since
+        let last_modified = match metadata.modified {
+            Some(modified) => format!("Last-Modified: {}\r\n", httpdate::fmt_http_date(modified)),
+            None => String::new(),
+        };
+
+        // Caches must not hand the encoded copy to clients that can't decode it
+        let mut encoding = String::new();
+        if let Some(coding) = coding {
+            encoding.push_str(&format!("Content-Encoding: {}\r\n", coding));
+        }
+        if varies {
+            encoding.push_str("Vary: Accept-Encoding\r\n");
+        }
+
+        // Each encoded sibling has its own size and date, hence its own tag
+        let etag = metadata
+            .etag
+            .clone()
+            .unwrap_or_else(|| weak_etag(metadata.len, metadata.modified));
+        if if_none_match.is_some_and(|tags| none_match(tags, &etag)) {
+            let headers = format!(
+                "HTTP/1.1 304 Not Modified\r\nETag: {}\r\n{}{}Set-Cookie: {}\r\n\r\n",
+                etag,
+                if varies { "Vary: Accept-Encoding\r\n" } else { "" },
+                last_modified,
+                cookie.to_header_value()
+            )
+            .into_bytes();
+            return Ok(Self::with_head(headers, reader, 0));
+        }
+
+        let (status, content_range, start, length) = match parse_range(range, metadata.len) {
+            ByteRange::Full => ("200 OK", String::new(), 0, metadata.len),
+            ByteRange::Partial { start, end } => {
+                reader.seek(SeekFrom::Start(start))?;
+                (
+                    "206 Partial Content",
+                    format!("Content-Range: bytes {}-{}/{}\r\n", start, end, metadata.len),
+                    start,
+                    end - start + 1,
+                )
+            }
+            ByteRange::Unsatisfiable => (
+                "416 Range Not Satisfiable",
+                format!("Content-Range: bytes */{}\r\n", metadata.len),
+                0,
+                0,
+            ),
+        };
 
         let headers = format!(
-            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nContent-Type: {}\r\nSet-Cookie: {}\r\n\r\n",
-            metadata.len(),
+            "HTTP/1.1 {}\r\nContent-Length: {}\r\nContent-Type: {}\r\nAccept-Ranges: bytes\r\nETag: {}\r\n{}{}{}{}Set-Cookie: {}\r\n\r\n",
+            status,
+            length,
             content_type,
+            etag,
+            content_range,
+            encoding,
+            disposition,
+            last_modified,
             cookie.to_header_value()
         )
         .into_bytes();
 
-        Ok(Self {
+        let mut response = Self::with_head(headers, reader, length);
+        if coding.is_none() && status == "200 OK" && file.is_some() {
+            response.source = metadata.modified.map(|modified| (PathBuf::from(file_path), modified));
+        }
+        // Plain files may skip the buffer and go out with sendfile
+        response.direct = file
+            .filter(|_| cfg!(target_os = "linux"))
+            .map(|file| (file, start));
+        Ok(response)
+    }
+
+    /// Add the `Cache-Control` (and `Expires`) lines for `value` to the head.
+    /// A 416 is left alone, caching it would outlive the file's next change.
+    pub fn cache_control(mut self, value: Option<&str>) -> Self {
+        if let Some(value) = value
+            && !self.headers.starts_with(b"HTTP/1.1 416")
+        {
+            // Just before the blank line ending the head
+            let end = self.headers.len() - 2;
+            self.headers.splice(end..end, cache_headers(value).into_bytes());
+        }
+        self
+    }
+
+    /// Send `headers`, then `length` bytes from `reader`
+    fn with_head(headers: Vec<u8>, reader: Box<dyn ContentReader>, length: u64) -> Self {
+        Self {
             headers,
             headers_sent: false,
             headers_index: 0,
-            reader: BufReader::new(file),
-            buffer: [0; 8192],
+            reader,
+            buffer: vec![0; buffer_size_for(length)],
             buf_len: 0,
             buf_index: 0,
+            remaining: length,
             finished: false,
-        })
+            direct: None,
+            source: None,
+        }
     }
 
-    /// Fill the buffer if it's empty
+    /// Fill the buffer if it's empty, never sending more than the advertised
+    /// Content-Length. A file that shrinks while being served is an error so the
+    /// caller drops the connection instead of corrupting keep-alive framing.
     fn fill_buffer(&mut self) -> io::Result<()> {
         if self.buf_index >= self.buf_len && !self.finished {
-            let n = self.reader.read(&mut self.buffer)?;
+            if self.remaining == 0 {
+                self.finished = true;
+                self.buf_index = 0;
+                self.buf_len = 0;
+                return Ok(());
+            }
+
+            let want = usize::try_from(self.remaining).map_or(self.buffer.len(), |r| r.min(self.buffer.len()));
+            let n = self.reader.read(&mut self.buffer[..want])?;
             self.buf_index = 0;
             self.buf_len = n;
             if n == 0 {
-                self.finished = true;
+                warn!(
+                    "File truncated while serving: {} bytes short of Content-Length",
+                    self.remaining
+                );
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "file shorter than advertised Content-Length",
+                ));
             }
+            self.remaining -= n as u64;
         }
         Ok(())
     }
@@ -111,10 +561,85 @@ impl HttpResponseCommon for FileResponse {
         self.headers_sent && self.finished && self.buf_index >= self.buf_len
     }
 
+    fn buffered_len(&self) -> usize {
+        self.headers.capacity() + self.buffer.capacity()
+    }
+
     fn fill_if_needed(&mut self) -> io::Result<()> {
         if self.headers_sent && self.buf_index >= self.buf_len && !self.finished {
             self.fill_buffer()?;
         }
         Ok(())
     }
-}
\ No newline at end of file
+
+    fn status(&self) -> Option<u16> {
+        status_code(&self.headers)
+    }
+
+    fn source_file(&self) -> Option<(&Path, SystemTime)> {
+        self.source.as_ref().map(|(path, modified)| (path.as_path(), *modified))
+    }
+
+    fn sendfile_len(&self) -> u64 {
+        match self.direct {
+            Some(_) if self.headers_sent && self.buf_index >= self.buf_len => self.remaining,
+            _ => 0,
+        }
+    }
+
+    /// Same Content-Length guarantee as `fill_buffer`
+    fn sendfile(&mut self, fd: i32, max: usize) -> io::Result<usize> {
+        let Some((file, offset)) = self.direct.as_mut() else {
+            return Ok(0);
+        };
+        let count = usize::try_from(self.remaining).map_or(max, |r| r.min(max));
+        let sent = sendfile::send(fd, file, *offset, count)?;
+        if sent == 0 && count > 0 {
+            warn!(
+                "File truncated while serving: {} bytes short of Content-Length",
+                self.remaining
+            );
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "file shorter than advertised Content-Length",
+            ));
+        }
+        *offset += sent as u64;
+        self.remaining -= sent as u64;
+        if self.remaining == 0 {
+            self.finished = true;
+        }
+        Ok(sent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn meta(etag: Option<&str>) -> ContentMeta {
+        ContentMeta {
+            len: 10,
+            modified: Some(UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000)),
+            is_dir: false,
+            etag: etag.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn if_range_accepts_the_strong_etag_or_the_exact_date() {
+        let bundled = meta(Some("\"0123abcd\""));
+        let date = httpdate::fmt_http_date(bundled.modified.unwrap());
+
+        assert!(if_range_matches(None, &bundled));
+        assert!(if_range_matches(Some(&"\"0123abcd\"".to_string()), &bundled));
+        assert!(if_range_matches(Some(&date), &bundled));
+        assert!(!if_range_matches(Some(&"\"ffff\"".to_string()), &bundled));
+        assert!(!if_range_matches(Some(&"W/\"0123abcd\"".to_string()), &bundled));
+        assert!(!if_range_matches(Some(&"Tue, 14 Nov 2023 22:13:21 GMT".to_string()), &bundled));
+        // Files on disk only have weak tags, which If-Range never takes
+        let plain = meta(None);
+        let weak = weak_etag(plain.len, plain.modified);
+        assert!(!if_range_matches(Some(&weak), &plain));
+    }
+}
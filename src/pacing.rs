@@ -0,0 +1,124 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use crate::config::{Config, ServerConfig};
+
+/// Smallest write worth waking a paced connection for
+const MIN_GRANT: usize = 4096;
+
+/// Largest single grant, so connections sharing a bucket take turns
+const MAX_GRANT: usize = 64 * 1024;
+
+/// Share of a second of traffic a bucket can hold, i.e. the largest burst
+const BURST_DIVISOR: u64 = 10;
+
+/// Egress buckets keyed by server name, shared by every worker so the cap
+/// holds for the vhost as a whole
+static PACERS: OnceLock<HashMap<String, Pacer>> = OnceLock::new();
+
+/// Token bucket limiting the bytes sent by all connections of one server
+pub struct Pacer {
+    rate: u64,     // Bytes per second
+    capacity: u64, // Largest burst
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    tokens: u64,
+    refilled: Instant,
+}
+
+impl Pacer {
+    fn new(rate: u64) -> Self {
+        let capacity = (rate / BURST_DIVISOR).max(1);
+        Self {
+            rate,
+            capacity,
+            bucket: Mutex::new(Bucket {
+                tokens: capacity,
+                refilled: Instant::now(),
+            }),
+        }
+    }
+
+    /// Reserve up to `wanted` bytes; 0 means the connection must wait.
+    /// Small grants are refused so waiting connections write in useful chunks.
+    pub fn take(&self, wanted: usize) -> usize {
+        let mut bucket = self.bucket.lock().unwrap_or_else(|e| e.into_inner());
+        self.refill(&mut bucket);
+        let worth_it = (wanted as u64).min(MIN_GRANT as u64).min(self.capacity);
+        if bucket.tokens < worth_it {
+            return 0;
+        }
+        let granted = bucket.tokens.min(wanted.min(MAX_GRANT) as u64);
+        bucket.tokens -= granted;
+        granted as usize
+    }
+
+    /// Return the part of a grant the socket did not accept
+    pub fn give_back(&self, unused: usize) {
+        if unused == 0 {
+            return;
+        }
+        let mut bucket = self.bucket.lock().unwrap_or_else(|e| e.into_inner());
+        bucket.tokens = bucket.tokens.saturating_add(unused as u64).min(self.capacity);
+    }
+
+    /// How long until a refused `take` can succeed
+    pub fn wait(&self) -> Duration {
+        let bucket = self.bucket.lock().unwrap_or_else(|e| e.into_inner());
+        let target = (MIN_GRANT as u64).min(self.capacity);
+        let missing = target.saturating_sub(bucket.tokens);
+        Duration::from_micros(missing.saturating_mul(1_000_000) / self.rate).max(Duration::from_millis(1))
+    }
+
+    fn refill(&self, bucket: &mut Bucket) {
+        let now = Instant::now();
+        // In u128: a long idle spell times a high rate overflows u64
+        let earned = now.duration_since(bucket.refilled).as_micros() * u128::from(self.rate) / 1_000_000;
+        let earned = u64::try_from(earned).unwrap_or(u64::MAX).min(self.capacity);
+        if earned > 0 {
+            bucket.tokens = bucket.tokens.saturating_add(earned).min(self.capacity);
+            bucket.refilled = now;
+        }
+    }
+}
+
+/// Create the buckets of servers with a `max_bandwidth`, once per process.
+/// Servers sharing a name share the first cap configured for it.
+pub fn init(config: &Config) {
+    let mut pacers = HashMap::new();
+    for server in &config.servers {
+        if let Some(rate) = server.max_bandwidth {
+            pacers
+                .entry(server.server_name.to_ascii_lowercase())
+                .or_insert_with(|| Pacer::new(rate));
+        }
+    }
+    let _ = PACERS.set(pacers);
+}
+
+/// The bucket responses of `server` draw from, if it is capped
+pub fn for_server(server: &ServerConfig) -> Option<&'static Pacer> {
+    server.max_bandwidth?;
+    PACERS.get()?.get(&server.server_name.to_ascii_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refill_after_a_long_idle_spell_stops_at_capacity() {
+        let pacer = Pacer::new(u64::MAX);
+        let Some(long_ago) = Instant::now().checked_sub(Duration::from_secs(3600)) else {
+            return;
+        };
+        let mut bucket = pacer.bucket.lock().unwrap();
+        bucket.tokens = 0;
+        bucket.refilled = long_ago;
+        pacer.refill(&mut bucket);
+        assert_eq!(bucket.tokens, pacer.capacity);
+    }
+}
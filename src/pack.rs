@@ -0,0 +1,172 @@
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use crate::config::{self, Config};
+
+const BLOCK: usize = 512;
+
+/// Address `serve-bundle` listens on without `--listen`
+pub const DEFAULT_LISTEN: &str = "127.0.0.1:8080";
+
+/// `localserver pack <dir> -o <file>`: store a static site in one archive, to
+/// be served by routes with `bundle:` or by `serve-bundle`. Returns the exit
+/// status.
+pub fn run(dir: &str, output: &str) -> i32 {
+    match pack(Path::new(dir), Path::new(output)) {
+        Ok(count) => {
+            println!("Packed {} file(s) from {} into {}", count, dir, output);
+            0
+        }
+        Err(e) => {
+            eprintln!("Cannot pack {}: {}", dir, e);
+            1
+        }
+    }
+}
+
+/// Write the regular files under `dir` to a tar archive at `output`, in name
+/// order and with paths relative to `dir`. The archive is written next to
+/// `output` and renamed over it once complete.
+pub fn pack(dir: &Path, output: &Path) -> io::Result<usize> {
+    let mut files = Vec::new();
+    collect_files(dir, Path::new(""), &mut files)?;
+    // Packing into the site directory must not pick up an older archive
+    let skipped = fs::canonicalize(output).ok();
+    files.retain(|(_, path)| fs::canonicalize(path).ok() != skipped);
+
+    let temp = output.with_extension("partial");
+    let result = (|| {
+        let mut out = BufWriter::new(File::create(&temp)?);
+        for (name, path) in &files {
+            let metadata = fs::metadata(path)?;
+            let mtime = metadata
+                .modified()
+                .ok()
+                .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |d| d.as_secs());
+            write_member(&mut out, name, metadata.len(), mtime)?;
+            let copied = io::copy(&mut File::open(path)?, &mut out)?;
+            if copied != metadata.len() {
+                return Err(io::Error::other(format!("{} changed while packing", path.display())));
+            }
+            pad(&mut out, copied as usize)?;
+        }
+        // End of archive: two empty blocks
+        out.write_all(&[0u8; BLOCK * 2])?;
+        out.into_inner().map_err(|e| e.into_error())?.sync_all()
+    })();
+    match result {
+        Ok(()) => fs::rename(&temp, output).map(|_| files.len()),
+        Err(e) => {
+            let _ = fs::remove_file(&temp);
+            Err(e)
+        }
+    }
+}
+
+/// Regular files below `dir`, as (archive name, path), sorted by name
+fn collect_files(dir: &Path, prefix: &Path, files: &mut Vec<(String, PathBuf)>) -> io::Result<()> {
+    let mut entries: Vec<_> = fs::read_dir(dir)?.collect::<Result<_, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let path = entry.path();
+        let relative = prefix.join(entry.file_name());
+        let metadata = fs::metadata(&path)?;
+        if metadata.is_dir() {
+            collect_files(&path, &relative, files)?;
+        } else if metadata.is_file() {
+            let name = relative
+                .components()
+                .map(|c| c.as_os_str().to_str())
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("{} is not UTF-8", path.display())))?
+                .join("/");
+            files.push((name, path));
+        }
+    }
+    Ok(())
+}
+
+/// Header of a regular file. Names over 100 bytes go in a GNU long-name
+/// member first, which `TarBundle` and common tar tools understand.
+fn write_member(out: &mut impl Write, name: &str, len: u64, mtime: u64) -> io::Result<()> {
+    // The size field holds 11 octal digits
+    if len >= 1 << 33 {
+        return Err(io::Error::other(format!("{} is too large for a bundle (8 GiB or more)", name)));
+    }
+    if name.len() > 100 {
+        let mut long_name = name.as_bytes().to_vec();
+        long_name.push(0);
+        out.write_all(&header("././@LongLink", long_name.len() as u64, 0, b'L'))?;
+        out.write_all(&long_name)?;
+        pad(out, long_name.len())?;
+    }
+    out.write_all(&header(name, len, mtime, b'0'))
+}
+
+fn header(name: &str, len: u64, mtime: u64, type_flag: u8) -> [u8; BLOCK] {
+    let mut header = [0u8; BLOCK];
+    let name = name.as_bytes();
+    let name_len = name.len().min(100);
+    header[..name_len].copy_from_slice(&name[..name_len]);
+    octal(&mut header[100..108], 0o644);
+    octal(&mut header[108..116], 0); // uid
+    octal(&mut header[116..124], 0); // gid
+    octal(&mut header[124..136], len);
+    octal(&mut header[136..148], mtime);
+    header[156] = type_flag;
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+
+    // The checksum is computed with its own field filled with spaces
+    header[148..156].fill(b' ');
+    let sum: u64 = header.iter().map(|b| *b as u64).sum();
+    octal(&mut header[148..155], sum);
+    header
+}
+
+/// Zero-padded octal digits ending with a NUL, as tar numeric fields are
+fn octal(field: &mut [u8], value: u64) {
+    let width = field.len() - 1;
+    let digits = format!("{:0width$o}", value, width = width);
+    field[..width].copy_from_slice(&digits.as_bytes()[digits.len() - width..]);
+    field[width] = 0;
+}
+
+/// Fill the last block of a member's data
+fn pad(out: &mut impl Write, written: usize) -> io::Result<()> {
+    let rest = (BLOCK - written % BLOCK) % BLOCK;
+    out.write_all(&[0u8; BLOCK][..rest])
+}
+
+/// `localserver serve-bundle <file> [--listen host:port]`: the config of a
+/// server answering GET from the archive alone, with its index.html at `/`
+pub fn bundle_config(bundle: &str, listen: &str) -> Result<Config, Box<dyn Error>> {
+    let (host, port) = listen
+        .rsplit_once(':')
+        .and_then(|(host, port)| Some((host.trim_matches(['[', ']']), port.parse::<u16>().ok()?)))
+        .ok_or_else(|| format!("Invalid --listen '{}', expected host:port", listen))?;
+
+    let mut config = config::parse_config(&format!(
+        "servers:
+  - server_name: \"bundle\"
+    host: {}
+    ports:
+      - {}
+    default_server: true
+    root: \".\"
+    routes:
+      - path: \"/\"
+        methods: [\"GET\"]
+        root: \".\"
+        default_file: \"index.html\"
+",
+        host, port
+    ))?;
+    // Set here rather than in the text, where quotes or `#` would need escaping
+    config.servers[0].routes[0].bundle = Some(bundle.to_string());
+    Ok(config)
+}
@@ -0,0 +1,109 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// First bytes of a version 2 (binary) header
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Longest version 1 (text) header, CRLF included
+pub const V1_MAX_LEN: usize = 107;
+
+/// Bytes to look at before the header length is known: the v2 fixed part,
+/// which is also enough to tell either version from anything else
+pub const PREFIX_LEN: usize = 16;
+
+/// What the start of a connection says about its PROXY header
+#[derive(Debug, PartialEq)]
+pub enum Header {
+    /// More bytes are needed; at least this many in total
+    Incomplete(usize),
+    /// A header of `len` bytes, carrying the client's address unless the
+    /// proxy sent it on its own behalf (health checks, `UNKNOWN`)
+    Complete { len: usize, source: Option<SocketAddr> },
+}
+
+/// Parse the PROXY protocol header (v1 or v2) a load balancer sends ahead of
+/// the client's bytes. Anything else is an error, so a connection that didn't
+/// come through the proxy can't pass for another client.
+pub fn parse(buf: &[u8]) -> io::Result<Header> {
+    if buf.starts_with(b"PROXY ") {
+        return parse_v1(buf);
+    }
+    let v1_so_far = b"PROXY ".starts_with(&buf[..buf.len().min(6)]);
+    let v2_so_far = V2_SIGNATURE.starts_with(&buf[..buf.len().min(V2_SIGNATURE.len())]);
+    if !v1_so_far && !v2_so_far {
+        return Err(invalid("connection does not start with a PROXY header"));
+    }
+    if buf.len() < PREFIX_LEN {
+        return Ok(Header::Incomplete(PREFIX_LEN));
+    }
+    parse_v2(buf)
+}
+
+/// `PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n`, or `PROXY UNKNOWN ...\r\n`
+fn parse_v1(buf: &[u8]) -> io::Result<Header> {
+    let Some(end) = buf.windows(2).position(|w| w == b"\r\n") else {
+        if buf.len() >= V1_MAX_LEN {
+            return Err(invalid("PROXY v1 header too long"));
+        }
+        return Ok(Header::Incomplete(V1_MAX_LEN));
+    };
+    let len = end + 2;
+    if len > V1_MAX_LEN {
+        return Err(invalid("PROXY v1 header too long"));
+    }
+
+    let line = std::str::from_utf8(&buf[..end]).map_err(|_| invalid("PROXY v1 header is not text"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    let source = match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => None,
+        ["PROXY", family @ ("TCP4" | "TCP6"), src, _dst, sport, _dport] => {
+            let ip: IpAddr = src.parse().map_err(|_| invalid("bad PROXY v1 source address"))?;
+            let port: u16 = sport.parse().map_err(|_| invalid("bad PROXY v1 source port"))?;
+            if ip.is_ipv4() != (*family == "TCP4") {
+                return Err(invalid("PROXY v1 address does not match its family"));
+            }
+            Some(SocketAddr::new(ip, port))
+        }
+        _ => return Err(invalid("malformed PROXY v1 header")),
+    };
+    Ok(Header::Complete { len, source })
+}
+
+/// Binary header: signature, version/command, family/protocol, length, addresses
+fn parse_v2(buf: &[u8]) -> io::Result<Header> {
+    let version_command = buf[12];
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+    let len = PREFIX_LEN + u16::from_be_bytes([buf[14], buf[15]]) as usize;
+    if buf.len() < len {
+        return Ok(Header::Incomplete(len));
+    }
+
+    let addresses = &buf[PREFIX_LEN..len];
+    let source = match (version_command & 0x0f, buf[13]) {
+        // LOCAL: the proxy talking for itself, keep the socket's address
+        (0x0, _) => None,
+        // PROXY over TCP/IPv4
+        (0x1, 0x11) if addresses.len() >= 12 => {
+            let ip = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Some(SocketAddr::new(IpAddr::V4(ip), port))
+        }
+        // PROXY over TCP/IPv6
+        (0x1, 0x21) if addresses.len() >= 36 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&addresses[..16]);
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port))
+        }
+        // Other families (unix sockets, UDP) carry nothing we can use
+        (0x1, _) => None,
+        _ => return Err(invalid("unsupported PROXY v2 command")),
+    };
+    Ok(Header::Complete { len, source })
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
@@ -1,17 +1,34 @@
-use std::{io::{self, Read}, path::Path, time::Instant};
-use mio::net::TcpStream;
-use crate::cgi::run_cgi;
+use std::collections::HashSet;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::{io, path::{Component, Path, PathBuf}, time::Instant};
+use crate::admin;
+use crate::auth;
+use crate::dav;
+use crate::batch::handle_batch;
+use crate::dirstat::{handle_stat, wants_stat};
+use crate::metrics;
+use crate::pacing;
+use crate::stats;
+use crate::upstream;
+use crate::cgi::{CgiContext, CgiSession, DEFAULT_BUFFER_SIZE, route_script, run_cgi, script_allowed, split_script_path};
+use crate::fastcgi::run_fastcgi;
+use crate::filters;
+use crate::write::should_keep_alive;
+use crate::transport::Transport;
 use crate::handler::*;
-use crate::{config::Route, utils::{HttpHeaders, session::handle_session}};
-use crate::response::{HttpResponseBuilder, handle_method_not_allowed};
-use crate::{config::ServerConfig, models::{HttpResponseCommon, SimpleResponse}, request::{HttpRequest, ParserState}, server::{ListenerInfo, SocketData, SocketStatus, Status}, utils::{HttpMethod, cookie::Cookie}};
+use crate::{config::{Route, SplitKey}, utils::{HttpHeaders, form, session::handle_session}};
+use crate::response::{HttpResponseBuilder, handle_method_not_allowed, reason_phrase};
+use crate::{config::ServerConfig, models::{HttpResponseCommon, SimpleResponse}, request::{HEADERS_TOO_LARGE, HttpRequest, ParserState, STREAM_BACKLOG_LIMIT, SpillPolicy, UNSUPPORTED_TRANSFER_CODING, URI_TOO_LONG}, server::{CloseReason, IDLE_TIMEOUT, ListenerInfo, SocketData, SocketStatus, Status}, utils::{HttpMethod, cookie::Cookie}};
 
-fn resolve_file_path(
+pub(crate) fn resolve_file_path(
     server: &ServerConfig,
     route: &crate::config::Route,
     request_path: &str,
 ) -> Option<String> {
-    println!(
+    debug!(
         "Resolving file path for request_path: '{}' under route: '{}'",
         request_path, route.path
     );
@@ -19,17 +36,26 @@ fn resolve_file_path(
     let route_root = &route.root;
     let base = format!("{}/{}", server_root, route_root);
 
+    // Bundled content is not on disk; map the path lexically and let the
+    // bundle look it up
+    if route.bundle.is_some() {
+        let relative = strip_route_prefix(route, request_path).unwrap_or("");
+        if relative.split('/').any(|segment| segment == "..") {
+            return None;
+        }
+        return Some(format!("{}/{}", base, relative.trim_start_matches('/')));
+    }
+
     let base_path = match Path::new(&base).canonicalize() {
         Ok(path) => path,
         Err(_) => return None,
     };
 
-    let relative_path = request_path
-        .strip_prefix(&route.path)
+    let relative_path = strip_route_prefix(route, request_path)
         .unwrap_or("")
         .trim_start_matches('/');
 
-    let full_path = base_path.join(relative_path);
+    let full_path = join_request_path(&base_path, relative_path, route.case_insensitive == Some(true))?;
     let canonical = match full_path.canonicalize() {
         Ok(path) => path,
         Err(_) => {
@@ -43,14 +69,75 @@ fn resolve_file_path(
     };
 
     if canonical.starts_with(&base_path) {
-        canonical.to_str().map(|s| s.to_string())
+        plain_path_string(&canonical)
     } else {
         None
     }
 }
 
+/// Append a `/`-separated request path to `base` one name at a time. A
+/// segment holding a drive (`C:`), a `\` or a UNC prefix would otherwise
+/// replace the base on Windows instead of extending it.
+///
+/// With `case_insensitive`, a name missing on disk is looked up among its
+/// directory's entries ignoring case. Names matching several entries
+/// (`readme` with both `README` and `Readme` present) are refused rather
+/// than guessed; names matching none are kept as given, for uploads.
+fn join_request_path(base: &Path, relative: &str, case_insensitive: bool) -> Option<PathBuf> {
+    let mut path = base.to_path_buf();
+    for segment in relative.split('/').filter(|s| !s.is_empty() && *s != ".") {
+        let mut components = Path::new(segment).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(_)), None) if case_insensitive => {
+                let name = entry_ignoring_case(&path, segment)?;
+                path.push(name);
+            }
+            (Some(Component::Normal(name)), None) if name == segment => path.push(name),
+            (Some(Component::ParentDir), None) => path.push(".."),
+            _ => return None,
+        }
+    }
+    Some(path)
+}
+
+/// The entry of `dir` named `name` up to case, `None` when ambiguous
+fn entry_ignoring_case(dir: &Path, name: &str) -> Option<String> {
+    if dir.join(name).symlink_metadata().is_ok() {
+        return Some(name.to_string());
+    }
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return Some(name.to_string());
+    };
+    let wanted = name.to_lowercase();
+    let mut matches = entries
+        .flatten()
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|entry| entry.to_lowercase() == wanted);
+    match (matches.next(), matches.next()) {
+        (Some(found), None) => Some(found),
+        (None, _) => Some(name.to_string()),
+        (Some(_), Some(_)) => {
+            warn!("{}: several entries match '{}' ignoring case", dir.display(), name);
+            None
+        }
+    }
+}
+
+/// Canonical paths are `\\?\C:\...` on Windows; scripts and logs get the
+/// usual `C:\...` form when there is one
+fn plain_path_string(path: &Path) -> Option<String> {
+    let path = path.to_str()?;
+    #[cfg(windows)]
+    if let Some(plain) = path.strip_prefix(r"\\?\")
+        && !plain.starts_with(r"UNC\")
+    {
+        return Some(plain.to_string());
+    }
+    Some(path.to_string())
+}
+
 
-fn find_matching_route<'a>(server: &'a ServerConfig, request_path: &str) -> Option<&'a Route> {
+pub(crate) fn find_matching_route<'a>(server: &'a ServerConfig, request_path: &str) -> Option<&'a Route> {
     server
         .routes
         .iter()
@@ -58,17 +145,96 @@ fn find_matching_route<'a>(server: &'a ServerConfig, request_path: &str) -> Opti
             if route.path == "/" {
                 true
             } else {
-                request_path == route.path || request_path.starts_with(&(route.path.clone() + "/"))
+                strip_route_prefix(route, request_path).is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
             }
         })
         .max_by_key(|route| route.path.len())
 }
 
+/// For a route with `split`, a copy serving from the variant this client
+/// lands on. The same session (or address, with `split_by: ip`) always gets
+/// the same variant; the route path is mixed in so experiments on different
+/// routes don't pick the same clients.
+fn pick_split(route: &Route, cookie: &Cookie, peer: Option<IpAddr>) -> Option<Route> {
+    if route.split.is_empty() {
+        return None;
+    }
+    let mut hasher = DefaultHasher::new();
+    route.path.hash(&mut hasher);
+    match route.split_by {
+        // The cookie going out, so a new visitor's first page and the
+        // requests that follow agree
+        SplitKey::Session => cookie.value().hash(&mut hasher),
+        SplitKey::Ip => peer.map(|ip| ip.to_canonical()).hash(&mut hasher),
+    }
 
-fn extract_hostname(headers: &HttpHeaders) -> &str {
+    let total: u64 = route.split.iter().map(|v| v.weight as u64).sum();
+    let mut point = hasher.finish() % total;
+    let variant = route.split.iter().find(|v| {
+        let inside = point < v.weight as u64;
+        point = point.saturating_sub(v.weight as u64);
+        inside
+    })?;
+    debug!("Split: {} served from '{}'", route.path, variant.root);
+
+    let mut chosen = route.clone();
+    chosen.root = variant.root.clone();
+    Some(chosen)
+}
+
+/// Primary roots currently failed over, shared by every worker so each
+/// failover and recovery is logged once
+static FAILED_OVER: Mutex<Option<HashSet<String>>> = Mutex::new(None);
+
+/// For a route with `fallback_root`, a copy serving from it while the
+/// primary root is missing or unreadable, e.g. halfway through a deploy.
+/// The primary is looked at on every request, so it takes over again as
+/// soon as it is back.
+fn failover_root(server: &ServerConfig, route: &Route) -> Option<Route> {
+    let fallback = route.fallback_root.as_ref()?;
+    let primary = format!("{}/{}", server.root, route.root);
+    let healthy = std::fs::read_dir(&primary).is_ok();
+
+    let mut failed = FAILED_OVER.lock().unwrap_or_else(|e| e.into_inner());
+    let failed = failed.get_or_insert_with(HashSet::new);
+    if healthy {
+        if failed.remove(&primary) {
+            info!("Failover: {} root '{}' is back, serving from it again", route.path, primary);
+        }
+        return None;
+    }
+    if failed.insert(primary.clone()) {
+        warn!(
+            "Failover: {} root '{}' is unavailable, serving from '{}'",
+            route.path, primary, fallback
+        );
+    }
+
+    let mut chosen = route.clone();
+    chosen.root = fallback.clone();
+    Some(chosen)
+}
+
+/// What follows the route's path in `request_path`, ignoring ASCII case on
+/// `case_insensitive` routes
+pub(crate) fn strip_route_prefix<'a>(route: &Route, request_path: &'a str) -> Option<&'a str> {
+    if route.case_insensitive != Some(true) {
+        return request_path.strip_prefix(route.path.as_str());
+    }
+    let prefix = request_path.get(..route.path.len())?;
+    prefix
+        .eq_ignore_ascii_case(&route.path)
+        .then(|| &request_path[route.path.len()..])
+}
+
+
+/// Host header name, or the TLS SNI name when the client sent no Host
+fn extract_hostname<'a>(headers: &'a HttpHeaders, stream: &'a dyn Transport) -> &'a str {
     headers
         .get("host")
         .and_then(|h| h.split(':').next())
+        .filter(|h| !h.is_empty())
+        .or_else(|| stream.sni_hostname())
         .unwrap_or("")
 }
 
@@ -86,9 +252,9 @@ fn select_server<'a>(listener_info: &'a ListenerInfo, hostname: &str) -> &'a Ser
     if let Some(srv) = listener_info
         .servers
         .iter()
-        .find(|s| s.server_name == hostname)
+        .find(|s| s.server_name.eq_ignore_ascii_case(hostname))
     {
-        println!(
+        debug!(
             "Selected server '{}' for Host: {}",
             srv.server_name, hostname
         );
@@ -104,7 +270,7 @@ fn select_server<'a>(listener_info: &'a ListenerInfo, hostname: &str) -> &'a Ser
         )
     });
 
-    println!(
+    debug!(
         "No match for Host: '{}', using default server '{}'",
         hostname, default_srv.server_name
     );
@@ -112,41 +278,161 @@ fn select_server<'a>(listener_info: &'a ListenerInfo, hostname: &str) -> &'a Ser
     default_srv
 }
 
+/// Interim response to `Expect: 100-continue`
+const CONTINUE: &[u8] = b"HTTP/1.1 100 Continue\r\n\r\n";
+
+/// Tell a client that sent `Expect: 100-continue` to go on with the body,
+/// instead of letting it wait a second before sending it anyway. A body
+/// declared too large gets no go-ahead, the 413 answers it. False for an
+/// expectation the server can't meet, which is answered 417.
+fn answer_expect(stream: &mut dyn Transport, socket: &mut SocketStatus, max_body_size: usize) -> bool {
+    let Some(request) = socket.request.get_before_done() else {
+        return true;
+    };
+    let Some(expect) = request.headers.get("expect") else {
+        return true;
+    };
+    if !expect.eq_ignore_ascii_case("100-continue") {
+        info!("Unsupported expectation '{}'", expect);
+        socket.expectation_failed = true;
+        return false;
+    }
+    // HTTP/1.0 clients don't know about interim responses
+    let waiting = request.version == "HTTP/1.1"
+        && !socket.request.done()
+        && socket.request.body_len() == 0
+        && socket.request.expected_body_len().is_none_or(|len| len <= max_body_size);
+    if waiting && let Err(e) = stream.write_all(CONTINUE) {
+        // The client sends the body on its own once it stops waiting
+        info!("Cannot send 100 Continue: {}", e);
+    }
+    true
+}
+
 fn read_request(
-    stream: &mut TcpStream,
+    stream: &mut dyn Transport,
     socket: &mut SocketStatus,
     listener_info: Option<&ListenerInfo>,
 ) -> Option<bool> {
-    let mut buf = [0u8; 4096];
-
     loop {
-        socket.ttl = Instant::now();
+        if socket.read_budget == 0 {
+            socket.budget_exhausted = true;
+            return Some(false);
+        }
 
-        match stream.read(&mut buf) {
-            Ok(0) => return None,
+        // The script consuming a streamed body is behind; its stdin pipe
+        // becoming writable resumes reading
+        if socket.request.stream_backlog() >= STREAM_BACKLOG_LIMIT {
+            return Some(false);
+        }
 
-            Ok(n) => {
-                socket.request.append(buf[..n].to_vec()).ok()?;
+        // A pipelining client sent the start of this request with the last one
+        let read = match socket.request.take_pipelined() {
+            Some(data) => Ok(data),
+            None => stream.read(socket.read_buffer.as_mut_slice()).map(|n| {
+                let data = socket.read_buffer.as_mut_slice()[..n].to_vec();
+                socket.read_buffer.record(n);
+                stats::record_received(n);
+                data
+            }),
+        };
+        match read {
+            Ok(data) if data.is_empty() => {
+                socket.closing(CloseReason::ClientEof);
+                return None;
+            }
+
+            Ok(data) => {
+                let now = Instant::now();
+                socket.ttl = now;
+                socket.request_started.get_or_insert(now);
+                socket.read_budget = socket.read_budget.saturating_sub(data.len());
+                // Scans and stray handshakes get no answer and no parsing
+                if let Some(reason) = socket.request.screen(&data) {
+                    info!("Not an HTTP request line ({}), closing", reason.as_str());
+                    metrics::record_line_rejection(reason);
+                    socket.closing(CloseReason::ProtocolError);
+                    return None;
+                }
+                if let Err(e) = socket.request.append(data, &socket.head_limits) {
+                    info!("Malformed request: {}", e);
+                    match e {
+                        URI_TOO_LONG => socket.uri_too_long = true,
+                        HEADERS_TOO_LARGE => socket.headers_too_large = true,
+                        UNSUPPORTED_TRANSFER_CODING => socket.unsupported_coding = true,
+                        _ => {}
+                    }
+                    socket.bad_request = true;
+                    socket.request.set_state(ParserState::Complete);
+                    return Some(true);
+                }
+
+                // Nothing sent in clear to a TLS port is served
+                if stream.plain_http_on_tls() && socket.request.header_done() {
+                    socket.bad_request = true;
+                    socket.request.set_state(ParserState::Complete);
+                    return Some(true);
+                }
 
                 if socket.request.header_done() && !socket.server_selected {
-                    println!("hello");
                     let request = socket.request.get_before_done()?;
-                    let hostname = extract_hostname(&request.headers);
+                    let hostname = extract_hostname(&request.headers, stream);
                     let info = listener_info?;
 
                     let selected = select_server(info, hostname);
+                    socket.spill_dir = selected.upload_tmp_dir.as_ref().map(PathBuf::from);
                     socket.max_body_size = Some(selected.client_max_body_size);
+                    socket.apply_timeouts(selected);
+                    socket.pacer = pacing::for_server(selected);
                     socket.server_selected = true;
-                }
 
-                if let Some(max) = socket.max_body_size {
-                    if socket.request.body_len() > max {
-                        socket.body_too_large = true;
+                    if streams_to_cgi(selected, socket.request.get_before_done()?, stream.is_tls())
+                        && socket.request.stream_body().is_some()
+                    {
+                        debug!("Streaming request body to CGI");
+                    } else if let Some(dir) = &selected.upload_tmp_dir {
+                        let policy = SpillPolicy {
+                            dir: PathBuf::from(dir),
+                            threshold: selected.upload_spill_threshold,
+                        };
+                        if let Err(e) = socket.request.set_spill(policy) {
+                            info!("Cannot spool request body: {}", e);
+                            socket.bad_request = true;
+                            socket.request.set_state(ParserState::Complete);
+                            return Some(true);
+                        }
+                    }
+                    if socket.memory_pressure && !socket.spill_under_pressure() {
+                        info!("Request body can't be spilled under memory pressure, sending 503");
+                        socket.spill_refused = true;
+                        socket.bad_request = true;
+                        socket.closing(CloseReason::ServerClose);
+                        socket.request.set_state(ParserState::Complete);
+                        return Some(true);
+                    }
+
+                    if !answer_expect(stream, socket, selected.client_max_body_size) {
+                        socket.bad_request = true;
                         socket.request.set_state(ParserState::Complete);
                         return Some(true);
                     }
                 }
 
+                // Reject as soon as the declared length is known to be too big
+                if let Some(max) = socket.max_body_size
+                    && (socket.request.body_len() > max
+                        || socket.request.expected_body_len().is_some_and(|len| len > max))
+                {
+                    socket.body_too_large = true;
+                    socket.request.set_state(ParserState::Complete);
+                    return Some(true);
+                }
+
+                // Hand what arrived to a script already reading the body
+                if let Some(response) = socket.response.as_mut() {
+                    response.poll_producer();
+                }
+
                 if socket.request.done() {
                     return Some(true);
                 }
@@ -156,7 +442,15 @@ fn read_request(
                 return Some(false);
             }
 
-            Err(_) => return None,
+            // TLS failures surface as InvalidData
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                socket.closing(CloseReason::ProtocolError);
+                return None;
+            }
+            Err(_) => {
+                socket.closing(CloseReason::ReadError);
+                return None;
+            }
         }
     }
 }
@@ -167,53 +461,633 @@ pub fn handle_read_state(
     listener_info: Option<&ListenerInfo>,
 ) -> Option<bool> {
     let read_result = read_request(
-        &mut socket_data.stream,
+        &mut *socket_data.stream,
         &mut socket_data.status,
         listener_info,
     );
 
+    if socket_data.status.response.is_none()
+        && !socket_data.status.body_too_large
+        && let Some(request) = socket_data.status.request.get_before_done()
+        && request.body_stream.is_some()
+    {
+        start_streamed_cgi(socket_data, listener_info);
+    }
+
     match read_result {
         Some(true) => {}
         other => return other,
     }
 
+    if socket_data.status.bad_request {
+        return respond_bad_request(socket_data, listener_info);
+    }
+
+    // CGI waits for the server to grant a slot so cheap responses go out first.
+    // A script reading a streamed body is already running.
+    let request = socket_data.status.request.get()?;
+    if !socket_data.status.heavy_allowed
+        && request.body_stream.is_none()
+        && is_heavy_request(request, &*socket_data.stream, listener_info)
+    {
+        socket_data.status.status = Status::Queued;
+        return Some(false);
+    }
+
+    process_request(socket_data, listener_info)
+}
+
+/// How long a connection in the read phase has been waiting on its client
+pub enum ReadTimeout {
+    None,
+    Idle,    // No request in progress; close quietly
+    Stalled, // Stopped sending mid-request; answer 408
+    Exhausted, // Spent the whole request budget; answer 503
+}
+
+pub fn check_read_timeout(socket: &SocketStatus, now: Instant) -> ReadTimeout {
+    let Some(started) = socket.request_started else {
+        return if now.duration_since(socket.ttl) > IDLE_TIMEOUT {
+            ReadTimeout::Idle
+        } else {
+            ReadTimeout::None
+        };
+    };
+
+    if socket.deadline_passed(now) {
+        return ReadTimeout::Exhausted;
+    }
+
+    // The head has a total deadline so trickled headers can't hold a slot
+    let stalled = if !socket.request.header_done() {
+        now.duration_since(started) > socket.header_timeout
+    } else {
+        now.duration_since(socket.ttl) > socket.body_timeout
+    };
+
+    if stalled {
+        ReadTimeout::Stalled
+    } else {
+        ReadTimeout::None
+    }
+}
+
+/// Answer 408 to a client that stopped sending, then close the connection
+pub fn respond_timeout(socket_data: &mut SocketData, listener_info: Option<&ListenerInfo>) {
+    abandon_request(socket_data, listener_info, HttpResponseBuilder::request_timeout(), CloseReason::Timeout);
+}
+
+/// Answer 503 to a request that used up its request_timeout, then close
+pub fn respond_budget_exhausted(socket_data: &mut SocketData, listener_info: Option<&ListenerInfo>) {
+    abandon_request(socket_data, listener_info, HttpResponseBuilder::service_unavailable(), CloseReason::Timeout);
+}
+
+/// Answer 503 to a request whose body would have to be held in memory while
+/// the worker is over memory_watermark, then close
+pub fn respond_memory_pressure(socket_data: &mut SocketData, listener_info: Option<&ListenerInfo>) {
+    abandon_request(socket_data, listener_info, HttpResponseBuilder::service_unavailable(), CloseReason::ServerClose);
+}
+
+fn abandon_request(
+    socket_data: &mut SocketData,
+    listener_info: Option<&ListenerInfo>,
+    builder: HttpResponseBuilder,
+    reason: CloseReason,
+) {
+    // Like a parse error, the stream position is unknown: never reuse it
+    socket_data.status.bad_request = true;
+    socket_data.status.closing(reason);
+    // The error response itself is only bound by the write timeout
+    socket_data.status.request_timeout = None;
+    socket_data.status.request.set_state(ParserState::Complete);
+    if respond_error_and_close(socket_data, listener_info, builder).is_none() {
+        socket_data.status.status = Status::Finish;
+    }
+}
+
+/// Body of the 400 sent for plain HTTP on a TLS port, like nginx's 497 page
+const PLAIN_HTTP_ON_TLS_PAGE: &str = "<html>\r\n<head><title>400 Bad Request</title></head>\r\n<body>\r\n\
+    <h1>400 Bad Request</h1>\r\n<p>The plain HTTP request was sent to HTTPS port</p>\r\n</body>\r\n</html>\r\n";
+
+/// Answer 400 for a request the parser rejected, then close the connection
+fn respond_bad_request(
+    socket_data: &mut SocketData,
+    listener_info: Option<&ListenerInfo>,
+) -> Option<bool> {
+    let status = &socket_data.status;
+    if status.uri_too_long
+        || status.headers_too_large
+        || status.unsupported_coding
+        || status.expectation_failed
+        || status.spill_refused
+    {
+        let code = if status.uri_too_long {
+            414
+        } else if status.headers_too_large {
+            431
+        } else if status.unsupported_coding {
+            501
+        } else if status.spill_refused {
+            503
+        } else {
+            417
+        };
+        return respond_error_and_close(socket_data, listener_info, HttpResponseBuilder::new(code, reason_phrase(code)));
+    }
+    if socket_data.stream.plain_http_on_tls() {
+        // A custom 400 page would not say what went wrong
+        info!("Plain HTTP request on a TLS port, refusing");
+        let response = HttpResponseBuilder::bad_request()
+            .header("Content-Type", "text/html")
+            .header("Connection", "close")
+            .body(PLAIN_HTTP_ON_TLS_PAGE.as_bytes().to_vec())
+            .build();
+        socket_data.status.response = Some(Box::new(SimpleResponse::new(response)));
+        socket_data.status.status = Status::Write;
+        return Some(true);
+    }
+    respond_error_and_close(socket_data, listener_info, HttpResponseBuilder::bad_request())
+}
+
+fn respond_error_and_close(
+    socket_data: &mut SocketData,
+    listener_info: Option<&ListenerInfo>,
+    builder: HttpResponseBuilder,
+) -> Option<bool> {
+    let info = listener_info?;
+    let hostname = socket_data
+        .status
+        .request
+        .get_before_done()
+        .map(|r| extract_hostname(&r.headers, &*socket_data.stream))
+        .unwrap_or("");
+    let server = select_server(info, hostname);
+    let error_path = get_error_page_path(server, builder.status_code());
+
+    let body = std::fs::read(&error_path).unwrap_or_else(|_| builder.status_text().as_bytes().to_vec());
+    let response = builder
+        .header("Content-Type", "text/html")
+        .header("Connection", "close")
+        .body(body)
+        .build();
+    socket_data.status.response = Some(Box::new(SimpleResponse::new(response)));
+    socket_data.status.status = Status::Write;
+    Some(true)
+}
+
+/// Same URL on the server's HTTPS port
+fn https_location(server: &ServerConfig, hostname: &str, request: &HttpRequest) -> String {
+    let host = if hostname.is_empty() { server.server_name.as_str() } else { hostname };
+    let port = match server.https_port {
+        Some(443) | None => String::new(),
+        Some(port) => format!(":{}", port),
+    };
+    // The path was decoded while parsing, encode it again for the header
+    let path: Vec<_> = request.path.split('/').map(urlencoding::encode).collect();
+    let mut location = format!("https://{}{}{}", host, port, path.join("/"));
+    if !request.query_string.is_empty() {
+        location.push('?');
+        location.push_str(&request.query_string);
+    }
+    location
+}
+
+/// Whether routing will hand the request to a CGI script, so its body can be
+/// piped to the script while it arrives. Mirrors the checks of `route_request`;
+/// bodies it might still turn away (too large, redirects, method overrides)
+/// and FastCGI routes are read in full as usual.
+fn streams_to_cgi(server: &ServerConfig, request: &HttpRequest, is_tls: bool) -> bool {
+    if request.method != HttpMethod::POST || admin::is_admin_path(&request.path) {
+        return false;
+    }
+    if server.method_override
+        && (request.headers.get("x-http-method-override").is_some()
+            || request.headers.get("content-type").is_some_and(|v| form::is_urlencoded(v)))
+    {
+        return false;
+    }
+    let too_large = request
+        .headers
+        .get("content-length")
+        .and_then(|v| v.trim().parse::<usize>().ok())
+        .is_none_or(|len| len > server.client_max_body_size);
+    if too_large {
+        return false;
+    }
+
+    let Some(route) = find_matching_route(server, &request.path) else {
+        return false;
+    };
+    let redirected = (!is_tls && route.force_https.unwrap_or(server.force_https)) || route.redirect.is_some();
+    let allowed = route.methods.iter().any(|m| m == "POST") && !admin::rejects(route, &request.method);
+    // The script would run before route_request answers the 401
+    !redirected
+        && allowed
+        && auth::allows(route, request)
+        && route.proxy_pass.is_none()
+        && route.fastcgi_pass.is_none()
+        && route_script(route, &request.path).is_some()
+}
+
+/// Start the script of a request whose body is streamed, while the body is
+/// still being read. The response waits in the socket state until routing
+/// picks it up once the request is complete.
+fn start_streamed_cgi(socket_data: &mut SocketData, listener_info: Option<&ListenerInfo>) {
+    let (Some(info), Some(request)) = (listener_info, socket_data.status.request.get_before_done()) else {
+        return;
+    };
+    let server = select_server(info, extract_hostname(&request.headers, &*socket_data.stream));
+    let route = find_matching_route(server, &request.path);
+    let failover_route = route.and_then(|route| failover_root(server, route));
+    let route = failover_route.as_ref().or(route);
+    let script = route.and_then(|route| {
+        let (_, script_name, path_info) = route_script(route, &request.path)?;
+        let script_path = resolve_file_path(server, route, script_name)?;
+        Some((route, script_name.to_string(), path_info.to_string(), script_path))
+    });
+    let Some((route, script_name, path_info, script_path)) = script else {
+        abandon_request(socket_data, listener_info, HttpResponseBuilder::internal_error(), CloseReason::ServerClose);
+        return;
+    };
+
+    let mut context = CgiContext::from_request(request);
+    context.script_name = script_name;
+    context.path_info = path_info;
+    let started = run_script(info, server, route, context, &script_path, socket_data);
+    // Keep reading the body; routing switches to writing once it is complete
+    socket_data.status.status = Status::Read;
+    if !started {
+        abandon_request(socket_data, listener_info, HttpResponseBuilder::internal_error(), CloseReason::ServerClose);
+    }
+}
+
+/// Whether the request will run an expensive handler (CGI, directory summaries)
+fn is_heavy_request(
+    request: &HttpRequest,
+    stream: &dyn Transport,
+    listener_info: Option<&ListenerInfo>,
+) -> bool {
+    let Some(info) = listener_info else {
+        return false;
+    };
+    let server = select_server(info, extract_hostname(&request.headers, stream));
+    let Some(route) = find_matching_route(server, &request.path) else {
+        return false;
+    };
+    // A cold `?stat=1` walks the whole subtree
+    let stat = route.stat == Some(true) && wants_stat(&request.query_string);
+    // A batch copies and moves files synchronously, one operation after another
+    let batch = route.batch == Some(true)
+        && request.method == HttpMethod::POST
+        && request.query_string == "batch";
+    stat || batch || route_script(route, &request.path).is_some()
+}
+
+/// Route a fully read request and prepare its response
+pub fn process_request(
+    socket_data: &mut SocketData,
+    listener_info: Option<&ListenerInfo>,
+) -> Option<bool> {
+    if socket_data.status.deadline_passed(Instant::now()) {
+        info!("Request budget exhausted before routing, sending 503");
+        respond_budget_exhausted(socket_data, listener_info);
+        return Some(true);
+    }
+
+    socket_data.status.requests += 1;
+    let result = route_request(socket_data, listener_info);
+    if socket_data.status.deadline_passed(Instant::now()) {
+        info!("Handler overran the request budget");
+        // Let the response out; only the write timeout applies from here
+        socket_data.status.request_timeout = None;
+    }
+    if result.is_some() {
+        socket_data.status.error_pending = true;
+        render_error_handler(socket_data, listener_info);
+        adapt_response(socket_data);
+    }
+    // Time spent in handlers doesn't count against the client's write timeout
+    socket_data.status.ttl = Instant::now();
+    result
+}
+
+/// Fit the routed response to the connection: HTTP/1.0 framing, and
+/// `Connection: close` once the connection has served enough
+fn adapt_response(socket_data: &mut SocketData) {
+    // HTTP/1.0 clients don't know chunked framing, the version has to match too
+    if let Some(request) = socket_data.status.request.get()
+        && request.version == "HTTP/1.0"
+        && let Some(response) = socket_data.status.response.take()
+    {
+        let keep_alive = should_keep_alive(request);
+        socket_data.status.response = Some(filters::http10(response, keep_alive));
+    }
+    // Tell the client before it sends another request down this connection
+    if (socket_data.status.last_request || socket_data.status.worn_out(Instant::now()))
+        && let Some(response) = socket_data.status.response.take()
+    {
+        socket_data.status.last_request = true;
+        socket_data.status.response = Some(filters::close_connection(response));
+    }
+}
+
+/// Send a response whose status only became known once its producer
+/// answered (CGI, FastCGI, a backend) through the error handler
+pub fn render_pending_error(socket_data: &mut SocketData, listener_info: Option<&ListenerInfo>) {
+    if socket_data.status.error_pending && render_error_handler(socket_data, listener_info) {
+        adapt_response(socket_data);
+    }
+}
+
+/// Replace a 4xx/5xx response with the output of the server's error_handler
+/// script, falling back to the original response if the script fails. Runs
+/// once per response, as soon as its status is known and before any of it
+/// is sent; true when the response was replaced.
+///
+/// 401, 405 and 416 keep their static response: their `WWW-Authenticate`,
+/// `Allow` and `Content-Range` headers are part of the answer.
+fn render_error_handler(socket_data: &mut SocketData, listener_info: Option<&ListenerInfo>) -> bool {
+    if !socket_data.status.error_pending {
+        return false;
+    }
+    let Some(response) = socket_data.status.response.as_deref() else {
+        socket_data.status.error_pending = false;
+        return false;
+    };
+    let Some(status) = response.status() else {
+        return false;
+    };
+    socket_data.status.error_pending = false;
+    if status < 400 || matches!(status, 401 | 405 | 416) || socket_data.status.response_status.is_some() {
+        return false;
+    }
+    let (Some(info), Some(request)) = (listener_info, socket_data.status.request.get()) else {
+        return false;
+    };
+
+    let server = select_server(info, extract_hostname(&request.headers, &*socket_data.stream));
+    let Some(handler) = &server.error_handler else {
+        return false;
+    };
+    // The handler's own failures keep the static response
+    if request.path == *handler {
+        return false;
+    }
+    let Some(route) = find_matching_route(server, handler) else {
+        return false;
+    };
+    if !route.cgi.iter().any(|h| handler.ends_with(h.extension.as_str())) || !script_allowed(route, handler) {
+        return false;
+    }
+    let Some(script_path) = resolve_file_path(server, route, handler) else {
+        return false;
+    };
+
+    let mut context = CgiContext::from_request(request);
+    context.env = vec![
+        ("REDIRECT_STATUS".to_string(), status.to_string()),
+        ("REDIRECT_URL".to_string(), request.path.clone()),
+        ("REDIRECT_QUERY_STRING".to_string(), request.query_string.clone()),
+        ("REDIRECT_REQUEST_METHOD".to_string(), context.method.clone()),
+    ];
+    // The handler renders a page, it never consumes the failed request's body
+    context.method = "GET".to_string();
+    context.path = handler.clone();
+    context.script_name = handler.clone();
+    context.query_string = String::new();
+    context.body = Vec::new();
+    context.body_file = None;
+    context.body_stream = None;
+    context.status = status;
+
+    debug!("Rendering {} through error handler {}", status, handler);
+    let original = socket_data.status.response.take();
+    let rendered = run_script(info, server, route, context, &script_path, socket_data);
+    if !rendered {
+        socket_data.status.response = original;
+    }
+    socket_data.status.status = Status::Write;
+    rendered
+}
+
+/// Run a route's script through its FastCGI application, or as a CGI process.
+/// The script must finish within the request budget and the route's `cgi_timeout`.
+fn run_script(
+    info: &ListenerInfo,
+    server: &ServerConfig,
+    route: &Route,
+    mut context: CgiContext,
+    script_path: &str,
+    socket_data: &mut SocketData,
+) -> bool {
+    let host = context
+        .headers
+        .iter()
+        .find(|(k, _)| k == "host")
+        .and_then(|(_, v)| v.split(':').next())
+        .filter(|h| !h.is_empty());
+    context.server_name = host.unwrap_or(&server.server_name).to_string();
+    context.server_port = info.port;
+    context.headers_allow = route.cgi_headers_allow.clone();
+    context.headers_deny = route.cgi_headers_deny.clone();
+    if route.cgi_session == Some(true)
+        && let Some(id) = context.session_id.clone()
+    {
+        context.session = Some(CgiSession {
+            store: socket_data.session_store.clone(),
+            id,
+            cookie: context.session_cookie.take(),
+        });
+    }
+    let session_env = context.session.as_ref().map(CgiSession::variables).unwrap_or_default();
+    // Variables of the request (error handler's REDIRECT_*) win over the
+    // session's, which win over the route's
+    let request_env = std::mem::take(&mut context.env);
+    context.env = route.cgi_env.iter().cloned().chain(session_env).chain(request_env).collect();
+    if !context.path_info.is_empty() {
+        context.path_translated = find_matching_route(server, &context.path_info)
+            .and_then(|r| resolve_file_path(server, r, &context.path_info));
+    }
+
+    let cgi_deadline = route.cgi_timeout.map(|timeout| Instant::now() + timeout);
+    context.deadline = match (socket_data.status.deadline(), cgi_deadline) {
+        (Some(request), Some(cgi)) => Some(request.min(cgi)),
+        (request, cgi) => request.or(cgi),
+    };
+    context.timeout_page = Some(get_error_page_path(server, 504));
+    context.buffer_size = route.cgi_buffer_size.unwrap_or(DEFAULT_BUFFER_SIZE);
+    context.max_output = route.cgi_max_output;
+    match &route.fastcgi_pass {
+        Some(address) => run_fastcgi(address, context, script_path, socket_data),
+        None => run_cgi(route, context, script_path, socket_data),
+    }
+}
+
+/// Methods a POST may be turned into by `method_override`
+const OVERRIDABLE_METHODS: [&str; 3] = ["DELETE", "PUT", "PATCH"];
+
+/// For servers with `method_override`, turn a POST into the method named by
+/// `X-HTTP-Method-Override` or a `_method` form field, so clients behind
+/// proxies that only pass GET and POST can still delete. Runs before routing,
+/// so the route's method checks see the overridden method.
+fn apply_method_override(socket_data: &mut SocketData, info: &ListenerInfo) {
+    let Some(request) = socket_data.status.request.get() else {
+        return;
+    };
+    if request.method != HttpMethod::POST {
+        return;
+    }
+    let server = select_server(info, extract_hostname(&request.headers, &*socket_data.stream));
+    if !server.method_override {
+        return;
+    }
+
+    let (method, source) = match request.headers.get("x-http-method-override") {
+        Some(value) => (value.trim().to_ascii_uppercase(), "X-HTTP-Method-Override"),
+        None => match request.form_param("_method") {
+            Some(value) => (value.trim().to_ascii_uppercase(), "_method"),
+            None => return,
+        },
+    };
+    if !OVERRIDABLE_METHODS.contains(&method.as_str()) {
+        info!("Method override: ignoring {} '{}' for {}", source, method, request.path);
+        return;
+    }
+
+    info!("Method override: POST -> {} for {} (via {})", method, request.path, source);
+    if let Some(request) = socket_data.status.request.get_mut() {
+        request.method = HttpMethod::from_str(&method);
+    }
+}
+
+/// `Cache-Control: no-cache` from an admin client skips the server's caches:
+/// compressed copies are made afresh and directory summaries walked again.
+/// Anyone else gets the cached answers as usual.
+fn apply_cache_bypass(socket_data: &mut SocketData) {
+    let Some(request) = socket_data.status.request.get() else {
+        return;
+    };
+    let no_cache = request
+        .headers
+        .get_all("cache-control")
+        .flat_map(|value| value.split(','))
+        .any(|directive| directive.trim().eq_ignore_ascii_case("no-cache"));
+    if !no_cache || !admin::privileged(socket_data.stream.peer_addr().ok().map(|addr| addr.ip())) {
+        return;
+    }
+    info!("Cache bypass requested for {}", request.path);
+    if let Some(request) = socket_data.status.request.get_mut() {
+        request.cache_bypass = true;
+    }
+}
+
+fn route_request(
+    socket_data: &mut SocketData,
+    listener_info: Option<&ListenerInfo>,
+) -> Option<bool> {
+    socket_data.status.heavy_allowed = false;
+    if let Some(info) = listener_info {
+        apply_method_override(socket_data, info);
+    }
+    apply_cache_bypass(socket_data);
     let request: &HttpRequest = socket_data.status.request.get()?;
 
     // handle cookies and sessions
     let cookie: Cookie = handle_session(request, &mut socket_data.session_store);
 
     // Select server based on Host header
-    let hostname = extract_hostname(&request.headers);
+    let hostname = extract_hostname(&request.headers, &*socket_data.stream);
     let info = listener_info.expect("No listener info available");
     let selected_server: &ServerConfig = select_server(info, hostname);
 
     // check if the socket says body too large
     match socket_data.status.body_too_large {
         true => {
-            println!(" too large qflksqdjflmqsdkjflqmskdfjlqskdjf");
             // Body is too large → return 413 Payload Too Large
             let response = HttpResponseBuilder::new(413, "Payload Too Large")
+                .header("Connection", "close")
                 .body(b"Request body too large".to_vec())
                 .build();
+            // The rest of the body is still on its way, the stream can't be reused
+            socket_data.status.bad_request = true;
+            socket_data.status.closing(CloseReason::BodyTooLarge);
             socket_data.status.response = Some(Box::new(SimpleResponse::new(response)));
             socket_data.status.status = Status::Write;
 
             return Some(true);
         }
         false => {
-            println!("false false false ")
             // Body size is fine → continue processing
         }
     }
 
+    let peer = socket_data.stream.peer_addr().ok().map(|addr| addr.ip());
+    if let Some(response) = admin::handle(request, peer) {
+        socket_data.status.response = Some(response);
+        socket_data.status.status = Status::Write;
+        return Some(true);
+    }
+
+    // Cache eviction answers for any path, but only to admin clients
+    if request.method.to_str() == "PURGE" {
+        let response_bytes = if admin::privileged(peer) {
+            handle_purge(selected_server, request, &cookie)
+        } else {
+            let error_path = get_error_page_path(selected_server, 403);
+            HttpResponseBuilder::serve_error_page(&error_path, 403, "Forbidden", &cookie)
+        };
+        socket_data.status.response = Some(Box::new(SimpleResponse::new(response_bytes)));
+        socket_data.status.status = Status::Write;
+        return Some(true);
+    }
+
+    // Unknown to every route: no point in looking for one
+    let method = request.method.to_str();
+    if !selected_server.routes.iter().any(|route| route_implements(route, method)) {
+        let response_bytes = handle_not_implemented(selected_server, method, &cookie);
+        socket_data.status.response = Some(Box::new(SimpleResponse::new(response_bytes)));
+        socket_data.status.status = Status::Write;
+        return Some(true);
+    }
+
+    // `OPTIONS *` asks what the server supports, not about a resource
+    if request.path == "*" {
+        let response_bytes = if request.method.to_str() == "OPTIONS" {
+            handle_server_options(selected_server, &cookie)
+        } else {
+            HttpResponseBuilder::bad_request().cookie(&cookie).build()
+        };
+        socket_data.status.response = Some(Box::new(SimpleResponse::new(response_bytes)));
+        socket_data.status.status = Status::Write;
+        return Some(true);
+    }
+
     let selected_route = find_matching_route(selected_server, &request.path);
 
     if let Some(route) = selected_route {
-        if let Some(redirect) = &route.redirect {
+        stats::record_route(&selected_server.server_name, &route.path);
+        socket_data.status.route = Some(format!("{}{}", selected_server.server_name, route.path));
+        let split_route = pick_split(route, &cookie, peer);
+        let route = split_route.as_ref().unwrap_or(route);
+        let failover_route = failover_root(selected_server, route);
+        let route = failover_route.as_ref().unwrap_or(route);
+
+        if !socket_data.stream.is_tls()
+            && route.force_https.unwrap_or(selected_server.force_https)
+        {
+            let location = https_location(selected_server, hostname, request);
+            info!("Redirecting plain HTTP request to {}", location);
+            let response_bytes = HttpResponseBuilder::moved_permanently(&location)
+                .cookie(&cookie)
+                .build();
+            socket_data.status.response = Some(Box::new(SimpleResponse::new(response_bytes)));
+        } else if let Some(redirect) = &route.redirect {
             let response_bytes = HttpResponseBuilder::redirect(redirect)
                 .cookie(&cookie)
                 .build();
             socket_data.status.response = Some(Box::new(SimpleResponse::new(response_bytes)));
+        } else if let Some(response_bytes) = auth::challenge(route, request, &cookie) {
+            socket_data.status.response = Some(Box::new(SimpleResponse::new(response_bytes)));
         } else {
             let request_method = &request.method;
             let method_allowed = route
@@ -221,29 +1095,98 @@ pub fn handle_read_state(
                 .iter()
                 .any(|m| HttpMethod::from_str(m) == *request_method);
 
-            if !method_allowed {
+            // Routes handing OPTIONS to a script or upstream let it answer
+            let delegates_options = route.methods.iter().any(|m| m == "OPTIONS")
+                && (!route.cgi.is_empty() || route.proxy_pass.is_some());
+
+            if request_method.to_str() == "OPTIONS" && !delegates_options {
+                let allowed = route_methods(route);
+                let response_bytes = handle_options(selected_server, route, &allowed, &cookie);
+                socket_data.status.response = Some(Box::new(SimpleResponse::new(response_bytes)));
+            } else if admin::rejects(route, request_method) {
+                info!("Read-only: refusing {} {}", request_method.to_str(), request.path);
+                let allowed = admin::read_only_methods(route);
+                let response_bytes = handle_method_not_allowed(&allowed, selected_server, &cookie);
+                socket_data.status.response = Some(Box::new(SimpleResponse::new(response_bytes)));
+            } else if !method_allowed {
                 let allowed = &route.methods;
-                let response_bytes = handle_method_not_allowed(&allowed, &selected_server, &cookie);
+                let response_bytes = handle_method_not_allowed(allowed, selected_server, &cookie);
                 socket_data.status.response = Some(Box::new(SimpleResponse::new(response_bytes)));
             } else {
                 let file_path = resolve_file_path(selected_server, route, &request.path)
-                    .unwrap_or_else(|| "".to_string());
+                    .unwrap_or_default();
 
-                if let Some(cgi_ext) = &route.cgi {
-                    if request.path.ends_with(cgi_ext) {
-                        let cgi_context = crate::cgi::CgiContext::from_request(request);
-                        if run_cgi(route, cgi_context, &file_path, socket_data) {
-                            return Some(true);
-                        } else {
-                            return None;
-                        }
+                if let Some(target) = &route.proxy_pass {
+                    let response = upstream::proxy(target, request, socket_data, selected_server.proxy_max_response_size);
+                    socket_data.status.response = Some(filters::apply(response, selected_server, route, request));
+                    socket_data.status.status = Status::Write;
+                    return Some(true);
+                }
+
+                // Started while the body was still arriving
+                if request.body_stream.is_some()
+                    && let Some(response) = socket_data.status.response.take()
+                {
+                    socket_data.status.response = Some(filters::apply(response, selected_server, route, request));
+                    socket_data.status.status = Status::Write;
+                    return Some(true);
+                }
+
+                // A script outside `cgi_scripts` is neither run nor served as source
+                if let Some((_, script_name, _)) = split_script_path(&request.path, &route.cgi)
+                    && !script_allowed(route, script_name)
+                {
+                    info!("CGI: {} is not in cgi_scripts, refusing", script_name);
+                    let error_path = get_error_page_path(selected_server, 403);
+                    let response_bytes = HttpResponseBuilder::serve_error_page(&error_path, 403, "Forbidden", &cookie);
+                    socket_data.status.response = Some(Box::new(SimpleResponse::new(response_bytes)));
+                    socket_data.status.status = Status::Write;
+                    return Some(true);
+                }
+
+                if let Some((_, script_name, path_info)) = route_script(route, &request.path) {
+                    let script_path = resolve_file_path(selected_server, route, script_name)
+                        .unwrap_or_default();
+                    let mut cgi_context = CgiContext::from_request(request);
+                    cgi_context.script_name = script_name.to_string();
+                    cgi_context.path_info = path_info.to_string();
+                    cgi_context.session_id = Some(cookie.value().to_string());
+                    cgi_context.session_cookie = Some(cookie.clone());
+                    // Failures still queue an error response
+                    run_script(info, selected_server, route, cgi_context, &script_path, socket_data);
+                    if let Some(request) = socket_data.status.request.get()
+                        && let Some(response) = socket_data.status.response.take()
+                    {
+                        socket_data.status.response = Some(filters::apply(response, selected_server, route, request));
                     }
+                    return Some(true);
+                }
+
+                if matches!(request_method, HttpMethod::DELETE | HttpMethod::POST | HttpMethod::PATCH)
+                    && let Some(response_bytes) = check_unmodified_since(&file_path, request, &cookie)
+                {
+                    socket_data.status.response = Some(Box::new(SimpleResponse::new(response_bytes)));
+                    socket_data.status.status = Status::Write;
+                    return Some(true);
                 }
 
                 let response: Box<dyn HttpResponseCommon> = match request_method {
-                    HttpMethod::GET => handle_get(&file_path, &selected_server, &request, &cookie),
+                    HttpMethod::GET if route.stat == Some(true) && wants_stat(&request.query_string) => {
+                        let response_bytes =
+                            handle_stat(&file_path, request, selected_server, &cookie);
+                        Box::new(SimpleResponse::new(response_bytes))
+                    }
+                    HttpMethod::GET => {
+                        handle_get(&file_path, selected_server, route, request, &cookie)
+                    }
+                    HttpMethod::POST
+                        if route.batch == Some(true) && request.query_string == "batch" =>
+                    {
+                        let response_bytes = handle_batch(selected_server, request, &cookie);
+                        Box::new(SimpleResponse::new(response_bytes))
+                    }
                     HttpMethod::POST => {
-                        let response_bytes = handle_post(&file_path, &request, &cookie);
+                        let response_bytes = handle_post(&file_path, request, &cookie);
                         Box::new(SimpleResponse::new(response_bytes))
                     }
                     HttpMethod::DELETE => {
@@ -251,14 +1194,26 @@ pub fn handle_read_state(
                         let response_bytes = handle_delete(&file_path, &error_path, &cookie);
                         Box::new(SimpleResponse::new(response_bytes))
                     }
-                    HttpMethod::Other(_) => {
-                        let allowed = &route.methods;
+                    HttpMethod::PATCH => {
+                        let error_path = get_error_page_path(selected_server, 404);
+                        let response_bytes = handle_patch(&file_path, &error_path, request, &cookie);
+                        Box::new(SimpleResponse::new(response_bytes))
+                    }
+                    HttpMethod::Other(method)
+                        if route.webdav == Some(true) && dav::METHODS.contains(&method.as_str()) =>
+                    {
                         let response_bytes =
-                            handle_method_not_allowed(&allowed, &selected_server, &cookie);
+                            dav::handle(method, &file_path, selected_server, route, request, &cookie);
+                        Box::new(SimpleResponse::new(response_bytes))
+                    }
+                    // Listed on a route that has nothing to answer it with
+                    HttpMethod::Other(method) => {
+                        let response_bytes = handle_not_implemented(selected_server, method, &cookie);
                         Box::new(SimpleResponse::new(response_bytes))
                     }
                 };
 
+                let response = filters::apply(response, selected_server, route, request);
                 socket_data.status.response = Some(response);
             }
         }
@@ -271,4 +1226,203 @@ pub fn handle_read_state(
 
     socket_data.status.status = Status::Write;
     Some(true)
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::HttpClient;
+    use crate::config::{ListenErrorPolicy, parse_config};
+    use crate::transport::{ReadStep, ScriptedTransport};
+    use crate::utils::session::SessionStore;
+    use mio::Token;
+    use std::fs;
+    use std::time::Duration;
+
+    const CONFIG: &str = "servers:
+  - server_name: \"test\"
+    host: 127.0.0.1
+    ports:
+      - 8080
+    default_server: true
+    root: \"./public\"
+    client_max_body_size: 1000
+    routes:
+      - path: \"/\"
+        methods: [\"GET\", \"POST\"]
+        root: \".\"
+";
+
+    fn listener() -> ListenerInfo {
+        listener_for(CONFIG)
+    }
+
+    fn listener_for(config: &str) -> ListenerInfo {
+        let config = parse_config(config).expect("test config");
+        ListenerInfo {
+            listener: None,
+            host: "127.0.0.1".to_string(),
+            port: 8080,
+            servers: config.servers,
+            server_indices: vec![0],
+            default_server_index: 0,
+            tls: None,
+            proxy_protocol: false,
+            policy: ListenErrorPolicy::Abort,
+            failures: 0,
+            retry_at: None,
+            leader: None,
+        }
+    }
+
+    fn socket(transport: ScriptedTransport) -> SocketData {
+        SocketData {
+            stream: Box::new(transport),
+            status: SocketStatus::new(),
+            listener_token: Token(0),
+            session_store: SessionStore::new(),
+            http_client: HttpClient::new(),
+        }
+    }
+
+    #[test]
+    fn request_line_split_across_reads() {
+        let info = listener();
+        let mut socket = socket(
+            ScriptedTransport::new()
+                .data(b"GET /blog")
+                .read(ReadStep::WouldBlock)
+                .data(b".html HTTP/1.1\r\nHost: test\r\n\r\n"),
+        );
+
+        assert_eq!(handle_read_state(&mut socket, Some(&info)), Some(false));
+        assert!(socket.status.request.get_before_done().is_none());
+        assert_eq!(socket.status.status, Status::Read);
+
+        assert_eq!(handle_read_state(&mut socket, Some(&info)), Some(true));
+        let request = socket.status.request.get().expect("parsed request");
+        assert_eq!(request.path, "/blog.html");
+        assert_eq!(socket.status.status, Status::Write);
+        assert!(socket.status.response.is_some());
+    }
+
+    #[test]
+    fn body_resumes_after_would_block() {
+        let info = listener();
+        let mut socket = socket(
+            ScriptedTransport::new()
+                .data(b"POST / HTTP/1.1\r\nHost: test\r\nContent-Length: 10\r\n\r\nhello")
+                .read(ReadStep::WouldBlock)
+                .data(b"world"),
+        );
+
+        assert_eq!(handle_read_state(&mut socket, Some(&info)), Some(false));
+        assert!(socket.status.server_selected);
+        assert!(!socket.status.request.done());
+        assert_eq!(socket.status.request.body_len(), 5);
+
+        assert_eq!(handle_read_state(&mut socket, Some(&info)), Some(true));
+        let request = socket.status.request.get().expect("complete request");
+        assert_eq!(request.body.as_deref(), Some(&b"helloworld"[..]));
+        assert_eq!(socket.status.status, Status::Write);
+    }
+
+    #[test]
+    fn eof_before_a_request_closes() {
+        let info = listener();
+        let mut socket = socket(ScriptedTransport::new().read(ReadStep::Eof));
+
+        assert_eq!(handle_read_state(&mut socket, Some(&info)), None);
+        assert_eq!(socket.status.close_reason, Some(CloseReason::ClientEof));
+        assert!(socket.status.response.is_none());
+    }
+
+    #[test]
+    fn eof_mid_body_closes_without_a_response() {
+        let info = listener();
+        let mut socket = socket(
+            ScriptedTransport::new()
+                .data(b"POST / HTTP/1.1\r\nHost: test\r\nContent-Length: 10\r\n\r\nhel")
+                .read(ReadStep::Eof),
+        );
+
+        assert_eq!(handle_read_state(&mut socket, Some(&info)), None);
+        assert_eq!(socket.status.close_reason, Some(CloseReason::ClientEof));
+        assert!(!socket.status.request.done());
+        assert!(socket.status.response.is_none());
+    }
+
+    #[test]
+    fn transfer_codings_other_than_chunked_are_refused() {
+        let info = listener();
+        for (coding, status) in [("gzip, chunked", 501), ("chunked, gzip", 400)] {
+            let request = format!("POST / HTTP/1.1\r\nHost: test\r\nTransfer-Encoding: {}\r\n\r\n", coding);
+            let mut socket = socket(ScriptedTransport::new().data(request.as_bytes()));
+
+            assert_eq!(handle_read_state(&mut socket, Some(&info)), Some(true));
+            let response = socket.status.response.as_ref().expect("error response");
+            assert_eq!(response.status(), Some(status));
+            assert!(socket.status.bad_request);
+        }
+    }
+
+    /// Read a request and let its (CGI) response finish
+    fn respond(info: &ListenerInfo, request: &[u8]) -> Box<dyn HttpResponseCommon> {
+        let mut socket = socket(ScriptedTransport::new().data(request));
+        assert_eq!(handle_read_state(&mut socket, Some(info)), Some(true));
+        render_pending_error(&mut socket, Some(info));
+        let mut response = socket.status.response.take().expect("response");
+        let started = Instant::now();
+        while response.status().is_none() && started.elapsed() < Duration::from_secs(5) {
+            response.poll_producer();
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        response
+    }
+
+    #[test]
+    fn error_handler_renders_errors_but_keeps_the_401_challenge() {
+        let root = std::env::temp_dir().join(format!("error-handler-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("errors.sh"), "printf 'Content-Type: text/plain\\n\\nrendered %s\\n' \"$REDIRECT_STATUS\"\n").unwrap();
+        let config = format!(
+            "servers:
+  - server_name: \"test\"
+    host: 127.0.0.1
+    ports:
+      - 8080
+    default_server: true
+    root: \"{}\"
+    error_handler: \"/errors.sh\"
+    routes:
+      - path: \"/private\"
+        methods: [\"GET\"]
+        root: \".\"
+        auth: [basic]
+      - path: \"/\"
+        methods: [\"GET\"]
+        root: \".\"
+        cgi: {{\".sh\": \"/bin/sh\"}}
+",
+            root.display()
+        );
+        let info = listener_for(&config);
+
+        let mut missing = respond(&info, b"GET /missing.html HTTP/1.1\r\nHost: test\r\n\r\n");
+        assert_eq!(missing.status(), Some(404));
+        let mut sent = Vec::new();
+        while !missing.is_finished() {
+            missing.fill_if_needed().unwrap();
+            sent.extend_from_slice(missing.peek());
+            let n = missing.peek().len();
+            missing.next(n);
+        }
+        assert!(String::from_utf8_lossy(&sent).contains("rendered 404"));
+
+        let denied = respond(&info, b"GET /private/a.html HTTP/1.1\r\nHost: test\r\n\r\n");
+        assert_eq!(denied.status(), Some(401));
+        let head = String::from_utf8_lossy(denied.peek()).to_ascii_lowercase();
+        assert!(head.contains("www-authenticate: basic realm="));
+
+        let _ = fs::remove_dir_all(&root);
+    }
+}
@@ -1,4 +1,15 @@
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use uuid::Uuid;
+
 use crate::utils::cookie::extract_session_id;
+use crate::utils::form;
 use crate::utils::{HttpHeaders, HttpMethod};
 
 #[derive(Clone)]
@@ -9,7 +20,275 @@ pub struct HttpRequest {
     pub version: String,
     pub headers: HttpHeaders,
     pub body: Option<Vec<u8>>,
+    pub body_file: Option<Rc<SpooledBody>>, // Set instead of `body` for spilled uploads
+    pub body_stream: Option<Rc<RefCell<BodyStream>>>, // Set instead of `body` when piped to a handler
     pub session_id: Option<String>, 
+    pub cache_bypass: bool, // A privileged client sent `Cache-Control: no-cache`
+}
+
+/// Request body spilled to a temporary file; the file is removed with the last handle
+pub struct SpooledBody {
+    path: PathBuf,
+    len: usize,
+}
+
+impl SpooledBody {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn open(&self) -> io::Result<File> {
+        File::open(&self.path)
+    }
+}
+
+impl Drop for SpooledBody {
+    fn drop(&mut self) {
+        // Handlers may have moved the file into place already
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Request body handed to its consumer while it is still being received.
+/// The reader drains what arrived; the socket is only read again once the
+/// backlog is below `STREAM_BACKLOG_LIMIT`.
+pub struct BodyStream {
+    data: VecDeque<u8>,
+    len: usize, // Declared Content-Length
+    complete: bool,
+    abandoned: bool, // The consumer stopped reading; the rest is discarded
+}
+
+/// Received body bytes the consumer may lag behind before reading pauses
+pub const STREAM_BACKLOG_LIMIT: usize = 64 * 1024;
+
+impl BodyStream {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Bytes received but not consumed yet
+    pub fn backlog(&self) -> usize {
+        self.data.len()
+    }
+}
+
+/// Reading end of a streamed body: `WouldBlock` until more has arrived,
+/// end of file once the whole body went through
+pub struct BodyStreamReader(pub Rc<RefCell<BodyStream>>);
+
+impl Read for BodyStreamReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut stream = self.0.borrow_mut();
+        if stream.data.is_empty() {
+            return if stream.complete {
+                Ok(0)
+            } else {
+                Err(io::ErrorKind::WouldBlock.into())
+            };
+        }
+        let n = stream.data.len().min(buf.len());
+        for (slot, byte) in buf.iter_mut().zip(stream.data.drain(..n)) {
+            *slot = byte;
+        }
+        Ok(n)
+    }
+}
+
+impl Drop for BodyStreamReader {
+    fn drop(&mut self) {
+        // The request must still be read to the end to keep the connection usable
+        let mut stream = self.0.borrow_mut();
+        stream.abandoned = true;
+        stream.data = VecDeque::new();
+    }
+}
+
+/// Bounds on the request head, checked as it arrives so an endless request
+/// line or header section is refused before it fills memory
+#[derive(Debug, Clone, Copy)]
+pub struct HeadLimits {
+    pub max_request_line: usize, // Request line, method and version included
+    pub max_header_size: usize,  // Header fields after the request line, in bytes
+    pub max_header_count: usize,
+    pub strict: bool, // Heads must follow RFC 9112 to the letter, see `check_strict_head`
+}
+
+/// Error for a request line over `max_request_line`, answered with 414
+pub const URI_TOO_LONG: &str = "Request line too long";
+/// Error for a header section over its size or count limit, answered with 431
+pub const HEADERS_TOO_LARGE: &str = "Request header fields too large";
+/// Error for a body sent with a transfer coding other than chunked, answered with 501
+pub const UNSUPPORTED_TRANSFER_CODING: &str = "Transfer coding not implemented";
+
+/// Why the start of a request was dropped without an answer: traffic that
+/// isn't HTTP at all, closed before the parser spends anything on it
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum LineRejection {
+    TlsHandshake, // TLS ClientHello on a plain HTTP port
+    Binary,       // Control bytes where the request line should be
+    Method,       // Method that isn't a token, or far longer than any real one
+    Target,       // Target that is neither a path, `*` nor an absolute URI
+}
+
+impl LineRejection {
+    pub const ALL: [LineRejection; 4] = [
+        LineRejection::TlsHandshake,
+        LineRejection::Binary,
+        LineRejection::Method,
+        LineRejection::Target,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LineRejection::TlsHandshake => "tls_handshake",
+            LineRejection::Binary => "binary",
+            LineRejection::Method => "method",
+            LineRejection::Target => "target",
+        }
+    }
+}
+
+/// Methods longer than this are scans, not HTTP
+const MAX_METHOD_LEN: usize = 20;
+
+/// Characters allowed in a token: methods and header field names (RFC 9110 §5.6.2)
+fn is_tchar(b: &u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(b)
+}
+
+/// Look at what arrived of a request line, complete or not
+fn screen_request_line(data: &[u8]) -> Option<LineRejection> {
+    // Empty lines may come before a request (RFC 9112 §2.2)
+    let start = data.iter().position(|b| *b != b'\r' && *b != b'\n')?;
+    let data = &data[start..];
+    // A TLS record starts with its content type, 22 for a handshake
+    if data[0] == 0x16 {
+        return Some(LineRejection::TlsHandshake);
+    }
+
+    let line = match data.iter().position(|b| *b == b'\n') {
+        Some(end) => data[..end].strip_suffix(b"\r").unwrap_or(&data[..end]),
+        None => data,
+    };
+    if line.iter().any(|b| b.is_ascii_control()) {
+        return Some(LineRejection::Binary);
+    }
+
+    let mut parts = line.splitn(3, |b| *b == b' ');
+    let method = parts.next().unwrap_or_default();
+    if method.len() > MAX_METHOD_LEN || !method.iter().all(is_tchar) {
+        return Some(LineRejection::Method);
+    }
+    let target = parts.next()?;
+    if method.is_empty() {
+        return Some(LineRejection::Method);
+    }
+    // Origin form, `*`, or the scheme (or CONNECT authority) of the other forms
+    match target.first() {
+        Some(b'/' | b'*') | None => None,
+        Some(b) if b.is_ascii_alphanumeric() => None,
+        Some(_) => Some(LineRejection::Target),
+    }
+}
+
+/// What a `strict` listener asks of a complete head beyond the usual
+/// parsing: CRLF line endings only, a request line of exactly three parts
+/// split by single spaces, token methods and field names, no obs-fold, and
+/// no control characters in field values (RFC 9112 §2.2, §3, §5)
+fn check_strict_head(head: &[u8]) -> Result<(), &'static str> {
+    for (i, b) in head.iter().enumerate() {
+        match b {
+            b'\n' if i == 0 || head[i - 1] != b'\r' => return Err("Bare LF in request head"),
+            b'\r' if head.get(i + 1) != Some(&b'\n') => return Err("Bare CR in request head"),
+            _ => {}
+        }
+    }
+
+    let mut lines = head.split(|b| *b == b'\n').map(|line| line.strip_suffix(b"\r").unwrap_or(line));
+    let request_line = lines.next().unwrap_or_default();
+    let parts: Vec<&[u8]> = request_line.split(|b| *b == b' ').collect();
+    if parts.len() != 3 || parts.iter().any(|part| part.is_empty()) {
+        return Err("Request line is not method, target and version split by single spaces");
+    }
+    if !parts[0].iter().all(is_tchar) {
+        return Err("Invalid character in method");
+    }
+    let version_ok = matches!(parts[2], [b'H', b'T', b'T', b'P', b'/', major, b'.', minor]
+        if major.is_ascii_digit() && minor.is_ascii_digit());
+    if !version_ok {
+        return Err("Invalid HTTP version");
+    }
+
+    for line in lines.take_while(|line| !line.is_empty()) {
+        if line[0] == b' ' || line[0] == b'\t' {
+            return Err("Obsolete line folding in request head");
+        }
+        let colon = line.iter().position(|b| *b == b':').ok_or("Header field without a colon")?;
+        // Also refuses whitespace before the colon (RFC 9112 §5.1)
+        if colon == 0 || !line[..colon].iter().all(is_tchar) {
+            return Err("Invalid header field name");
+        }
+        if line[colon + 1..].iter().any(|b| (b.is_ascii_control() && *b != b'\t') || *b == 0x7f) {
+            return Err("Invalid character in header field value");
+        }
+    }
+    Ok(())
+}
+
+/// Strict reading of the request target and Host (RFC 9112 §3.2): one Host
+/// field, required from HTTP/1.1 on; `*` only for OPTIONS, an authority only
+/// for CONNECT; an absolute `http(s)` URI is reduced to its path, and its
+/// authority takes the place of Host
+fn strict_target(method: &str, target: &str, version: &str, headers: &mut HttpHeaders) -> Result<String, &'static str> {
+    match headers.get_all("host").count() {
+        0 if version != "HTTP/1.0" => return Err("Missing Host header"),
+        0 | 1 => {}
+        _ => return Err("Repeated Host header"),
+    }
+    if target.contains('#') {
+        return Err("Fragment in request target");
+    }
+    if target.starts_with('/') || (target == "*" && method == "OPTIONS") {
+        return Ok(target.to_string());
+    }
+    if method == "CONNECT" && !target.contains('/') {
+        return Ok(target.to_string());
+    }
+
+    let (scheme, rest) = target.split_once("://").ok_or("Invalid request target")?;
+    if !scheme.eq_ignore_ascii_case("http") && !scheme.eq_ignore_ascii_case("https") {
+        return Err("Unsupported scheme in request target");
+    }
+    let (authority, path) = rest.split_at(rest.find(['/', '?']).unwrap_or(rest.len()));
+    // Userinfo in an http URI is an error for the recipient (RFC 9110 §4.2.4)
+    if authority.is_empty() || authority.contains('@') {
+        return Err("Invalid authority in request target");
+    }
+    headers.insert("host", authority);
+    if path.starts_with('/') {
+        Ok(path.to_string())
+    } else {
+        Ok(format!("/{}", path))
+    }
+}
+
+/// Bodies larger than `threshold` go to a temp file in `dir` instead of memory
+pub struct SpillPolicy {
+    pub dir: PathBuf,
+    pub threshold: usize,
 }
 
 
@@ -26,9 +305,10 @@ pub enum ParserState {
 pub enum BodyType {
     ContentLength(usize),
     Chunked {
-        bytes_read: usize,
-        current_chunk_size: Option<usize>,
+        bytes_read: usize,                 // raw bytes consumed after the headers
+        current_chunk_size: Option<usize>, // None while waiting for a size line
         current_chunk_read: usize,
+        in_trailers: bool,
     },
     None,
 }
@@ -38,6 +318,17 @@ pub struct HttpRequestBuilder {
     buffer: Vec<u8>,
     state: ParserState,
     request: Option<HttpRequest>,
+    spill: Option<SpillPolicy>,
+    spool: Option<(File, SpooledBody)>,
+    consumed: usize, // raw body bytes already moved out of `buffer`
+    request_end: usize, // end of the complete request in `buffer`, pipelined bytes follow
+    pipelined: Vec<u8>, // start of this request, received with the previous one
+}
+
+impl Default for HttpRequestBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl HttpRequestBuilder {
@@ -46,16 +337,82 @@ impl HttpRequestBuilder {
             buffer: Vec::new(),
             state: ParserState::ParsingHeaders,
             request: None,
+            spill: None,
+            spool: None,
+            consumed: 0,
+            request_end: 0,
+            pipelined: Vec::new(),
+        }
+    }
+
+    /// Builder for the request after this complete one, holding what a
+    /// pipelining client already sent of it
+    pub fn next_request(&mut self) -> HttpRequestBuilder {
+        let mut next = HttpRequestBuilder::new();
+        if self.done() && self.request_end < self.buffer.len() {
+            next.pipelined = self.buffer.split_off(self.request_end);
+        }
+        next
+    }
+
+    /// Bytes received with the previous request, to parse before reading more
+    pub fn take_pipelined(&mut self) -> Option<Vec<u8>> {
+        if self.pipelined.is_empty() {
+            return None;
+        }
+        Some(std::mem::take(&mut self.pipelined))
+    }
+
+    /// Whether part of this request came with the previous one
+    pub fn has_pipelined(&self) -> bool {
+        !self.pipelined.is_empty()
+    }
+
+    /// Enable spilling for this request; applies to what is already buffered
+    pub fn set_spill(&mut self, policy: SpillPolicy) -> Result<(), &'static str> {
+        self.spill = Some(policy);
+        self.parse_body()
+    }
+
+    /// Pipe a Content-Length body to a consumer as it arrives instead of
+    /// collecting it; what is already buffered goes first. None when the
+    /// body is chunked, already complete or being spooled.
+    pub fn stream_body(&mut self) -> Option<Rc<RefCell<BodyStream>>> {
+        let len = self.expected_body_len()?;
+        if self.spool.is_some() {
+            return None;
         }
+        let stream = Rc::new(RefCell::new(BodyStream {
+            data: VecDeque::new(),
+            len,
+            complete: false,
+            abandoned: false,
+        }));
+        self.request.as_mut()?.body_stream = Some(Rc::clone(&stream));
+        self.parse_body().ok()?;
+        Some(stream)
+    }
+
+    /// Body bytes waiting for the consumer of a streamed body
+    pub fn stream_backlog(&self) -> usize {
+        self.request
+            .as_ref()
+            .and_then(|r| r.body_stream.as_ref())
+            .map_or(0, |stream| stream.borrow().backlog())
     }
 
-    pub fn append(&mut self, data: Vec<u8>) -> Result<(), &'static str> {
+    pub fn append(&mut self, data: Vec<u8>, limits: &HeadLimits) -> Result<(), &'static str> {
         self.buffer.extend(data);
 
         match &self.state {
             ParserState::ParsingHeaders => {
-                if let Some(headers_end) = self.find_headers_end() {
-                    self.parse_headers(headers_end)?;
+                let headers_end = self.find_headers_end();
+                self.check_head_limits(headers_end, limits)?;
+                if let Some(headers_end) = headers_end {
+                    if limits.strict {
+                        check_strict_head(&self.buffer[..headers_end])?;
+                    }
+                    self.parse_headers(headers_end, limits.strict)?;
                 }
             }
             ParserState::ParsingBody { .. } => {
@@ -67,14 +424,37 @@ impl HttpRequestBuilder {
         Ok(())
     }
 
+    /// Screen `data`, about to be appended, while the request line is still
+    /// coming in
+    pub fn screen(&self, data: &[u8]) -> Option<LineRejection> {
+        if !matches!(self.state, ParserState::ParsingHeaders) {
+            return None;
+        }
+        let start = self.buffer.iter().position(|b| *b != b'\r' && *b != b'\n');
+        if start.is_some_and(|start| self.buffer[start..].contains(&b'\n')) {
+            return None;
+        }
+        if self.buffer.is_empty() {
+            return screen_request_line(data);
+        }
+        let mut line = self.buffer.clone();
+        line.extend_from_slice(data);
+        screen_request_line(&line)
+    }
+
     pub fn body_len(&self) -> usize {
         match &self.state {
             ParserState::ParsingBody { headers_end, .. } => {
-                self.buffer.len().saturating_sub(*headers_end)
+                self.consumed + self.buffer.len().saturating_sub(*headers_end)
             }
             ParserState::Complete => {
                 if let Some(req) = &self.request {
-                    req.body.as_ref().map(|b| b.len()).unwrap_or(0)
+                    match (&req.body, &req.body_file, &req.body_stream) {
+                        (Some(body), _, _) => body.len(),
+                        (None, Some(file), _) => file.len(),
+                        (None, None, Some(stream)) => stream.borrow().len(),
+                        (None, None, None) => 0,
+                    }
                 } else {
                     0
                 }
@@ -82,6 +462,17 @@ impl HttpRequestBuilder {
             _ => 0,
         }
     }
+    /// Bytes held by the builder, for memory accounting
+    pub fn buffered_len(&self) -> usize {
+        self.buffer.capacity()
+            + self
+                .request
+                .as_ref()
+                .and_then(|r| r.body.as_ref())
+                .map_or(0, |b| b.capacity())
+            + self.stream_backlog()
+    }
+
     pub fn set_state(&mut self, state: ParserState) {
         self.state = state;
     }
@@ -98,18 +489,54 @@ impl HttpRequestBuilder {
         None
     }
 
-    fn parse_path_and_query(full_path: &str) -> (String, String) {
-        if let Some(query_start) = full_path.find('?') {
-            let path = full_path[..query_start].to_string();
-            let query = full_path[query_start + 1..].to_string();
-            (path, query)
-        } else {
-            (full_path.to_string(), String::new())
+    /// Whether the head received so far (up to `headers_end` once known) is
+    /// within `limits`; an unfinished line counts as it stands
+    fn check_head_limits(&self, headers_end: Option<usize>, limits: &HeadLimits) -> Result<(), &'static str> {
+        let head = &self.buffer[..headers_end.unwrap_or(self.buffer.len())];
+        let line_end = head.iter().position(|b| *b == b'\n');
+        if line_end.unwrap_or(head.len()) > limits.max_request_line {
+            return Err(URI_TOO_LONG);
         }
+        let Some(line_end) = line_end else {
+            return Ok(());
+        };
+        let fields = &head[line_end + 1..];
+        let count = fields
+            .split(|b| *b == b'\n')
+            .filter(|line| !line.is_empty() && *line != b"\r")
+            .count();
+        if fields.len() > limits.max_header_size || count > limits.max_header_count {
+            return Err(HEADERS_TOO_LARGE);
+        }
+        Ok(())
     }
 
-    fn parse_headers(&mut self, headers_end: usize) -> Result<(), &'static str> {
+    /// Split the target, percent-decode the path and resolve its dot
+    /// segments; a path that doesn't decode to UTF-8 can't name a file, so it
+    /// is rejected, not guessed at
+    fn parse_path_and_query(full_path: &str) -> Result<(String, String), &'static str> {
+        let (raw_path, query) = match full_path.split_once('?') {
+            Some((path, query)) => (path, query.to_string()),
+            None => (full_path, String::new()),
+        };
+
+        let path = String::from_utf8(percent_decode(raw_path, false))
+            .map_err(|_| "Invalid UTF-8 in request path")?;
+        if path.contains('\0') {
+            return Err("NUL byte in request path");
+        }
+        Ok((normalize_path(&path)?, query))
+    }
+
+    fn parse_headers(&mut self, headers_end: usize, strict: bool) -> Result<(), &'static str> {
         let headers_section = &self.buffer[..headers_end];
+        let line_end = headers_section
+            .iter()
+            .position(|b| *b == b'\n')
+            .unwrap_or(headers_section.len());
+        std::str::from_utf8(&headers_section[..line_end])
+            .map_err(|_| "Invalid UTF-8 in request line")?;
+
         let s = String::from_utf8_lossy(headers_section);
         let mut lines = s.lines();
 
@@ -125,24 +552,30 @@ impl HttpRequestBuilder {
             if line.is_empty() {
                 break;
             }
+            // Repeated fields are all kept, CGI scripts get them joined
             if let Some((key, val)) = line.split_once(":") {
-                headers.insert(key, val);
+                headers.append(key, val);
             }
         }
 
-        // Add keep-alive by default if not specified
-        if !headers.get("connection").is_some() {
+        // Add keep-alive by default if not specified; HTTP/1.0 closes by default
+        if headers.get("connection").is_none() && parts[2] != "HTTP/1.0" {
             headers.insert("connection", "keep-alive");
         }
 
         // Extract session ID from Cookie header
-        let cookie_header = headers.get("cookie").map(|s| s.as_str());
-        let session_id = extract_session_id(cookie_header);
+        let cookie_header = headers.get_combined("cookie");
+        let session_id = extract_session_id(cookie_header.as_deref());
 
-        let body_type = self.determine_body_type(&headers);
+        let body_type = self.determine_body_type(&headers)?;
 
         // Parse path and query string
-        let (path, query_string) = Self::parse_path_and_query(parts[1]);
+        let target = if strict {
+            strict_target(parts[0], parts[1], parts[2], &mut headers)?
+        } else {
+            parts[1].to_string()
+        };
+        let (path, query_string) = Self::parse_path_and_query(&target)?;
 
         self.request = Some(HttpRequest {
             method: HttpMethod::from_str(parts[0]),
@@ -151,7 +584,10 @@ impl HttpRequestBuilder {
             version: parts[2].to_string(),
             headers,
             body: None,
+            body_file: None,
+            body_stream: None,
             session_id,
+            cache_bypass: false,
         });
 
         self.state = ParserState::ParsingBody {
@@ -164,24 +600,83 @@ impl HttpRequestBuilder {
         Ok(())
     }
 
-    fn determine_body_type(&self, headers: &HttpHeaders) -> BodyType {
-        if let Some(transfer_encoding) = headers.get("transfer-encoding") {
-            if transfer_encoding.to_lowercase().contains("chunked") {
-                return BodyType::Chunked {
-                    bytes_read: 0,
-                    current_chunk_size: None,
-                    current_chunk_read: 0,
-                };
+    fn determine_body_type(&self, headers: &HttpHeaders) -> Result<BodyType, &'static str> {
+        let field_values = |name: &'static str| {
+            headers
+                .iter()
+                .filter(move |(key, _)| key.as_str() == name)
+                .map(|(_, value)| value.as_str())
+        };
+
+        if headers.get("transfer-encoding").is_some() {
+            // Framing by both is how requests get smuggled past an intermediary (RFC 9112 §6.1)
+            if headers.get("content-length").is_some() {
+                return Err("Both Transfer-Encoding and Content-Length");
+            }
+            let codings: Vec<String> = field_values("transfer-encoding")
+                .flat_map(|v| v.split(','))
+                .map(|coding| coding.trim().to_ascii_lowercase())
+                .filter(|coding| !coding.is_empty())
+                .collect();
+            // Without chunked last, and only once, the body has no known end (§6.3)
+            if codings.last().is_none_or(|coding| coding != "chunked")
+                || codings.iter().filter(|coding| *coding == "chunked").count() > 1
+            {
+                return Err("Transfer-Encoding not ending with chunked");
+            }
+            // Codings under chunked would have to be decoded
+            if codings.len() > 1 {
+                return Err(UNSUPPORTED_TRANSFER_CODING);
             }
+            return Ok(BodyType::Chunked {
+                bytes_read: 0,
+                current_chunk_size: None,
+                current_chunk_read: 0,
+                in_trailers: false,
+            });
         }
 
-        if let Some(content_length) = headers.get("content-length") {
-            if let Ok(length) = content_length.trim().parse::<usize>() {
-                return BodyType::ContentLength(length);
+        if headers.get("content-length").is_some() {
+            // A list of values (e.g. "5, 5"), or the field repeated, is only
+            // valid if they all agree
+            let mut values = field_values("content-length")
+                .flat_map(|v| v.split(','))
+                .map(|v| v.trim().parse::<usize>());
+            let length = values
+                .next()
+                .and_then(|v| v.ok())
+                .ok_or("Invalid Content-Length")?;
+            if values.any(|v| v != Ok(length)) {
+                return Err("Conflicting Content-Length values");
             }
+            return Ok(BodyType::ContentLength(length));
         }
 
-        BodyType::None
+        Ok(BodyType::None)
+    }
+
+    /// Whether the body still being received is collected in memory: not
+    /// spooled, not streamed to a consumer, and not empty
+    pub fn buffers_body(&self) -> bool {
+        match &self.state {
+            ParserState::ParsingBody { body_type, .. } => {
+                self.spool.is_none()
+                    && self.request.as_ref().is_none_or(|r| r.body_stream.is_none())
+                    && !matches!(body_type, BodyType::None | BodyType::ContentLength(0))
+            }
+            _ => false,
+        }
+    }
+
+    /// Body size announced by Content-Length, once headers are parsed
+    pub fn expected_body_len(&self) -> Option<usize> {
+        match &self.state {
+            ParserState::ParsingBody {
+                body_type: BodyType::ContentLength(len),
+                ..
+            } => Some(*len),
+            _ => None,
+        }
     }
 
     fn parse_body(&mut self) -> Result<(), &'static str> {
@@ -195,18 +690,58 @@ impl HttpRequestBuilder {
 
         match body_type {
             BodyType::None => {
+                self.request_end = headers_end;
                 self.state = ParserState::Complete;
                 Ok(())
             }
             BodyType::ContentLength(expected_length) => {
+                let expected_length = *expected_length;
+                if let Some(stream) = self.request.as_ref().and_then(|r| r.body_stream.clone()) {
+                    let wanted = expected_length - self.consumed;
+                    let end = (headers_end + wanted).min(self.buffer.len());
+                    let mut stream = stream.borrow_mut();
+                    let data = self.buffer.drain(headers_end..end);
+                    if stream.abandoned {
+                        drop(data);
+                    } else {
+                        stream.data.extend(data);
+                    }
+                    self.consumed += end - headers_end;
+                    if self.consumed == expected_length {
+                        stream.complete = true;
+                        self.request_end = headers_end;
+                        self.state = ParserState::Complete;
+                    }
+                    return Ok(());
+                }
+                if self.spool.is_none()
+                    && self.spill.as_ref().is_some_and(|p| expected_length > p.threshold)
+                {
+                    self.start_spool()?;
+                }
+                if self.spool.is_some() {
+                    let wanted = expected_length - self.consumed;
+                    let end = (headers_end + wanted).min(self.buffer.len());
+                    let data: Vec<u8> = self.buffer.drain(headers_end..end).collect();
+                    self.consumed += data.len();
+                    self.write_spool(&data)?;
+                    if self.consumed == expected_length {
+                        self.finish_spool()?;
+                        self.request_end = headers_end;
+                        self.state = ParserState::Complete;
+                    }
+                    return Ok(());
+                }
+
                 let body_start = headers_end;
                 let available = self.buffer.len().saturating_sub(body_start);
 
-                if available >= *expected_length {
+                if available >= expected_length {
                     let body = self.buffer[body_start..body_start + expected_length].to_vec();
                     if let Some(ref mut req) = self.request {
                         req.body = Some(body);
                     }
+                    self.request_end = body_start + expected_length;
                     self.state = ParserState::Complete;
                 }
                 Ok(())
@@ -216,51 +751,172 @@ impl HttpRequestBuilder {
     }
 
     fn parse_chunked_body(&mut self, headers_end: usize) -> Result<(), &'static str> {
-        let mut body_data = Vec::new();
-        let mut pos = headers_end;
+        let result = self.decode_chunks(headers_end);
+        self.spool_chunked(headers_end)?;
+        result
+    }
 
-        loop {
-            // Find chunk size line ending
-            let chunk_header_end = self.buffer[pos..]
-                .windows(2)
-                .position(|w| w == b"\r\n")
-                .map(|p| pos + p);
-
-            let chunk_header_end = match chunk_header_end {
-                Some(end) => end,
-                None => return Ok(()), // Need more data for chunk size
-            };
+    /// Move decoded chunk data to the spool file once it outgrows the threshold
+    fn spool_chunked(&mut self, headers_end: usize) -> Result<(), &'static str> {
+        if self.spool.is_none() {
+            let decoded = self
+                .request
+                .as_ref()
+                .and_then(|r| r.body.as_ref())
+                .map_or(0, Vec::len);
+            if self.spill.as_ref().is_none_or(|p| decoded <= p.threshold) {
+                return Ok(());
+            }
+            self.start_spool()?;
+        }
 
-            // Parse chunk size
-            let chunk_size_str = String::from_utf8_lossy(&self.buffer[pos..chunk_header_end]);
-            let chunk_size_str = chunk_size_str.split(';').next().unwrap_or("").trim();
-            let chunk_size =
-                usize::from_str_radix(chunk_size_str, 16).map_err(|_| "Invalid chunk size")?;
+        let data = self
+            .request
+            .as_mut()
+            .and_then(|r| r.body.take())
+            .unwrap_or_default();
+        self.write_spool(&data)?;
 
-            // Move past chunk size line
-            pos = chunk_header_end + 2;
+        // Raw chunk framing already decoded is no longer needed
+        if let ParserState::ParsingBody {
+            body_type: BodyType::Chunked { bytes_read, .. },
+            ..
+        } = &mut self.state
+        {
+            self.buffer.drain(headers_end..headers_end + *bytes_read);
+            self.consumed += *bytes_read;
+            *bytes_read = 0;
+        }
 
-            if chunk_size == 0 {
-                // Last chunk - check for trailing \r\n
-                if self.buffer.len() >= pos + 2 {
-                    if let Some(ref mut req) = self.request {
-                        req.body = Some(body_data);
+        if self.done() {
+            self.finish_spool()?;
+        }
+        Ok(())
+    }
+
+    fn start_spool(&mut self) -> Result<(), &'static str> {
+        let policy = self.spill.as_ref().ok_or("No upload directory configured")?;
+        fs::create_dir_all(&policy.dir).map_err(|_| "Cannot create upload temp directory")?;
+        let path = policy.dir.join(format!("upload-{}.tmp", Uuid::new_v4()));
+        let file = File::create(&path).map_err(|_| "Cannot create upload temp file")?;
+        debug!("Spilling request body to {}", path.display());
+        self.spool = Some((file, SpooledBody { path, len: 0 }));
+        Ok(())
+    }
+
+    fn write_spool(&mut self, data: &[u8]) -> Result<(), &'static str> {
+        let Some((file, body)) = self.spool.as_mut() else {
+            return Ok(());
+        };
+        file.write_all(data)
+            .map_err(|_| "Cannot write upload temp file")?;
+        body.len += data.len();
+        Ok(())
+    }
+
+    fn finish_spool(&mut self) -> Result<(), &'static str> {
+        let Some((mut file, body)) = self.spool.take() else {
+            return Ok(());
+        };
+        file.flush().map_err(|_| "Cannot write upload temp file")?;
+        if let Some(req) = self.request.as_mut() {
+            req.body = None;
+            req.body_file = Some(Rc::new(body));
+        }
+        Ok(())
+    }
+
+    /// Incrementally decode a chunked body, resuming where the previous call stopped
+    fn decode_chunks(&mut self, headers_end: usize) -> Result<(), &'static str> {
+        const MAX_CHUNK_LINE: usize = 4096;
+
+        let ParserState::ParsingBody {
+            body_type:
+                BodyType::Chunked {
+                    bytes_read,
+                    current_chunk_size,
+                    current_chunk_read,
+                    in_trailers,
+                },
+            ..
+        } = &mut self.state
+        else {
+            return Ok(());
+        };
+
+        let request = self.request.as_mut().ok_or("Missing request")?;
+        let body = request.body.get_or_insert_with(Vec::new);
+
+        loop {
+            let pos = headers_end + *bytes_read;
+            let pending = &self.buffer[pos..];
+
+            if *in_trailers {
+                // Trailer fields are ignored; an empty line ends the message
+                let Some(line_end) = pending.windows(2).position(|w| w == b"\r\n") else {
+                    if pending.len() > MAX_CHUNK_LINE {
+                        return Err("Trailer line too long");
                     }
+                    return Ok(());
+                };
+                *bytes_read += line_end + 2;
+                if line_end == 0 {
+                    self.request_end = headers_end + *bytes_read;
                     self.state = ParserState::Complete;
                     return Ok(());
-                } else {
-                    return Ok(()); // Need more data for final \r\n
                 }
+                continue;
             }
 
-            // Check if we have the full chunk + trailing \r\n
-            if self.buffer.len() < pos + chunk_size + 2 {
-                return Ok(()); // Need more data
-            }
+            match *current_chunk_size {
+                None => {
+                    let Some(line_end) = pending.windows(2).position(|w| w == b"\r\n") else {
+                        if pending.len() > MAX_CHUNK_LINE {
+                            return Err("Chunk size line too long");
+                        }
+                        return Ok(()); // Need more data for chunk size
+                    };
 
-            // Read chunk data
-            body_data.extend_from_slice(&self.buffer[pos..pos + chunk_size]);
-            pos += chunk_size + 2; // Skip chunk data and trailing \r\n
+                    // Parse chunk size, ignoring chunk extensions
+                    let line = std::str::from_utf8(&pending[..line_end])
+                        .map_err(|_| "Invalid chunk size")?;
+                    let size_str = line.split(';').next().unwrap_or("").trim();
+                    if size_str.is_empty() {
+                        return Err("Invalid chunk size");
+                    }
+                    let size =
+                        usize::from_str_radix(size_str, 16).map_err(|_| "Invalid chunk size")?;
+
+                    *bytes_read += line_end + 2;
+                    if size == 0 {
+                        *in_trailers = true;
+                    } else {
+                        *current_chunk_size = Some(size);
+                        *current_chunk_read = 0;
+                    }
+                }
+                Some(size) => {
+                    let take = (size - *current_chunk_read).min(pending.len());
+                    body.extend_from_slice(&pending[..take]);
+                    *bytes_read += take;
+                    *current_chunk_read += take;
+
+                    if *current_chunk_read < size {
+                        return Ok(()); // Need more data
+                    }
+
+                    // Chunk data must be followed by CRLF
+                    let after = &pending[take..];
+                    if after.len() < 2 {
+                        return Ok(());
+                    }
+                    if &after[..2] != b"\r\n" {
+                        return Err("Missing CRLF after chunk data");
+                    }
+                    *bytes_read += 2;
+                    *current_chunk_size = None;
+                }
+            }
         }
     }
 
@@ -283,27 +939,93 @@ impl HttpRequestBuilder {
             None
         }
     }
+
+    pub fn get_mut(&mut self) -> Option<&mut HttpRequest> {
+        if self.done() {
+            self.request.as_mut()
+        } else {
+            None
+        }
+    }
 }
 
-impl HttpRequest {
-    pub fn parse_query(&self) -> Vec<(String, String)> {
-        if self.query_string.is_empty() {
-            return Vec::new();
+/// Resolve the `.` and `..` segments of a decoded path (RFC 3986, 5.2.4), so
+/// routes are matched and files looked up on the path actually meant. This
+/// runs after decoding, so `%2e%2e%2f` is a `../` like any other; a path
+/// climbing above `/` is an attempt to leave the root and is refused.
+pub fn normalize_path(path: &str) -> Result<String, &'static str> {
+    let Some(rest) = path.strip_prefix('/') else {
+        return Ok(path.to_string()); // `*`, for OPTIONS
+    };
+    let parts: Vec<&str> = rest.split('/').collect();
+    let mut segments: Vec<&str> = Vec::with_capacity(parts.len());
+    for (i, part) in parts.iter().enumerate() {
+        match *part {
+            "." => {}
+            ".." => {
+                if segments.pop().is_none() {
+                    return Err("Request path escapes the root");
+                }
+            }
+            segment => {
+                segments.push(segment);
+                continue;
+            }
+        }
+        // `/a/b/..` names the directory `/a/`, trailing slash included
+        if i == parts.len() - 1 {
+            segments.push("");
         }
+    }
+    Ok(format!("/{}", segments.join("/")))
+}
 
-        self.query_string
-            .split('&')
-            .filter_map(|pair| {
-                let mut parts = pair.splitn(2, '=');
-                let key = parts.next()?.to_string();
-                let value = parts.next().unwrap_or("").to_string();
+/// Decode `%XX` escapes to raw bytes; malformed escapes are kept literally
+pub fn percent_decode(s: &str, plus_as_space: bool) -> Vec<u8> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
 
-                let key = Self::url_decode(&key);
-                let value = Self::url_decode(&value);
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len()
+                && bytes[i + 1].is_ascii_hexdigit()
+                && bytes[i + 2].is_ascii_hexdigit() =>
+            {
+                out.push(hex_value(bytes[i + 1]) << 4 | hex_value(bytes[i + 2]));
+                i += 3;
+                continue;
+            }
+            b'+' if plus_as_space => out.push(b' '),
+            b => out.push(b),
+        }
+        i += 1;
+    }
+
+    out
+}
 
-                Some((key, value))
-            })
-            .collect()
+fn hex_value(digit: u8) -> u8 {
+    match digit {
+        b'0'..=b'9' => digit - b'0',
+        b'a'..=b'f' => digit - b'a' + 10,
+        _ => digit - b'A' + 10,
+    }
+}
+
+impl HttpRequest {
+    /// The whole body, read back from its temporary file when it was spilled.
+    /// For handlers that need it in one piece, such as a JSON document.
+    pub fn read_body(&self) -> io::Result<Cow<'_, [u8]>> {
+        match (&self.body, &self.body_file) {
+            (Some(body), _) => Ok(Cow::Borrowed(body)),
+            (None, Some(spooled)) => fs::read(spooled.path()).map(Cow::Owned),
+            (None, None) => Ok(Cow::Borrowed(&[])),
+        }
+    }
+
+    pub fn parse_query(&self) -> Vec<(String, String)> {
+        form::parse(&self.query_string)
     }
 
     pub fn query_param(&self, key: &str) -> Option<String> {
@@ -313,32 +1035,169 @@ impl HttpRequest {
             .map(|(_, v)| v)
     }
 
-    fn url_decode(s: &str) -> String {
-        let mut result = String::new();
-        let mut chars = s.chars().peekable();
-
-        while let Some(ch) = chars.next() {
-            if ch == '%' {
-                let hex: String = chars.by_ref().take(2).collect();
-                if hex.len() == 2 {
-                    if let Ok(byte) = u8::from_str_radix(&hex, 16) {
-                        result.push(byte as char);
-                        continue;
-                    }
-                }
-                result.push('%');
-                result.push_str(&hex);
-            } else if ch == '+' {
-                result.push(' ');
-            } else {
-                result.push(ch);
-            }
+    /// Fields of a urlencoded form body, or None when the body isn't one or
+    /// isn't held in memory (spilled to disk or streamed to a handler)
+    pub fn form(&self) -> Option<Vec<(String, String)>> {
+        if !self.headers.get("content-type").is_some_and(|v| form::is_urlencoded(v)) {
+            return None;
         }
+        let body = self.body.as_deref()?;
+        Some(form::parse(&String::from_utf8_lossy(body)))
+    }
 
-        result
+    pub fn form_param(&self, key: &str) -> Option<String> {
+        self.form()?
+            .into_iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v)
     }
 
     pub fn get_session_id(&self) -> Option<&String> {
         self.session_id.as_ref()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: HeadLimits = HeadLimits {
+        max_request_line: 8 * 1024,
+        max_header_size: 32 * 1024,
+        max_header_count: 100,
+        strict: false,
+    };
+
+    fn parse(head: &str) -> Result<HttpRequestBuilder, &'static str> {
+        let mut builder = HttpRequestBuilder::new();
+        builder.append(format!("POST /up HTTP/1.1\r\nHost: test\r\n{}\r\n", head).into_bytes(), &LIMITS)?;
+        Ok(builder)
+    }
+
+    #[test]
+    fn chunked_framing() {
+        let mut builder = parse("Transfer-Encoding: chunked\r\n").unwrap();
+        builder.append(b"5\r\nhello\r\n0\r\n\r\n".to_vec(), &LIMITS).unwrap();
+        assert!(builder.done());
+        assert_eq!(builder.get().unwrap().body.as_deref(), Some(&b"hello"[..]));
+    }
+
+    #[test]
+    fn transfer_encoding_with_content_length_is_refused() {
+        assert!(parse("Transfer-Encoding: chunked\r\nContent-Length: 5\r\n").is_err());
+        assert!(parse("Content-Length: 5\r\nTransfer-Encoding: gzip\r\n").is_err());
+    }
+
+    #[test]
+    fn chunked_must_be_the_final_coding() {
+        assert_eq!(parse("Transfer-Encoding: gzip\r\n").err(), Some("Transfer-Encoding not ending with chunked"));
+        assert_eq!(parse("Transfer-Encoding: chunked, gzip\r\n").err(), Some("Transfer-Encoding not ending with chunked"));
+        assert_eq!(parse("Transfer-Encoding: chunked\r\nTransfer-Encoding: chunked\r\n").err(), Some("Transfer-Encoding not ending with chunked"));
+        assert_eq!(parse("Transfer-Encoding: \r\n").err(), Some("Transfer-Encoding not ending with chunked"));
+    }
+
+    #[test]
+    fn codings_under_chunked_are_not_implemented() {
+        assert_eq!(parse("Transfer-Encoding: gzip, chunked\r\n").err(), Some(UNSUPPORTED_TRANSFER_CODING));
+        assert_eq!(parse("Transfer-Encoding: gzip\r\nTransfer-Encoding: chunked\r\n").err(), Some(UNSUPPORTED_TRANSFER_CODING));
+    }
+
+    #[test]
+    fn content_length_framing() {
+        let mut builder = parse("Content-Length: 5, 5\r\n").unwrap();
+        builder.append(b"hello".to_vec(), &LIMITS).unwrap();
+        assert!(builder.done());
+        assert!(parse("Content-Length: 5\r\nContent-Length: 6\r\n").is_err());
+    }
+
+    #[test]
+    fn chunked_body_arriving_byte_by_byte() {
+        let mut builder = parse("Transfer-Encoding: chunked\r\n").unwrap();
+        let body = b"4;name=value\r\nWiki\r\n0000F\r\npedia in chunks\r\n0\r\nExpires: never\r\n\r\n";
+        for byte in body {
+            assert!(!builder.done());
+            builder.append(vec![*byte], &LIMITS).unwrap();
+        }
+        assert!(builder.done());
+        builder.append(b"GET / HTTP/1.1\r\n".to_vec(), &LIMITS).unwrap();
+        assert_eq!(builder.get().unwrap().body.as_deref(), Some(&b"Wikipedia in chunks"[..]));
+        let mut next = builder.next_request();
+        assert_eq!(next.take_pipelined().as_deref(), Some(&b"GET / HTTP/1.1\r\n"[..]));
+    }
+
+    #[test]
+    fn malformed_chunks_are_refused() {
+        let cases: [&[u8]; 5] = [
+            b"zz\r\nhello\r\n",
+            b"\r\nhello\r\n",
+            b";ext\r\nhello\r\n",
+            b"5\r\nhelloXX0\r\n\r\n",
+            b"fffffffffffffffffffffffff\r\n",
+        ];
+        for data in cases {
+            let mut builder = parse("Transfer-Encoding: chunked\r\n").unwrap();
+            assert!(builder.append(data.to_vec(), &LIMITS).is_err(), "{:?}", String::from_utf8_lossy(data));
+        }
+
+        let mut builder = parse("Transfer-Encoding: chunked\r\n").unwrap();
+        assert_eq!(builder.append(vec![b'1'; 5000], &LIMITS), Err("Chunk size line too long"));
+    }
+
+    fn spill_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("spill-{}", uuid::Uuid::new_v4()))
+    }
+
+    #[test]
+    fn body_over_the_threshold_is_spilled() {
+        let dir = spill_dir();
+        let mut builder = parse("Content-Length: 10\r\n").unwrap();
+        builder.append(b"hello".to_vec(), &LIMITS).unwrap();
+        builder.set_spill(SpillPolicy { dir: dir.clone(), threshold: 8 }).unwrap();
+        builder.append(b"world".to_vec(), &LIMITS).unwrap();
+        assert!(builder.done());
+
+        let request = builder.get().unwrap();
+        assert!(request.body.is_none());
+        let spooled = request.body_file.clone().expect("spilled body");
+        assert_eq!(spooled.len(), 10);
+        assert_eq!(fs::read(spooled.path()).unwrap(), b"helloworld");
+        assert_eq!(request.read_body().unwrap().as_ref(), b"helloworld");
+
+        // The temp file goes with the last reference to it
+        let path = spooled.path().to_path_buf();
+        drop(spooled);
+        drop(builder);
+        assert!(!path.exists());
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn body_under_the_threshold_stays_in_memory() {
+        let dir = spill_dir();
+        let mut builder = parse("Content-Length: 5\r\n").unwrap();
+        builder.set_spill(SpillPolicy { dir: dir.clone(), threshold: 8 }).unwrap();
+        builder.append(b"hello".to_vec(), &LIMITS).unwrap();
+        assert!(builder.done());
+        let request = builder.get().unwrap();
+        assert!(request.body_file.is_none());
+        assert_eq!(request.body.as_deref(), Some(&b"hello"[..]));
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn chunked_body_is_spilled_once_it_outgrows_the_threshold() {
+        let dir = spill_dir();
+        let mut builder = parse("Transfer-Encoding: chunked\r\n").unwrap();
+        builder.set_spill(SpillPolicy { dir: dir.clone(), threshold: 8 }).unwrap();
+        builder.append(b"5\r\nhello\r\n".to_vec(), &LIMITS).unwrap();
+        assert!(!dir.exists());
+        builder.append(b"5\r\nworld\r\n5\r\nagain\r\n0\r\n\r\n".to_vec(), &LIMITS).unwrap();
+        assert!(builder.done());
+
+        let request = builder.get().unwrap();
+        assert!(request.body.is_none());
+        assert_eq!(request.read_body().unwrap().as_ref(), b"helloworldagain");
+        drop(builder);
+        let _ = fs::remove_dir_all(dir);
+    }
+}
@@ -1,15 +1,58 @@
 use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use ring::digest;
 
 use crate::{
-    config::ServerConfig,
-    utils::{HttpHeaders, cookie::{Cookie}},
+    config::{Route, ServerConfig},
+    request::{SpooledBody, percent_decode},
+    storage::ContentSource,
+    utils::{HttpHeaders, cookie::{Cookie}, range::{ByteRange, parse_range}},
 };
 
+/// Standard reason phrase for the statuses this server produces
+pub fn reason_phrase(status_code: u16) -> &'static str {
+    match status_code {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        206 => "Partial Content",
+        207 => "Multi-Status",
+        301 => "Moved Permanently",
+        302 => "Found",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        412 => "Precondition Failed",
+        413 => "Payload Too Large",
+        414 => "URI Too Long",
+        415 => "Unsupported Media Type",
+        416 => "Range Not Satisfiable",
+        417 => "Expectation Failed",
+        424 => "Failed Dependency",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "Unknown",
+    }
+}
+
 pub struct HttpResponseBuilder {
     status_code: u16,
     status_text: String,
     headers: HttpHeaders,
     cookies: Vec<Cookie>, // <-- new
+    accept_ranges: bool,
+    range: Option<String>,
 
     body: Vec<u8>,
 }
@@ -22,14 +65,30 @@ impl HttpResponseBuilder {
             headers: HttpHeaders::new(),
             body: Vec::new(),
             cookies: Vec::new(),
+            accept_ranges: false,
+            range: None,
         }
     }
 
+    pub fn status_code(&self) -> u16 {
+        self.status_code
+    }
+
+    pub fn status_text(&self) -> &str {
+        &self.status_text
+    }
+
     pub fn header(mut self, key: &str, value: &str) -> Self {
         self.headers.insert(key, value);
         self
     }
 
+    /// Add a value without replacing the ones already set for `key`
+    pub fn append_header(mut self, key: &str, value: &str) -> Self {
+        self.headers.append(key, value);
+        self
+    }
+
     pub fn headers(mut self, headers: HttpHeaders) -> Self {
         self.headers = headers;
         self
@@ -45,14 +104,47 @@ impl HttpResponseBuilder {
         self
     }
 
+    /// Honor the request's Range header when the response is a plain 200
+    pub fn range(mut self, range_header: Option<&String>) -> Self {
+        self.accept_ranges = true;
+        self.range = range_header.cloned();
+        self
+    }
+
     pub fn build(mut self) -> Vec<u8> {
-        // Auto-add Content-Length if not present
-        self.headers
-            .insert("Content-Length", &self.body.len().to_string());
+        if self.accept_ranges && self.status_code == 200 {
+            self.apply_range();
+        }
+
+        // Auto-add Content-Length if not present; a 204 must not carry one
+        if self.status_code != 204 {
+            self.headers
+                .insert("Content-Length", &self.body.len().to_string());
+        }
+
+        let mut bytes = self.serialize_head();
+        bytes.extend_from_slice(&self.body);
+        bytes
+    }
+
+    /// Status line and headers for a body of `len` bytes sent afterwards
+    pub fn build_head(mut self, len: u64) -> Vec<u8> {
+        self.headers.insert("Content-Length", &len.to_string());
+        self.serialize_head()
+    }
+
+    /// Status line and headers for a body streamed with chunked encoding
+    pub fn build_chunked_head(mut self) -> Vec<u8> {
+        self.headers.remove("Content-Length");
+        self.headers.insert("Transfer-Encoding", "chunked");
+        self.serialize_head()
+    }
+
+    fn serialize_head(&mut self) -> Vec<u8> {
         // Inject all cookies as headers
         for cookie in self.cookies.iter() {
             let (key, value) = cookie.to_header_pair();
-            self.headers.insert(&key, &value);
+            self.headers.append(&key, &value);
         }
 
         let mut response = format!("HTTP/1.1 {} {}\r\n", self.status_code, self.status_text);
@@ -62,10 +154,30 @@ impl HttpResponseBuilder {
         }
 
         response.push_str("\r\n");
+        response.into_bytes()
+    }
 
-        let mut bytes = response.into_bytes();
-        bytes.extend_from_slice(&self.body);
-        bytes
+    fn apply_range(&mut self) {
+        self.headers.insert("Accept-Ranges", "bytes");
+        let len = self.body.len() as u64;
+
+        match parse_range(self.range.as_deref(), len) {
+            ByteRange::Full => {}
+            ByteRange::Partial { start, end } => {
+                self.status_code = 206;
+                self.status_text = "Partial Content".to_string();
+                self.headers
+                    .insert("Content-Range", &format!("bytes {}-{}/{}", start, end, len));
+                self.body = self.body[start as usize..=end as usize].to_vec();
+            }
+            ByteRange::Unsatisfiable => {
+                self.status_code = 416;
+                self.status_text = "Range Not Satisfiable".to_string();
+                self.headers
+                    .insert("Content-Range", &format!("bytes */{}", len));
+                self.body = Vec::new();
+            }
+        }
     }
 
     // === Convenience methods ===
@@ -77,12 +189,12 @@ impl HttpResponseBuilder {
         Self::new(201, "Created")
     }
 
-    pub fn redirect(location: &String) -> Self {
-        Self::new(302, "Found").header("Location", location)
+    pub fn moved_permanently(location: &str) -> Self {
+        Self::new(301, "Moved Permanently").header("Location", location)
     }
 
-    pub fn not_found() -> Self {
-        Self::new(404, "Not Found")
+    pub fn redirect(location: &str) -> Self {
+        Self::new(302, "Found").header("Location", location)
     }
 
     pub fn method_not_allowed() -> Self {
@@ -100,6 +212,18 @@ impl HttpResponseBuilder {
         Self::new(400, "Bad Request")
     }
 
+    pub fn request_timeout() -> Self {
+        Self::new(408, "Request Timeout")
+    }
+
+    pub fn service_unavailable() -> Self {
+        Self::new(503, "Service Unavailable")
+    }
+
+    pub fn precondition_failed() -> Self {
+        Self::new(412, "Precondition Failed")
+    }
+
     pub fn unsupported_media_type() -> Self {
         Self::new(415, "Unsupported Media Type")
     }
@@ -107,24 +231,24 @@ impl HttpResponseBuilder {
     // === File serving methods ===
     /// Serve a directory listing as HTML
     pub fn serve_directory_listing(
+        source: &dyn ContentSource,
         server_root: &str,
         route_root: &str,
         route_path: &str,
         cookie: &Cookie,
+        range: Option<&String>,
     ) -> Vec<u8> {
         let mut listing = String::from("<html><body><h1>Directory Listing</h1><ul>");
 
         let dir_path = format!("{}/{}", server_root, route_root);
-        if let Ok(entries) = fs::read_dir(dir_path) {
-            for entry in entries {
-                if let Ok(entry) = entry {
-                    let file_name = entry.file_name();
-                    let file_name_str = file_name.to_string_lossy();
-                    listing.push_str(&format!(
-                        "<li><a href=\"{}\\{}\">{}</a></li>",
-                        route_path, file_name_str, file_name_str
-                    ));
-                }
+        if let Ok(names) = source.list(&dir_path) {
+            for file_name_str in names {
+                listing.push_str(&format!(
+                    "<li><a href=\"{}/{}\">{}</a></li>",
+                    escape_html(route_path.trim_end_matches('/')),
+                    urlencoding::encode(&file_name_str),
+                    escape_html(&file_name_str)
+                ));
             }
         }
 
@@ -135,6 +259,7 @@ impl HttpResponseBuilder {
         Self::ok()
             .body(listing.into_bytes().to_vec())
             .cookie(cookie)
+            .range(range)
             .build()
     }
 
@@ -144,7 +269,7 @@ impl HttpResponseBuilder {
     pub fn serve_error_page(error_page_path: &str, status_code: u16, status_text: &str , cookie :&Cookie   ) -> Vec<u8> {
         match fs::read(error_page_path) {
             Ok(content) => {
-                println!(
+                debug!(
                     "Serving custom {} error page from: {}",
                     status_code, error_page_path
                 );
@@ -155,7 +280,7 @@ impl HttpResponseBuilder {
                     .build()
             }
             Err(_) => {
-                println!(
+                info!(
                     "Error page '{}' not found, sending minimal {} response",
                     error_page_path, status_code
                 );
@@ -167,6 +292,66 @@ impl HttpResponseBuilder {
 
 }
 
+/// Escape text for use in HTML content and attribute values
+pub fn escape_html(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// `attachment` disposition keeping non-ASCII names intact through `filename*`
+pub fn content_disposition(file_name: &str) -> String {
+    let fallback: String = file_name
+        .chars()
+        .map(|c| if c.is_ascii() && c != '"' && c != '\\' && !c.is_ascii_control() { c } else { '_' })
+        .collect();
+    format!(
+        "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+        fallback,
+        urlencoding::encode(file_name)
+    )
+}
+
+/// Cache-Control for a static file of `route`: the override for its
+/// extension if there is one, otherwise the route's `cache_control`
+pub fn cache_control_for<'a>(route: &'a Route, path: &str) -> Option<&'a str> {
+    std::path::Path::new(path)
+        .extension()
+        .and_then(|e| e.to_str())
+        .and_then(|ext| {
+            route
+                .cache_control_by_extension
+                .iter()
+                .find(|(e, _)| e.eq_ignore_ascii_case(ext))
+        })
+        .map(|(_, value)| value.as_str())
+        .or(route.cache_control.as_deref())
+}
+
+/// `Cache-Control` header line for `value`, plus a matching `Expires` for
+/// HTTP/1.0 caches when it sets a `max-age`
+pub fn cache_headers(value: &str) -> String {
+    let mut out = format!("Cache-Control: {}\r\n", value);
+    let max_age = value
+        .split(',')
+        .filter_map(|directive| directive.trim().strip_prefix("max-age="))
+        .find_map(|secs| secs.trim_matches('"').parse::<u64>().ok());
+    if let Some(secs) = max_age {
+        let expires = SystemTime::now() + Duration::from_secs(secs);
+        out.push_str(&format!("Expires: {}\r\n", httpdate::fmt_http_date(expires)));
+    }
+    out
+}
+
 // Helper function to detect content type from file extension
 pub fn detect_content_type(path: &str) -> &'static str {
     if let Some(ext) = std::path::Path::new(path)
@@ -221,78 +406,287 @@ pub fn handle_method_not_allowed(
     }
 }
 
-pub(crate) fn extract_multipart_files<'a>(
-    body: &'a [u8],
-    boundary: &str,
-) -> Vec<(String, &'a [u8])> {
-    let mut files = Vec::new();
+/// Status code of a serialized response, from its status line
+pub(crate) fn status_code(head: &[u8]) -> Option<u16> {
+    let code = head.strip_prefix(b"HTTP/1.")?.get(2..5)?;
+    std::str::from_utf8(code).ok()?.parse().ok()
+}
 
-    let boundary = format!("--{}", boundary);
-    let boundary = boundary.as_bytes();
+/// Largest header block accepted for one multipart part
+const MAX_PART_HEADERS: usize = 16 * 1024;
+/// Ordinary form fields are kept in memory, up to this many bytes in all
+const MAX_FORM_FIELDS: usize = 1024 * 1024;
+/// Bytes read from the body at a time while scanning for delimiters
+const MULTIPART_CHUNK: usize = 64 * 1024;
+
+/// A file part written to disk by `store_multipart`
+pub(crate) struct StoredFile {
+    pub name: String,   // Base name under the upload directory
+    pub size: u64,
+    pub sha256: String, // Hex digest of the stored bytes
+}
 
-    let mut pos = 0;
+/// What `store_multipart` kept from a multipart/form-data body
+#[derive(Default)]
+pub(crate) struct MultipartUpload {
+    pub files: Vec<StoredFile>,
+    pub fields: Vec<(String, Vec<u8>)>, // Parts without a filename, in body order; repeated names are kept
+}
 
-    while let Some(b_start) = find_bytes(&body[pos..], boundary) {
-        let part_start = pos + b_start + boundary.len();
+/// Reads a body a chunk at a time while looking for delimiters, so parts of
+/// any size pass through a fixed buffer
+struct MultipartReader<R> {
+    reader: R,
+    buf: Vec<u8>,
+    pos: usize, // Start of the unread bytes in `buf`
+    eof: bool,
+}
 
-        if part_start >= body.len() {
-            break;
+impl<R: Read> MultipartReader<R> {
+    fn new(reader: R) -> Self {
+        MultipartReader { reader, buf: Vec::new(), pos: 0, eof: false }
+    }
+
+    fn pending(&self) -> &[u8] {
+        &self.buf[self.pos..]
+    }
+
+    /// Read the next chunk; false once the body is exhausted
+    fn fill(&mut self) -> io::Result<bool> {
+        if self.eof {
+            return Ok(false);
         }
+        self.buf.drain(..self.pos);
+        self.pos = 0;
+        let start = self.buf.len();
+        self.buf.resize(start + MULTIPART_CHUNK, 0);
+        let read = loop {
+            match self.reader.read(&mut self.buf[start..]) {
+                Ok(n) => break n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    self.buf.truncate(start);
+                    return Err(e);
+                }
+            }
+        };
+        self.buf.truncate(start + read);
+        self.eof = read == 0;
+        Ok(read > 0)
+    }
 
-        if let Some(b_end) = find_bytes(&body[part_start..], boundary) {
-            let part = &body[part_start..part_start + b_end];
-            pos = part_start + b_end;
+    /// Whether the unread bytes start with `prefix`, reading as needed
+    fn starts_with(&mut self, prefix: &[u8]) -> io::Result<bool> {
+        while self.pending().len() < prefix.len() && self.fill()? {}
+        Ok(self.pending().starts_with(prefix))
+    }
 
-            let header_end = find_bytes(part, b"\r\n\r\n");
-            let Some(header_end) = header_end else {
-                continue;
-            };
+    /// Pass everything before the next `needle` to `out` and skip the needle.
+    /// False when the body ends first.
+    fn copy_until(
+        &mut self,
+        needle: &[u8],
+        mut out: impl FnMut(&[u8]) -> io::Result<()>,
+    ) -> io::Result<bool> {
+        loop {
+            if let Some(at) = find_bytes(self.pending(), needle) {
+                out(&self.pending()[..at])?;
+                self.pos += at + needle.len();
+                return Ok(true);
+            }
+            // The tail may be the start of a needle split across two reads
+            let safe = self.pending().len().saturating_sub(needle.len() - 1);
+            out(&self.pending()[..safe])?;
+            self.pos += safe;
+            if !self.fill()? {
+                return Ok(false);
+            }
+        }
+    }
+}
 
-            let headers = &part[..header_end];
-            let data = &part[header_end + 4..];
+/// Where the data of the current part goes
+enum PartSink {
+    File { path: PathBuf, file: fs::File, name: String, size: u64, hash: Box<digest::Context> },
+    Field { name: String, value: Vec<u8> },
+    Discard,
+}
 
-            let headers_str = match std::str::from_utf8(headers) {
-                Ok(s) => s,
-                Err(_) => continue,
-            };
+impl PartSink {
+    fn write(&mut self, bytes: &[u8], field_bytes: &mut usize) -> io::Result<()> {
+        match self {
+            PartSink::File { file, size, hash, .. } => {
+                file.write_all(bytes)?;
+                hash.update(bytes);
+                *size += bytes.len() as u64;
+            }
+            PartSink::Field { value, .. } => {
+                *field_bytes += bytes.len();
+                if *field_bytes > MAX_FORM_FIELDS {
+                    return Err(io::Error::new(io::ErrorKind::FileTooLarge, "Form fields too large"));
+                }
+                value.extend_from_slice(bytes);
+            }
+            PartSink::Discard => {}
+        }
+        Ok(())
+    }
 
-            if !headers_str.contains("Content-Disposition") {
-                continue;
+    fn finish(self, upload: &mut MultipartUpload) {
+        match self {
+            PartSink::File { name, size, hash, .. } => {
+                let sha256 = hash.finish().as_ref().iter().map(|b| format!("{:02x}", b)).collect();
+                upload.files.push(StoredFile { name, size, sha256 });
             }
+            PartSink::Field { name, value } => upload.fields.push((name, value)),
+            PartSink::Discard => {}
+        }
+    }
 
-            let filename = extract_filename_from_disposition(headers_str);
-            let Some(filename) = filename else { continue };
+    /// Drop a part that was not received in full
+    fn discard(self) {
+        if let PartSink::File { path, file, .. } = self {
+            drop(file);
+            let _ = fs::remove_file(path);
+        }
+    }
+}
 
-            let data = strip_trailing_crlf(data);
+/// Store a multipart/form-data body read from `body`: file parts are written
+/// under `dir` as they stream by, ordinary fields are kept in memory.
+/// Delimiters are matched with their leading CRLF, so part data may contain
+/// the boundary text or end with CRLF and still be stored byte for byte. A
+/// part cut short by the end of the body is dropped.
+///
+/// Errors of kind `InvalidData` mean a malformed body and `FileTooLarge` too
+/// much form field data; the others come from reading or writing files.
+pub(crate) fn store_multipart(body: impl Read, boundary: &str, dir: &Path) -> io::Result<MultipartUpload> {
+    let mut upload = MultipartUpload::default();
+    let mut reader = MultipartReader::new(body);
+    let delimiter = format!("--{}", boundary);
+    let next_delimiter = format!("\r\n--{}", boundary);
+    let mut field_bytes = 0;
+
+    // The first delimiter may open the body; anything before it is preamble
+    if !reader.copy_until(delimiter.as_bytes(), |_| Ok(()))? {
+        return Ok(upload);
+    }
 
-            files.push((filename, data));
+    loop {
+        // `--` right after a delimiter closes the body
+        if reader.starts_with(b"--")? || !reader.copy_until(b"\r\n", |_| Ok(()))? {
+            break;
+        }
+
+        // A part without headers starts with the blank line right away
+        let mut headers = Vec::new();
+        let has_headers = if reader.starts_with(b"\r\n")? {
+            reader.pos += 2;
+            true
         } else {
+            reader.copy_until(b"\r\n\r\n", |bytes| {
+                headers.extend_from_slice(bytes);
+                match headers.len() > MAX_PART_HEADERS {
+                    true => Err(io::Error::new(io::ErrorKind::InvalidData, "Multipart part headers too large")),
+                    false => Ok(()),
+                }
+            })?
+        };
+        if !has_headers {
             break;
         }
+
+        let mut sink = match part_disposition(&headers) {
+            Some((_, Some(filename))) => match base_file_name(&filename) {
+                Some(name) => {
+                    let path = dir.join(&name);
+                    debug!("Writing file to: {}", path.display());
+                    PartSink::File {
+                        file: fs::File::create(&path)?,
+                        path,
+                        name,
+                        size: 0,
+                        hash: Box::new(digest::Context::new(&digest::SHA256)),
+                    }
+                }
+                None => PartSink::Discard,
+            },
+            Some((Some(name), None)) => PartSink::Field { name, value: Vec::new() },
+            _ => PartSink::Discard,
+        };
+        match reader.copy_until(next_delimiter.as_bytes(), |bytes| sink.write(bytes, &mut field_bytes)) {
+            Ok(true) => sink.finish(&mut upload),
+            Ok(false) => {
+                sink.discard();
+                break;
+            }
+            Err(e) => {
+                sink.discard();
+                return Err(e);
+            }
+        }
     }
 
-    files
+    Ok(upload)
 }
 
-fn find_bytes(haystack: &[u8], needle: &[u8]) -> Option<usize> {
-    haystack.windows(needle.len()).position(|w| w == needle)
+/// Field name and filename from the Content-Disposition of a part's headers;
+/// None when the part has no usable Content-Disposition
+fn part_disposition(headers: &[u8]) -> Option<(Option<String>, Option<String>)> {
+    let headers_str = std::str::from_utf8(headers).ok()?;
+
+    let disposition = headers_str
+        .lines()
+        .find(|line| line.to_ascii_lowercase().starts_with("content-disposition"))?;
+    let name = disposition
+        .split(';')
+        .skip(1)
+        .filter_map(|param| param.trim().split_once('='))
+        .find(|(key, _)| key.trim().eq_ignore_ascii_case("name"))
+        .map(|(_, value)| value.trim().trim_matches('"').to_string());
+
+    Some((name, extract_filename_from_disposition(disposition)))
 }
 
-fn strip_trailing_crlf(data: &[u8]) -> &[u8] {
-    let mut end = data.len();
-    if end >= 2 && &data[end - 2..end] == b"\r\n" {
-        end -= 2;
+/// The last component of a client-supplied file name. Browsers on Windows may
+/// send the full `C:\Users\...` path, and neither separator may reach the disk.
+fn base_file_name(name: &str) -> Option<String> {
+    let mut base = name.rsplit(['/', '\\']).next()?.trim();
+    // A drive-relative name such as `C:photo.jpg`
+    if let [drive, b':', ..] = base.as_bytes()
+        && drive.is_ascii_alphabetic()
+    {
+        base = &base[2..];
     }
-    data[..end].as_ref()
+    if base.is_empty() || base == "." || base == ".." {
+        return None;
+    }
+    Some(base.to_string())
 }
 
-fn extract_filename_from_disposition(part: &str) -> Option<String> {
-    // Find the Content-Disposition line
-    let disposition_line = part
-        .lines()
-        .find(|line| line.contains("Content-Disposition"))?;
+pub(crate) fn find_bytes(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
 
-    // Look for filename="..." or filename*=...
+fn extract_filename_from_disposition(disposition_line: &str) -> Option<String> {
+    // RFC 5987 form carries the exact UTF-8 name: filename*=UTF-8''caf%C3%A9.txt
+    if let Some(start) = disposition_line.find("filename*=") {
+        let value = disposition_line[start + 10..]
+            .split(';')
+            .next()
+            .unwrap_or("")
+            .trim()
+            .trim_matches('"');
+        if let Some((charset, rest)) = value.split_once('\'')
+            && charset.eq_ignore_ascii_case("utf-8")
+            && let Some((_lang, encoded)) = rest.split_once('\'')
+            && let Ok(name) = String::from_utf8(percent_decode(encoded, false))
+        {
+            return Some(name);
+        }
+    }
+
+    // Look for filename="..."
     if let Some(start) = disposition_line.find("filename=\"") {
         let start = start + 10; // length of 'filename="'
         let end = disposition_line[start..].find('"')?;
@@ -303,7 +697,7 @@ fn extract_filename_from_disposition(part: &str) -> Option<String> {
     if let Some(start) = disposition_line.find("filename=") {
         let start = start + 9; // length of 'filename='
         let end = disposition_line[start..]
-            .find(|c: char| c == ';' || c == '\r' || c == '\n')
+            .find([';', '\r', '\n'])
             .unwrap_or(disposition_line[start..].len());
         return Some(disposition_line[start..start + end].trim().to_string());
     }
@@ -320,12 +714,12 @@ pub(crate) fn extract_boundary(content_type: &str) -> Option<String> {
 
 pub(crate) fn write_file(path: &str, data: &[u8], cookie: &Cookie        ) -> Vec<u8> {
     if let Ok(s) = std::str::from_utf8(data) {
-        println!("body as string: {}", s);
+        debug!("body as string: {}", s);
     } else {
-        println!("body is binary, cannot print as string");
+        debug!("body is binary, cannot print as string");
     }
 
-    println!("Writing file to: {}", path);
+    debug!("Writing file to: {}", path);
     match fs::write(path, data) {
         Ok(_) => HttpResponseBuilder::ok()
             .header("Content-Type", "text/plain")
@@ -338,3 +732,105 @@ pub(crate) fn write_file(path: &str, data: &[u8], cookie: &Cookie        ) -> Ve
             .build(),
     }
 }
+
+/// Store a spilled upload at `path` without reading it back into memory
+pub(crate) fn move_file(path: &str, spooled: &SpooledBody, cookie: &Cookie) -> Vec<u8> {
+    debug!("Moving {} to: {}", spooled.path().display(), path);
+    // Rename fails across filesystems; copying is the fallback
+    let result = fs::rename(spooled.path(), path)
+        .or_else(|_| fs::copy(spooled.path(), path).map(|_| ()));
+    match result {
+        Ok(_) => HttpResponseBuilder::ok()
+            .header("Content-Type", "text/plain")
+            .body(b"Upload successful".to_vec())
+            .cookie(cookie)
+            .build(),
+        Err(e) => HttpResponseBuilder::internal_error()
+            .body(e.to_string().into_bytes())
+            .cookie(cookie)
+            .build(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Hands the body out a few bytes at a time, so delimiters straddle reads
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = buf.len().min(7).min(self.0.len());
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    fn upload_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("multipart-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn body(parts: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut body = b"preamble\r\n".to_vec();
+        for (headers, data) in parts {
+            body.extend_from_slice(format!("--XyZ\r\n{}\r\n\r\n", headers).as_bytes());
+            body.extend_from_slice(data);
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend_from_slice(b"--XyZ--\r\n");
+        body
+    }
+
+    #[test]
+    fn parts_are_stored_byte_for_byte() {
+        let dir = upload_dir();
+        let tricky: &[u8] = b"--XyZ inside, and a trailing CRLF\r\n";
+        let large: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        let body = body(&[
+            ("Content-Disposition: form-data; name=\"title\"", b"Holidays"),
+            ("Content-Disposition: form-data; name=\"a\"; filename=\"C:\\tmp\\a.txt\"", tricky),
+            ("Content-Disposition: form-data; name=\"b\"; filename=\"b.bin\"", &large),
+        ]);
+
+        let upload = store_multipart(Trickle(&body), "XyZ", &dir).unwrap();
+
+        assert_eq!(upload.fields, vec![("title".to_string(), b"Holidays".to_vec())]);
+        let names: Vec<&str> = upload.files.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, ["a.txt", "b.bin"]);
+        assert_eq!(fs::read(dir.join("a.txt")).unwrap(), tricky);
+        assert_eq!(fs::read(dir.join("b.bin")).unwrap(), large);
+        assert_eq!(upload.files[1].size, large.len() as u64);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn truncated_part_is_dropped() {
+        let dir = upload_dir();
+        let mut body = body(&[("Content-Disposition: form-data; name=\"a\"; filename=\"a.txt\"", b"complete")]);
+        body.truncate(body.len() - b"--XyZ--\r\n".len());
+        body.extend_from_slice(b"--XyZ\r\nContent-Disposition: form-data; name=\"b\"; filename=\"b.txt\"\r\n\r\ncut sh");
+
+        let upload = store_multipart(body.as_slice(), "XyZ", &dir).unwrap();
+
+        assert_eq!(upload.files.len(), 1);
+        assert!(dir.join("a.txt").exists());
+        assert!(!dir.join("b.txt").exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn oversized_fields_are_refused() {
+        let dir = upload_dir();
+        let value = vec![b'x'; MAX_FORM_FIELDS + 1];
+        let body = body(&[("Content-Disposition: form-data; name=\"big\"", &value)]);
+
+        let error = store_multipart(body.as_slice(), "XyZ", &dir).err().unwrap();
+
+        assert_eq!(error.kind(), io::ErrorKind::FileTooLarge);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
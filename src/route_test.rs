@@ -0,0 +1,215 @@
+use std::path::Path;
+
+use crate::admin;
+use crate::cgi::{DEFAULT_BUFFER_SIZE, handler_interpreter, script_allowed, split_script_path};
+use crate::config::{Config, Route, ServerConfig, format_duration};
+use crate::handler::route_implements;
+use crate::read::{find_matching_route, resolve_file_path};
+use crate::request::{normalize_path, percent_decode};
+use crate::utils::HttpMethod;
+
+/// `localserver route-test <METHOD> <path> [--host name] [--port n]`: show how
+/// the config would route a request, without starting the server. Returns
+/// the exit status.
+pub fn run(config: &Config, method: &str, target: &str, host: Option<&str>, port: Option<u16>) -> i32 {
+    let method = method.to_ascii_uppercase();
+
+    // Decoded and normalized the way the request parser would
+    let raw_path = target.split('?').next().unwrap_or("");
+    let path = match String::from_utf8(percent_decode(raw_path, false)) {
+        Ok(decoded) => match normalize_path(&decoded) {
+            Ok(path) => path,
+            Err(e) => {
+                println!("{} {}: 400 Bad Request ({})", method, target, e);
+                return 0;
+            }
+        },
+        Err(_) => {
+            println!("{} {}: 400 Bad Request (invalid UTF-8 in path)", method, target);
+            return 0;
+        }
+    };
+
+    let Some(port) = port.or_else(|| config.servers.first()?.ports.first().copied()) else {
+        eprintln!("No server listens on any port");
+        return 1;
+    };
+    // Servers sharing the first listener on that port, as the server groups them
+    let Some(first) = config.servers.iter().find(|s| s.ports.contains(&port)) else {
+        eprintln!("No server listens on port {}", port);
+        return 1;
+    };
+    let listener: Vec<&ServerConfig> = config
+        .servers
+        .iter()
+        .filter(|s| s.host == first.host && s.ports.contains(&port))
+        .collect();
+    let hostname = host.unwrap_or("").split(':').next().unwrap_or("");
+    let (server, how) = match listener.iter().find(|s| s.server_name.eq_ignore_ascii_case(hostname)) {
+        Some(server) => (*server, "matched by name"),
+        None => (
+            *listener.iter().find(|s| s.default_server).unwrap_or(&listener[0]),
+            "default server",
+        ),
+    };
+
+    println!("Request:  {} {} (Host: {}, port {})", method, path, host.unwrap_or("-"), port);
+    println!("Listener: {}:{}{}", server.host, port, if server.tls.is_some() { " (TLS)" } else { "" });
+    println!("Server:   {} ({})", server.server_name, how);
+    print_limits(server);
+
+    admin::init(config);
+    if admin::is_admin_path(&path) {
+        println!("Handler:  admin endpoint");
+        return 0;
+    }
+    if !server.routes.iter().any(|route| route_implements(route, &method)) {
+        println!("Result:   501 Not Implemented, no route can answer {}", method);
+        return 0;
+    }
+    let Some(route) = find_matching_route(server, &path) else {
+        println!("Route:    none");
+        println!("Result:   404 Not Found");
+        return 0;
+    };
+    println!("Route:    {}", route.path);
+    // Served from the fallback while the primary root is unreadable
+    let primary = format!("{}/{}", server.root, route.root);
+    let failover = route.fallback_root.as_ref().filter(|_| std::fs::read_dir(&primary).is_err()).map(|fallback| {
+        println!("Note:     root '{}' is unavailable, serving from fallback_root '{}'", primary, fallback);
+        let mut chosen = route.clone();
+        chosen.root = fallback.clone();
+        chosen
+    });
+    print_route(server, failover.as_ref().unwrap_or(route), &method, &path);
+    0
+}
+
+fn print_limits(server: &ServerConfig) {
+    println!("Limits:   client_max_body_size {} bytes", server.client_max_body_size);
+    println!(
+        "          max_request_line {}, max_header_size {}, max_header_count {}",
+        server.max_request_line, server.max_header_size, server.max_header_count
+    );
+    if server.strict {
+        println!("          strict HTTP parsing");
+    }
+    println!(
+        "          header_timeout {}, body_timeout {}, write_timeout {}",
+        format_duration(server.header_timeout),
+        format_duration(server.body_timeout),
+        format_duration(server.write_timeout)
+    );
+    if let Some(timeout) = server.request_timeout {
+        println!("          request_timeout {}", format_duration(timeout));
+    }
+    if let Some(rate) = server.max_bandwidth {
+        println!("          max_bandwidth {} bytes/s", rate);
+    }
+}
+
+fn print_route(server: &ServerConfig, route: &Route, method: &str, path: &str) {
+    let allowed = route.methods.iter().any(|m| m == method) || method == "OPTIONS";
+    println!(
+        "Methods:  {}{}",
+        route.methods.join(", "),
+        if admin::rejects(route, &HttpMethod::from_str(method)) { " (read-only)" } else { "" }
+    );
+    if let Some(schemes) = &route.auth {
+        println!(
+            "Auth:     {} (realm {})",
+            schemes.join(", "),
+            route.auth_realm.as_deref().unwrap_or("localserver")
+        );
+    }
+    if route.force_https.unwrap_or(server.force_https) {
+        println!("Note:     plain HTTP requests are redirected to HTTPS");
+    }
+
+    if let Some(target) = &route.redirect {
+        println!("Handler:  redirect to {}", target);
+        return;
+    }
+    if let Some(upstream) = &route.proxy_pass {
+        println!("Handler:  proxy_pass to {}", upstream);
+        return;
+    }
+    if !allowed {
+        println!("Result:   405 Method Not Allowed");
+        return;
+    }
+
+    if let Some((handler, script, path_info)) = split_script_path(path, &route.cgi) {
+        print_file(server, route, script);
+        if !script_allowed(route, script) {
+            println!("Result:   403 Forbidden, {} is not in cgi_scripts", script);
+            return;
+        }
+        let via = match &route.fastcgi_pass {
+            Some(address) => format!("FastCGI at {}", address),
+            None => handler_interpreter(handler).unwrap_or("direct execution").to_string(),
+        };
+        println!("Handler:  CGI {} via {}", handler.extension, via);
+        println!("          SCRIPT_NAME {}, PATH_INFO {:?}", script, path_info);
+        if let Some(timeout) = route.cgi_timeout {
+            println!("          cgi_timeout {}", format_duration(timeout));
+        }
+        if route.cgi_session == Some(true) {
+            println!("          session values as SESSION_*, changed with X-Session-Set / X-Session-Unset");
+        }
+        println!(
+            "          buffered up to {} bytes, output {}",
+            route.cgi_buffer_size.unwrap_or(DEFAULT_BUFFER_SIZE),
+            route.cgi_max_output.map_or("unlimited".to_string(), |max| format!("capped at {} bytes", max))
+        );
+        return;
+    }
+
+    if !route.split.is_empty() {
+        // Each variant resolves the path under its own root
+        for variant in &route.split {
+            let mut variant_route = route.clone();
+            variant_route.root = variant.root.clone();
+            println!("Split:    weight {} (by {})", variant.weight, route.split_by.as_str());
+            print_file(server, &variant_route, path);
+        }
+    } else {
+        print_file(server, route, path);
+    }
+    if let Some(fallback) = &route.not_found {
+        println!("Fallback: {} with 200 when the file is missing", fallback);
+    }
+
+    let handler = match method {
+        "GET" if route.list_directory == Some(true) && route.path.trim_matches('/') == path.trim_matches('/') => {
+            "directory listing"
+        }
+        "GET" => "static file",
+        "POST" if route.batch == Some(true) => "upload, or batch file operations with ?batch",
+        "POST" => "upload",
+        "DELETE" => "delete file",
+        "PATCH" => "partial file update",
+        "OPTIONS" => "OPTIONS (204 with Allow)",
+        _ if route.webdav == Some(true) => "WebDAV",
+        _ => "none (501 Not Implemented)",
+    };
+    println!("Handler:  {}", handler);
+    if let Some(bundle) = &route.bundle {
+        println!("          served from bundle {}", bundle);
+    }
+}
+
+fn print_file(server: &ServerConfig, route: &Route, path: &str) {
+    match resolve_file_path(server, route, path) {
+        Some(file) => {
+            let state = match Path::new(&file).metadata() {
+                _ if route.bundle.is_some() => "in bundle",
+                Ok(m) if m.is_dir() => "directory",
+                Ok(_) => "file",
+                Err(_) => "missing",
+            };
+            println!("Path:     {} ({})", file, state);
+        }
+        None => println!("Path:     unresolved (outside the root, or a directory on the way is missing)"),
+    }
+}
@@ -6,6 +6,12 @@ pub struct Router {
     routes: std::collections::HashMap<String, Handler>,
 }
 
+impl Default for Router {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Router {
     pub fn new() -> Self {
         Router {
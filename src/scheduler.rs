@@ -0,0 +1,150 @@
+use std::fs;
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant, SystemTime};
+
+use uuid::Uuid;
+
+use crate::compress_cache;
+use crate::config::{TaskAction, TaskConfig};
+use crate::daemon;
+use crate::utils::session::SessionStore;
+
+struct ScheduledTask {
+    config: TaskConfig,
+    next_run: Instant,
+    running: Option<Child>,
+}
+
+/// Runs the periodic tasks declared in the `tasks:` config block.
+///
+/// Ticked from the event loop, so every action must return quickly; commands
+/// are spawned and reaped on later ticks rather than waited on.
+pub struct Scheduler {
+    tasks: Vec<ScheduledTask>,
+}
+
+impl Scheduler {
+    pub fn new(tasks: &[TaskConfig]) -> Self {
+        let now = Instant::now();
+        Self {
+            tasks: tasks
+                .iter()
+                .map(|config| ScheduledTask {
+                    next_run: now + config.every + random_jitter(config.jitter),
+                    config: config.clone(),
+                    running: None,
+                })
+                .collect(),
+        }
+    }
+
+    pub fn tick(&mut self, session_store: &SessionStore) {
+        let now = Instant::now();
+
+        for task in &mut self.tasks {
+            if let Some(child) = task.running.as_mut() {
+                match child.try_wait() {
+                    Ok(Some(status)) => {
+                        info!("Task '{}' finished with {}", task.config.name, status);
+                        task.running = None;
+                    }
+                    Ok(None) => {}
+                    Err(e) => {
+                        error!("Task '{}' wait error: {}", task.config.name, e);
+                        task.running = None;
+                    }
+                }
+            }
+
+            if now < task.next_run {
+                continue;
+            }
+            task.next_run = now + task.config.every + random_jitter(task.config.jitter);
+
+            // Overlap prevention: a slow run just skips its next slot
+            if task.running.is_some() {
+                info!(
+                    "Task '{}' still running, skipping this run",
+                    task.config.name
+                );
+                continue;
+            }
+
+            match &task.config.action {
+                TaskAction::SessionCleanup => {
+                    let removed = session_store.cleanup();
+                    info!("Task '{}' removed {} session(s)", task.config.name, removed);
+                }
+                TaskAction::TempCleanup { dir, max_age } => {
+                    let removed = remove_older_than(Path::new(dir), *max_age);
+                    info!(
+                        "Task '{}' removed {} file(s) from {}",
+                        task.config.name, removed, dir
+                    );
+                }
+                TaskAction::CachePrune { dir, max_size } => {
+                    let removed = compress_cache::prune(dir, *max_size);
+                    info!(
+                        "Task '{}' removed {} file(s) from {}",
+                        task.config.name, removed, dir
+                    );
+                }
+                TaskAction::LogRotate { max_size, keep } => {
+                    for path in daemon::rotate_logs(*max_size, *keep) {
+                        info!("Task '{}' rotated {}", task.config.name, path);
+                    }
+                }
+                TaskAction::Command(command) => {
+                    match Command::new("sh")
+                        .arg("-c")
+                        .arg(command)
+                        .stdin(Stdio::null())
+                        .spawn()
+                    {
+                        Ok(child) => task.running = Some(child),
+                        Err(e) => error!("Task '{}' failed to start: {}", task.config.name, e),
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn random_jitter(max: Duration) -> Duration {
+    if max.is_zero() {
+        return Duration::ZERO;
+    }
+    let millis = max.as_millis().max(1);
+    let r = Uuid::new_v4().as_u128() % millis;
+    Duration::from_millis(r as u64)
+}
+
+fn remove_older_than(dir: &Path, max_age: Duration) -> usize {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return 0;
+    };
+
+    let now = SystemTime::now();
+    let mut removed = 0;
+
+    for entry in entries.flatten() {
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        if !metadata.is_file() {
+            continue;
+        }
+        let expired = metadata
+            .modified()
+            .ok()
+            .and_then(|m| now.duration_since(m).ok())
+            .is_some_and(|age| age > max_age);
+
+        if expired && fs::remove_file(entry.path()).is_ok() {
+            removed += 1;
+        }
+    }
+
+    removed
+}
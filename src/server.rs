@@ -1,26 +1,124 @@
-use crate::config::{Config, ServerConfig};
+use crate::admin::{self, ConnectionInfo, ListenerState};
+use crate::auth;
+use crate::client::{HttpClient, OutboundPool};
+use crate::config::{
+    AcceptStrategy, Config, DEFAULT_MAX_HEADER_COUNT, DEFAULT_MAX_HEADER_SIZE, DEFAULT_MAX_REQUEST_LINE,
+    DEFAULT_PHASE_TIMEOUT, ListenErrorPolicy, ServerConfig, format_duration,
+};
+use crate::crash;
+use crate::daemon;
+use crate::metrics;
+use crate::pacing::{self, Pacer};
 use crate::models::HttpResponseCommon;
-use crate::read::handle_read_state;
-use crate::request::HttpRequestBuilder;
+use crate::read::{
+    ReadTimeout, check_read_timeout, handle_read_state, process_request, respond_budget_exhausted,
+    render_pending_error, respond_memory_pressure, respond_timeout,
+};
+use crate::request::{HeadLimits, HttpRequestBuilder, SpillPolicy};
+use crate::shutdown;
+use crate::stats;
+use crate::scheduler::Scheduler;
+use crate::utils::buffer::AdaptiveBuffer;
+use crate::utils::affinity;
+use crate::utils::net::{bind_listener, bind_std_listener, resolve_listen_addr};
 use crate::utils::session::SessionStore;
-use crate::write::handle_write_state;
-use mio::net::{TcpListener, TcpStream};
+use crate::write::{handle_lingering_state, handle_write_state, should_keep_alive, write_timed_out};
+use crate::tls::{self, ClientStream, listener_config};
+use crate::transport::Transport;
+use crate::upstream;
+use mio::net::TcpListener;
 use mio::{Events, Interest, Poll, Token};
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::io::{self};
-use std::net::Shutdown;
+use std::net::{Shutdown, SocketAddr};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
 use std::time::{Duration, Instant};
 
 const LISTENER_TOKEN_START: usize = 0;
 const CONNECTION_TOKEN_START: usize = 10000;
+// Bytes a single connection may move per loop iteration before yielding
+const READ_BUDGET_PER_TICK: usize = 64 * 1024;
+const WRITE_BUDGET_PER_TICK: usize = 256 * 1024;
+// Connections idle longer than this are dropped
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(5);
+// How long the rest of a refused request is read and dropped before closing
+pub const LINGER_TIMEOUT: Duration = Duration::from_secs(2);
+// How often workers refresh their connection list for the admin API
+const CONNECTIONS_PUBLISH_INTERVAL: Duration = Duration::from_millis(250);
+// Rebind delay after a listener failure, doubled up to the max
+const LISTENER_RETRY_MIN: Duration = Duration::from_secs(1);
+const LISTENER_RETRY_MAX: Duration = Duration::from_secs(60);
 
-#[derive(PartialEq, Debug)]
+/// A socket bound once for every worker, and the flag held by the worker
+/// currently accepting on it
+type SharedListener = (std::net::TcpListener, Arc<AtomicBool>);
+
+/// Shared sockets by address, with `accept_strategy: leader_follower`
+static SHARED_LISTENERS: OnceLock<Mutex<HashMap<SocketAddr, SharedListener>>> = OnceLock::new();
+
+#[derive(PartialEq, Debug, Clone, Copy)]
 pub enum Status {
     Read,
+    Queued,
     Write,
+    Lingering, // Error sent, dropping what the client still sends until it stops
     Finish,
 }
 
+/// Why a connection ended, counted and logged when it is dropped
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum CloseReason {
+    ClientEof,     // The client closed its end
+    ReadError,     // Reading from the client failed (reset...)
+    WriteError,    // Writing to the client failed
+    Timeout,       // Idle, stalled or over its request budget
+    Completed,     // Response sent and the client didn't ask for keep-alive
+    ServerClose,   // The server ended it: connection limits, handler errors...
+    ProtocolError, // Malformed request or TLS failure
+    BodyTooLarge,  // Request body over client_max_body_size
+}
+
+impl Status {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Status::Read => "read",
+            Status::Queued => "queued",
+            Status::Write => "write",
+            Status::Lingering => "lingering",
+            Status::Finish => "finish",
+        }
+    }
+}
+
+impl CloseReason {
+    pub const ALL: [CloseReason; 8] = [
+        CloseReason::ClientEof,
+        CloseReason::ReadError,
+        CloseReason::WriteError,
+        CloseReason::Timeout,
+        CloseReason::Completed,
+        CloseReason::ServerClose,
+        CloseReason::ProtocolError,
+        CloseReason::BodyTooLarge,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CloseReason::ClientEof => "client_eof",
+            CloseReason::ReadError => "read_error",
+            CloseReason::WriteError => "write_error",
+            CloseReason::Timeout => "timeout",
+            CloseReason::Completed => "completed",
+            CloseReason::ServerClose => "server_close",
+            CloseReason::ProtocolError => "protocol_error",
+            CloseReason::BodyTooLarge => "body_too_large",
+        }
+    }
+}
+
 pub struct SocketStatus {
     pub ttl: Instant,
     pub status: Status,
@@ -28,22 +126,190 @@ pub struct SocketStatus {
     pub response: Option<Box<dyn HttpResponseCommon>>,
     pub server_selected: bool,
     pub body_too_large: bool,
+    pub bad_request: bool,
+    pub uri_too_long: bool,
+    pub headers_too_large: bool,
+    pub unsupported_coding: bool, // The body came with a transfer coding other than chunked
+    pub expectation_failed: bool, // Expect header other than 100-continue
+    pub spill_refused: bool, // Body with nowhere to spill over memory_watermark, answered with 503
+    pub head_limits: HeadLimits,
     pub max_body_size: Option<usize>,
+    pub heavy_allowed: bool,
+    pub read_budget: usize,
+    pub write_budget: usize,
+    pub budget_exhausted: bool,
+    pub read_buffer: AdaptiveBuffer,
+    pub request_started: Option<Instant>, // First byte of the current request
+    pub header_timeout: Duration,
+    pub body_timeout: Duration,
+    pub write_timeout: Duration,
+    pub request_timeout: Option<Duration>, // Budget shared by every stage of a request
+    pub awaiting_events: bool, // Streamed response parked until its producer has more
+    pub pacer: Option<&'static Pacer>, // Egress cap of the selected server
+    pub paced_until: Option<Instant>,  // Writes held back until the cap allows more
+    pub opened: Instant,               // When the connection was accepted
+    pub requests: usize,               // Requests routed on this connection so far
+    pub max_age: Option<Duration>,     // From the selected server's max_connection_age
+    pub max_requests: Option<usize>,   // From the selected server's max_connection_requests
+    pub last_request: bool,            // Answered with Connection: close, the connection ends after it
+    pub close_reason: Option<CloseReason>, // Set by whatever decided the connection ends
+    pub response_status: Option<u16>,  // Status of the response being written, once its status line went out
+    pub error_pending: bool,           // The response may still go through the error handler once its status is known
+    pub peer: Option<SocketAddr>,      // Kept for the close log, the socket may be shut down by then
+    pub route: Option<String>,         // Server name and route path the request in flight was routed to
+    pub spill_dir: Option<PathBuf>,    // upload_tmp_dir of the selected server
+    pub memory_pressure: bool,         // The worker is over memory_watermark, bodies go to disk
+}
+
+impl Default for SocketStatus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SocketStatus {
+    pub fn new() -> Self {
+        SocketStatus {
+            ttl: Instant::now(),
+            status: Status::Read,
+            request: HttpRequestBuilder::new(),
+            response: None,
+            server_selected: false,
+            max_body_size: None,
+            body_too_large: false,
+            bad_request: false,
+            uri_too_long: false,
+            headers_too_large: false,
+            unsupported_coding: false,
+            expectation_failed: false,
+            spill_refused: false,
+            head_limits: HeadLimits {
+                max_request_line: DEFAULT_MAX_REQUEST_LINE,
+                max_header_size: DEFAULT_MAX_HEADER_SIZE,
+                max_header_count: DEFAULT_MAX_HEADER_COUNT,
+                strict: false,
+            },
+            heavy_allowed: false,
+            read_budget: READ_BUDGET_PER_TICK,
+            write_budget: WRITE_BUDGET_PER_TICK,
+            budget_exhausted: false,
+            read_buffer: AdaptiveBuffer::new(),
+            request_started: None,
+            header_timeout: DEFAULT_PHASE_TIMEOUT,
+            body_timeout: DEFAULT_PHASE_TIMEOUT,
+            write_timeout: DEFAULT_PHASE_TIMEOUT,
+            request_timeout: None,
+            awaiting_events: false,
+            pacer: None,
+            paced_until: None,
+            opened: Instant::now(),
+            requests: 0,
+            max_age: None,
+            max_requests: None,
+            last_request: false,
+            close_reason: None,
+            response_status: None,
+            error_pending: false,
+            peer: None,
+            route: None,
+            spill_dir: None,
+            memory_pressure: false,
+        }
+    }
+
+    /// Record why the connection is ending; the first reason given wins
+    pub fn closing(&mut self, reason: CloseReason) {
+        self.close_reason.get_or_insert(reason);
+    }
+
+    /// Use the phase timeouts and connection limits of the server handling
+    /// this connection
+    pub fn apply_timeouts(&mut self, server: &ServerConfig) {
+        self.header_timeout = server.header_timeout;
+        self.body_timeout = server.body_timeout;
+        self.write_timeout = server.write_timeout;
+        self.request_timeout = server.request_timeout;
+        self.max_age = server.max_connection_age;
+        self.max_requests = server.max_connection_requests;
+    }
+
+    /// Use the request head limits of the listener's default server; heads
+    /// are read before their Host can pick another one
+    pub fn apply_head_limits(&mut self, server: &ServerConfig) {
+        self.head_limits = HeadLimits {
+            max_request_line: server.max_request_line,
+            max_header_size: server.max_header_size,
+            max_header_count: server.max_header_count,
+            strict: server.strict,
+        };
+    }
+
+    /// Over memory_watermark, a body still collected in memory goes to disk
+    /// from here on. False when the server has no upload_tmp_dir to put it in.
+    pub fn spill_under_pressure(&mut self) -> bool {
+        if !self.request.buffers_body() {
+            return true;
+        }
+        let Some(dir) = self.spill_dir.clone() else {
+            return false;
+        };
+        self.request.set_spill(SpillPolicy { dir, threshold: 0 }).is_ok()
+    }
+
+    /// Whether the connection has lived or served enough, or the server is
+    /// shutting down, so it closes after the current response, keep-alive or not
+    pub fn worn_out(&self, now: Instant) -> bool {
+        self.max_age.is_some_and(|age| now.duration_since(self.opened) >= age)
+            || self.max_requests.is_some_and(|max| self.requests >= max)
+            || shutdown::requested()
+    }
+
+    /// Between requests, or done: nothing is lost by closing it now
+    pub fn idle(&self) -> bool {
+        match self.status {
+            Status::Read => self.request_started.is_none() && !self.request.has_pipelined(),
+            Status::Finish => true,
+            _ => false,
+        }
+    }
+
+    /// When the current request must be done, if the server sets a budget
+    pub fn deadline(&self) -> Option<Instant> {
+        Some(self.request_started? + self.request_timeout?)
+    }
+
+    pub fn deadline_passed(&self, now: Instant) -> bool {
+        self.deadline().is_some_and(|deadline| now >= deadline)
+    }
 }
 
 pub struct SocketData {
-    pub stream: TcpStream,
+    pub stream: Box<dyn Transport>,
     pub status: SocketStatus,
     pub listener_token: Token,
     pub session_store: SessionStore,
+    pub http_client: HttpClient,
 }
 
 pub struct ListenerInfo {
-    pub listener: TcpListener,
+    pub listener: Option<TcpListener>, // None while the port is not bound
     pub host: String,
     pub port: u16,
     pub servers: Vec<ServerConfig>,
+    pub server_indices: Vec<usize>, // Position of each server in the config, for route changes
     pub default_server_index: usize,
+    pub tls: Option<Arc<rustls::ServerConfig>>,
+    pub proxy_protocol: bool, // Connections open with a PROXY header from a load balancer
+    pub policy: ListenErrorPolicy,
+    pub failures: u32,
+    pub retry_at: Option<Instant>,
+    pub leader: Option<Arc<AtomicBool>>, // Set when the socket is shared with the other workers
+}
+
+impl ListenerInfo {
+    fn address(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
 }
 
 pub struct Server {
@@ -52,22 +318,96 @@ pub struct Server {
     listeners: HashMap<Token, ListenerInfo>,
     connections: HashMap<Token, SocketData>,
     session_store: SessionStore,
+    http_client: HttpClient,
+    outbound: OutboundPool,
+    heavy_queue: VecDeque<Token>,
+    ready_queue: VecDeque<Token>,
+    over_watermark: bool,
+    paused_listeners: Vec<Token>,
+    pipe_owners: HashMap<Token, Token>, // Producer pipe -> connection it feeds
     next_token: usize,
+    stall_threshold: Duration,
+    worker: usize,
+    accept_strategy: Option<AcceptStrategy>, // None with a single worker
+    routes_generation: u64, // Last route table change picked up from the admin API
+    drain_deadline: Option<Instant>, // Set once a shutdown is requested
+    connections_published: Instant, // Last connection list handed to the admin API
 }
 
 impl Server {
     pub fn new() -> io::Result<Self> {
+        Self::with_session_store(SessionStore::new())
+    }
+
+    fn with_session_store(session_store: SessionStore) -> io::Result<Self> {
         Ok(Server {
             poll: Poll::new()?,
             events: Events::with_capacity(1024),
             listeners: HashMap::new(),
             connections: HashMap::new(),
-            session_store: SessionStore::new(),
+            session_store,
+            http_client: HttpClient::new(),
+            outbound: OutboundPool::new(),
+            heavy_queue: VecDeque::new(),
+            ready_queue: VecDeque::new(),
+            over_watermark: false,
+            paused_listeners: Vec::new(),
+            pipe_owners: HashMap::new(),
             next_token: CONNECTION_TOKEN_START,
+            stall_threshold: Duration::from_millis(100),
+            worker: 0,
+            accept_strategy: None,
+            routes_generation: 0,
+            drain_deadline: None,
+            connections_published: Instant::now(),
         })
     }
 
+    /// Client for outbound requests driven by this server's event loop
+    pub fn http_client(&self) -> HttpClient {
+        self.http_client.clone()
+    }
+
+    /// Serve until SIGINT/SIGTERM, then drain. With `workers: N`, N-1 extra
+    /// threads run their own event loop on the same ports, either on
+    /// SO_REUSEPORT listeners of their own or on one shared socket
+    /// (`accept_strategy`); sessions are shared, the rest of the state is
+    /// per worker.
     pub fn run(&mut self, config: Config) -> io::Result<()> {
+        crash::init(&config);
+        admin::init(&config);
+        auth::init(&config);
+        upstream::init(&config);
+        pacing::init(&config);
+        tls::init(&config);
+        metrics::init_workers(config.workers);
+        stats::init(&config);
+        shutdown::install(config.workers);
+        for worker in 1..config.workers {
+            let config = config.clone();
+            let session_store = self.session_store.clone();
+            thread::Builder::new()
+                .name(format!("worker-{}", worker))
+                .spawn(move || {
+                    let result = Server::with_session_store(session_store)
+                        .and_then(|mut server| server.event_loop(config, worker));
+                    if let Err(e) = result {
+                        // A silently missing worker would hide the failure
+                        error!("Worker {} stopped: {}", worker, e);
+                        crash::report(&format!("worker {} stopped: {}", worker, e));
+                        std::process::exit(1);
+                    }
+                })?;
+        }
+
+        self.event_loop(config, 0)
+    }
+
+    fn event_loop(&mut self, config: Config, worker: usize) -> io::Result<()> {
+        self.worker = worker;
+        self.accept_strategy = (config.workers > 1).then_some(config.accept_strategy);
+        self.stall_threshold = config.loop_stall_threshold;
+        self.pin(&config);
         let mut listener_map: HashMap<(String, u16), Vec<(usize, ServerConfig)>> = HashMap::new();
 
         for (idx, server) in config.servers.iter().enumerate() {
@@ -75,135 +415,722 @@ impl Server {
                 let key = (server.host.clone(), port);
                 listener_map
                     .entry(key)
-                    .or_insert_with(Vec::new)
+                    .or_default()
                     .push((idx, server.clone()));
             }
         }
 
-        let mut token_counter = LISTENER_TOKEN_START;
-
-        for ((host, port), server_list) in listener_map {
-            println!("Setting up listener on {}:{}... ", host, port);
-            let addr = format!("{}:{}", host, port).parse().unwrap();
-            let mut listener = TcpListener::bind(addr)?;
-            let token = Token(token_counter);
-            token_counter += 1;
-
-            self.poll
-                .registry()
-                .register(&mut listener, token, Interest::READABLE)?;
+        for (offset, ((host, port), server_list)) in listener_map.into_iter().enumerate() {
+            let token = Token(LISTENER_TOKEN_START + offset);
 
             let default_idx = server_list
                 .iter()
                 .position(|(_, srv)| srv.default_server)
                 .unwrap_or(0);
 
-            let servers: Vec<ServerConfig> = server_list.into_iter().map(|(_, srv)| srv).collect();
+            let (server_indices, servers): (Vec<usize>, Vec<ServerConfig>) =
+                server_list.into_iter().unzip();
+            let tls = listener_config(&servers, default_idx).map_err(|e| {
+                io::Error::new(io::ErrorKind::InvalidInput, format!("{}:{}: {}", host, port, e))
+            })?;
 
-            println!(
-                "Listening on {}:{} with {} server(s)",
-                host,
-                port,
-                servers.len()
-            );
-            for (i, srv) in servers.iter().enumerate() {
-                println!(
-                    "  - {} {}",
-                    srv.server_name,
-                    if i == default_idx { "(default)" } else { "" }
+            let proxy_protocol = servers.iter().any(|s| s.proxy_protocol);
+
+            if worker == 0 {
+                info!(
+                    "Listening on {}:{} with {} server(s){}{}",
+                    host,
+                    port,
+                    servers.len(),
+                    if tls.is_some() { " over TLS" } else { "" },
+                    if proxy_protocol { " behind PROXY protocol" } else { "" }
                 );
+                for (i, srv) in servers.iter().enumerate() {
+                    info!(
+                        "  - {} {}",
+                        srv.server_name,
+                        if i == default_idx { "(default)" } else { "" }
+                    );
+                }
             }
 
+            // The default server decides what happens when the port fails
+            let policy = servers[default_idx].listen_error;
             self.listeners.insert(
                 token,
                 ListenerInfo {
-                    listener,
+                    listener: None,
                     host,
                     port,
                     servers,
+                    server_indices,
                     default_server_index: default_idx,
+                    tls,
+                    proxy_protocol,
+                    policy,
+                    failures: 0,
+                    retry_at: None,
+                    leader: None,
                 },
             );
+            if let Err(e) = self.open_listener(token) {
+                self.listener_down(token, e)?;
+            }
         }
+        if worker == 0 {
+            daemon::ready();
+        }
+
+        // Periodic tasks run once, not once per worker
+        let mut scheduler = Scheduler::new(if worker == 0 { &config.tasks } else { &[] });
+        // Each worker keeps its share of the memory budget
+        let memory_watermark = config.memory_watermark.map(|w| w / config.workers);
 
         loop {
-            self.session_store.cleanup();
+            let iteration_started = Instant::now();
+            if shutdown::requested() {
+                if self.drain_deadline.is_none() {
+                    self.begin_drain(config.drain_timeout);
+                }
+                if self.drain() {
+                    shutdown::drained();
+                    if worker == 0 {
+                        // The summary covers what every worker served
+                        while !shutdown::all_drained() {
+                            thread::sleep(Duration::from_millis(10));
+                        }
+                        info!("Shutdown complete");
+                        stats::report();
+                    }
+                    return Ok(());
+                }
+            }
+            if worker == 0 {
+                self.session_store.cleanup();
+            }
+            scheduler.tick(&self.session_store);
             self.check_timeouts();
-            let timeout = Some(Duration::from_millis(100)); // wait max 100ms
+            self.poll_streams();
+            self.resume_paced();
+            self.retry_listeners();
+            self.follow_shared_listeners()?;
+            self.follow_route_changes();
+            self.check_memory(memory_watermark)?;
+            self.outbound
+                .start_pending(&self.http_client, self.poll.registry(), &mut self.next_token);
+            self.outbound.resume(self.poll.registry());
+            self.outbound.check_timeouts(self.poll.registry());
+            let timeout = if self.heavy_queue.is_empty() && self.ready_queue.is_empty() {
+                // wait max 100ms, less when a paced response may write again sooner
+                let max_wait = Duration::from_millis(100);
+                Some(self.next_paced_wakeup().map_or(max_wait, |wait| wait.min(max_wait)))
+            } else {
+                Some(Duration::ZERO) // deferred work is waiting
+            };
 
-            self.poll.poll(&mut self.events, timeout)?;
-
-            for event in self.events.iter() {
-                let token = event.token();
+            let poll_started = Instant::now();
+            match self.poll.poll(&mut self.events, timeout) {
+                // A signal arrived, shutdown requests are seen next iteration
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                result => result?,
+            }
+            let waited = poll_started.elapsed();
 
+            let tokens: Vec<Token> = self.events.iter().map(|event| event.token()).collect();
+            for token in tokens {
                 if token.0 < CONNECTION_TOKEN_START {
-                    if let Some(listener_info) = self.listeners.get_mut(&token) {
-                        loop {
-                            match listener_info.listener.accept() {
-                                Ok((mut stream, _)) => {
-                                    let conn_token = Token(self.next_token);
-                                    self.next_token += 1;
-
-                                    self.poll
-                                        .registry()
-                                        .register(
-                                            &mut stream,
-                                            conn_token,
-                                            Interest::READABLE.add(Interest::WRITABLE),
-                                        )
-                                        .unwrap();
-
-                                    self.connections.insert(
-                                        conn_token,
-                                        SocketData {
-                                            stream,
-                                            status: SocketStatus {
-                                                ttl: Instant::now(),
-                                                status: Status::Read,
-                                                request: HttpRequestBuilder::new(),
-                                                response: None,
-                                                server_selected: false,
-                                                max_body_size: None,
-                                                body_too_large: false,
-                                            },
-                                            listener_token: token,
-                                            session_store: self.session_store.clone(),
-                                        },
-                                    );
-
-                                    println!(
-                                        "Accepted connection {:?} from listener {:?}",
-                                        conn_token, token
-                                    );
-                                }
-                                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                                    break;
-                                }
-                                Err(e) => {
-                                    eprintln!("Accept error: {:?}", e);
-                                    break;
-                                }
-                            }
+                    if self.over_watermark {
+                        // Leave the backlog in the kernel until memory is released
+                        if !self.paused_listeners.contains(&token) {
+                            self.paused_listeners.push(token);
                         }
+                    } else {
+                        self.accept_connections(token)?;
                     }
+                } else if self.outbound.contains(token) {
+                    self.outbound.handle_event(token, self.poll.registry());
+                } else if let Some(&owner) = self.pipe_owners.get(&token) {
+                    self.pipe_ready(owner);
                 } else {
-                    if let Some(socket_data) = self.connections.get_mut(&token) {
-                        loop {
-                            let listener_info = self.listeners.get(&socket_data.listener_token);
-                            match Server::handle(socket_data, listener_info) {
-                                Some(true) => {
-                                    continue;
-                                }
-                                Some(false) => {
-                                    break;
-                                }
-                                None => {
-                                    let _ = socket_data.stream.shutdown(Shutdown::Both);
-                                    self.connections.remove(&token);
-                                    break;
-                                }
+                    self.drive_connection(token);
+                }
+            }
+
+            // Connections that used up their budget last time get another turn.
+            // Edge-triggered events won't fire again for data already buffered.
+            let ready: Vec<Token> = self.ready_queue.drain(..).collect();
+            for token in ready {
+                self.drive_connection(token);
+            }
+
+            // Heavy handlers run after every cheap response of this iteration
+            let mut budget = config.heavy_handlers_per_tick;
+            while budget > 0 {
+                let Some(token) = self.heavy_queue.pop_front() else {
+                    break;
+                };
+                // Requests answered while queued (budget exhausted) don't need a slot
+                if let Some(socket_data) = self.connections.get_mut(&token)
+                    && matches!(socket_data.status.status, Status::Queued)
+                {
+                    socket_data.status.heavy_allowed = true;
+                    budget -= 1;
+                    self.drive_connection(token);
+                }
+            }
+
+            // Waiting in poll is idle time; anything else long means blocking work
+            let busy = iteration_started.elapsed().saturating_sub(waited);
+            metrics::LOOP_ITERATION.observe(busy);
+            if let Some(stats) = metrics::worker(worker) {
+                stats.busy_us.fetch_add(busy.as_micros() as u64, Ordering::Relaxed);
+                stats.connections.store(self.connections.len() as u64, Ordering::Relaxed);
+            }
+            stats::observe_connections(metrics::open_connections());
+            self.publish_connections();
+            if busy > self.stall_threshold {
+                metrics::LOOP_STALLS.fetch_add(1, Ordering::Relaxed);
+                warn!(
+                    "Worker {}: event loop iteration took {}ms (threshold {}ms)",
+                    worker,
+                    busy.as_millis(),
+                    self.stall_threshold.as_millis()
+                );
+            }
+        }
+    }
+
+    /// Hand the admin API a fresh list of this worker's connections, a few
+    /// times a second and only when it is served
+    fn publish_connections(&mut self) {
+        if !admin::enabled() || self.connections_published.elapsed() < CONNECTIONS_PUBLISH_INTERVAL {
+            return;
+        }
+        self.connections_published = Instant::now();
+        let mut connections: Vec<ConnectionInfo> = self
+            .connections
+            .iter()
+            .map(|(token, conn)| {
+                let status = &conn.status;
+                let request = status.request.get_before_done();
+                ConnectionInfo {
+                    token: token.0,
+                    peer: conn.stream.peer_addr().ok().or(status.peer),
+                    state: if status.idle() { "idle" } else { status.status.as_str() },
+                    tls: conn.stream.is_tls(),
+                    keep_alive: !status.last_request
+                        && !status.response.as_ref().is_some_and(|r| r.closes_connection())
+                        && request.map_or(status.requests > 0, should_keep_alive),
+                    upgrade: request.and_then(|r| r.headers.get("upgrade")).cloned(),
+                    request_bytes: status.request.buffered_len(),
+                    read_buffer_bytes: status.read_buffer.capacity(),
+                    response_bytes: status.response.as_ref().map_or(0, |r| r.buffered_len()),
+                    route: status.route.clone(),
+                    requests: status.requests,
+                    age: status.opened.elapsed(),
+                }
+            })
+            .collect();
+        connections.sort_by_key(|conn| conn.token);
+        admin::publish_connections(self.worker, connections);
+    }
+
+    /// Stop accepting for good; connections in progress get `timeout` to finish
+    fn begin_drain(&mut self, timeout: Duration) {
+        info!(
+            "Worker {}: shutting down, {} connection(s) get up to {} to finish",
+            self.worker,
+            self.connections.len(),
+            format_duration(timeout)
+        );
+        self.drain_deadline = Some(Instant::now() + timeout);
+        self.paused_listeners.clear();
+        for info in self.listeners.values_mut() {
+            info.retry_at = None;
+            if let Some(mut listener) = info.listener.take() {
+                let _ = self.poll.registry().deregister(&mut listener);
+            }
+            // The shared socket stays bound while the map holds it
+            if info.leader.take().is_some()
+                && let Ok(addr) = resolve_listen_addr(&info.host, info.port)
+                && let Some(shared) = SHARED_LISTENERS.get()
+            {
+                shared.lock().unwrap_or_else(|e| e.into_inner()).remove(&addr);
+            }
+        }
+    }
+
+    /// Close connections with no request in progress, and every one left
+    /// once the drain deadline passes. Returns whether none are left.
+    fn drain(&mut self) -> bool {
+        let overdue = self.drain_deadline.is_some_and(|deadline| Instant::now() >= deadline);
+        let closing: Vec<Token> = self
+            .connections
+            .iter()
+            .filter(|(_, conn)| overdue || conn.status.idle())
+            .map(|(token, _)| *token)
+            .collect();
+        if overdue && !closing.is_empty() {
+            warn!("Worker {}: drain timeout, closing {} connection(s)", self.worker, closing.len());
+        }
+        for token in closing {
+            if let Some(mut conn) = self.connections.remove(&token) {
+                log_close(token, &conn, CloseReason::ServerClose);
+                let _ = self.poll.registry().deregister(&mut conn.stream);
+                let _ = conn.stream.shutdown(Shutdown::Both);
+            }
+        }
+        self.connections.is_empty()
+    }
+
+    /// Bind and register the listener behind `token`
+    fn open_listener(&mut self, token: Token) -> io::Result<()> {
+        let Some(info) = self.listeners.get_mut(&token) else {
+            return Ok(());
+        };
+        let addr = resolve_listen_addr(&info.host, info.port)?;
+        let mut listener = match self.accept_strategy {
+            Some(AcceptStrategy::Leader) => {
+                let (socket, leader) = shared_listener(addr)?;
+                info.leader = Some(leader);
+                TcpListener::from_std(socket)
+            }
+            Some(AcceptStrategy::ReusePort) => bind_listener(addr, true)?,
+            None => bind_listener(addr, false)?,
+        };
+        self.poll
+            .registry()
+            .register(&mut listener, token, Interest::READABLE)?;
+
+        info.listener = Some(listener);
+        info.failures = 0;
+        info.retry_at = None;
+        admin::record_listener(&info.address(), ListenerState::Listening);
+        Ok(())
+    }
+
+    /// Apply the listener's error policy after a bind or accept failure.
+    /// Only `abort` returns the error, stopping this event loop.
+    fn listener_down(&mut self, token: Token, error: io::Error) -> io::Result<()> {
+        let Some(info) = self.listeners.get_mut(&token) else {
+            return Ok(());
+        };
+        if let Some(mut listener) = info.listener.take() {
+            let _ = self.poll.registry().deregister(&mut listener);
+        }
+        // A broken shared socket is bound again by the next worker retrying
+        if info.leader.take().is_some()
+            && let Ok(addr) = resolve_listen_addr(&info.host, info.port)
+            && let Some(shared) = SHARED_LISTENERS.get()
+        {
+            shared.lock().unwrap_or_else(|e| e.into_inner()).remove(&addr);
+        }
+        let address = info.address();
+
+        match info.policy {
+            ListenErrorPolicy::Abort => {
+                admin::record_listener(&address, ListenerState::Failed { error: error.to_string() });
+                Err(io::Error::new(error.kind(), format!("{}: {}", address, error)))
+            }
+            ListenErrorPolicy::Skip => {
+                error!("Listener {} failed, skipping it: {}", address, error);
+                admin::record_listener(&address, ListenerState::Failed { error: error.to_string() });
+                Ok(())
+            }
+            ListenErrorPolicy::Retry => {
+                info.failures += 1;
+                let delay = LISTENER_RETRY_MIN
+                    .saturating_mul(1 << (info.failures - 1).min(16))
+                    .min(LISTENER_RETRY_MAX);
+                info.retry_at = Some(Instant::now() + delay);
+                warn!(
+                    "Listener {} failed ({}), retrying in {}s",
+                    address,
+                    error,
+                    delay.as_secs()
+                );
+                admin::record_listener(
+                    &address,
+                    ListenerState::Retrying {
+                        error: error.to_string(),
+                        failures: info.failures,
+                    },
+                );
+                Ok(())
+            }
+        }
+    }
+
+    fn retry_listeners(&mut self) {
+        let now = Instant::now();
+        let due: Vec<Token> = self
+            .listeners
+            .iter()
+            .filter(|(_, info)| info.retry_at.is_some_and(|at| at <= now))
+            .map(|(token, _)| *token)
+            .collect();
+
+        for token in due {
+            match self.open_listener(token) {
+                Ok(()) => {
+                    if let Some(info) = self.listeners.get(&token) {
+                        info!("Listener {} is back", info.address());
+                    }
+                }
+                // Retry policy never aborts
+                Err(e) => {
+                    let _ = self.listener_down(token, e);
+                }
+            }
+        }
+    }
+
+    fn accept_connections(&mut self, token: Token) -> io::Result<()> {
+        let Some(listener_info) = self.listeners.get(&token) else {
+            return Ok(());
+        };
+        // Followers leave the shared socket to the worker already draining it
+        let leader = listener_info.leader.clone();
+        if let Some(leader) = &leader
+            && leader.swap(true, Ordering::Acquire)
+        {
+            return Ok(());
+        }
+        let result = self.drain_listener(token);
+        if let Some(leader) = leader {
+            leader.store(false, Ordering::Release);
+        }
+        result
+    }
+
+    /// Accept every connection waiting on the listener behind `token`
+    fn drain_listener(&mut self, token: Token) -> io::Result<()> {
+        let Some(listener_info) = self.listeners.get_mut(&token) else {
+            return Ok(());
+        };
+        let Some(listener) = listener_info.listener.as_ref() else {
+            return Ok(());
+        };
+
+        loop {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    if let Some(stats) = metrics::worker(self.worker) {
+                        stats.accepted.fetch_add(1, Ordering::Relaxed);
+                    }
+                    let mut stream = match ClientStream::new(stream, listener_info.tls.as_ref(), listener_info.proxy_protocol) {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            error!("TLS setup error: {:?}", e);
+                            continue;
+                        }
+                    };
+                    let conn_token = Token(self.next_token);
+                    self.next_token += 1;
+
+                    self.poll
+                        .registry()
+                        .register(
+                            &mut stream,
+                            conn_token,
+                            Interest::READABLE.add(Interest::WRITABLE),
+                        )
+                        .unwrap();
+
+                    // Until the Host header picks a server, the default one applies
+                    let mut status = SocketStatus::new();
+                    status.peer = stream.peer_addr().ok();
+                    status.memory_pressure = self.over_watermark;
+                    let peer = status.peer;
+                    if let Some(server) = listener_info.servers.get(listener_info.default_server_index) {
+                        status.apply_timeouts(server);
+                        status.apply_head_limits(server);
+                    }
+
+                    self.connections.insert(
+                        conn_token,
+                        SocketData {
+                            stream: Box::new(stream),
+                            status,
+                            listener_token: token,
+                            session_store: self.session_store.clone(),
+                            http_client: self.http_client.clone(),
+                        },
+                    );
+
+                    debug!(
+                        "Accepted connection {:?} from listener {:?}: peer={}",
+                        conn_token,
+                        token,
+                        peer.map_or_else(|| "unknown".to_string(), |addr| addr.to_string())
+                    );
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    break;
+                }
+                Err(e) if accept_error_is_transient(&e) => {
+                    error!("Accept error: {:?}", e);
+                    break;
+                }
+                Err(e) => {
+                    error!("Listener {:?} error: {:?}", token, e);
+                    return self.listener_down(token, e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Bytes currently held in request, read and response buffers
+    fn buffered_bytes(&self) -> usize {
+        self.connections
+            .values()
+            .map(|conn| {
+                conn.status.request.buffered_len()
+                    + conn.status.read_buffer.capacity()
+                    + conn.status.response.as_ref().map_or(0, |r| r.buffered_len())
+            })
+            .sum()
+    }
+
+    fn check_memory(&mut self, watermark: Option<usize>) -> io::Result<()> {
+        let Some(watermark) = watermark else {
+            return Ok(());
+        };
+
+        let used = self.buffered_bytes();
+        if !self.over_watermark && used >= watermark {
+            warn!(
+                "Memory watermark reached ({} >= {} bytes), pausing accepts and spilling bodies to disk",
+                used, watermark
+            );
+            self.over_watermark = true;
+            // Bodies already coming in stop growing in memory too
+            let mut refused = Vec::new();
+            for (token, conn) in self.connections.iter_mut() {
+                conn.status.memory_pressure = true;
+                if conn.status.status == Status::Read
+                    && conn.status.server_selected
+                    && !conn.status.spill_under_pressure()
+                {
+                    refused.push(*token);
+                }
+            }
+            for token in refused {
+                if let Some(socket_data) = self.connections.get_mut(&token) {
+                    info!("Client {:?} body can't be spilled under memory pressure, sending 503", token);
+                    respond_memory_pressure(socket_data, self.listeners.get(&socket_data.listener_token));
+                }
+                self.drive_connection(token);
+            }
+        } else if self.over_watermark && used < watermark {
+            info!("Memory back under watermark ({} bytes), resuming accepts", used);
+            self.over_watermark = false;
+            for conn in self.connections.values_mut() {
+                conn.status.memory_pressure = false;
+            }
+            for token in std::mem::take(&mut self.paused_listeners) {
+                self.accept_connections(token)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Swap in the routes added, replaced or removed through the admin API
+    /// since the last look. Requests read the routes afresh, so the next
+    /// one on any connection sees the change.
+    fn follow_route_changes(&mut self) {
+        let Some((generation, routes)) = admin::route_changes(self.routes_generation) else {
+            return;
+        };
+        for info in self.listeners.values_mut() {
+            for (server, &idx) in info.servers.iter_mut().zip(&info.server_indices) {
+                server.routes = routes[idx].clone();
+            }
+        }
+        self.routes_generation = generation;
+    }
+
+    /// Every worker is woken for a shared socket but only one leads. A
+    /// connection arriving while the leader finishes would not wake anyone
+    /// again, so each iteration takes another look at the backlog.
+    fn follow_shared_listeners(&mut self) -> io::Result<()> {
+        if self.over_watermark || self.accept_strategy != Some(AcceptStrategy::Leader) {
+            return Ok(());
+        }
+        let shared: Vec<Token> = self
+            .listeners
+            .iter()
+            .filter(|(_, info)| info.leader.is_some())
+            .map(|(token, _)| *token)
+            .collect();
+        for token in shared {
+            self.accept_connections(token)?;
+        }
+        Ok(())
+    }
+
+    /// Pin this worker's thread to its core, if `worker_cpu_affinity` asks for it
+    fn pin(&self, config: &Config) {
+        let Some(core) = config.worker_cpu_affinity.core_for(self.worker, &affinity::allowed_cpus()) else {
+            return;
+        };
+        match affinity::pin_current_thread(core) {
+            Ok(()) => {
+                if let Some(stats) = metrics::worker(self.worker) {
+                    stats.cpu.store(core as i64, Ordering::Relaxed);
+                }
+                info!("Worker {} pinned to core {}", self.worker, core);
+            }
+            // Serving unpinned beats not serving
+            Err(e) => warn!("Worker {}: cannot pin to core {}: {}", self.worker, core, e),
+        }
+    }
+
+    /// Wake parked streams whose producer queued new data
+    fn poll_streams(&mut self) {
+        let parked: Vec<Token> = self
+            .connections
+            .iter()
+            .filter(|(_, conn)| conn.status.awaiting_events)
+            .map(|(token, _)| *token)
+            .collect();
+        for token in parked {
+            self.wake_stream(token);
+        }
+    }
+
+    /// A pipe of the connection's producer is ready. While the request body is
+    /// still streaming into a script, feed it and read what it made room for.
+    fn pipe_ready(&mut self, token: Token) {
+        let Some(conn) = self.connections.get_mut(&token) else {
+            return;
+        };
+        if conn.status.status != Status::Read {
+            self.wake_stream(token);
+            return;
+        }
+        if let Some(response) = conn.status.response.as_mut() {
+            response.poll_producer();
+        }
+        self.drive_connection(token);
+    }
+
+    /// Poll the producer of a parked stream. Registering for WRITABLE again
+    /// delivers a writable event on the next poll.
+    fn wake_stream(&mut self, token: Token) {
+        let Some(conn) = self.connections.get_mut(&token) else {
+            return;
+        };
+        if !conn.status.awaiting_events {
+            return;
+        }
+        let Some(response) = conn.status.response.as_mut() else {
+            return;
+        };
+        if response.poll_producer() {
+            conn.status.awaiting_events = false;
+            let _ = self.poll.registry().reregister(
+                &mut conn.stream,
+                token,
+                Interest::READABLE.add(Interest::WRITABLE),
+            );
+        }
+    }
+
+    /// Drive responses whose egress cap allows writing again. Their sockets
+    /// stayed writable, so no readiness event would wake them.
+    fn resume_paced(&mut self) {
+        let now = Instant::now();
+        let mut due: Vec<(Instant, Token)> = self
+            .connections
+            .iter_mut()
+            .filter(|(_, conn)| conn.status.paced_until.is_some_and(|at| at <= now))
+            .map(|(token, conn)| {
+                conn.status.paced_until = None;
+                (conn.status.ttl, *token)
+            })
+            .collect();
+        // Least recently served first, so one download can't keep the bucket
+        due.sort();
+        for (_, token) in due {
+            self.drive_connection(token);
+        }
+    }
+
+    fn next_paced_wakeup(&self) -> Option<Duration> {
+        let now = Instant::now();
+        self.connections
+            .values()
+            .filter_map(|conn| conn.status.paced_until)
+            .min()
+            .map(|at| at.saturating_duration_since(now))
+    }
+
+    fn drive_connection(&mut self, token: Token) {
+        if let Some(socket_data) = self.connections.get_mut(&token) {
+            socket_data.status.read_budget = READ_BUDGET_PER_TICK;
+            socket_data.status.write_budget = WRITE_BUDGET_PER_TICK;
+            socket_data.status.budget_exhausted = false;
+
+            loop {
+                let listener_info = self.listeners.get(&socket_data.listener_token);
+                let phase = socket_data.status.status;
+                let started = Instant::now();
+                let result = Server::handle(socket_data, listener_info);
+                let elapsed = started.elapsed();
+                metrics::HANDLER.observe(elapsed);
+                if elapsed > self.stall_threshold {
+                    warn!(
+                        "Handler for {:?} ({:?}) blocked the event loop for {}ms",
+                        token,
+                        phase,
+                        elapsed.as_millis()
+                    );
+                }
+                // Pipes of a new producer wake this connection when they are ready
+                if let Some(response) = socket_data.status.response.as_mut() {
+                    match response.register_sources(self.poll.registry(), &mut self.next_token) {
+                        Ok(tokens) => {
+                            for pipe in tokens {
+                                self.pipe_owners.insert(pipe, token);
                             }
                         }
+                        Err(e) => error!("Failed to register producer of {:?}: {}", token, e),
+                    }
+                }
+                match result {
+                    Some(true) => {
+                        continue;
+                    }
+                    Some(false) => {
+                        // Parked streams only need to notice the client leaving
+                        if socket_data.status.awaiting_events {
+                            let _ = self.poll.registry().reregister(
+                                &mut socket_data.stream,
+                                token,
+                                Interest::READABLE,
+                            );
+                        }
+                        if socket_data.status.budget_exhausted
+                            && !self.ready_queue.contains(&token)
+                        {
+                            self.ready_queue.push_back(token);
+                        }
+                        if socket_data.status.status == Status::Queued
+                            && !socket_data.status.heavy_allowed
+                            && !self.heavy_queue.contains(&token)
+                        {
+                            self.heavy_queue.push_back(token);
+                        }
+                        break;
+                    }
+                    None => {
+                        let reason = socket_data.status.close_reason.unwrap_or(CloseReason::ServerClose);
+                        log_close(token, socket_data, reason);
+                        let _ = socket_data.stream.shutdown(Shutdown::Both);
+                        self.connections.remove(&token);
+                        break;
                     }
                 }
             }
@@ -216,27 +1143,154 @@ impl Server {
     ) -> Option<bool> {
         match socket_data.status.status {
             Status::Read => handle_read_state(socket_data, listener_info),
-            Status::Write => handle_write_state(socket_data),
+            Status::Queued if socket_data.status.heavy_allowed => {
+                process_request(socket_data, listener_info)
+            }
+            Status::Queued => Some(false),
+            Status::Write => {
+                render_pending_error(socket_data, listener_info);
+                handle_write_state(socket_data)
+            }
+            Status::Lingering => handle_lingering_state(socket_data),
             Status::Finish => None,
         }
     }
 
     fn check_timeouts(&mut self) {
-        const TIMEOUT: Duration = Duration::from_secs(5);
         let now = Instant::now();
         let mut expired = Vec::new();
+        let mut stalled = Vec::new();
+        let mut exhausted = Vec::new();
+        let mut lingered = Vec::new();
 
         for (token, conn) in &self.connections {
-            if now.duration_since(conn.status.ttl) > TIMEOUT {
-                expired.push(*token);
+            match conn.status.status {
+                Status::Read => match check_read_timeout(&conn.status, now) {
+                    ReadTimeout::Idle => expired.push(*token),
+                    ReadTimeout::Stalled => stalled.push(*token),
+                    ReadTimeout::Exhausted => exhausted.push(*token),
+                    ReadTimeout::None => {}
+                },
+                // Parked streams wait on their producer, not on the client
+                Status::Write if conn.status.awaiting_events => {}
+                Status::Write if conn.status.deadline_passed(now) => {
+                    info!("Client {:?} request budget exhausted while writing, aborting", token);
+                    expired.push(*token);
+                }
+                // Held back by the server's bandwidth cap, not by the client
+                Status::Write if conn.status.paced_until.is_some() => {}
+                Status::Write if write_timed_out(&conn.status, now) => {
+                    info!("Client {:?} stopped reading, aborting response", token);
+                    expired.push(*token);
+                }
+                // Queued requests are waiting on us, not on the client, but
+                // still count against the request budget
+                Status::Queued if conn.status.deadline_passed(now) => exhausted.push(*token),
+                Status::Queued | Status::Write => {}
+                Status::Lingering => {
+                    if now.duration_since(conn.status.ttl) > LINGER_TIMEOUT {
+                        lingered.push(*token);
+                    }
+                }
+                Status::Finish => {
+                    if now.duration_since(conn.status.ttl) > IDLE_TIMEOUT {
+                        expired.push(*token);
+                    }
+                }
+            }
+        }
+
+        // Clients that stall mid-request get a 408 before the connection closes
+        for token in stalled {
+            if let Some(socket_data) = self.connections.get_mut(&token) {
+                info!("Client {:?} stalled mid-request, sending 408", token);
+                respond_timeout(socket_data, self.listeners.get(&socket_data.listener_token));
+            }
+            self.drive_connection(token);
+        }
+
+        for token in exhausted {
+            if let Some(socket_data) = self.connections.get_mut(&token) {
+                info!("Client {:?} request budget exhausted, sending 503", token);
+                respond_budget_exhausted(socket_data, self.listeners.get(&socket_data.listener_token));
+            }
+            self.drive_connection(token);
+        }
+
+        // Done lingering; the connection ends for the error it was answered with
+        for token in lingered {
+            if let Some(mut conn) = self.connections.remove(&token) {
+                let reason = conn.status.close_reason.unwrap_or(CloseReason::ProtocolError);
+                log_close(token, &conn, reason);
+                let _ = self.poll.registry().deregister(&mut conn.stream);
+                let _ = conn.stream.shutdown(Shutdown::Both);
             }
         }
 
         for token in expired {
             if let Some(mut conn) = self.connections.remove(&token) {
+                log_close(token, &conn, CloseReason::Timeout);
                 let _ = self.poll.registry().deregister(&mut conn.stream);
                 let _ = conn.stream.shutdown(Shutdown::Both);
             }
         }
+
+        // Pipes are closed with their response; forget the ones that are gone
+        let connections = &self.connections;
+        self.pipe_owners.retain(|_, owner| connections.contains_key(owner));
+    }
+}
+
+/// Count a connection teardown and log it with what the connection did
+fn log_close(token: Token, conn: &SocketData, reason: CloseReason) {
+    metrics::record_close(reason);
+    // Once a PROXY header is read the stream names the real client
+    let peer = conn
+        .stream
+        .peer_addr()
+        .ok()
+        .or(conn.status.peer)
+        .map_or_else(|| "unknown".to_string(), |addr| addr.to_string());
+    debug!(
+        "Connection {:?} closed: reason={} peer={} requests={} age={}ms",
+        token,
+        reason.as_str(),
+        peer,
+        conn.status.requests,
+        conn.status.opened.elapsed().as_millis()
+    );
+}
+
+/// Accept failures that concern one connection or a momentary resource
+/// shortage, not the listening socket itself
+fn accept_error_is_transient(e: &io::Error) -> bool {
+    if matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::OutOfMemory
+    ) {
+        return true;
+    }
+    #[cfg(unix)]
+    if let Some(code) = e.raw_os_error() {
+        return matches!(code, libc::EMFILE | libc::ENFILE | libc::ENOBUFS | libc::ENOMEM);
+    }
+    false
+}
+
+/// The socket every worker shares for `addr`, bound by the first one asking
+fn shared_listener(addr: SocketAddr) -> io::Result<SharedListener> {
+    let mut shared = SHARED_LISTENERS
+        .get_or_init(|| Mutex::new(HashMap::new()))
+        .lock()
+        .unwrap_or_else(|e| e.into_inner());
+    if let Some((socket, leader)) = shared.get(&addr) {
+        return Ok((socket.try_clone()?, leader.clone()));
     }
+    let socket = bind_std_listener(addr, false)?;
+    let leader = Arc::new(AtomicBool::new(false));
+    shared.insert(addr, (socket.try_clone()?, leader.clone()));
+    Ok((socket, leader))
 }
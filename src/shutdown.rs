@@ -0,0 +1,39 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// Set by the signal handler, noticed by every worker's event loop
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+/// Workers still serving in-flight requests after a shutdown request
+static DRAINING: AtomicUsize = AtomicUsize::new(0);
+
+/// Turn SIGINT and SIGTERM into a shutdown request, so the workers drain
+/// and the run summary is written before the process ends. Elsewhere
+/// Ctrl-C ends it right away.
+pub fn install(workers: usize) {
+    DRAINING.store(workers, Ordering::Relaxed);
+    #[cfg(unix)]
+    unsafe {
+        let handler = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+}
+
+#[cfg(unix)]
+extern "C" fn on_signal(_: libc::c_int) {
+    // Only async-signal-safe work here
+    REQUESTED.store(true, Ordering::Relaxed);
+}
+
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::Relaxed)
+}
+
+/// A worker has no connection left, or ran out of drain time
+pub fn drained() {
+    DRAINING.fetch_sub(1, Ordering::AcqRel);
+}
+
+/// Whether every worker is done draining
+pub fn all_drained() -> bool {
+    DRAINING.load(Ordering::Acquire) == 0
+}
@@ -0,0 +1,131 @@
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::Instant;
+
+use crate::config::Config;
+use crate::utils::json;
+
+/// Routes listed in the shutdown summary
+const TOP_ROUTES: usize = 10;
+
+/// Status codes counted one by one, 100 to 599
+const STATUS_MIN: u16 = 100;
+const STATUS_COUNT: usize = 500;
+
+/// Totals of the whole run, shared by every worker, for the summary
+/// written when the server shuts down
+static STATUSES: [AtomicU64; STATUS_COUNT] = [const { AtomicU64::new(0) }; STATUS_COUNT];
+static BYTES_RECEIVED: AtomicU64 = AtomicU64::new(0);
+static BYTES_SENT: AtomicU64 = AtomicU64::new(0);
+static PEAK_CONNECTIONS: AtomicU64 = AtomicU64::new(0);
+static ROUTES: Mutex<Option<HashMap<String, u64>>> = Mutex::new(None);
+static SETTINGS: OnceLock<StatsSettings> = OnceLock::new();
+
+struct StatsSettings {
+    started: Instant,
+    file: Option<String>,
+}
+
+pub fn init(config: &Config) {
+    let _ = SETTINGS.set(StatsSettings {
+        started: Instant::now(),
+        file: config.stats_file.clone(),
+    });
+}
+
+/// A response went out with `status`
+pub fn record_response(status: u16) {
+    if let Some(count) = status
+        .checked_sub(STATUS_MIN)
+        .and_then(|index| STATUSES.get(usize::from(index)))
+    {
+        count.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// A request was routed to `route` of `server`
+pub fn record_route(server: &str, route: &str) {
+    let mut routes = ROUTES.lock().unwrap_or_else(|e| e.into_inner());
+    *routes
+        .get_or_insert_with(HashMap::new)
+        .entry(format!("{}{}", server, route))
+        .or_default() += 1;
+}
+
+pub fn record_received(bytes: usize) {
+    BYTES_RECEIVED.fetch_add(bytes as u64, Ordering::Relaxed);
+}
+
+pub fn record_sent(bytes: usize) {
+    BYTES_SENT.fetch_add(bytes as u64, Ordering::Relaxed);
+}
+
+/// Connections open across every worker right now
+pub fn observe_connections(open: u64) {
+    PEAK_CONNECTIONS.fetch_max(open, Ordering::Relaxed);
+}
+
+/// Print the run summary, and write it as JSON to `stats_file` when set
+pub fn report() {
+    let Some(settings) = SETTINGS.get() else {
+        return;
+    };
+    let uptime = settings.started.elapsed().as_secs_f64();
+    let statuses: Vec<(u16, u64)> = STATUSES
+        .iter()
+        .enumerate()
+        .map(|(index, count)| (STATUS_MIN + index as u16, count.load(Ordering::Relaxed)))
+        .filter(|(_, count)| *count > 0)
+        .collect();
+    let requests: u64 = statuses.iter().map(|(_, count)| count).sum();
+    let received = BYTES_RECEIVED.load(Ordering::Relaxed);
+    let sent = BYTES_SENT.load(Ordering::Relaxed);
+    let peak = PEAK_CONNECTIONS.load(Ordering::Relaxed);
+    let mut routes: Vec<(String, u64)> = ROUTES
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .take()
+        .unwrap_or_default()
+        .into_iter()
+        .collect();
+    // Busiest first, ties in name order so reports compare across runs
+    routes.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    routes.truncate(TOP_ROUTES);
+
+    info!(
+        "Run summary after {:.1}s: {} requests, peak {} connections, {} bytes received, {} bytes sent",
+        uptime, requests, peak, received, sent
+    );
+    if !statuses.is_empty() {
+        let counts: Vec<String> = statuses.iter().map(|(status, count)| format!("{} x{}", status, count)).collect();
+        info!("  Statuses: {}", counts.join(", "));
+    }
+    if !routes.is_empty() {
+        let counts: Vec<String> = routes.iter().map(|(route, count)| format!("{} ({})", route, count)).collect();
+        info!("  Top routes: {}", counts.join(", "));
+    }
+
+    let Some(path) = &settings.file else {
+        return;
+    };
+    let mut out = String::new();
+    let _ = write!(
+        out,
+        "{{\"uptime_seconds\": {:.3}, \"requests\": {}, \"peak_connections\": {}, \"bytes_received\": {}, \"bytes_sent\": {}",
+        uptime, requests, peak, received, sent
+    );
+    let counts: Vec<String> = statuses.iter().map(|(status, count)| format!("\"{}\": {}", status, count)).collect();
+    let _ = write!(out, ", \"statuses\": {{{}}}", counts.join(", "));
+    let counts: Vec<String> = routes
+        .iter()
+        .map(|(route, count)| format!("{{\"route\": \"{}\", \"requests\": {}}}", json::escape(route), count))
+        .collect();
+    let _ = writeln!(out, ", \"top_routes\": [{}]}}", counts.join(", "));
+    match fs::write(path, out) {
+        Ok(()) => info!("Run summary written to {}", path),
+        Err(e) => error!("Cannot write run summary to {}: {}", path, e),
+    }
+}
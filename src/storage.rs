@@ -0,0 +1,362 @@
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{self, BufReader, Cursor, Read, Seek};
+use std::rc::Rc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use ring::digest;
+
+use crate::config::{Route, ServerConfig};
+
+/// What a backend knows about a stored entry
+#[derive(Debug, Clone)]
+pub struct ContentMeta {
+    pub len: u64,
+    pub modified: Option<SystemTime>,
+    pub is_dir: bool,
+    pub etag: Option<String>, // Strong validator when the backend knows the content's hash
+}
+
+/// Readable and seekable content handed to responses
+pub trait ContentReader: Read + Seek {}
+
+impl<T: Read + Seek> ContentReader for T {}
+
+/// Backend that served content is read from.
+///
+/// Paths are the resolved paths produced by routing (for the local filesystem,
+/// real paths on disk). Other backends interpret them as keys.
+pub trait ContentSource {
+    fn metadata(&self, path: &str) -> io::Result<ContentMeta>;
+    fn open(&self, path: &str) -> io::Result<Box<dyn ContentReader>>;
+    /// Names of the direct children of a directory
+    fn list(&self, path: &str) -> io::Result<Vec<String>>;
+    /// The content as a file of its own, for `sendfile`. None for backends
+    /// that don't keep each entry in a separate file.
+    fn open_file(&self, _path: &str) -> Option<io::Result<File>> {
+        None
+    }
+}
+
+/// Content stored on the local filesystem
+pub struct LocalFs;
+
+impl ContentSource for LocalFs {
+    fn metadata(&self, path: &str) -> io::Result<ContentMeta> {
+        let metadata = fs::metadata(path)?;
+        Ok(ContentMeta {
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+            is_dir: metadata.is_dir(),
+            etag: None,
+        })
+    }
+
+    fn open(&self, path: &str) -> io::Result<Box<dyn ContentReader>> {
+        Ok(Box::new(BufReader::new(File::open(path)?)))
+    }
+
+    fn open_file(&self, path: &str) -> Option<io::Result<File>> {
+        Some(File::open(path))
+    }
+
+    fn list(&self, path: &str) -> io::Result<Vec<String>> {
+        let mut names: Vec<String> = fs::read_dir(path)?
+            .flatten()
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        Ok(names)
+    }
+}
+
+/// Name that selects the archive baked in with the `embed-bundle` feature
+pub const EMBEDDED_BUNDLE: &str = "embedded";
+
+#[cfg(feature = "embed-bundle")]
+static EMBEDDED_BYTES: &[u8] = include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/bundle.tar"));
+
+struct BundleEntry {
+    offset: usize,
+    len: usize,
+    modified: Option<SystemTime>,
+    etag: String, // From a hash of the member's bytes, so it holds across repacks
+}
+
+/// Site content read from a single uncompressed tar archive held in memory.
+///
+/// Keys are the archive member paths without leading `./` or `/`; directories
+/// are implied by the files below them. Members are hashed once on load and
+/// served with strong ETags.
+pub struct TarBundle {
+    data: Rc<Cow<'static, [u8]>>,
+    entries: BTreeMap<String, BundleEntry>,
+}
+
+impl TarBundle {
+    pub fn parse(data: Cow<'static, [u8]>) -> io::Result<Self> {
+        const BLOCK: usize = 512;
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+
+        let mut entries = BTreeMap::new();
+        let mut long_name: Option<String> = None;
+        let mut pos = 0;
+
+        while pos + BLOCK <= data.len() {
+            let header = &data[pos..pos + BLOCK];
+            if header.iter().all(|b| *b == 0) {
+                break; // End-of-archive marker
+            }
+
+            let len = parse_octal(&header[124..136]).ok_or_else(|| invalid("bad tar size field"))?;
+            let mtime = parse_octal(&header[136..148]);
+            let type_flag = header[156];
+            let offset = pos + BLOCK;
+            if offset + len > data.len() {
+                return Err(invalid("truncated tar archive"));
+            }
+
+            let name = match long_name.take() {
+                Some(name) => name,
+                None => {
+                    let name = field_str(&header[0..100]);
+                    let prefix = if &header[257..262] == b"ustar" {
+                        field_str(&header[345..500])
+                    } else {
+                        String::new()
+                    };
+                    if prefix.is_empty() { name } else { format!("{}/{}", prefix, name) }
+                }
+            };
+
+            match type_flag {
+                // GNU long name: the data block holds the next member's name
+                b'L' => long_name = Some(field_str(&data[offset..offset + len])),
+                b'0' | 0 => {
+                    entries.insert(
+                        normalize_key(&name),
+                        BundleEntry {
+                            offset,
+                            len,
+                            modified: mtime.map(|t| UNIX_EPOCH + Duration::from_secs(t as u64)),
+                            etag: content_etag(&data[offset..offset + len]),
+                        },
+                    );
+                }
+                _ => {} // Directories are implied; links and pax headers are not served
+            }
+
+            pos = offset + len.div_ceil(BLOCK) * BLOCK;
+        }
+
+        Ok(Self {
+            data: Rc::new(data),
+            entries,
+        })
+    }
+
+    /// Load a bundle by path, or the archive baked into the binary
+    pub fn load(name: &str) -> io::Result<Self> {
+        if name == EMBEDDED_BUNDLE {
+            #[cfg(feature = "embed-bundle")]
+            return Self::parse(Cow::Borrowed(EMBEDDED_BYTES));
+            #[cfg(not(feature = "embed-bundle"))]
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "built without the embed-bundle feature",
+            ));
+        }
+        Self::parse(Cow::Owned(fs::read(name)?))
+    }
+
+    fn is_dir(&self, key: &str) -> bool {
+        if key.is_empty() {
+            return true;
+        }
+        let prefix = format!("{}/", key);
+        self.entries
+            .range(prefix.clone()..)
+            .next()
+            .is_some_and(|(k, _)| k.starts_with(&prefix))
+    }
+}
+
+impl ContentSource for TarBundle {
+    fn metadata(&self, path: &str) -> io::Result<ContentMeta> {
+        let key = normalize_key(path);
+        if let Some(entry) = self.entries.get(&key) {
+            return Ok(ContentMeta {
+                len: entry.len as u64,
+                modified: entry.modified,
+                is_dir: false,
+                etag: Some(entry.etag.clone()),
+            });
+        }
+        if self.is_dir(&key) {
+            return Ok(ContentMeta {
+                len: 0,
+                modified: None,
+                is_dir: true,
+                etag: None,
+            });
+        }
+        Err(io::Error::new(io::ErrorKind::NotFound, "not in bundle"))
+    }
+
+    fn open(&self, path: &str) -> io::Result<Box<dyn ContentReader>> {
+        let entry = self
+            .entries
+            .get(&normalize_key(path))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "not in bundle"))?;
+        Ok(Box::new(Cursor::new(BundleSlice {
+            data: Rc::clone(&self.data),
+            start: entry.offset,
+            end: entry.offset + entry.len,
+        })))
+    }
+
+    fn list(&self, path: &str) -> io::Result<Vec<String>> {
+        let key = normalize_key(path);
+        if !self.is_dir(&key) {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "not a bundle directory"));
+        }
+        let prefix = if key.is_empty() { key } else { format!("{}/", key) };
+
+        let mut names: Vec<String> = self
+            .entries
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .filter_map(|(k, _)| k[prefix.len()..].split('/').next())
+            .map(str::to_string)
+            .collect();
+        names.dedup();
+        Ok(names)
+    }
+}
+
+/// One member's bytes, sharing the archive buffer
+struct BundleSlice {
+    data: Rc<Cow<'static, [u8]>>,
+    start: usize,
+    end: usize,
+}
+
+impl AsRef<[u8]> for BundleSlice {
+    fn as_ref(&self) -> &[u8] {
+        &self.data[self.start..self.end]
+    }
+}
+
+/// A bundle rooted at a route: routed paths under `base` map to archive keys
+struct RoutedBundle {
+    bundle: Rc<TarBundle>,
+    base: String,
+}
+
+impl RoutedBundle {
+    fn key<'a>(&self, path: &'a str) -> &'a str {
+        path.strip_prefix(self.base.as_str()).unwrap_or(path)
+    }
+}
+
+impl ContentSource for RoutedBundle {
+    fn metadata(&self, path: &str) -> io::Result<ContentMeta> {
+        self.bundle.metadata(self.key(path))
+    }
+
+    fn open(&self, path: &str) -> io::Result<Box<dyn ContentReader>> {
+        self.bundle.open(self.key(path))
+    }
+
+    fn list(&self, path: &str) -> io::Result<Vec<String>> {
+        self.bundle.list(self.key(path))
+    }
+}
+
+/// Archive member key for a path: no empty, `.` or leading-slash segments
+fn normalize_key(path: &str) -> String {
+    path.split('/')
+        .filter(|segment| !segment.is_empty() && *segment != ".")
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Strong ETag from the first 128 bits of the content's SHA-256
+fn content_etag(content: &[u8]) -> String {
+    let hash = digest::digest(&digest::SHA256, content);
+    let hex: String = hash.as_ref()[..16].iter().map(|b| format!("{:02x}", b)).collect();
+    format!("\"{}\"", hex)
+}
+
+fn field_str(field: &[u8]) -> String {
+    let end = field.iter().position(|b| *b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+fn parse_octal(field: &[u8]) -> Option<usize> {
+    let text = field_str(field);
+    let text = text.trim();
+    if text.is_empty() {
+        return Some(0);
+    }
+    usize::from_str_radix(text, 8).ok()
+}
+
+thread_local! {
+    // Bundles are parsed once and shared by every route that names them
+    static BUNDLES: RefCell<HashMap<String, Rc<TarBundle>>> = RefCell::new(HashMap::new());
+}
+
+fn cached_bundle(name: &str) -> io::Result<Rc<TarBundle>> {
+    BUNDLES.with(|bundles| {
+        if let Some(bundle) = bundles.borrow().get(name) {
+            return Ok(Rc::clone(bundle));
+        }
+        let bundle = Rc::new(TarBundle::load(name)?);
+        bundles
+            .borrow_mut()
+            .insert(name.to_string(), Rc::clone(&bundle));
+        Ok(bundle)
+    })
+}
+
+/// Directory a route's routed paths start with
+pub fn route_base(server: &ServerConfig, route: &Route) -> String {
+    format!("{}/{}", server.root, route.root)
+}
+
+/// Backend serving a route's content
+pub fn content_source_for(server: &ServerConfig, route: &Route) -> Box<dyn ContentSource> {
+    let Some(name) = &route.bundle else {
+        return Box::new(LocalFs);
+    };
+    match cached_bundle(name) {
+        Ok(bundle) => Box::new(RoutedBundle {
+            bundle,
+            base: route_base(server, route),
+        }),
+        Err(e) => {
+            error!("Cannot load bundle '{}': {}", name, e);
+            Box::new(EmptySource)
+        }
+    }
+}
+
+/// Stand-in for a bundle that failed to load: every lookup is a 404
+struct EmptySource;
+
+impl ContentSource for EmptySource {
+    fn metadata(&self, _path: &str) -> io::Result<ContentMeta> {
+        Err(io::ErrorKind::NotFound.into())
+    }
+
+    fn open(&self, _path: &str) -> io::Result<Box<dyn ContentReader>> {
+        Err(io::ErrorKind::NotFound.into())
+    }
+
+    fn list(&self, _path: &str) -> io::Result<Vec<String>> {
+        Err(io::ErrorKind::NotFound.into())
+    }
+}
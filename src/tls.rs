@@ -0,0 +1,590 @@
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::{Shutdown, SocketAddr};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, OnceLock, RwLock, RwLockReadGuard};
+use std::time::{Duration, Instant};
+
+use mio::event::Source;
+use mio::net::TcpStream;
+use mio::{Interest, Registry, Token};
+use rustls::crypto::ring::{Ticketer, default_provider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{
+    ClientHello, NoServerSessionStorage, ProducesTickets, ResolvesServerCert, ServerSessionMemoryCache,
+    StoresServerSessions,
+};
+use rustls::sign::CertifiedKey;
+use rustls::{HandshakeKind, ServerConnection};
+
+use crate::config::{Config, ServerConfig, TlsConfig};
+use crate::metrics;
+use crate::proxy_protocol::{self, Header};
+use crate::transport::Transport;
+
+/// Resumption state shared by every listener and worker, so a returning
+/// client finds its session whichever worker accepts it
+struct Resumption {
+    sessions: Arc<dyn StoresServerSessions>,
+    ticketer: Option<Arc<dyn ProducesTickets>>,
+}
+
+static RESUMPTION: OnceLock<Resumption> = OnceLock::new();
+
+/// Set up the session cache and ticket keys, before any listener is built
+pub fn init(config: &Config) {
+    let sessions: Arc<dyn StoresServerSessions> = if config.tls_session_cache > 0 {
+        ServerSessionMemoryCache::new(config.tls_session_cache)
+    } else {
+        Arc::new(NoServerSessionStorage {})
+    };
+    let ticketer = if config.tls_session_tickets {
+        match RotatingTicketer::new(config.tls_ticket_rotation) {
+            Ok(ticketer) => Some(Arc::new(ticketer) as Arc<dyn ProducesTickets>),
+            Err(e) => {
+                error!("TLS: session tickets disabled, cannot make a ticket key: {}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+    let _ = RESUMPTION.set(Resumption { sessions, ticketer });
+}
+
+/// Ticket keys replaced every `rotation`. The previous key still decrypts
+/// for one more period, so tickets issued just before a switch stay usable
+/// and no key outlives two periods.
+#[derive(Debug)]
+struct RotatingTicketer {
+    rotation: Duration,
+    keys: RwLock<TicketKeys>,
+}
+
+#[derive(Debug)]
+struct TicketKeys {
+    current: Arc<dyn ProducesTickets>,
+    previous: Option<Arc<dyn ProducesTickets>>,
+    next_switch: Instant,
+}
+
+impl RotatingTicketer {
+    fn new(rotation: Duration) -> Result<Self, rustls::Error> {
+        Ok(Self {
+            rotation,
+            keys: RwLock::new(TicketKeys {
+                current: Ticketer::new()?,
+                previous: None,
+                next_switch: Instant::now() + rotation,
+            }),
+        })
+    }
+
+    /// The keys to use now, rotating them first when their time is up
+    fn keys(&self) -> RwLockReadGuard<'_, TicketKeys> {
+        let now = Instant::now();
+        {
+            let keys = self.keys.read().unwrap_or_else(|e| e.into_inner());
+            if now < keys.next_switch {
+                return keys;
+            }
+        }
+        {
+            let mut keys = self.keys.write().unwrap_or_else(|e| e.into_inner());
+            // Another worker may have rotated while we waited for the lock
+            if now >= keys.next_switch {
+                match Ticketer::new() {
+                    Ok(fresh) => {
+                        keys.previous = Some(mem::replace(&mut keys.current, fresh));
+                        metrics::TLS_TICKET_ROTATIONS.fetch_add(1, Ordering::Relaxed);
+                    }
+                    // Keep the current key rather than break resumption
+                    Err(e) => error!("TLS: cannot rotate the ticket key: {}", e),
+                }
+                keys.next_switch = now + self.rotation;
+            }
+        }
+        self.keys.read().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl ProducesTickets for RotatingTicketer {
+    fn enabled(&self) -> bool {
+        true
+    }
+
+    fn lifetime(&self) -> u32 {
+        self.rotation.as_secs() as u32
+    }
+
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        self.keys().current.encrypt(plain)
+    }
+
+    fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>> {
+        let keys = self.keys();
+        keys.current
+            .decrypt(cipher)
+            .or_else(|| keys.previous.as_ref()?.decrypt(cipher))
+    }
+}
+
+/// Load a certificate chain and its private key, checking that they match
+pub fn load_certified_key(tls: &TlsConfig) -> Result<Arc<CertifiedKey>, String> {
+    let certs = CertificateDer::pem_file_iter(&tls.cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("cannot read certificate '{}': {}", tls.cert, e))?;
+    if certs.is_empty() {
+        return Err(format!("no certificate found in '{}'", tls.cert));
+    }
+    let key = PrivateKeyDer::from_pem_file(&tls.key)
+        .map_err(|e| format!("cannot read private key '{}': {}", tls.key, e))?;
+
+    CertifiedKey::from_der(certs, key, &default_provider())
+        .map(Arc::new)
+        .map_err(|e| format!("certificate '{}' and key '{}': {}", tls.cert, tls.key, e))
+}
+
+/// Picks the certificate from SNI, falling back to the listener's default server
+#[derive(Debug)]
+struct SniResolver {
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+    default: Option<Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        client_hello
+            .server_name()
+            .and_then(|name| self.by_name.get(&name.to_ascii_lowercase()))
+            .or(self.default.as_ref())
+            .cloned()
+    }
+}
+
+/// TLS settings for a listener, or None when its servers serve plain HTTP.
+///
+/// All servers sharing a host:port must agree, since the first bytes of a
+/// connection decide between TLS and plain HTTP before any Host is known.
+pub fn listener_config(
+    servers: &[ServerConfig],
+    default_index: usize,
+) -> Result<Option<Arc<rustls::ServerConfig>>, String> {
+    let tls_count = servers.iter().filter(|s| s.tls.is_some()).count();
+    if tls_count == 0 {
+        return Ok(None);
+    }
+    if tls_count != servers.len() {
+        return Err("servers sharing a port must all use TLS or none".to_string());
+    }
+
+    let mut resolver = SniResolver {
+        by_name: HashMap::new(),
+        default: None,
+    };
+    for (i, server) in servers.iter().enumerate() {
+        let Some(tls) = &server.tls else {
+            continue;
+        };
+        let key = load_certified_key(tls)?;
+        if i == default_index {
+            resolver.default = Some(Arc::clone(&key));
+        }
+        // Like Host selection, the first server with a given name wins
+        resolver
+            .by_name
+            .entry(server.server_name.to_ascii_lowercase())
+            .or_insert(key);
+    }
+
+    let mut config = rustls::ServerConfig::builder_with_provider(Arc::new(default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::new(resolver));
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    if let Some(resumption) = RESUMPTION.get() {
+        config.session_storage = Arc::clone(&resumption.sessions);
+        if let Some(ticketer) = &resumption.ticketer {
+            config.ticketer = Arc::clone(ticketer);
+        }
+    }
+    Ok(Some(Arc::new(config)))
+}
+
+/// A client connection, optionally wrapped in TLS.
+///
+/// Reads and writes never block: the handshake and record layer advance as
+/// far as the socket allows and report `WouldBlock` otherwise, like a plain
+/// non-blocking stream.
+///
+/// On a `proxy_protocol` listener the connection opens with a PROXY header,
+/// consumed before anything else; the client it names is then reported as
+/// the peer.
+pub struct ClientStream {
+    tcp: TcpStream,
+    tls: Option<ServerConnection>,
+    handshake_counted: bool,
+    first_byte_seen: bool,
+    plain_http: bool,
+    proxy_pending: bool,
+    proxied_peer: Option<SocketAddr>,
+}
+
+impl ClientStream {
+    pub fn new(
+        tcp: TcpStream,
+        tls: Option<&Arc<rustls::ServerConfig>>,
+        proxy_protocol: bool,
+    ) -> io::Result<Self> {
+        let tls = match tls {
+            Some(config) => Some(ServerConnection::new(Arc::clone(config)).map_err(io::Error::other)?),
+            None => None,
+        };
+        Ok(Self {
+            tcp,
+            tls,
+            handshake_counted: false,
+            first_byte_seen: false,
+            plain_http: false,
+            proxy_pending: proxy_protocol,
+            proxied_peer: None,
+        })
+    }
+
+    /// Consume the PROXY header once all of it has arrived. Returns false if
+    /// the connection closed first; a header split across packets reports
+    /// `WouldBlock` until the rest comes in.
+    fn read_proxy_header(&mut self) -> io::Result<bool> {
+        let mut wanted = proxy_protocol::V1_MAX_LEN;
+        loop {
+            let mut head = vec![0u8; wanted];
+            let available = self.tcp.peek(&mut head)?;
+            if available == 0 {
+                return Ok(false);
+            }
+            match proxy_protocol::parse(&head[..available])? {
+                Header::Incomplete(needed) if needed > wanted => wanted = needed,
+                Header::Incomplete(_) => return Err(io::ErrorKind::WouldBlock.into()),
+                Header::Complete { len, source } => {
+                    self.tcp.read_exact(&mut head[..len])?;
+                    self.proxy_pending = false;
+                    self.proxied_peer = source;
+                    let from = self.tcp.peer_addr().map_or_else(|_| "unknown".to_string(), |a| a.to_string());
+                    match source {
+                        Some(client) => debug!("PROXY header from {}: client {}", from, client),
+                        None => debug!("PROXY header from {}: no client address, keeping the proxy's", from),
+                    }
+                    return Ok(true);
+                }
+            }
+        }
+    }
+
+    /// Look at the first byte on a TLS port without consuming it. A TLS
+    /// handshake record starts with 0x16; an uppercase letter is an HTTP
+    /// method, and the connection is downgraded to plain reads to answer it.
+    fn sniff_plain_http(&mut self) -> io::Result<()> {
+        let mut first = [0u8; 1];
+        if self.tcp.peek(&mut first)? == 0 {
+            return Ok(());
+        }
+        self.first_byte_seen = true;
+        if first[0].is_ascii_uppercase() {
+            self.tls = None;
+            self.plain_http = true;
+        }
+        Ok(())
+    }
+}
+
+/// Count a finished handshake as full or resumed, once per connection
+fn count_handshake(tls: &ServerConnection, counted: &mut bool) {
+    if *counted || tls.is_handshaking() {
+        return;
+    }
+    *counted = true;
+    let counter = match tls.handshake_kind() {
+        Some(HandshakeKind::Resumed) => &metrics::TLS_HANDSHAKES_RESUMED,
+        _ => &metrics::TLS_HANDSHAKES_FULL,
+    };
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+impl Transport for ClientStream {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self.proxied_peer {
+            Some(client) => Ok(client),
+            None => self.tcp.peer_addr(),
+        }
+    }
+
+    fn sni_hostname(&self) -> Option<&str> {
+        self.tls.as_ref().and_then(|tls| tls.server_name())
+    }
+
+    fn is_tls(&self) -> bool {
+        self.tls.is_some()
+    }
+
+    fn plain_http_on_tls(&self) -> bool {
+        self.plain_http
+    }
+
+    fn sendfile_fd(&self) -> Option<i32> {
+        // TLS records are encrypted in userspace, only plain sockets qualify
+        #[cfg(target_os = "linux")]
+        if self.tls.is_none() {
+            use std::os::fd::AsRawFd;
+            return Some(self.tcp.as_raw_fd());
+        }
+        None
+    }
+
+    fn shutdown(&mut self, how: Shutdown) -> io::Result<()> {
+        if let Some(tls) = self.tls.as_mut() {
+            tls.send_close_notify();
+            let _ = flush_tls(tls, &mut self.tcp);
+        }
+        self.tcp.shutdown(how)
+    }
+}
+
+/// Push queued TLS records to the socket
+fn flush_tls(tls: &mut ServerConnection, tcp: &mut TcpStream) -> io::Result<()> {
+    while tls.wants_write() {
+        if tls.write_tls(tcp)? == 0 {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+    }
+    Ok(())
+}
+
+impl Read for ClientStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.proxy_pending && !self.read_proxy_header()? {
+            return Ok(0);
+        }
+        if self.tls.is_some() && !self.first_byte_seen {
+            self.sniff_plain_http()?;
+        }
+        let Some(tls) = self.tls.as_mut() else {
+            return self.tcp.read(buf);
+        };
+
+        loop {
+            match tls.reader().read(buf) {
+                Ok(n) => return Ok(n),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+
+            // Handshake replies must go out before the peer sends more
+            match flush_tls(tls, &mut self.tcp) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+
+            if tls.read_tls(&mut self.tcp)? == 0 {
+                return Ok(0);
+            }
+            if let Err(e) = tls.process_new_packets() {
+                // Best effort: let the peer know why with an alert
+                let _ = flush_tls(tls, &mut self.tcp);
+                return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+            }
+            count_handshake(tls, &mut self.handshake_counted);
+        }
+    }
+}
+
+impl Write for ClientStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let Some(tls) = self.tls.as_mut() else {
+            return self.tcp.write(buf);
+        };
+
+        // Don't queue more plaintext while earlier records are still waiting
+        flush_tls(tls, &mut self.tcp)?;
+        let n = tls.writer().write(buf)?;
+        if n == 0 && !buf.is_empty() {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        match flush_tls(tls, &mut self.tcp) {
+            Ok(()) => Ok(n),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(n),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.tls.as_mut() {
+            Some(tls) => flush_tls(tls, &mut self.tcp),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Source for ClientStream {
+    fn register(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+        self.tcp.register(registry, token, interests)
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        self.tcp.reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        self.tcp.deregister(registry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::parse_config;
+    use rustls::pki_types::ServerName;
+    use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+    use std::thread;
+
+    fn testdata(name: &str) -> String {
+        format!("{}/testdata/tls/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    fn tls(cert: &str, key: &str) -> TlsConfig {
+        TlsConfig {
+            cert: testdata(cert),
+            key: testdata(key),
+        }
+    }
+
+    /// Servers on one port; `None` serves plain HTTP
+    fn servers(names: &[(&str, Option<&str>)]) -> Vec<ServerConfig> {
+        let mut yaml = String::from("servers:\n");
+        for (name, cert) in names {
+            yaml.push_str(&format!(
+                "  - server_name: \"{}\"\n    host: 127.0.0.1\n    ports:\n      - 8443\n    root: \"./public\"\n",
+                name
+            ));
+            if let Some(cert) = cert {
+                let (cert, key) = (testdata(cert), testdata("localhost.key"));
+                yaml.push_str(&format!("    tls: {{ cert: \"{}\", key: \"{}\" }}\n", cert, key));
+            }
+            yaml.push_str("    routes:\n      - path: \"/\"\n        methods: [\"GET\"]\n        root: \".\"\n");
+        }
+        parse_config(&yaml).expect("test config").servers
+    }
+
+    #[test]
+    fn certificate_and_key_must_match() {
+        assert!(load_certified_key(&tls("localhost.crt", "localhost.key")).is_ok());
+        let mismatch = load_certified_key(&tls("localhost.crt", "other.key")).unwrap_err();
+        assert!(mismatch.contains("other.key"), "{}", mismatch);
+        let missing = load_certified_key(&tls("missing.crt", "localhost.key")).unwrap_err();
+        assert!(missing.starts_with("cannot read certificate"), "{}", missing);
+    }
+
+    #[test]
+    fn a_port_is_all_tls_or_all_plain() {
+        assert!(listener_config(&servers(&[("a.test", None)]), 0).unwrap().is_none());
+        assert!(listener_config(&servers(&[("a.test", Some("localhost.crt")), ("b.test", None)]), 0).is_err());
+    }
+
+    #[test]
+    fn tickets_outlive_one_rotation_but_not_two() {
+        let ticketer = RotatingTicketer::new(Duration::from_millis(50)).unwrap();
+        let ticket = ticketer.encrypt(b"session").unwrap();
+        assert_eq!(ticketer.decrypt(&ticket).as_deref(), Some(&b"session"[..]));
+
+        thread::sleep(Duration::from_millis(60));
+        assert_eq!(ticketer.decrypt(&ticket).as_deref(), Some(&b"session"[..]));
+        assert!(ticketer.encrypt(b"session").is_some_and(|fresh| fresh != ticket));
+
+        thread::sleep(Duration::from_millis(60));
+        assert_eq!(ticketer.decrypt(&ticket), None);
+    }
+
+    /// Accept one connection from `client` on a listener serving `config`
+    fn accept(
+        config: Option<&Arc<rustls::ServerConfig>>,
+        client: impl FnOnce(std::net::TcpStream) + Send + 'static,
+    ) -> (ClientStream, thread::JoinHandle<()>) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = thread::spawn(move || client(std::net::TcpStream::connect(addr).unwrap()));
+        let (tcp, _) = listener.accept().unwrap();
+        tcp.set_nonblocking(true).unwrap();
+        (ClientStream::new(TcpStream::from_std(tcp), config, false).unwrap(), client)
+    }
+
+    /// Read until `len` bytes came in, retrying while the socket would block
+    fn read_all(stream: &mut ClientStream, len: usize) -> Vec<u8> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut data = Vec::new();
+        let mut buf = [0u8; 1024];
+        while data.len() < len {
+            match stream.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => data.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    assert!(Instant::now() < deadline, "timed out reading");
+                    thread::sleep(Duration::from_millis(5));
+                }
+                Err(e) => panic!("read failed: {}", e),
+            }
+        }
+        data
+    }
+
+    #[test]
+    fn handshake_picks_the_certificate_by_sni() {
+        let config = listener_config(&servers(&[("a.test", Some("localhost.crt")), ("b.test", Some("localhost.crt"))]), 0)
+            .unwrap()
+            .expect("TLS listener");
+        let (mut stream, client) = accept(Some(&config), |tcp| {
+            let mut roots = RootCertStore::empty();
+            for cert in CertificateDer::pem_file_iter(testdata("localhost.crt")).unwrap() {
+                roots.add(cert.unwrap()).unwrap();
+            }
+            let config = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+            let name = ServerName::try_from("b.test").unwrap();
+            let mut tls = StreamOwned::new(ClientConnection::new(Arc::new(config), name).unwrap(), tcp);
+            tls.write_all(b"ping").unwrap();
+            let mut reply = [0u8; 4];
+            tls.read_exact(&mut reply).unwrap();
+            assert_eq!(&reply, b"pong");
+        });
+
+        assert_eq!(read_all(&mut stream, 4), b"ping");
+        assert!(stream.is_tls());
+        assert_eq!(stream.sni_hostname(), Some("b.test"));
+        assert_eq!(stream.write(b"pong").unwrap(), 4);
+        client.join().unwrap();
+    }
+
+    #[test]
+    fn plain_http_on_a_tls_port_is_read_in_clear() {
+        let config = listener_config(&servers(&[("a.test", Some("localhost.crt"))]), 0)
+            .unwrap()
+            .expect("TLS listener");
+        let request = b"GET / HTTP/1.1\r\nHost: a.test\r\n\r\n";
+        let (mut stream, client) = accept(Some(&config), move |mut tcp| tcp.write_all(request).unwrap());
+
+        assert_eq!(read_all(&mut stream, request.len()), request);
+        assert!(stream.plain_http_on_tls());
+        assert!(!stream.is_tls());
+        client.join().unwrap();
+    }
+}
@@ -0,0 +1,164 @@
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr};
+
+use mio::event::Source;
+
+/// I/O behind a client connection.
+///
+/// The read and write state machines only see this trait, so they can be
+/// driven by something other than a socket. Implementations are
+/// non-blocking: `WouldBlock` means "come back on the next readiness event".
+pub trait Transport: Read + Write + Source {
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+
+    /// Host name the client asked for during a TLS handshake
+    fn sni_hostname(&self) -> Option<&str> {
+        None
+    }
+
+    fn is_tls(&self) -> bool {
+        false
+    }
+
+    /// Whether plain HTTP arrived on a TLS port. The connection is then read
+    /// in clear, only so the request can be refused with a readable page.
+    fn plain_http_on_tls(&self) -> bool {
+        false
+    }
+
+    /// Socket descriptor file bodies can be sent to with `sendfile` (Linux),
+    /// when bytes written there reach the client unchanged
+    fn sendfile_fd(&self) -> Option<i32> {
+        None
+    }
+
+    fn shutdown(&mut self, how: Shutdown) -> io::Result<()>;
+}
+
+#[cfg(test)]
+pub use scripted::{ReadStep, ScriptedTransport, WriteStep};
+
+/// In-memory transport replaying a scripted client, for driving
+/// `handle_read_state` / `handle_write_state` without sockets
+#[cfg(test)]
+mod scripted {
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::io::{self, Read, Write};
+    use std::net::{Ipv4Addr, Shutdown, SocketAddr};
+    use std::rc::Rc;
+
+    use mio::event::Source;
+    use mio::{Interest, Registry, Token};
+
+    use super::Transport;
+
+    pub enum ReadStep {
+        Data(Vec<u8>),
+        WouldBlock,
+        Eof,
+    }
+
+    pub enum WriteStep {
+        Accept(usize), // Take at most this many bytes from the next write
+        WouldBlock,
+    }
+
+    /// What the server sent, still readable once the transport is boxed
+    #[derive(Default)]
+    pub struct Output {
+        pub written: Vec<u8>,
+        pub shut_down: bool,
+    }
+
+    /// Reads follow the script (WouldBlock once it runs out); writes are
+    /// captured in `output`, limited by the write script when there is one
+    #[derive(Default)]
+    pub struct ScriptedTransport {
+        reads: VecDeque<ReadStep>,
+        writes: VecDeque<WriteStep>,
+        output: Rc<RefCell<Output>>,
+    }
+
+    impl ScriptedTransport {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        pub fn read(mut self, step: ReadStep) -> Self {
+            self.reads.push_back(step);
+            self
+        }
+
+        pub fn data(self, bytes: &[u8]) -> Self {
+            self.read(ReadStep::Data(bytes.to_vec()))
+        }
+
+        pub fn write(mut self, step: WriteStep) -> Self {
+            self.writes.push_back(step);
+            self
+        }
+
+        pub fn output(&self) -> Rc<RefCell<Output>> {
+            Rc::clone(&self.output)
+        }
+    }
+
+    impl Read for ScriptedTransport {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.reads.pop_front() {
+                Some(ReadStep::Data(mut bytes)) => {
+                    let n = bytes.len().min(buf.len());
+                    buf[..n].copy_from_slice(&bytes[..n]);
+                    if n < bytes.len() {
+                        self.reads.push_front(ReadStep::Data(bytes.split_off(n)));
+                    }
+                    Ok(n)
+                }
+                Some(ReadStep::Eof) => Ok(0),
+                Some(ReadStep::WouldBlock) | None => Err(io::ErrorKind::WouldBlock.into()),
+            }
+        }
+    }
+
+    impl Write for ScriptedTransport {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let n = match self.writes.pop_front() {
+                Some(WriteStep::Accept(limit)) => limit.min(buf.len()),
+                Some(WriteStep::WouldBlock) => return Err(io::ErrorKind::WouldBlock.into()),
+                None => buf.len(),
+            };
+            self.output.borrow_mut().written.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Source for ScriptedTransport {
+        fn register(&mut self, _: &Registry, _: Token, _: Interest) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn reregister(&mut self, _: &Registry, _: Token, _: Interest) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn deregister(&mut self, _: &Registry) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Transport for ScriptedTransport {
+        fn peer_addr(&self) -> io::Result<SocketAddr> {
+            Ok(SocketAddr::from((Ipv4Addr::LOCALHOST, 40000)))
+        }
+
+        fn shutdown(&mut self, _how: Shutdown) -> io::Result<()> {
+            self.output.borrow_mut().shut_down = true;
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,489 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::net::IpAddr;
+use std::rc::Rc;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+use crate::client::{ClientRequest, ClientStream};
+use crate::config::{BalanceStrategy, Config};
+use crate::models::HttpResponseCommon;
+use crate::request::HttpRequest;
+use crate::response::{HttpResponseBuilder, reason_phrase};
+use crate::utils::HttpHeaders;
+use crate::server::{IDLE_TIMEOUT, SocketData};
+
+/// Headers that only make sense on one hop and are never forwarded
+const HOP_BY_HOP: [&str; 10] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+    "content-length",
+    "host",
+    "expect", // Answered here, the backend gets the body in one go
+];
+
+/// Upstream groups from the config, shared by every worker so that
+/// in-flight counts cover the whole process
+static UPSTREAMS: OnceLock<HashMap<String, Upstream>> = OnceLock::new();
+
+struct Upstream {
+    strategy: BalanceStrategy,
+    backends: Vec<Backend>,
+    next: AtomicUsize, // Round-robin cursor
+}
+
+struct Backend {
+    address: String,
+    in_flight: AtomicUsize,
+}
+
+/// Build the upstream table from the loaded config, once per process
+pub fn init(config: &Config) {
+    let upstreams = config
+        .upstreams
+        .iter()
+        .map(|u| {
+            let backends = u
+                .servers
+                .iter()
+                .map(|address| Backend {
+                    address: address.clone(),
+                    in_flight: AtomicUsize::new(0),
+                })
+                .collect();
+            let upstream = Upstream {
+                strategy: u.strategy,
+                backends,
+                next: AtomicUsize::new(0),
+            };
+            (u.name.clone(), upstream)
+        })
+        .collect();
+    let _ = UPSTREAMS.set(upstreams);
+}
+
+/// A backend reserved for one proxied request; the in-flight slot is given
+/// back when the lease is dropped
+pub struct Lease {
+    backend: &'static Backend,
+}
+
+impl Lease {
+    fn new(backend: &'static Backend) -> Self {
+        backend.in_flight.fetch_add(1, Ordering::Relaxed);
+        Self { backend }
+    }
+
+    pub fn address(&self) -> &str {
+        &self.backend.address
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.backend.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Pick a backend of the named upstream according to its strategy
+pub fn pick(name: &str, client: Option<IpAddr>) -> Option<Lease> {
+    let upstream = UPSTREAMS.get()?.get(name)?;
+    let backends = &upstream.backends;
+    if backends.is_empty() {
+        return None;
+    }
+
+    let index = match upstream.strategy {
+        BalanceStrategy::RoundRobin => upstream.next.fetch_add(1, Ordering::Relaxed) % backends.len(),
+        BalanceStrategy::LeastConn => {
+            // Start from the cursor so ties don't always land on the first backend
+            let start = upstream.next.fetch_add(1, Ordering::Relaxed);
+            (0..backends.len())
+                .map(|i| (start + i) % backends.len())
+                .min_by_key(|&i| backends[i].in_flight.load(Ordering::Relaxed))
+                .unwrap_or(0)
+        }
+        BalanceStrategy::IpHash => {
+            let mut hasher = DefaultHasher::new();
+            client.map(|ip| ip.to_canonical()).hash(&mut hasher);
+            hasher.finish() as usize % backends.len()
+        }
+    };
+    Some(Lease::new(&backends[index]))
+}
+
+/// Forward the request to `target`, an upstream name or an `http://host:port`
+/// base URL. The returned response relays the backend's answer as it arrives,
+/// failing once its body passes `max_response` bytes.
+pub fn proxy(
+    target: &str,
+    request: &HttpRequest,
+    socket_data: &SocketData,
+    max_response: Option<usize>,
+) -> Box<dyn HttpResponseCommon> {
+    let peer = socket_data.stream.peer_addr().ok().map(|addr| addr.ip());
+
+    let (base, lease) = match target.strip_prefix("http://") {
+        Some(address) => (address.trim_end_matches('/').to_string(), None),
+        None => match pick(target, peer) {
+            Some(lease) => (lease.address().to_string(), Some(lease)),
+            None => {
+                warn!("Proxy: no backend available in upstream '{}'", target);
+                return Box::new(ProxyResponse::failed(502));
+            }
+        },
+    };
+
+    // The path was decoded while parsing, encode it again for the request line
+    let path: Vec<_> = request.path.split('/').map(urlencoding::encode).collect();
+    let mut url = format!("http://{}{}", base, path.join("/"));
+    if !request.query_string.is_empty() {
+        url.push('?');
+        url.push_str(&request.query_string);
+    }
+
+    let mut outbound = ClientRequest::new(request.method.to_str(), &url).max_response_size(max_response);
+    // A spilled body goes out from its file, never whole in memory
+    outbound = match &request.body_file {
+        Some(spooled) => match spooled.open() {
+            Ok(file) => outbound.body_file(file, spooled.len() as u64),
+            Err(e) => {
+                error!("Proxy: cannot read spooled request body: {}", e);
+                return Box::new(ProxyResponse::failed(500));
+            }
+        },
+        None => outbound.body(request.body.clone().unwrap_or_default()),
+    };
+    for (key, value) in request.headers.iter() {
+        if !HOP_BY_HOP.contains(&key.as_str()) {
+            outbound = outbound.append_header(key, value);
+        }
+    }
+
+    let forwarded_for = match (request.headers.get_combined("X-Forwarded-For"), peer) {
+        (Some(chain), Some(ip)) => format!("{}, {}", chain, ip),
+        (None, Some(ip)) => ip.to_string(),
+        (Some(chain), None) => chain.into_owned(),
+        (None, None) => String::new(),
+    };
+    if !forwarded_for.is_empty() {
+        outbound = outbound.header("X-Forwarded-For", &forwarded_for);
+    }
+    if let Some(host) = request.headers.get("Host") {
+        outbound = outbound.header("X-Forwarded-Host", host);
+    }
+    let scheme = if socket_data.stream.is_tls() { "https" } else { "http" };
+    outbound = outbound.header("X-Forwarded-Proto", scheme);
+
+    // Effective limits, so the application validates with the same values
+    outbound = outbound.header("X-Server-Idle-Timeout", &IDLE_TIMEOUT.as_secs().to_string());
+    if let Some(max) = socket_data.status.max_body_size {
+        outbound = outbound.header("X-Server-Max-Body-Size", &max.to_string());
+    }
+
+    // The upstream exchange may not outlive the request budget
+    if let Some(deadline) = socket_data.status.deadline() {
+        outbound = outbound.timeout(deadline.saturating_duration_since(Instant::now()));
+    }
+
+    debug!(
+        "Proxy: {} {} -> {}",
+        request.method.to_str(),
+        request.path,
+        base
+    );
+
+    Box::new(ProxyResponse {
+        data: Vec::new(),
+        index: 0,
+        status: None,
+        upstream: Some(socket_data.http_client.stream(outbound)),
+        head_request: request.method.to_str() == "HEAD",
+        head_sent: false,
+        chunked: false,
+        broken: false,
+        lease,
+    })
+}
+
+/// Response relayed from a backend: its head once received, then its body
+/// as it arrives, with chunked encoding when the backend gave no length
+pub struct ProxyResponse {
+    data: Vec<u8>,
+    index: usize,
+    status: Option<u16>, // Known once the backend's head arrived, or it failed
+    upstream: Option<Rc<RefCell<ClientStream>>>,
+    head_request: bool,
+    head_sent: bool,
+    chunked: bool,
+    broken: bool,         // The backend failed after the head went out
+    lease: Option<Lease>, // Held until the backend is done
+}
+
+impl ProxyResponse {
+    fn failed(status: u16) -> Self {
+        Self {
+            data: error_response(status),
+            index: 0,
+            status: Some(status),
+            upstream: None,
+            head_request: false,
+            head_sent: true,
+            chunked: false,
+            broken: false,
+            lease: None,
+        }
+    }
+
+    fn relay_head(&mut self, status: u16, headers: &HttpHeaders) {
+        let mut builder = HttpResponseBuilder::new(status, reason_phrase(status));
+        for (key, value) in headers.iter() {
+            if !HOP_BY_HOP.contains(&key.as_str()) {
+                builder = builder.append_header(key, value);
+            }
+        }
+        let bodyless = self.head_request || matches!(status, 100..=199 | 204 | 304);
+        let head = match headers.get("content-length").and_then(|v| v.trim().parse::<u64>().ok()) {
+            Some(len) => builder.build_head(len),
+            None if bodyless => builder.build_head(0),
+            None => {
+                self.chunked = true;
+                builder.build_chunked_head()
+            }
+        };
+        self.data.extend_from_slice(&head);
+        self.status = Some(status);
+        self.head_sent = true;
+    }
+
+    fn relay_body(&mut self, body: &[u8]) {
+        if body.is_empty() {
+            return;
+        }
+        if self.chunked {
+            self.data.extend_from_slice(format!("{:x}\r\n", body.len()).as_bytes());
+            self.data.extend_from_slice(body);
+            self.data.extend_from_slice(b"\r\n");
+        } else {
+            self.data.extend_from_slice(body);
+        }
+    }
+
+    fn end(&mut self, error: Option<io::Error>) {
+        match error {
+            Some(e) if self.head_sent => {
+                // Too late for an error page, the client sees the connection cut
+                error!("Proxy: upstream failed mid-response: {}", e);
+                self.broken = true;
+            }
+            Some(e) if e.kind() == io::ErrorKind::TimedOut => {
+                warn!("Proxy: upstream timed out");
+                self.data = error_response(504);
+                self.status = Some(504);
+            }
+            Some(e) => {
+                error!("Proxy: upstream failed: {}", e);
+                self.data = error_response(502);
+                self.status = Some(502);
+            }
+            None if self.chunked => self.data.extend_from_slice(b"0\r\n\r\n"),
+            None => {}
+        }
+        self.upstream = None;
+        self.lease = None;
+    }
+}
+
+fn error_response(status: u16) -> Vec<u8> {
+    HttpResponseBuilder::new(status, reason_phrase(status))
+        .header("Content-Type", "text/plain")
+        .body(format!("{}\n", reason_phrase(status)).into_bytes())
+        .build()
+}
+
+impl HttpResponseCommon for ProxyResponse {
+    fn peek(&self) -> &[u8] {
+        &self.data[self.index..]
+    }
+
+    fn next(&mut self, n: usize) {
+        self.index += n;
+    }
+
+    fn is_finished(&self) -> bool {
+        self.upstream.is_none() && !self.broken && self.index >= self.data.len()
+    }
+
+    fn fill_if_needed(&mut self) -> io::Result<()> {
+        if self.index >= self.data.len() {
+            self.data.clear();
+            self.index = 0;
+            if self.broken {
+                return Err(io::Error::other("upstream failed mid-response"));
+            }
+        }
+        Ok(())
+    }
+
+    fn status(&self) -> Option<u16> {
+        self.status
+    }
+
+    fn buffered_len(&self) -> usize {
+        self.data.capacity() + self.upstream.as_ref().map_or(0, |upstream| upstream.borrow().body.capacity())
+    }
+
+    fn is_waiting(&self) -> bool {
+        self.upstream.is_some() && self.index >= self.data.len()
+    }
+
+    fn poll_producer(&mut self) -> bool {
+        let Some(upstream) = self.upstream.clone() else {
+            return false;
+        };
+        let mut upstream = upstream.borrow_mut();
+        let queued = self.data.len();
+        if !self.head_sent
+            && let Some((status, headers)) = upstream.head.take()
+        {
+            self.relay_head(status, &headers);
+        }
+        if self.head_sent {
+            let body = std::mem::take(&mut upstream.body);
+            self.relay_body(&body);
+        }
+        if upstream.done {
+            let error = upstream.error.take();
+            drop(upstream);
+            self.end(error);
+            return true;
+        }
+        self.data.len() > queued
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn relaying(head_request: bool) -> (ProxyResponse, Rc<RefCell<ClientStream>>) {
+        let upstream = Rc::new(RefCell::new(ClientStream::default()));
+        let response = ProxyResponse {
+            data: Vec::new(),
+            index: 0,
+            status: None,
+            upstream: Some(Rc::clone(&upstream)),
+            head_request,
+            head_sent: false,
+            chunked: false,
+            broken: false,
+            lease: None,
+        };
+        (response, upstream)
+    }
+
+    fn headers(pairs: &[(&str, &str)]) -> HttpHeaders {
+        let mut headers = HttpHeaders::new();
+        for (key, value) in pairs {
+            headers.append(key, value);
+        }
+        headers
+    }
+
+    /// Everything queued for the client so far
+    fn drain(response: &mut ProxyResponse) -> String {
+        let out = String::from_utf8_lossy(response.peek()).into_owned();
+        let n = response.peek().len();
+        response.next(n);
+        response.fill_if_needed().unwrap();
+        out
+    }
+
+    #[test]
+    fn body_without_a_length_is_relayed_chunked() {
+        let (mut response, upstream) = relaying(false);
+        assert!(!response.poll_producer());
+        assert_eq!(response.status(), None);
+
+        let head = headers(&[("Content-Type", "text/plain"), ("Connection", "close"), ("Keep-Alive", "timeout=5")]);
+        upstream.borrow_mut().head = Some((200, head));
+        upstream.borrow_mut().body.extend_from_slice(b"hello");
+        assert!(response.poll_producer());
+        assert_eq!(response.status(), Some(200));
+        let head = drain(&mut response).to_ascii_lowercase();
+        assert!(head.contains("transfer-encoding: chunked"));
+        assert!(head.contains("content-type: text/plain"));
+        assert!(!head.contains("keep-alive") && !head.contains("connection: close"));
+        assert!(head.ends_with("\r\n\r\n5\r\nhello\r\n"), "{}", head);
+
+        upstream.borrow_mut().body.extend_from_slice(b"world!");
+        upstream.borrow_mut().done = true;
+        assert!(response.poll_producer());
+        assert_eq!(drain(&mut response), "6\r\nworld!\r\n0\r\n\r\n");
+        assert!(response.is_finished());
+    }
+
+    #[test]
+    fn announced_length_is_kept() {
+        let (mut response, upstream) = relaying(false);
+        upstream.borrow_mut().head = Some((404, headers(&[("Content-Length", "4")])));
+        upstream.borrow_mut().body.extend_from_slice(b"gone");
+        upstream.borrow_mut().done = true;
+        response.poll_producer();
+        let sent = drain(&mut response);
+        assert!(sent.starts_with("HTTP/1.1 404"));
+        assert!(sent.to_ascii_lowercase().contains("content-length: 4\r\n"));
+        assert!(sent.ends_with("\r\n\r\ngone"));
+        assert!(response.is_finished());
+    }
+
+    #[test]
+    fn head_response_announces_no_chunks() {
+        let (mut response, upstream) = relaying(true);
+        upstream.borrow_mut().head = Some((200, HttpHeaders::new()));
+        upstream.borrow_mut().done = true;
+        response.poll_producer();
+        let sent = drain(&mut response).to_ascii_lowercase();
+        assert!(!sent.contains("transfer-encoding"));
+        assert!(sent.ends_with("\r\n\r\n"));
+    }
+
+    #[test]
+    fn failures_before_the_head_become_error_pages() {
+        for (error, status) in [(io::ErrorKind::ConnectionRefused, 502), (io::ErrorKind::TimedOut, 504)] {
+            let (mut response, upstream) = relaying(false);
+            upstream.borrow_mut().error = Some(error.into());
+            upstream.borrow_mut().done = true;
+            assert!(response.poll_producer());
+            assert_eq!(response.status(), Some(status));
+            assert!(drain(&mut response).starts_with(&format!("HTTP/1.1 {}", status)));
+            assert!(response.is_finished());
+        }
+    }
+
+    #[test]
+    fn failure_after_the_head_cuts_the_response() {
+        let (mut response, upstream) = relaying(false);
+        upstream.borrow_mut().head = Some((200, headers(&[("Content-Length", "10")])));
+        upstream.borrow_mut().body.extend_from_slice(b"hel");
+        response.poll_producer();
+        upstream.borrow_mut().error = Some(io::ErrorKind::ConnectionReset.into());
+        upstream.borrow_mut().done = true;
+        response.poll_producer();
+
+        assert_eq!(response.status(), Some(200));
+        let n = response.peek().len();
+        response.next(n);
+        assert!(response.fill_if_needed().is_err());
+        assert!(!response.is_finished());
+    }
+}
@@ -0,0 +1,76 @@
+pub const MIN_BUFFER_SIZE: usize = 4 * 1024;
+pub const MAX_BUFFER_SIZE: usize = 256 * 1024;
+
+// Consecutive reads using under a quarter of the buffer before it shrinks
+const SHRINK_AFTER_SMALL_READS: u32 = 8;
+
+/// Per-connection read buffer that follows the observed transfer pattern.
+///
+/// Reads that fill the buffer double it (bulk uploads), a run of small reads
+/// halves it (API chatter), and idle keep-alive connections drop back to the
+/// minimum.
+pub struct AdaptiveBuffer {
+    buf: Vec<u8>,
+    small_reads: u32,
+}
+
+impl Default for AdaptiveBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AdaptiveBuffer {
+    pub fn new() -> Self {
+        Self {
+            buf: vec![0; MIN_BUFFER_SIZE],
+            small_reads: 0,
+        }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.buf
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Adjust the size after a read of `n` bytes
+    pub fn record(&mut self, n: usize) {
+        let size = self.buf.len();
+
+        if n == size && size < MAX_BUFFER_SIZE {
+            self.buf.resize((size * 2).min(MAX_BUFFER_SIZE), 0);
+            self.small_reads = 0;
+        } else if n < size / 4 {
+            self.small_reads += 1;
+            if self.small_reads >= SHRINK_AFTER_SMALL_READS && size > MIN_BUFFER_SIZE {
+                self.resize((size / 2).max(MIN_BUFFER_SIZE));
+                self.small_reads = 0;
+            }
+        } else {
+            self.small_reads = 0;
+        }
+    }
+
+    /// Release memory while the connection waits for its next request
+    pub fn reset(&mut self) {
+        if self.buf.len() > MIN_BUFFER_SIZE {
+            self.resize(MIN_BUFFER_SIZE);
+        }
+        self.small_reads = 0;
+    }
+
+    fn resize(&mut self, size: usize) {
+        self.buf.truncate(size);
+        self.buf.shrink_to_fit();
+    }
+}
+
+/// Buffer size for streaming a body of `len` bytes
+pub fn buffer_size_for(len: u64) -> usize {
+    usize::try_from(len)
+        .unwrap_or(MAX_BUFFER_SIZE)
+        .clamp(MIN_BUFFER_SIZE, MAX_BUFFER_SIZE)
+}
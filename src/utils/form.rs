@@ -0,0 +1,27 @@
+use crate::request::percent_decode;
+
+/// Media type of HTML form submissions without files
+pub const URLENCODED: &str = "application/x-www-form-urlencoded";
+
+/// Split an `application/x-www-form-urlencoded` body (or query string) into
+/// its fields, in order and with repeated names kept. `+` is a space and
+/// `%XX` a raw byte; bytes that aren't UTF-8 once decoded are replaced.
+/// A field without `=` has an empty value; empty fields are skipped.
+pub fn parse(data: &str) -> Vec<(String, String)> {
+    data.split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (decode(key), decode(value))
+        })
+        .collect()
+}
+
+/// Whether a `Content-Type` value announces a urlencoded form
+pub fn is_urlencoded(content_type: &str) -> bool {
+    content_type.split(';').next().unwrap_or("").trim().eq_ignore_ascii_case(URLENCODED)
+}
+
+fn decode(encoded: &str) -> String {
+    String::from_utf8_lossy(&percent_decode(encoded, true)).into_owned()
+}
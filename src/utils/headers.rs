@@ -1,38 +1,98 @@
-use std::collections::HashMap;
+use std::borrow::Cow;
 
+/// Header fields of a request or response, with lowercase names.
+///
+/// Repeated fields (Set-Cookie, a second Accept...) keep every value, and
+/// fields iterate in the order they were added, which is the order they are
+/// serialized in.
 #[derive(Debug, Default, Clone)]
 pub struct HttpHeaders {
-    inner: HashMap<String, String>,
+    fields: Vec<(String, String)>,
 }
 
 impl HttpHeaders {
     pub fn new() -> Self {
-        HttpHeaders {
-            inner: HashMap::new(),
-        }
+        HttpHeaders { fields: Vec::new() }
     }
 
+    /// Set `key` to a single value. It takes the place of the first field
+    /// already named `key`, the others are removed.
     pub fn insert(&mut self, key: &str, value: &str) {
+        let key = key.trim().to_ascii_lowercase();
+        let value = value.trim().to_string();
+        match self.fields.iter().position(|(k, _)| *k == key) {
+            Some(first) => {
+                self.fields[first].1 = value;
+                let mut rest = self.fields.split_off(first + 1);
+                rest.retain(|(k, _)| *k != key);
+                self.fields.append(&mut rest);
+            }
+            None => self.fields.push((key, value)),
+        }
+    }
 
-        // trim whitespace and convert key to lowercase
-        let value = value.trim();
-        let key = key.trim();
-        self.inner
-            .insert(key.to_ascii_lowercase(), value.to_string());
+    /// Add a value without replacing the ones already set for `key`
+    pub fn append(&mut self, key: &str, value: &str) {
+        self.fields.push((key.trim().to_ascii_lowercase(), value.trim().to_string()));
     }
 
+    /// First value of `key`
     pub fn get(&self, key: &str) -> Option<&String> {
-        self.inner.get(&key.to_ascii_lowercase())
+        self.get_all(key).next()
     }
+
+    /// Every value of `key`, in order
+    pub fn get_all<'a>(&'a self, key: &str) -> impl Iterator<Item = &'a String> {
+        let key = key.to_ascii_lowercase();
+        self.fields.iter().filter(move |(k, _)| *k == key).map(|(_, v)| v)
+    }
+
+    /// The values of `key` as one, the way RFC 9110 §5.3 combines a list
+    /// field sent several times: joined with `, `, or `; ` for Cookie.
+    /// Set-Cookie values can't be combined, only the first is returned.
+    pub fn get_combined(&self, key: &str) -> Option<Cow<'_, str>> {
+        let mut values = self.get_all(key);
+        let first = values.next()?;
+        let separator = match key.to_ascii_lowercase().as_str() {
+            "set-cookie" => return Some(Cow::Borrowed(first)),
+            "cookie" => "; ",
+            _ => ", ",
+        };
+        let Some(second) = values.next() else {
+            return Some(Cow::Borrowed(first));
+        };
+        let mut combined = format!("{}{}{}", first, separator, second);
+        for value in values {
+            combined.push_str(separator);
+            combined.push_str(value);
+        }
+        Some(Cow::Owned(combined))
+    }
+
+    /// Number of distinct field names
     pub fn len(&self) -> usize {
-        self.inner.len()
+        self.fields
+            .iter()
+            .enumerate()
+            .filter(|(i, (key, _))| !self.fields[..*i].iter().any(|(k, _)| k == key))
+            .count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
     }
 
+    /// Remove every field named `key`, returning the first value
     pub fn remove(&mut self, key: &str) -> Option<String> {
-        self.inner.remove(&key.to_ascii_lowercase())
+        let key = key.to_ascii_lowercase();
+        let first = self.fields.iter().position(|(k, _)| *k == key)?;
+        let value = self.fields.remove(first).1;
+        self.fields.retain(|(k, _)| *k != key);
+        Some(value)
     }
 
+    /// Every field in order, repeated names once per value
     pub fn iter(&self) -> impl Iterator<Item = (&String, &String)> {
-        self.inner.iter()
+        self.fields.iter().map(|(key, value)| (key, value))
     }
 }
@@ -3,15 +3,18 @@ pub enum HttpMethod {
     GET,
     POST,
     DELETE,
+    PATCH,
     Other(String),
 }
 
 impl HttpMethod {
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(method: &str) -> HttpMethod {
         match method {
             "GET" => HttpMethod::GET,
             "POST" => HttpMethod::POST,
             "DELETE" => HttpMethod::DELETE,
+            "PATCH" => HttpMethod::PATCH,
             _ => Self::Other(method.to_string()),
         }
     }
@@ -20,6 +23,7 @@ impl HttpMethod {
             HttpMethod::GET => "GET",
             HttpMethod::POST => "POST",
             HttpMethod::DELETE => "DELETE",
+            HttpMethod::PATCH => "PATCH",
             HttpMethod::Other(method) => method.as_str(),
         }
     }
@@ -1,6 +1,15 @@
+pub mod affinity;
+pub mod buffer;
 pub mod cookie;
+pub mod form;
 mod methods;
 mod headers;
+pub mod json;
+pub mod net;
+#[cfg(not(unix))]
+pub mod pipe;
+pub mod range;
+pub mod sendfile;
 pub mod session;
 
 pub use methods::HttpMethod;
@@ -0,0 +1,105 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+
+/// Resolve a `host:` config value into a bindable address.
+///
+/// Accepts literal IPv4/IPv6 addresses (including the `0.0.0.0` / `::`
+/// wildcards), network interface names such as `lo` or `eth0`, and hostnames.
+pub fn resolve_listen_addr(host: &str, port: u16) -> io::Result<SocketAddr> {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(SocketAddr::new(ip, port));
+    }
+
+    if let Some(ip) = interface_addr(host) {
+        return Ok(SocketAddr::new(ip, port));
+    }
+
+    (host, port).to_socket_addrs()?.next().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("cannot resolve listen host '{}'", host),
+        )
+    })
+}
+
+/// Bind a non-blocking listener; with `reuse_port` several event loops can
+/// bind the same address and the kernel spreads connections between them.
+pub fn bind_listener(addr: SocketAddr, reuse_port: bool) -> io::Result<mio::net::TcpListener> {
+    Ok(mio::net::TcpListener::from_std(bind_std_listener(addr, reuse_port)?))
+}
+
+/// Same as `bind_listener`, kept as a std socket so it can be cloned for
+/// event loops sharing it
+pub fn bind_std_listener(addr: SocketAddr, reuse_port: bool) -> io::Result<std::net::TcpListener> {
+    use socket2::{Domain, Socket, Type};
+
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    if reuse_port {
+        socket.set_reuse_port(true)?;
+    }
+    #[cfg(not(unix))]
+    if reuse_port {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "workers > 1 needs SO_REUSEPORT",
+        ));
+    }
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket.into())
+}
+
+/// First address of the named interface, preferring IPv4
+#[cfg(unix)]
+pub fn interface_addr(name: &str) -> Option<IpAddr> {
+    use std::ffi::CStr;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    let mut found_v6 = None;
+    let mut ifaddrs: *mut libc::ifaddrs = std::ptr::null_mut();
+
+    // SAFETY: getifaddrs fills a linked list that we walk read-only and free once
+    unsafe {
+        if libc::getifaddrs(&mut ifaddrs) != 0 {
+            return None;
+        }
+
+        let mut cursor = ifaddrs;
+        while !cursor.is_null() {
+            let entry = &*cursor;
+            cursor = entry.ifa_next;
+
+            if entry.ifa_addr.is_null() || CStr::from_ptr(entry.ifa_name).to_bytes() != name.as_bytes() {
+                continue;
+            }
+
+            match (*entry.ifa_addr).sa_family as i32 {
+                libc::AF_INET => {
+                    let addr = &*(entry.ifa_addr as *const libc::sockaddr_in);
+                    let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+                    libc::freeifaddrs(ifaddrs);
+                    return Some(IpAddr::V4(ip));
+                }
+                libc::AF_INET6 if found_v6.is_none() => {
+                    let addr = &*(entry.ifa_addr as *const libc::sockaddr_in6);
+                    found_v6 = Some(IpAddr::V6(Ipv6Addr::from(addr.sin6_addr.s6_addr)));
+                }
+                _ => {}
+            }
+        }
+
+        libc::freeifaddrs(ifaddrs);
+    }
+
+    found_v6
+}
+
+#[cfg(not(unix))]
+pub fn interface_addr(_name: &str) -> Option<IpAddr> {
+    None
+}
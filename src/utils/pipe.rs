@@ -0,0 +1,135 @@
+//! Stand-ins for `mio::unix::pipe` where mio has no anonymous pipes.
+//!
+//! A thread per pipe does the blocking I/O and hands data over a channel, so
+//! reads and writes never block the event loop. Registering is a no-op: the
+//! loop polls parked streams every iteration, which is enough for a dev box.
+
+use std::io::{self, Read, Write};
+use std::process::{ChildStdin, ChildStdout};
+use std::sync::mpsc::{self, Receiver as ChannelReceiver, SyncSender, TryRecvError, TrySendError};
+use std::thread;
+
+use mio::event::Source;
+use mio::{Interest, Registry, Token};
+
+/// Chunks queued for the script's stdin before writes would block
+const QUEUED_CHUNKS: usize = 4;
+
+/// Read end: the script's stdout
+pub struct Receiver {
+    chunks: ChannelReceiver<io::Result<Vec<u8>>>,
+    current: Vec<u8>,
+    pos: usize,
+}
+
+impl From<ChildStdout> for Receiver {
+    fn from(mut stdout: ChildStdout) -> Self {
+        let (tx, chunks) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buf = [0u8; 8192];
+            loop {
+                let chunk = match stdout.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(n) => Ok(buf[..n].to_vec()),
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => Err(e),
+                };
+                let failed = chunk.is_err();
+                if tx.send(chunk).is_err() || failed {
+                    break;
+                }
+            }
+        });
+        Receiver {
+            chunks,
+            current: Vec::new(),
+            pos: 0,
+        }
+    }
+}
+
+impl Receiver {
+    pub fn set_nonblocking(&self, _nonblocking: bool) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for Receiver {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.current.len() {
+            match self.chunks.try_recv() {
+                Ok(chunk) => {
+                    self.current = chunk?;
+                    self.pos = 0;
+                }
+                Err(TryRecvError::Empty) => return Err(io::ErrorKind::WouldBlock.into()),
+                // The reading thread is done: end of output
+                Err(TryRecvError::Disconnected) => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.current.len() - self.pos);
+        buf[..n].copy_from_slice(&self.current[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Write end: the script's stdin, closed when dropped
+pub struct Sender {
+    chunks: SyncSender<Vec<u8>>,
+}
+
+impl From<ChildStdin> for Sender {
+    fn from(mut stdin: ChildStdin) -> Self {
+        let (chunks, rx) = mpsc::sync_channel::<Vec<u8>>(QUEUED_CHUNKS);
+        thread::spawn(move || {
+            for chunk in rx {
+                if stdin.write_all(&chunk).is_err() {
+                    break;
+                }
+            }
+        });
+        Sender { chunks }
+    }
+}
+
+impl Sender {
+    pub fn set_nonblocking(&self, _nonblocking: bool) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Write for Sender {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.chunks.try_send(buf.to_vec()) {
+            Ok(()) => Ok(buf.len()),
+            Err(TrySendError::Full(_)) => Err(io::ErrorKind::WouldBlock.into()),
+            Err(TrySendError::Disconnected(_)) => Err(io::ErrorKind::BrokenPipe.into()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+macro_rules! unregistered_source {
+    ($pipe:ty) => {
+        impl Source for $pipe {
+            fn register(&mut self, _: &Registry, _: Token, _: Interest) -> io::Result<()> {
+                Ok(())
+            }
+
+            fn reregister(&mut self, _: &Registry, _: Token, _: Interest) -> io::Result<()> {
+                Ok(())
+            }
+
+            fn deregister(&mut self, _: &Registry) -> io::Result<()> {
+                Ok(())
+            }
+        }
+    };
+}
+
+unregistered_source!(Receiver);
+unregistered_source!(Sender);
@@ -0,0 +1,119 @@
+/// Outcome of matching a `Range` header against a body of known length
+#[derive(Debug, PartialEq)]
+pub enum ByteRange {
+    /// Inclusive byte offsets to send with 206 Partial Content
+    Partial { start: u64, end: u64 },
+    /// Syntactically valid but outside the body: answer 416
+    Unsatisfiable,
+    /// Missing, malformed or multi-range: serve the full body
+    Full,
+}
+
+/// Parse a single `bytes=` range (`a-b`, `a-` or `-suffix`).
+///
+/// Multiple ranges are answered with the full representation, which RFC 9110
+/// allows and keeps every response single-part.
+pub fn parse_range(header: Option<&str>, len: u64) -> ByteRange {
+    let Some(spec) = header.and_then(|h| h.trim().strip_prefix("bytes=")) else {
+        return ByteRange::Full;
+    };
+    if spec.contains(',') {
+        return ByteRange::Full;
+    }
+    let Some((first, last)) = spec.trim().split_once('-') else {
+        return ByteRange::Full;
+    };
+
+    let (start, end) = match (first.trim(), last.trim()) {
+        ("", "") => return ByteRange::Full,
+        ("", suffix) => {
+            let Ok(n) = suffix.parse::<u64>() else {
+                return ByteRange::Full;
+            };
+            if n == 0 || len == 0 {
+                return ByteRange::Unsatisfiable;
+            }
+            (len.saturating_sub(n), len - 1)
+        }
+        (first, last) => {
+            let Ok(start) = first.parse::<u64>() else {
+                return ByteRange::Full;
+            };
+            let end = match last {
+                "" => len.saturating_sub(1),
+                last => match last.parse::<u64>() {
+                    Ok(end) if end >= start => end.min(len.saturating_sub(1)),
+                    _ => return ByteRange::Full,
+                },
+            };
+            if start >= len {
+                return ByteRange::Unsatisfiable;
+            }
+            (start, end)
+        }
+    };
+
+    ByteRange::Partial { start, end }
+}
+
+/// Parse a request's `Content-Range: bytes a-b/len` (or `a-b/*`) into the
+/// inclusive offsets and the complete length, when given
+pub fn parse_content_range(header: &str) -> Option<(u64, u64, Option<u64>)> {
+    let (range, complete) = header.trim().strip_prefix("bytes ")?.split_once('/')?;
+    let (start, end) = range.trim().split_once('-')?;
+    let start = start.trim().parse::<u64>().ok()?;
+    let end = end.trim().parse::<u64>().ok()?;
+    // A range is `end - start + 1` bytes long, which must not overflow
+    if end < start || end == u64::MAX {
+        return None;
+    }
+    let complete = match complete.trim() {
+        "*" => None,
+        len => Some(len.parse::<u64>().ok()?),
+    };
+    if complete.is_some_and(|len| end >= len) {
+        return None;
+    }
+    Some((start, end, complete))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_ranges() {
+        assert_eq!(parse_range(Some("bytes=0-4"), 10), ByteRange::Partial { start: 0, end: 4 });
+        assert_eq!(parse_range(Some("bytes=6-"), 10), ByteRange::Partial { start: 6, end: 9 });
+        assert_eq!(parse_range(Some("bytes=-3"), 10), ByteRange::Partial { start: 7, end: 9 });
+        // Past the end is clamped; a suffix longer than the body takes all of it
+        assert_eq!(parse_range(Some("bytes=5-100"), 10), ByteRange::Partial { start: 5, end: 9 });
+        assert_eq!(parse_range(Some("bytes=-100"), 10), ByteRange::Partial { start: 0, end: 9 });
+    }
+
+    #[test]
+    fn ranges_outside_the_body_are_unsatisfiable() {
+        assert_eq!(parse_range(Some("bytes=10-"), 10), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range(Some("bytes=10-20"), 10), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range(Some("bytes=-0"), 10), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range(Some("bytes=-5"), 0), ByteRange::Unsatisfiable);
+    }
+
+    #[test]
+    fn anything_else_gets_the_full_body() {
+        for header in ["items=0-4", "bytes=", "bytes=-", "bytes=4-2", "bytes=a-b", "bytes=0-1,3-4", "bytes=5"] {
+            assert_eq!(parse_range(Some(header), 10), ByteRange::Full, "{}", header);
+        }
+        assert_eq!(parse_range(None, 10), ByteRange::Full);
+    }
+
+    #[test]
+    fn content_ranges() {
+        assert_eq!(parse_content_range("bytes 0-499/1234"), Some((0, 499, Some(1234))));
+        assert_eq!(parse_content_range("bytes 500-999/*"), Some((500, 999, None)));
+        assert_eq!(parse_content_range("bytes 5-4/10"), None);
+        assert_eq!(parse_content_range("bytes 0-10/10"), None);
+        assert_eq!(parse_content_range(&format!("bytes 0-{}/*", u64::MAX)), None);
+        assert_eq!(parse_content_range("bytes */10"), None);
+    }
+}
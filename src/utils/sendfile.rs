@@ -0,0 +1,27 @@
+use std::fs::File;
+use std::io;
+
+/// Copy up to `count` bytes of `file`, starting at `offset`, to the socket
+/// `fd` without passing them through userspace. A short count is normal on
+/// a non-blocking socket; 0 means the file ended before `offset + count`.
+#[cfg(target_os = "linux")]
+pub fn send(fd: i32, file: &File, offset: u64, count: usize) -> io::Result<usize> {
+    use std::os::fd::AsRawFd;
+
+    let mut offset = libc::off_t::try_from(offset)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "file offset out of range"))?;
+    // SAFETY: both descriptors stay open during the call, which only writes `offset`
+    let sent = unsafe { libc::sendfile(fd, file.as_raw_fd(), &mut offset, count) };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(sent as usize)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn send(_fd: i32, _file: &File, _offset: u64, _count: usize) -> io::Result<usize> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "sendfile is only supported on Linux",
+    ))
+}
@@ -1,6 +1,5 @@
-use std::cell::RefCell;
 use std::collections::HashMap;
-use std::rc::Rc;
+use std::sync::{Arc, Mutex, MutexGuard};
 use std::time::{Duration, Instant};
 use uuid::Uuid;
 
@@ -15,6 +14,12 @@ pub struct Session {
     pub data: HashMap<String, String>,
 }
 
+impl Default for Session {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Session {
     pub fn new() -> Self {
         let id = Uuid::new_v4().to_string();
@@ -53,37 +58,48 @@ impl Session {
     }
 }
 
+/// Sessions shared by every worker thread
 #[derive(Clone)]
 pub struct SessionStore {
-    inner: Rc<RefCell<HashMap<String, Session>>>,
+    inner: Arc<Mutex<HashMap<String, Session>>>,
+}
+
+impl Default for SessionStore {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl SessionStore {
     pub fn new() -> Self {
         Self {
-            inner: Rc::new(RefCell::new(HashMap::new())),
+            inner: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 
+    fn sessions(&self) -> MutexGuard<'_, HashMap<String, Session>> {
+        // A panic elsewhere can't leave the map half-updated; keep serving
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
     /// Create a new anonymous session
     pub fn create(&self) -> Session {
         let session = Session::new();
-        self.inner
-            .borrow_mut()
+        self.sessions()
             .insert(session.id.clone(), session.clone());
         session
     }
 
     /// Get a session by ID
     pub fn get(&self, session_id: &str) -> Option<Session> {
-        let sessions = self.inner.borrow();
+        let sessions = self.sessions();
         sessions.get(session_id).cloned()
     }
 
     /// Update a session
     /// Update a session
     pub fn update(&self, session: &Session) -> bool {
-        let mut sessions = self.inner.borrow_mut();
+        let mut sessions = self.sessions();
 
         if sessions.contains_key(&session.id) {
             sessions.insert(session.id.clone(), session.clone());
@@ -95,7 +111,7 @@ impl SessionStore {
 
     /// Clean up expired sessions
     pub fn cleanup(&self) -> usize {
-        let mut sessions = self.inner.borrow_mut();
+        let mut sessions = self.sessions();
         let before = sessions.len();
         sessions.retain(|_, session| !session.is_expired());
         before - sessions.len()
@@ -105,7 +121,7 @@ impl SessionStore {
     where
         F: FnMut(&mut Session),
     {
-        let mut sessions = self.inner.borrow_mut();
+        let mut sessions = self.sessions();
         if let Some(session) = sessions.get_mut(session_id) {
             f(session);
             true
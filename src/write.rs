@@ -1,8 +1,8 @@
 use std::{io, net::Shutdown, time::Instant};
-use std::io::{Write};
-use crate::{models::HttpResponseCommon, request::HttpRequestBuilder, server::{SocketData, Status}};
+use std::io::{Read, Write};
+use crate::{models::HttpResponseCommon, response::status_code, stats, server::{CloseReason, SocketData, SocketStatus, Status}};
 
-fn should_keep_alive(request: &crate::request::HttpRequest) -> bool {
+pub fn should_keep_alive(request: &crate::request::HttpRequest) -> bool {
     request
         .headers
         .get("connection")
@@ -10,30 +10,122 @@ fn should_keep_alive(request: &crate::request::HttpRequest) -> bool {
         .unwrap_or(false)
 }
 
+/// Whether the client stopped reading for longer than the write timeout
+pub fn write_timed_out(socket: &SocketStatus, now: Instant) -> bool {
+    now.duration_since(socket.ttl) > socket.write_timeout
+}
+
 fn write_response(socket: &mut SocketData) -> Option<bool> {
+    if socket.status.write_budget == 0 {
+        socket.status.budget_exhausted = true;
+        return Some(false);
+    }
+
     let response: &mut Box<dyn HttpResponseCommon + 'static> = socket.status.response.as_mut()?;
 
-    response.fill_if_needed().ok()?;
+    // File bodies go from the file to a plain socket inside the kernel,
+    // never through the response buffer
+    let direct = socket.stream.sendfile_fd().filter(|_| response.sendfile_len() > 0);
+    if direct.is_none() {
+        response.fill_if_needed().ok()?;
+    }
+    // A status that just became known may still send the response through
+    // the error handler, see `render_pending_error`
+    if socket.status.error_pending && response.status().is_some() {
+        return Some(true);
+    }
 
     let data = response.peek();
+    let pending = match direct {
+        Some(_) => usize::try_from(response.sendfile_len())
+            .map_or(socket.status.write_budget, |len| len.min(socket.status.write_budget)),
+        None => data.len(),
+    };
 
-    if data.is_empty() {
+    if pending == 0 {
+        // Long-lived streams park until their producer has more
+        if response.is_waiting() {
+            socket.status.awaiting_events = true;
+            return Some(false);
+        }
         return Some(true);
     }
-    match socket.stream.write(data) {
+
+    // Draw from the server's egress cap, or wait until it refills
+    let allowed = match socket.status.pacer {
+        Some(pacer) => {
+            let granted = pacer.take(pending);
+            if granted == 0 {
+                socket.status.paced_until = Some(Instant::now() + pacer.wait());
+                return Some(false);
+            }
+            granted
+        }
+        None => pending,
+    };
+
+    // The first bytes of a response are its status line
+    if socket.status.response_status.is_none()
+        && let Some(status) = status_code(data)
+    {
+        stats::record_response(status);
+        socket.status.response_status = Some(status);
+    }
+
+    let written = match direct {
+        Some(fd) => response.sendfile(fd, allowed),
+        None => socket.stream.write(&data[..allowed]),
+    };
+    match written {
         Ok(n) => {
-            response.next(n);
+            stats::record_sent(n);
+            if direct.is_none() {
+                response.next(n);
+            }
+            socket.status.write_budget = socket.status.write_budget.saturating_sub(n);
             if n > 0 {
                 socket.status.ttl = Instant::now();
             }
-            if response.is_finished() {
-                Some(false)
-            } else {
-                Some(true)
+            if let Some(pacer) = socket.status.pacer {
+                pacer.give_back(allowed - n);
+                // Let the other connections of the server have the next grant
+                if !response.is_finished() {
+                    socket.status.paced_until = Some(Instant::now() + pacer.wait());
+                    return Some(false);
+                }
+            }
+            // Finished responses go straight on to keep-alive or close: with
+            // edge-triggered polling no further writable event may arrive
+            Some(true)
+        }
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+            if let Some(pacer) = socket.status.pacer {
+                pacer.give_back(allowed);
             }
+            Some(false)
+        }
+        Err(_) => {
+            socket.status.closing(CloseReason::WriteError);
+            None
+        }
+    }
+}
+
+/// Read and drop what the client sends after an early error, until it
+/// closes its end; `check_timeouts` ends it after `LINGER_TIMEOUT`
+pub fn handle_lingering_state(socket_data: &mut SocketData) -> Option<bool> {
+    let status = &mut socket_data.status;
+    loop {
+        if status.read_budget == 0 {
+            status.budget_exhausted = true;
+            return Some(false);
+        }
+        match socket_data.stream.read(status.read_buffer.as_mut_slice()) {
+            Ok(0) => return None,
+            Ok(n) => status.read_budget = status.read_budget.saturating_sub(n),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Some(false),
+            Err(_) => return None,
         }
-        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Some(false),
-        Err(_) => None,
     }
 }
 
@@ -49,22 +141,121 @@ pub fn handle_write_state(socket_data: &mut SocketData) -> Option<bool> {
     let response = socket_data.status.response.as_ref()?;
 
     if !response.is_finished() {
-        println!("Response not finished yet.");
+        debug!("Response not finished yet.");
+        return Some(true);
+    }
+
+    // Encrypted records may still be queued in the TLS layer
+    match socket_data.stream.flush() {
+        Ok(()) => {}
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Some(false),
+        Err(_) => {
+            socket_data.status.closing(CloseReason::WriteError);
+            return None;
+        }
+    }
+
+    // The stream position is unknown after a parse error, never reuse it.
+    // The client may still be sending a body the error refused: closing with
+    // it unread would reset the connection, taking the response with it.
+    if socket_data.status.bad_request {
+        socket_data.status.closing(CloseReason::ProtocolError);
+        let _ = socket_data.stream.shutdown(Shutdown::Write);
+        socket_data.status.status = Status::Lingering;
+        socket_data.status.ttl = Instant::now();
         return Some(true);
     }
 
     let request = socket_data.status.request.get()?;
-    let keep_alive = should_keep_alive(request);
+    let keep_alive = should_keep_alive(request)
+        && !socket_data.status.last_request
+        && !socket_data.status.response.as_ref().is_some_and(|r| r.closes_connection());
 
     if keep_alive {
         socket_data.status.status = Status::Read;
-        socket_data.status.request = HttpRequestBuilder::new();
+        // Bytes past the request already belong to the next pipelined one
+        socket_data.status.request = socket_data.status.request.next_request();
+        socket_data.status.server_selected = false;
+        socket_data.status.request_started = None;
         socket_data.status.response = None;
-        println!("Keeping connection alive for next request.");
+        socket_data.status.response_status = None;
+        socket_data.status.error_pending = false;
+        socket_data.status.route = None;
+        socket_data.status.read_buffer.reset();
+        socket_data.status.close_reason = None;
+        debug!("Keeping connection alive for next request.");
         Some(true)
     } else {
-        println!("Closing connection.");
+        debug!("Closing connection.");
+        let reason = match socket_data.status.last_request {
+            true => CloseReason::ServerClose,
+            false => CloseReason::Completed,
+        };
+        socket_data.status.closing(reason);
         let _ = socket_data.stream.shutdown(Shutdown::Both);
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::HttpClient;
+    use crate::models::SimpleResponse;
+    use crate::response::HttpResponseBuilder;
+    use crate::transport::{ScriptedTransport, WriteStep};
+    use crate::utils::session::SessionStore;
+    use mio::Token;
+
+    /// A connection whose request `head` is read, about to send `response`
+    fn socket(transport: ScriptedTransport, head: &str, response: &[u8]) -> SocketData {
+        let mut status = SocketStatus::new();
+        status.request.append(head.as_bytes().to_vec(), &status.head_limits).expect("request");
+        assert!(status.request.done());
+        status.response = Some(Box::new(SimpleResponse::new(response.to_vec())));
+        status.status = Status::Write;
+        SocketData {
+            stream: Box::new(transport),
+            status,
+            listener_token: Token(0),
+            session_store: SessionStore::new(),
+            http_client: HttpClient::new(),
+        }
+    }
+
+    #[test]
+    fn partial_write_resumes_where_it_stopped() {
+        let response = HttpResponseBuilder::ok().body(b"hello world".to_vec()).build();
+        let transport = ScriptedTransport::new()
+            .write(WriteStep::Accept(10))
+            .write(WriteStep::WouldBlock);
+        let output = transport.output();
+        let mut socket = socket(transport, "GET / HTTP/1.1\r\nHost: test\r\nConnection: keep-alive\r\n\r\n", &response);
+
+        assert_eq!(handle_write_state(&mut socket), Some(true));
+        assert_eq!(output.borrow().written, response[..10]);
+        assert_eq!(socket.status.status, Status::Write);
+
+        assert_eq!(handle_write_state(&mut socket), Some(false));
+        assert_eq!(output.borrow().written.len(), 10);
+
+        assert_eq!(handle_write_state(&mut socket), Some(true));
+        assert_eq!(output.borrow().written, response);
+        assert_eq!(socket.status.response_status, None);
+        assert_eq!(socket.status.status, Status::Read);
+        assert!(!output.borrow().shut_down);
+    }
+
+    #[test]
+    fn connection_close_shuts_the_socket_down() {
+        let response = HttpResponseBuilder::ok().body(b"bye".to_vec()).build();
+        let transport = ScriptedTransport::new();
+        let output = transport.output();
+        let mut socket = socket(transport, "GET / HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n", &response);
+
+        assert_eq!(handle_write_state(&mut socket), None);
+        assert_eq!(output.borrow().written, response);
+        assert!(output.borrow().shut_down);
+        assert_eq!(socket.status.close_reason, Some(CloseReason::Completed));
+    }
+}